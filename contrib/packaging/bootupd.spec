@@ -55,6 +55,8 @@ License:        Apache-2.0 AND (Apache-2.0 WITH LLVM-exception) AND BSD-3-Clause
 %{_libexecdir}/bootupd
 %{_prefix}/lib/bootupd/grub2-static/
 %{_unitdir}/bootloader-update.service
+%{_unitdir}/bootupd-update.service
+%{_unitdir}/bootupd-update.timer
 
 %prep
 %autosetup -n %{crate}-%{version} -p1 -Sgit -a1
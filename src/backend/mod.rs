@@ -1,3 +1,7 @@
 //! Internal logic for bootloader and system state manipulation.
 
 mod statefile;
+
+pub(crate) use statefile::{
+    lock_operation, operation_in_progress, set_wait_for_lock, waits_for_lock, StateLocked,
+};
@@ -1,13 +1,78 @@
 //! On-disk saved state.
+//!
+//! The state file of `/boot` is copied to the root of the ESP, and each copy
+//! is written along with its SHA-512 checksum.  If the state file of `/boot`
+//! is missing or doesn't parse, the copy is used instead, provided it
+//! matches its checksum, so that the system doesn't appear unmanaged; a
+//! state file of `/boot` which parses is always preferred, even with a stale
+//! checksum, so that the state is never rolled back to an older copy.
 
 use crate::model::SavedState;
+use crate::sha512string::SHA512String;
 use anyhow::{bail, Context, Result};
 use fn_error_context::context;
 use fs2::FileExt;
 use openat_ext::OpenatDirExt;
+use openssl::hash::{Hasher, MessageDigest};
 use std::fs::File;
 use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// System-wide lock taken by any operation modifying the bootloaders or the
+/// state, shared by `bootupctl`, the D-Bus daemon and installs.
+const LOCK_PATH: &str = "/run/bootupd.lock";
+
+/// Whether to wait for the lock rather than fail if it is held.
+static WAIT_FOR_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// The error when another process holds the write lock, e.g. a concurrent
+/// update.
+#[derive(Debug)]
+pub(crate) struct StateLocked;
+
+impl std::fmt::Display for StateLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Another bootupd operation is in progress")
+    }
+}
+
+impl std::error::Error for StateLocked {}
+
+/// Wait for the operations in progress to finish rather than fail with
+/// [`StateLocked`], e.g. for `bootupctl --wait`.
+pub(crate) fn set_wait_for_lock(wait: bool) {
+    WAIT_FOR_LOCK.store(wait, Ordering::SeqCst);
+}
+
+pub(crate) fn waits_for_lock() -> bool {
+    WAIT_FOR_LOCK.load(Ordering::SeqCst)
+}
+
+/// Take the system-wide operation lock, released when the returned file is
+/// closed.
+#[context("Locking {LOCK_PATH}")]
+pub(crate) fn lock_operation() -> Result<File> {
+    let lockfile = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK_PATH)?;
+    if waits_for_lock() {
+        if lockfile.try_lock_exclusive().is_err() {
+            log::info!("Waiting for another bootupd operation to finish");
+            lockfile.lock_exclusive()?;
+        }
+        return Ok(lockfile);
+    }
+    // Fail rather than wait, so that concurrent clients get a distinct error
+    match lockfile.try_lock_exclusive() {
+        Ok(()) => Ok(lockfile),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(StateLocked.into()),
+        Err(e) => Err(e.into()),
+    }
+}
 
 /// Suppress SIGTERM while active
 // TODO: In theory we could record if we got SIGTERM and exit
@@ -28,13 +93,112 @@ impl Drop for SignalTerminationGuard {
     }
 }
 
+/// Whether another process holds the operation lock; unlike taking it, this
+/// doesn't make concurrent operations fail.
+pub(crate) fn operation_in_progress() -> Result<bool> {
+    let st = match rustix::fs::stat(LOCK_PATH) {
+        Ok(st) => st,
+        Err(rustix::io::Errno::NOENT) => return Ok(false),
+        Err(e) => return Err(e).context(format!("stat {LOCK_PATH}")),
+    };
+    let locks = std::fs::read_to_string("/proc/locks")?;
+    Ok(lock_listed(
+        &locks,
+        rustix::fs::major(st.st_dev),
+        rustix::fs::minor(st.st_dev),
+        st.st_ino,
+    ))
+}
+
+/// Whether `locks`, in the format of `/proc/locks`, lists a lock held on the
+/// inode `ino` of the device `major:minor`.
+fn lock_listed(locks: &str, major: u32, minor: u32, ino: u64) -> bool {
+    let id = format!("{major:02x}:{minor:02x}:{ino}");
+    // Waiters are listed as `N: -> FLOCK ...`, shifting the fields
+    locks
+        .lines()
+        .any(|l| l.split_whitespace().nth(5) == Some(id.as_str()))
+}
+
+/// The suffix of the file with the checksum of each copy of the state file.
+const CHECKSUM_SUFFIX: &str = ".sha512";
+
+/// A state file loaded by `SavedState::load_migrated`.
+#[derive(Debug)]
+pub(crate) struct LoadedState {
+    pub(crate) state: SavedState,
+    /// The migrations applied to its layout, see `model::migrate_state`
+    pub(crate) migrations: Vec<&'static str>,
+    /// Whether a copy is missing, corrupted or out of date
+    pub(crate) needs_sync: bool,
+}
+
+fn checksum(data: &[u8]) -> Result<String> {
+    let mut h = Hasher::new(MessageDigest::sha512())?;
+    h.update(data)?;
+    Ok(SHA512String::from_hasher(&mut h).to_string())
+}
+
+/// Read the copy of the state file in `dir`, if any, and whether it
+/// matches its checksum (or has none, e.g. written by older versions).
+fn read_copy(dir: &openat::Dir) -> Result<Option<(String, bool)>> {
+    let Some(f) = dir.open_file_optional(SavedState::STATEFILE_NAME)? else {
+        return Ok(None);
+    };
+    let mut s = String::new();
+    std::io::BufReader::new(f).read_to_string(&mut s)?;
+    let sumname = format!("{}{CHECKSUM_SUFFIX}", SavedState::STATEFILE_NAME);
+    let verified = match dir.open_file_optional(&sumname)? {
+        Some(f) => {
+            let mut expected = String::new();
+            std::io::BufReader::new(f).read_to_string(&mut expected)?;
+            expected.trim() == checksum(s.as_bytes())?
+        }
+        None => true,
+    };
+    Ok(Some((s, verified)))
+}
+
+/// Atomically write the copy of the state file `data` in `dir`; the
+/// checksum first, so that an interrupted write leaves a mismatch.
+fn write_copy(dir: &openat::Dir, data: &[u8]) -> Result<()> {
+    let sumname = format!("{}{CHECKSUM_SUFFIX}", SavedState::STATEFILE_NAME);
+    let sum = checksum(data)?;
+    dir.write_file_with_sync(&sumname, 0o644, |w| {
+        w.write_all(format!("{sum}\n").as_bytes())
+    })?;
+    dir.write_file_with_sync(SavedState::STATEFILE_NAME, 0o644, |w| w.write_all(data))?;
+    Ok(())
+}
+
+/// The directory of the copy of the state file on the ESP, if it is
+/// mounted read-write at `/boot/efi`.
+fn copy_dir(sysroot: &openat::Dir, writable: bool) -> Result<Option<openat::Dir>> {
+    let Some(dir) = sysroot.sub_dir_optional(SavedState::STATEFILE_COPY_DIR)? else {
+        return Ok(None);
+    };
+    if !dir.exists("EFI")? {
+        return Ok(None);
+    }
+    if writable {
+        let fd = unsafe { rustix::fd::BorrowedFd::borrow_raw(dir.as_raw_fd()) };
+        let st = rustix::fs::fstatvfs(fd)?;
+        if st.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY) {
+            log::debug!("Not updating the copy of the state file on the read-only ESP");
+            return Ok(None);
+        }
+    }
+    Ok(Some(dir))
+}
+
 impl SavedState {
-    /// System-wide bootupd write lock (relative to sysroot).
-    const WRITE_LOCK_PATH: &'static str = "run/bootupd-lock";
     /// Top-level directory for statefile (relative to sysroot).
     pub(crate) const STATEFILE_DIR: &'static str = "boot";
     /// On-disk bootloader statefile, akin to a tiny rpm/dpkg database, stored in `/boot`.
     pub(crate) const STATEFILE_NAME: &'static str = "bootupd-state.json";
+    /// Where a copy of the statefile is kept (relative to sysroot), on the
+    /// ESP, so that losing one of them doesn't make the system unmanaged.
+    pub(crate) const STATEFILE_COPY_DIR: &'static str = "boot/efi";
 
     /// Try to acquire a system-wide lock to ensure non-conflicting state updates.
     ///
@@ -42,8 +206,7 @@ impl SavedState {
     /// ensures a single instance) this is a double check against other
     /// execution paths.
     pub(crate) fn acquire_write_lock(sysroot: openat::Dir) -> Result<StateLockGuard> {
-        let lockfile = sysroot.write_file(Self::WRITE_LOCK_PATH, 0o644)?;
-        lockfile.lock_exclusive()?;
+        let lockfile = lock_operation()?;
         let guard = StateLockGuard {
             sysroot,
             termguard: Some(SignalTerminationGuard::new()?),
@@ -69,30 +232,93 @@ impl SavedState {
         let sysroot = openat::Dir::open(root_path)
             .with_context(|| format!("opening sysroot '{}'", root_path.display()))?;
 
-        let statefile_path = Path::new(Self::STATEFILE_DIR).join(Self::STATEFILE_NAME);
-        let saved_state = if let Some(statusf) = sysroot.open_file_optional(&statefile_path)? {
-            let mut bufr = std::io::BufReader::new(statusf);
-            let mut s = String::new();
-            bufr.read_to_string(&mut s)?;
-            let state: serde_json::Result<SavedState> = serde_json::from_str(s.as_str());
-            let r = match state {
-                Ok(s) => s,
-                Err(orig_err) => {
-                    let state: serde_json::Result<crate::model_legacy::SavedState01> =
-                        serde_json::from_str(s.as_str());
-                    match state {
-                        Ok(s) => s.upconvert(),
-                        Err(_) => {
-                            return Err(orig_err.into());
-                        }
+        Ok(Self::load_migrated(&sysroot)?.map(|l| l.state))
+    }
+
+    /// Read the state file of `sysroot`, or its copy on the ESP if it is
+    /// missing or doesn't parse; also returns whether the copies differ.
+    ///
+    /// A primary that parses is always preferred, even if it doesn't match
+    /// its checksum: that is only a stale or torn `.sha512`, and falling back
+    /// to the copy would silently roll the state back.  The copy on the ESP,
+    /// on FAT, is only trusted if it matches its checksum.
+    fn read_reconciled(sysroot: &openat::Dir) -> Result<Option<(String, bool)>> {
+        let primary = match sysroot.sub_dir_optional(Self::STATEFILE_DIR)? {
+            Some(dir) => read_copy(&dir).and_then(|p| match p {
+                Some((s, verified)) => {
+                    serde_json::from_str::<serde_json::Value>(&s).context("Parsing")?;
+                    Ok(Some((s, verified)))
+                }
+                None => Ok(None),
+            }),
+            None => Ok(None),
+        };
+        let copy = copy_dir(sysroot, false)?.map(|dir| {
+            read_copy(&dir).and_then(|c| match c {
+                Some((_, false)) => bail!("Checksum mismatch"),
+                c => Ok(c.map(|(s, _)| s)),
+            })
+        });
+        let primary_path = Path::new(Self::STATEFILE_DIR).join(Self::STATEFILE_NAME);
+        let copy_path = Path::new(Self::STATEFILE_COPY_DIR).join(Self::STATEFILE_NAME);
+        match (primary, copy) {
+            (Ok(Some((s, verified))), copy) => {
+                if !verified {
+                    log::warn!("Checksum mismatch for /{}", primary_path.display());
+                }
+                let in_sync = match copy {
+                    None => true,
+                    Some(Ok(Some(c))) => c == s,
+                    Some(Ok(None)) => false,
+                    Some(Err(e)) => {
+                        log::warn!("Ignoring /{}: {e:#}", copy_path.display());
+                        false
+                    }
+                };
+                Ok(Some((s, !(verified && in_sync))))
+            }
+            (primary, Some(Ok(Some(c)))) => {
+                match primary {
+                    Ok(_) => log::warn!("State file missing, using /{}", copy_path.display()),
+                    Err(e) => {
+                        log::warn!("Invalid state file ({e:#}), using /{}", copy_path.display())
+                    }
+                }
+                Ok(Some((c, true)))
+            }
+            (Err(e), _) => Err(e),
+            (Ok(None), _) => Ok(None),
+        }
+    }
+
+    /// Load the state file of `sysroot`, from its copy on the ESP if it is
+    /// missing or doesn't parse, migrating it to the current schema version in
+    /// memory.
+    #[context("Loading saved state")]
+    pub(crate) fn load_migrated(sysroot: &openat::Dir) -> Result<Option<LoadedState>> {
+        let Some((s, needs_sync)) = Self::read_reconciled(sysroot)? else {
+            return Ok(None);
+        };
+        let mut value: serde_json::Value = serde_json::from_str(s.as_str())?;
+        let migrations = crate::model::migrate_state(&mut value)?;
+        let (state, migrations) = match serde_json::from_value::<SavedState>(value) {
+            Ok(state) => (state, migrations),
+            Err(orig_err) => {
+                let state: serde_json::Result<crate::model_legacy::SavedState01> =
+                    serde_json::from_str(s.as_str());
+                match state {
+                    Ok(s) => (s.upconvert(), vec!["convert the timestamps to UTC"]),
+                    Err(_) => {
+                        return Err(orig_err.into());
                     }
                 }
-            };
-            Some(r)
-        } else {
-            None
+            }
         };
-        Ok(saved_state)
+        Ok(Some(LoadedState {
+            state,
+            migrations,
+            needs_sync,
+        }))
     }
 
     /// Check whether statefile exists.
@@ -118,13 +344,96 @@ pub(crate) struct StateLockGuard {
 }
 
 impl StateLockGuard {
-    /// Atomically replace the on-disk state with a new version.
+    /// Atomically replace the on-disk state with a new version, then its
+    /// copy on the ESP.
     pub(crate) fn update_state(&mut self, state: &SavedState) -> Result<()> {
+        let data = serde_json::to_vec(state)?;
         let subdir = self.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
-        subdir.write_file_with_sync(SavedState::STATEFILE_NAME, 0o644, |w| -> Result<()> {
-            serde_json::to_writer(w, state)?;
-            Ok(())
-        })?;
+        write_copy(&subdir, &data)?;
+        let r = copy_dir(&self.sysroot, true).and_then(|d| match d {
+            Some(d) => write_copy(&d, &data),
+            None => Ok(()),
+        });
+        if let Err(e) = r {
+            log::warn!("Failed to update the copy of the state file on the ESP: {e:#}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redundant_state() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let p = td.path();
+        std::fs::create_dir_all(p.join(SavedState::STATEFILE_DIR))?;
+        std::fs::create_dir_all(p.join(SavedState::STATEFILE_COPY_DIR).join("EFI"))?;
+        let sysroot = openat::Dir::open(p)?;
+        assert!(SavedState::load_migrated(&sysroot)?.is_none());
+
+        let mut state = SavedState::default();
+        state.static_configs = Some(crate::model::ContentMetadata {
+            timestamp: Default::default(),
+            version: "1".into(),
+            signing_keys: Vec::new(),
+        });
+        SavedState::unlocked(openat::Dir::open(p)?)?.update_state(&state)?;
+        let primary = p
+            .join(SavedState::STATEFILE_DIR)
+            .join(SavedState::STATEFILE_NAME);
+        let copy = p
+            .join(SavedState::STATEFILE_COPY_DIR)
+            .join(SavedState::STATEFILE_NAME);
+        assert_eq!(std::fs::read(&primary)?, std::fs::read(&copy)?);
+        let loaded = SavedState::load_migrated(&sysroot)?.unwrap();
+        assert!(!loaded.needs_sync);
+        assert!(loaded.migrations.is_empty());
+
+        // A stale checksum doesn't roll the state back to the copy
+        let data = std::fs::read_to_string(&primary)?;
+        std::fs::write(&primary, data.replace("\"1\"", "\"2\""))?;
+        let loaded = SavedState::load_migrated(&sysroot)?.unwrap();
+        assert!(loaded.needs_sync);
+        assert_eq!(loaded.state.static_configs.unwrap().version, "2");
+
+        // Torn write
+        std::fs::write(&primary, &data[..data.len() / 2])?;
+        let loaded = SavedState::load_migrated(&sysroot)?.unwrap();
+        assert!(loaded.needs_sync);
+        assert_eq!(loaded.state.static_configs.unwrap().version, "1");
+
+        // The copy is only used if it matches its checksum
+        let copysum = copy.with_extension("json.sha512");
+        let copysum_data = std::fs::read(&copysum)?;
+        std::fs::write(&copysum, "sha512:0\n")?;
+        assert!(SavedState::load_migrated(&sysroot).is_err());
+        std::fs::write(&copysum, copysum_data)?;
+
+        std::fs::remove_file(&primary)?;
+        let loaded = SavedState::load_migrated(&sysroot)?.unwrap();
+        assert!(loaded.needs_sync);
+
+        // Written by an older version, without checksum nor copy
+        std::fs::write(&primary, &data)?;
+        std::fs::remove_file(&copy)?;
+        std::fs::remove_file(primary.with_extension("json.sha512"))?;
+        let loaded = SavedState::load_migrated(&sysroot)?.unwrap();
+        assert!(loaded.needs_sync);
+        assert_eq!(loaded.state.static_configs.unwrap().version, "1");
         Ok(())
     }
+
+    #[test]
+    fn test_lock_listed() {
+        let locks = "1: POSIX  ADVISORY  WRITE 812 00:19:1187 0 EOF\n\
+                     2: FLOCK  ADVISORY  WRITE 4242 00:1a:5678 0 EOF\n\
+                     2: -> FLOCK  ADVISORY  WRITE 4243 00:1a:5678 0 EOF\n";
+        assert!(lock_listed(locks, 0, 0x1a, 5678));
+        assert!(!lock_listed(locks, 0, 0x1a, 1187));
+        assert!(!lock_listed(locks, 0, 0x19, 5678));
+        assert!(!lock_listed("", 0, 0x1a, 5678));
+    }
 }
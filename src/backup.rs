@@ -0,0 +1,218 @@
+//! Archives of the installed bootloaders, for disaster recovery or to
+//! snapshot a system before an upgrade.
+//!
+//! An archive is a tarball holding a copy of the state file, the tracked
+//! files of the ESP under `EFI/`, and a manifest recording where the BIOS
+//! bootloader lives on each device along with its checksum.  Restoring
+//! writes the ESP content back (to every ESP, like updates) and the state
+//! file; the raw BIOS bootloader is only checked against the archive.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContentMetadata, SavedState};
+use crate::sha512string::SHA512String;
+use crate::util::CommandRunExt;
+
+/// The version of the archive layout
+const FORMAT_VERSION: u32 = 1;
+/// The archive manifest
+const MANIFEST: &str = "manifest.json";
+/// The directory with the tracked files of the ESP
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const EFI_CONTENT: &str = "EFI";
+
+/// The raw BIOS bootloader written by grub2-install on a device.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+struct CoreImg {
+    /// The `(offset, length)` regions of the device holding it
+    regions: Vec<(u64, u64)>,
+    /// Their checksum
+    sha512: SHA512String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Manifest {
+    version: u32,
+    timestamp: DateTime<Utc>,
+    /// The BIOS bootloader, keyed by device
+    #[serde(default)]
+    core_img: BTreeMap<String, CoreImg>,
+}
+
+/// Record the BIOS bootloader of the devices it was installed to.
+#[cfg(target_arch = "x86_64")]
+fn core_img_manifest(state: &SavedState) -> Result<BTreeMap<String, CoreImg>> {
+    let Some(checksums) = state
+        .installed
+        .get("BIOS")
+        .and_then(|bios| bios.raw_checksums.as_ref())
+    else {
+        return Ok(Default::default());
+    };
+    checksums
+        .keys()
+        .map(|device| {
+            let img = CoreImg {
+                regions: crate::bios::core_img_regions(device)?,
+                sha512: crate::bios::checksum_core_img(device)?,
+            };
+            Ok((device.clone(), img))
+        })
+        .collect()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn core_img_manifest(_state: &SavedState) -> Result<BTreeMap<String, CoreImg>> {
+    Ok(Default::default())
+}
+
+/// Warn about the BIOS bootloaders which changed since the archive was
+/// made, as they are not restored.
+#[cfg(target_arch = "x86_64")]
+fn check_core_img(core_img: &BTreeMap<String, CoreImg>) {
+    for (device, img) in core_img {
+        let found = std::fs::File::open(device)
+            .with_context(|| format!("opening {device}"))
+            .and_then(|mut f| crate::bios::checksum_regions(&mut f, &img.regions));
+        match found {
+            Ok(found) if found == img.sha512 => {}
+            Ok(_) => eprintln!(
+                "warning: The BIOS bootloader on {device} differs from the backup and is not restored"
+            ),
+            Err(e) => eprintln!("warning: Failed to check the BIOS bootloader on {device}: {e:#}"),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn check_core_img(core_img: &BTreeMap<String, CoreImg>) {
+    if !core_img.is_empty() {
+        eprintln!("warning: Not checking the BIOS bootloader on this architecture");
+    }
+}
+
+/// Write the archived ESP content back, updating its entry in `state`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn restore_efi(stagingd: &openat::Dir, state: &mut SavedState) -> Result<()> {
+    let Some(efi) = state.installed.get_mut("EFI") else {
+        return Ok(());
+    };
+    let current = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let content = stagingd.sub_dir(EFI_CONTENT)?;
+    *efi = crate::efi::Efi::default().restore(&content, efi, current.installed.get("EFI"))?;
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn restore_efi(_stagingd: &openat::Dir, _state: &mut SavedState) -> Result<()> {
+    Ok(())
+}
+
+/// Archive the installed bootloaders to `dest`.
+#[context("Backing up to {}", dest.display())]
+pub(crate) fn backup(dest: &Path) -> Result<()> {
+    let Some(state) = SavedState::load_from_disk("/")? else {
+        bail!("No components are installed");
+    };
+    let staging = tempfile::tempdir()?;
+    let stagingd = openat::Dir::open(staging.path())?;
+    stagingd.write_file_contents(
+        SavedState::STATEFILE_NAME,
+        0o644,
+        serde_json::to_vec(&state)?,
+    )?;
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if let Some(filetree) = state
+        .installed
+        .get("EFI")
+        .and_then(|efi| efi.filetree.as_ref())
+    {
+        let efi = crate::efi::Efi::default();
+        efi.ensure_mounted_esp(Path::new("/"))?;
+        let esp = efi.open_esp()?;
+        stagingd.create_dir(EFI_CONTENT, 0o700)?;
+        filetree.copy_files(&esp, &stagingd.sub_dir(EFI_CONTENT)?)?;
+    }
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        timestamp: Utc::now(),
+        core_img: core_img_manifest(&state)?,
+    };
+    stagingd.write_file_contents(MANIFEST, 0o644, serde_json::to_vec_pretty(&manifest)?)?;
+    Command::new("tar")
+        .arg("-C")
+        .arg(staging.path())
+        .arg("-cf")
+        .arg(dest)
+        .arg(".")
+        .run()?;
+    Ok(())
+}
+
+/// Restore the bootloaders archived in `src`; returns the restored versions.
+#[context("Restoring from {}", src.display())]
+pub(crate) fn restore(src: &Path) -> Result<BTreeMap<String, ContentMetadata>> {
+    let staging = tempfile::tempdir()?;
+    Command::new("tar")
+        .arg("-C")
+        .arg(staging.path())
+        .arg("-xf")
+        .arg(src)
+        .run()?;
+    let stagingd = openat::Dir::open(staging.path())?;
+    let manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(
+        stagingd
+            .open_file(MANIFEST)
+            .context("Not a bootupd backup")?,
+    ))?;
+    if manifest.version != FORMAT_VERSION {
+        bail!("Unsupported backup format version {}", manifest.version);
+    }
+    let mut state: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(
+        stagingd.open_file(SavedState::STATEFILE_NAME)?,
+    ))?;
+    // The archive may have been made by an older version
+    crate::model::migrate_state(&mut state)?;
+    let mut state: SavedState = serde_json::from_value(state)?;
+    log::debug!("Restoring backup made at {}", manifest.timestamp);
+
+    crate::util::ensure_writable_mount("/boot")?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    restore_efi(&stagingd, &mut state)?;
+    check_core_img(&manifest.core_img);
+    state_guard.update_state(&state)?;
+    Ok(state
+        .installed
+        .into_iter()
+        .map(|(name, inst)| (name, inst.meta))
+        .collect())
+}
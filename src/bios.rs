@@ -1,41 +1,203 @@
+use anyhow::Context;
 use anyhow::{bail, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
 #[cfg(target_arch = "powerpc64")]
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process::Command;
 
 use crate::blockdev;
 use crate::component::*;
+use crate::config::{BiosConfig, Config};
 use crate::model::*;
 use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::sha512string::SHA512String;
 
 // grub2-install file path
 pub(crate) const GRUB_BIN: &str = "usr/sbin/grub2-install";
 
+/// Size of a disk sector as used by the partition table
+#[cfg(target_arch = "x86_64")]
+const SECTOR_SIZE: u64 = 512;
+/// Size of the MBR boot code area, this is where GRUB's boot.img lives
+#[cfg(target_arch = "x86_64")]
+const MBR_BOOTCODE_SIZE: u64 = 440;
+/// Conventional end of the post-MBR gap (1MiB) where core.img is embedded
+/// on DOS partitioned disks
+#[cfg(target_arch = "x86_64")]
+const MBR_GAP_END: u64 = 2048 * SECTOR_SIZE;
+
+/// Hash the given `(offset, length)` regions of a file
+pub(crate) fn checksum_regions<F: Read + Seek>(
+    f: &mut F,
+    regions: &[(u64, u64)],
+) -> Result<SHA512String> {
+    let mut hasher =
+        Hasher::new(MessageDigest::sha512()).expect("openssl sha512 hasher creation failed");
+    for &(offset, len) in regions {
+        f.seek(SeekFrom::Start(offset))?;
+        let n = std::io::copy(&mut f.by_ref().take(len), &mut hasher)?;
+        if n != len {
+            bail!("Short read at offset {offset}: expected {len} bytes, got {n}");
+        }
+    }
+    Ok(SHA512String::from_hasher(&mut hasher))
+}
+
+/// Find the raw regions of `device` written by grub2-install: the MBR boot code,
+/// plus the embedded core.img; which lives in the BIOS boot partition on GPT, or
+/// otherwise in the gap between the MBR and the first partition.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn core_img_regions(device: &str) -> Result<Vec<(u64, u64)>> {
+    let mut regions = vec![(0, MBR_BOOTCODE_SIZE)];
+    let partitions = blockdev::partitions_of(device)?;
+    if let Some(bios_boot) = partitions
+        .iter()
+        .find(|p| p.parttype.as_str() == blockdev::BIOS_BOOT_TYPE_GUID)
+    {
+        regions.push((bios_boot.start * SECTOR_SIZE, bios_boot.size * SECTOR_SIZE));
+    } else {
+        let gap_end = partitions
+            .iter()
+            .map(|p| p.start * SECTOR_SIZE)
+            .min()
+            .unwrap_or(MBR_GAP_END)
+            .min(MBR_GAP_END);
+        if gap_end > SECTOR_SIZE {
+            regions.push((SECTOR_SIZE, gap_end - SECTOR_SIZE));
+        }
+    }
+    Ok(regions)
+}
+
+/// Compute a checksum of the BIOS bootloader installed on `device`.
+#[cfg(target_arch = "x86_64")]
+#[context("Computing checksum of BIOS bootloader on {device}")]
+pub(crate) fn checksum_core_img(device: &str) -> Result<SHA512String> {
+    let regions = core_img_regions(device)?;
+    let mut f = std::fs::File::open(device).with_context(|| format!("opening {device}"))?;
+    checksum_regions(&mut f, &regions)
+}
+
+/// What the MBR boot code of a device is.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, PartialEq, Eq)]
+enum BootCode {
+    /// No boot code at all
+    Empty,
+    /// GRUB's boot.img
+    Grub,
+    /// Something else, e.g. syslinux or another OS's loader
+    Foreign,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl BootCode {
+    fn classify(code: &[u8]) -> Self {
+        if code.iter().all(|&b| b == 0) {
+            Self::Empty
+        } else if code.windows(5).any(|w| w == b"GRUB ") {
+            // boot.img embeds its error message prefix
+            Self::Grub
+        } else {
+            Self::Foreign
+        }
+    }
+}
+
+/// Read and classify the MBR boot code of `device`.
+#[cfg(target_arch = "x86_64")]
+#[context("Reading boot code of {device}")]
+fn read_boot_code(device: &str) -> Result<BootCode> {
+    let mut f = std::fs::File::open(device)?;
+    let mut code = vec![0u8; MBR_BOOTCODE_SIZE as usize];
+    f.read_exact(&mut code)?;
+    Ok(BootCode::classify(&code))
+}
+
+/// Compute the checksums to be saved in the installed state, if supported on
+/// this architecture.
+fn raw_checksums_for<S: AsRef<str>>(
+    devices: &[S],
+) -> Result<Option<BTreeMap<String, SHA512String>>> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut r = BTreeMap::new();
+        for device in devices {
+            let device = device.as_ref();
+            r.insert(device.to_string(), checksum_core_img(device)?);
+        }
+        Ok(Some(r))
+    }
+    #[cfg(target_arch = "powerpc64")]
+    {
+        let mut r = BTreeMap::new();
+        for device in devices {
+            let device = device.as_ref();
+            r.insert(device.to_string(), checksum_prep(device)?);
+        }
+        Ok(Some(r))
+    }
+}
+
+/// Compute a checksum of the PReP partition of `device`, where grub2-install
+/// writes the GRUB core image.
+#[cfg(target_arch = "powerpc64")]
+#[context("Computing checksum of PReP partition of {device}")]
+pub(crate) fn checksum_prep(device: &str) -> Result<SHA512String> {
+    let prep = target_device(device)?;
+    let mut f = std::fs::File::open(&*prep).with_context(|| format!("opening {prep}"))?;
+    let len = f.seek(SeekFrom::End(0))?;
+    checksum_regions(&mut f, &[(0, len)])
+}
+
+/// The modules embedded by default: mdraid1x because it's needed by CoreOS's
+/// default of "install raw disk image", and part_gpt because in some cases
+/// probing of the partition map can fail such as in a container, but we
+/// always use GPT.
+#[cfg(target_arch = "x86_64")]
+const DEFAULT_MODULES: &[&str] = &["mdraid1x", "part_gpt"];
+
+/// The modules to embed with grub2-install: the default ones unless
+/// replaced, then those of `config` and `extra`.
+#[cfg(target_arch = "x86_64")]
+fn grub_modules(config: &BiosConfig, extra: &[String]) -> Vec<String> {
+    let mut r: Vec<String> = if config.replace_default_modules {
+        Vec::new()
+    } else {
+        DEFAULT_MODULES.iter().map(|m| m.to_string()).collect()
+    };
+    for module in config.modules.iter().chain(extra) {
+        if !r.contains(module) {
+            r.push(module.clone());
+        }
+    }
+    r
+}
+
 #[cfg(target_arch = "powerpc64")]
 fn target_device(device: &str) -> Result<Cow<str>> {
     const PREPBOOT_GUID: &str = "9E1A2D38-C612-4316-AA26-8B49521E5A8B";
     /// We make a best-effort to support MBR partitioning too.
     const PREPBOOT_MBR_TYPE: &str = "41";
 
-    // Here we use lsblk to see if the device has any partitions at all
-    let dev = bootc_blockdev::list_dev(device.into())?;
-    if dev.children.is_none() {
+    let partitions = blockdev::partitions_of(device)?;
+    if partitions.is_empty() {
         return Ok(device.into());
     };
-    // If it does, directly call `sfdisk` and bypass lsblk because inside a container
-    // we may not have all the cached udev state (that I think is in /run).
-    let device = bootc_blockdev::partitions_of(device.into())?;
-    let prepdev = device
-        .partitions
+    let prepdev = partitions
         .iter()
         .find(|p| matches!(p.parttype.as_str(), PREPBOOT_GUID | PREPBOOT_MBR_TYPE))
         .ok_or_else(|| {
             anyhow::anyhow!("Failed to find PReP partition with GUID {PREPBOOT_GUID}")
         })?;
-    Ok(prepdev.path().as_str().to_owned().into())
+    Ok(prepdev.node.clone().into())
 }
 
 #[derive(Default)]
@@ -58,8 +220,16 @@ impl Bios {
         }
     }
 
-    // Run grub2-install
-    fn run_grub_install(&self, dest_root: &str, device: &str) -> Result<()> {
+    // Run grub2-install, embedding `extra_modules` besides the configured ones
+    fn run_grub_install(
+        &self,
+        dest_root: &str,
+        device: &str,
+        extra_modules: &[String],
+    ) -> Result<()> {
+        let ids = blockdev::device_ids(device)?;
+        let config = Config::load(Path::new("/"))?;
+        config.bios.check_device(&ids)?;
         if !self.check_grub_modules()? {
             bail!("Failed to find grub2-modules");
         }
@@ -70,17 +240,20 @@ impl Bios {
 
         let mut cmd = Command::new(grub_install);
         let boot_dir = Path::new(dest_root).join("boot");
-        // We forcibly inject mdraid1x because it's needed by CoreOS's default of "install raw disk image"
-        // We also add part_gpt because in some cases probing of the partition map can fail such
-        // as in a container, but we always use GPT.
         #[cfg(target_arch = "x86_64")]
-        cmd.args(["--target", "i386-pc"])
-            .args(["--boot-directory", boot_dir.to_str().unwrap()])
-            .args(["--modules", "mdraid1x part_gpt"])
-            .arg(device);
+        {
+            let modules = grub_modules(&config.bios, extra_modules);
+            cmd.args(["--target", "i386-pc"])
+                .args(["--boot-directory", boot_dir.to_str().unwrap()]);
+            if !modules.is_empty() {
+                cmd.args(["--modules", &modules.join(" ")]);
+            }
+            cmd.arg(device);
+        }
 
         #[cfg(target_arch = "powerpc64")]
         {
+            let _ = extra_modules;
             let device = target_device(device)?;
             cmd.args(&["--target", "powerpc-ieee1275"])
                 .args(&["--boot-directory", boot_dir.to_str().unwrap()])
@@ -88,7 +261,14 @@ impl Bios {
                 .arg(&*device);
         }
 
+        // e.g. an eMMC boot partition given as --device, locked until the
+        // write is done
+        let unlocked = blockdev::is_emmc_boot_partition(device)
+            .then(|| blockdev::EmmcBootPartition::unlock(device))
+            .transpose()?;
+        crate::sdnotify::status(&format!("Running grub2-install on {device}"));
         let cmdout = cmd.output()?;
+        drop(unlocked);
         if !cmdout.status.success() {
             std::io::stderr().write_all(&cmdout.stderr)?;
             bail!("Failed to run {:?}", cmd);
@@ -96,13 +276,27 @@ impl Bios {
         Ok(())
     }
 
+    // Run grub2-install on each of the devices, e.g. all members of a RAID1 /boot
+    fn run_grub_install_all(
+        &self,
+        dest_root: &str,
+        devices: &[String],
+        extra_modules: &[String],
+    ) -> Result<()> {
+        for device in devices {
+            self.run_grub_install(dest_root, device, extra_modules)?;
+            log::debug!("Install grub modules on {device}");
+        }
+        Ok(())
+    }
+
     // check bios_boot partition on gpt type disk
     fn get_bios_boot_partition(&self) -> Option<String> {
-        match blockdev::get_single_device("/") {
-            Ok(device) => {
-                let bios_boot_part =
-                    blockdev::get_bios_boot_partition(&device).expect("get bios_boot part");
-                return bios_boot_part;
+        match blockdev::find_colocated_bios_boot("/") {
+            Ok(parts) => {
+                if let Some(part) = parts.into_iter().next() {
+                    return Some(part);
+                }
             }
             Err(e) => log::warn!("Get error: {}", e),
         }
@@ -120,29 +314,46 @@ impl Component for Bios {
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
-        _update_firmware: bool,
+        devices: &[String],
+        opts: &InstallComponentOptions,
     ) -> Result<InstalledContent> {
         let Some(meta) = get_component_update(src_root, self)? else {
             anyhow::bail!("No update metadata for component {} found", self.name());
         };
 
-        self.run_grub_install(dest_root, device)?;
+        self.run_grub_install_all(dest_root, devices, &opts.bios_modules)?;
+        let raw_checksums = raw_checksums_for(devices)?;
         Ok(InstalledContent {
             meta,
             filetree: None,
             adopted_from: None,
+            raw_checksums,
+            esps: None,
+            bios_modules: (!opts.bios_modules.is_empty()).then(|| opts.bios_modules.clone()),
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
         })
     }
 
-    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
         let grub_install = Path::new(sysroot_path).join(GRUB_BIN);
         if !grub_install.exists() {
             bail!("Failed to find {:?}", grub_install);
         }
 
-        // Query the rpm database and list the package and build times for /usr/sbin/grub2-install
-        let meta = packagesystem::query_files(sysroot_path, [&grub_install])?;
+        // Query the package database and list the package and build times for /usr/sbin/grub2-install
+        let meta =
+            packagesystem::query_payload(sysroot_path, [&grub_install], &grub_install, opts)?;
         write_update_metadata(sysroot_path, self, &meta)?;
         Ok(meta)
     }
@@ -153,7 +364,38 @@ impl Component for Bios {
             log::debug!("Skip BIOS adopt");
             return Ok(None);
         }
-        crate::component::query_adopt_state()
+        #[allow(unused_mut)]
+        let Some(mut adoptable) = crate::component::query_adopt_state()?
+        else {
+            return Ok(None);
+        };
+        #[cfg(target_arch = "x86_64")]
+        {
+            let devices = match blockdev::get_bootloader_devices("/") {
+                Ok(devices) => devices,
+                Err(e) => {
+                    log::debug!("Not inspecting the boot code: {e:#}");
+                    return Ok(Some(adoptable));
+                }
+            };
+            let mut empty = true;
+            for device in devices.iter() {
+                match read_boot_code(device)? {
+                    BootCode::Empty => {}
+                    BootCode::Grub => empty = false,
+                    BootCode::Foreign => {
+                        log::info!("Not adopting {device}, which has non-GRUB boot code");
+                        return Ok(None);
+                    }
+                }
+            }
+            // Nothing to adopt, e.g. a disk only booted via EFI; installing
+            // GRUB would be harmless, but isn't needed
+            if empty {
+                adoptable.confident = false;
+            }
+        }
+        Ok(Some(adoptable))
     }
 
     fn adopt_update(&self, _: &openat::Dir, update: &ContentMetadata) -> Result<InstalledContent> {
@@ -162,13 +404,30 @@ impl Component for Bios {
         };
 
         let target_root = "/";
-        let device = blockdev::get_single_device(&target_root)?;
-        self.run_grub_install(target_root, &device)?;
-        log::debug!("Install grub modules on {device}");
+        let devices = blockdev::get_bootloader_devices(&target_root)?;
+        // e.g. /boot on LVM or dm-crypt, which GRUB must unlock itself
+        let stack = blockdev::boot_block_stack(target_root)?;
+        if stack.is_encrypted() {
+            log::info!("/boot is encrypted, on {}", stack.disks.join(" "));
+        }
+        let extra_modules = stack.grub_modules();
+        self.run_grub_install_all(target_root, &devices, &extra_modules)?;
+        let raw_checksums = raw_checksums_for(&devices)?;
         Ok(InstalledContent {
             meta: update.clone(),
             filetree: None,
             adopted_from: Some(meta.version),
+            raw_checksums,
+            esps: None,
+            bios_modules: (!extra_modules.is_empty()).then_some(extra_modules),
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
         })
     }
 
@@ -176,29 +435,151 @@ impl Component for Bios {
         get_component_update(sysroot, self)
     }
 
-    fn run_update(&self, sysroot: &openat::Dir, _: &InstalledContent) -> Result<InstalledContent> {
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        _: ProgressFn,
+    ) -> Result<InstalledContent> {
         let updatemeta = self.query_update(sysroot)?.expect("update available");
         let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
         let dest_root = std::fs::read_link(dest_fd)?;
-        let device = blockdev::get_single_device(&dest_root)?;
+        let devices = blockdev::get_bootloader_devices(&dest_root)?;
 
         let dest_root = dest_root.to_string_lossy().into_owned();
-        self.run_grub_install(&dest_root, &device)?;
-        log::debug!("Install grub modules on {device}");
+        let extra_modules = current.bios_modules.clone().unwrap_or_default();
+        self.run_grub_install_all(&dest_root, &devices, &extra_modules)?;
+        let raw_checksums = raw_checksums_for(&devices)?;
 
         let adopted_from = None;
         Ok(InstalledContent {
             meta: updatemeta,
             filetree: None,
             adopted_from,
+            raw_checksums,
+            esps: None,
+            bios_modules: current.bios_modules.clone(),
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
         })
     }
 
-    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
-        Ok(ValidationResult::Skip)
+    fn plan_update(&self, sysroot: &openat::Dir, _: &InstalledContent) -> Result<Vec<String>> {
+        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        let dest_root = std::fs::read_link(dest_fd)?;
+        let devices = blockdev::get_bootloader_devices(&dest_root)?;
+        let r = devices
+            .iter()
+            .map(|device| format!("Run: /{GRUB_BIN} on {device}"))
+            .collect();
+        Ok(r)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        // Older installs don't record any checksums
+        let Some(expected) = current.raw_checksums.as_ref() else {
+            return Ok(ValidationResult::Skip);
+        };
+        let mut errs = Vec::new();
+        for (device, expected) in expected.iter() {
+            if !Path::new(device).exists() {
+                errs.push(ValidationError::new(ValidationErrorKind::Missing, device));
+                continue;
+            }
+            let Some(found) = raw_checksums_for(&[device])? else {
+                return Ok(ValidationResult::Skip);
+            };
+            if found.get(device) != Some(expected) {
+                errs.push(ValidationError::new(ValidationErrorKind::Modified, device));
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn repair(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<Repaired> {
+        let ValidationResult::Errors(errs) = self.validate(current)? else {
+            return Ok(Repaired {
+                inst: current.clone(),
+                repaired: Vec::new(),
+            });
+        };
+        // grub2-install writes the version of the booted deployment
+        if self.query_update(sysroot)?.as_ref() != Some(&current.meta) {
+            bail!(
+                "The installed version of {} is not available; run `bootupctl update`",
+                self.name()
+            );
+        }
+        let devices: Vec<String> = errs
+            .into_iter()
+            .filter(|e| matches!(e.kind, ValidationErrorKind::Modified))
+            .map(|e| e.path)
+            .collect();
+        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        let dest_root = std::fs::read_link(dest_fd)?;
+        let dest_root = dest_root.to_string_lossy().into_owned();
+        let extra_modules = current.bios_modules.clone().unwrap_or_default();
+        self.run_grub_install_all(&dest_root, &devices, &extra_modules)?;
+        let mut inst = current.clone();
+        if let (Some(checksums), Some(updated)) =
+            (inst.raw_checksums.as_mut(), raw_checksums_for(&devices)?)
+        {
+            checksums.extend(updated);
+        }
+        Ok(Repaired {
+            inst,
+            repaired: devices,
+        })
     }
 
     fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
         Ok(None)
     }
 }
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_checksum_regions() -> Result<()> {
+        let mut data = vec![0u8; 4096];
+        let mut f = Cursor::new(data.clone());
+        let regions = [(0, MBR_BOOTCODE_SIZE), (SECTOR_SIZE, 1024)];
+        let orig = checksum_regions(&mut f, &regions)?;
+        // Changes outside of the regions are ignored
+        data[MBR_BOOTCODE_SIZE as usize] = 0xff;
+        data[3000] = 0xff;
+        let mut f = Cursor::new(data.clone());
+        assert_eq!(orig, checksum_regions(&mut f, &regions)?);
+        // But changes inside are not
+        data[SECTOR_SIZE as usize + 1] = 0xff;
+        let mut f = Cursor::new(data.clone());
+        assert_ne!(orig, checksum_regions(&mut f, &regions)?);
+        // Reading past the end is an error
+        let mut f = Cursor::new(data);
+        assert!(checksum_regions(&mut f, &[(4000, 512)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_code() {
+        let mut code = vec![0u8; MBR_BOOTCODE_SIZE as usize];
+        assert_eq!(BootCode::classify(&code), BootCode::Empty);
+        code[..3].copy_from_slice(&[0xeb, 0x63, 0x90]);
+        assert_eq!(BootCode::classify(&code), BootCode::Foreign);
+        code[0x180..0x18e].copy_from_slice(b"GRUB \0Geom\0Hard");
+        assert_eq!(BootCode::classify(&code), BootCode::Grub);
+    }
+}
@@ -1,10 +1,248 @@
+//! The disks and partitions backing the filesystems.
+//!
+//! Device mapper and MD RAID devices are walked down to their partitions
+//! through `/sys/class/block/*/slaves`, so that e.g. `/boot` on LVM over
+//! LUKS resolves to every physical disk of the volume group, while a
+//! dm-multipath map is itself the disk, so that it is only written once.
+//! Partition tables are read from the disks directly, and disk identifiers
+//! from the udev database or sysfs, so that this works in minimal
+//! containers without util-linux; sfdisk and lsblk are only fallbacks.
+
 use camino::Utf8Path;
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use anyhow::{bail, Context, Result};
-use bootc_blockdev::PartitionTable;
 use fn_error_context::context;
 
+use crate::util::CommandRunExt;
+
+/// Where the kernel exposes the block devices
+const SYSFS: &str = "/sys";
+/// The udev database, with the properties of each device
+const UDEV_DATA: &str = "/run/udev/data";
+/// The unit of the `start` and `size` attributes of partitions in sysfs
+const SYSFS_SECTOR_SIZE: u64 = 512;
+
+/// Returns the sysfs directory of the block device `device`, e.g.
+/// `/sys/devices/.../block/sda/sda2` for `/dev/sda2`.
+fn sysfs_dir(sysfs: &Path, device: &str) -> Result<PathBuf> {
+    let path = Path::new(device)
+        .canonicalize()
+        .with_context(|| format!("canonicalizing {device}"))?;
+    let Some(name) = path.file_name() else {
+        bail!("Invalid block device {device}");
+    };
+    let dir = sysfs.join("class/block").join(name);
+    dir.canonicalize()
+        .with_context(|| format!("resolving {dir:?}"))
+}
+
+/// Read the sysfs attribute `path`; returns `None` if it doesn't exist or
+/// is empty.
+fn read_attr(path: &Path) -> Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s.trim().to_string()).filter(|s| !s.is_empty())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("reading {path:?}")),
+    }
+}
+
+/// Read the properties (`E:` lines) of the block device of sysfs
+/// directory `dir` from the udev database in `udev_data`; returns `None`
+/// without a database, e.g. in a container.
+fn udev_properties(udev_data: &Path, dir: &Path) -> Result<Option<BTreeMap<String, String>>> {
+    let Some(devnum) = read_attr(&dir.join("dev"))? else {
+        return Ok(None);
+    };
+    let path = udev_data.join(format!("b{devnum}"));
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {path:?}")),
+    };
+    let props = data
+        .lines()
+        .filter_map(|l| l.strip_prefix("E:")?.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    Ok(Some(props))
+}
+
+/// The layers of block devices backing a filesystem.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct BlockStack {
+    /// The whole disks at the bottom, e.g. `/dev/sda`
+    pub(crate) disks: Vec<String>,
+    /// The device mapper UUIDs of the layers above them, e.g.
+    /// `CRYPT-LUKS2-<uuid>-luks-<uuid>` for dm-crypt or `LVM-<uuid>`
+    pub(crate) dm_uuids: Vec<String>,
+}
+
+impl BlockStack {
+    /// Returns `true` if a layer is a dm-crypt mapping.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.dm_uuids.iter().any(|u| u.starts_with("CRYPT-"))
+    }
+
+    /// The GRUB modules needed to read the filesystem on top of the stack,
+    /// besides the default ones.
+    pub(crate) fn grub_modules(&self) -> Vec<String> {
+        let mut modules = Vec::new();
+        let mut add = |m: &str| {
+            if !modules.iter().any(|n| n == m) {
+                modules.push(m.to_string());
+            }
+        };
+        for uuid in self.dm_uuids.iter() {
+            if uuid.starts_with("CRYPT-LUKS2-") {
+                add("cryptodisk");
+                add("luks2");
+            } else if uuid.starts_with("CRYPT-LUKS1-") {
+                add("cryptodisk");
+                add("luks");
+            } else if uuid.starts_with("LVM-") {
+                add("lvm");
+            }
+        }
+        modules
+    }
+}
+
+/// Returns `true` if `uuid` is the device mapper UUID of a multipath map,
+/// i.e. of a disk reachable through several paths.
+fn is_multipath_map(uuid: &str) -> bool {
+    uuid.starts_with("mpath-")
+}
+
+/// Add to `stack` the block device `name` (e.g. `sda2` or `dm-0`) of
+/// `sysfs`.  Device mapper (e.g. LVM or dm-crypt) and MD RAID devices are
+/// walked down through their `slaves/` to the physical partitions; a
+/// partition resolves to its parent disk.  Multipath maps are disks in
+/// themselves: their paths (`sd*` devices) are not walked, so that each
+/// disk is written once, through the map.
+fn walk_block_stack(sysfs: &Path, name: &str, stack: &mut BlockStack) -> Result<()> {
+    let path = sysfs.join("class/block").join(name);
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("resolving {path:?}"))?;
+    let dm_uuid = path.join("dm/uuid");
+    if dm_uuid.exists() {
+        let uuid = std::fs::read_to_string(&dm_uuid)
+            .with_context(|| format!("reading {dm_uuid:?}"))?
+            .trim()
+            .to_string();
+        log::debug!("{name} is a device mapper device: {uuid}");
+        if is_multipath_map(&uuid) {
+            let dm_name = path.join("dm/name");
+            let dm_name = std::fs::read_to_string(&dm_name)
+                .with_context(|| format!("reading {dm_name:?}"))?;
+            let disk = format!("/dev/mapper/{}", dm_name.trim());
+            if !stack.disks.contains(&disk) {
+                stack.disks.push(disk);
+            }
+            return Ok(());
+        }
+        stack.dm_uuids.push(uuid);
+    }
+    let slaves = path.join("slaves");
+    let mut slave_names = Vec::new();
+    if slaves.exists() {
+        for entry in std::fs::read_dir(&slaves).with_context(|| format!("reading {slaves:?}"))? {
+            slave_names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+    }
+    if !slave_names.is_empty() {
+        slave_names.sort();
+        for slave in slave_names {
+            walk_block_stack(sysfs, &slave, stack)?;
+        }
+        return Ok(());
+    }
+    let disk = if path.join("partition").exists() {
+        path.parent()
+            .and_then(|p| p.file_name())
+            .ok_or_else(|| anyhow::anyhow!("Failed to find the disk of {name}"))?
+    } else {
+        path.file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid block device {path:?}"))?
+    };
+    let disk = format!("/dev/{}", disk.to_string_lossy());
+    if !stack.disks.contains(&disk) {
+        stack.disks.push(disk);
+    }
+    Ok(())
+}
+
+/// Find the block devices backing `device` (e.g. `/dev/mapper/vg-root` or
+/// `/dev/mapper/luks-<uuid>`), walking through LVM, dm-crypt and RAID.
+#[context("Inspecting block devices of {device}")]
+pub(crate) fn block_stack(device: &str) -> Result<BlockStack> {
+    let path = Path::new(device)
+        .canonicalize()
+        .with_context(|| format!("canonicalizing {device}"))?;
+    let Some(name) = path.file_name() else {
+        bail!("Invalid block device {device}");
+    };
+    let mut stack = BlockStack::default();
+    walk_block_stack(Path::new(SYSFS), &name.to_string_lossy(), &mut stack)?;
+    Ok(stack)
+}
+
+/// Add to `r` the partitions below the block device of sysfs directory
+/// `dir`, walking down through its `slaves/`.
+fn walk_partitions(dir: &Path, r: &mut Vec<String>) -> Result<()> {
+    if dir.join("partition").exists() {
+        let name = dir
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid block device {dir:?}"))?;
+        r.push(format!("/dev/{}", name.to_string_lossy()));
+        return Ok(());
+    }
+    let slaves = dir.join("slaves");
+    if !slaves.exists() {
+        return Ok(());
+    }
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&slaves).with_context(|| format!("reading {slaves:?}"))? {
+        names.push(entry?.file_name());
+    }
+    names.sort();
+    for name in names {
+        let path = slaves.join(name);
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("resolving {path:?}"))?;
+        walk_partitions(&path, r)?;
+    }
+    Ok(())
+}
+
+/// Find the partitions backing the block device `device`, e.g. the
+/// members of a RAID1 array.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+#[context("Finding partitions of {device}")]
+pub(crate) fn backing_partitions(device: &str) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    walk_partitions(&sysfs_dir(Path::new(SYSFS), device)?, &mut r)?;
+    Ok(r)
+}
+
+/// Find the whole disks backing the block device `device`.
+pub(crate) fn find_parent_disks(device: &str) -> Result<Vec<String>> {
+    Ok(block_stack(device)?.disks)
+}
+
+/// Inspect the block devices backing `/boot` of the target root.
+#[context("Inspecting block devices of boot")]
+pub(crate) fn boot_block_stack<P: AsRef<Path>>(target_root: P) -> Result<BlockStack> {
+    let bootdir = openat::Dir::open(&target_root.as_ref().join("boot"))?;
+    let fsinfo = crate::filesystem::inspect_filesystem(&bootdir, ".")?;
+    block_stack(&fsinfo.source)
+}
+
 #[context("get parent devices from mount point boot")]
 pub fn get_devices<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
     let target_root = target_root.as_ref();
@@ -16,13 +254,17 @@ pub fn get_devices<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
     // Run findmnt to get the source path of mount point boot
     let fsinfo = crate::filesystem::inspect_filesystem(&bootdir, ".")?;
     // Find the parent devices of the source path
-    let parent_devices = bootc_blockdev::find_parent_devices(&fsinfo.source)
+    let parent_devices = find_parent_disks(&fsinfo.source)
         .with_context(|| format!("while looking for backing devices of {}", fsinfo.source))?;
     log::debug!("Find parent devices: {parent_devices:?}");
     Ok(parent_devices)
 }
 
 // Get single device for the target root
+#[cfg_attr(
+    not(any(target_arch = "aarch64", target_arch = "riscv64")),
+    allow(dead_code)
+)]
 pub fn get_single_device<P: AsRef<Path>>(target_root: P) -> Result<String> {
     let mut devices = get_devices(&target_root)?.into_iter();
     let Some(parent) = devices.next() else {
@@ -35,20 +277,336 @@ pub fn get_single_device<P: AsRef<Path>>(target_root: P) -> Result<String> {
     Ok(parent)
 }
 
+/// Get all the parent disks of `/boot` for the target root on which a
+/// bootloader should be installed.  For a single disk this is just that disk;
+/// for e.g. a RAID1 `/boot` this is every member of the array, so that a
+/// failed disk does not leave the machine unbootable.
+#[context("Finding bootloader target devices")]
+pub fn get_bootloader_devices<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
+    let mut devices = get_devices(&target_root)?;
+    devices.sort();
+    devices.dedup();
+    if devices.is_empty() {
+        bail!("Failed to find parent device");
+    }
+    if devices.len() > 1 {
+        log::info!("Found multiple parent devices: {}", devices.join(" "));
+    }
+    Ok(devices)
+}
+
+/// A partition of a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Partition {
+    /// The device path, e.g. `/dev/sda2`
+    pub(crate) node: String,
+    /// The start, in 512 byte sectors
+    pub(crate) start: u64,
+    /// The size, in 512 byte sectors
+    pub(crate) size: u64,
+    /// The GPT partition type GUID in upper case, or the MBR partition
+    /// type in hex (e.g. `41`)
+    pub(crate) parttype: String,
+}
+
+/// Signature of the GPT header
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Number of the primary MBR partitions
+const MBR_PARTITIONS: u32 = 4;
+/// The GPT partition attribute of partitions booted by legacy BIOS boot code
+const GPT_LEGACY_BIOS_BOOTABLE: u64 = 1 << 2;
+/// The boot indicator of the active MBR partition
+const MBR_ACTIVE: u8 = 0x80;
+
+/// Format the mixed-endian GUID `b` as a string, e.g.
+/// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+fn format_guid(b: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{}-{}",
+        u32::from_le_bytes(b[0..4].try_into().unwrap()),
+        u16::from_le_bytes(b[4..6].try_into().unwrap()),
+        u16::from_le_bytes(b[6..8].try_into().unwrap()),
+        hex::encode_upper(&b[8..10]),
+        hex::encode_upper(&b[10..16]),
+    )
+}
+
+/// An entry of a partition table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TableEntry {
+    /// The GPT partition type GUID, or the MBR partition type in hex
+    parttype: String,
+    /// Whether the partition is legacy BIOS bootable on GPT, or active on MBR
+    bootable: bool,
+}
+
+/// The partition table of a disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PartitionTable {
+    gpt: bool,
+    /// The entries by partition number
+    entries: BTreeMap<u32, TableEntry>,
+}
+
+/// Read the GPT or MBR partition table of a disk with logical sectors of
+/// `sector_size` bytes.
+fn read_partition_table<F: Read + Seek>(disk: &mut F, sector_size: u64) -> Result<PartitionTable> {
+    let mut header = vec![0u8; 92];
+    disk.seek(SeekFrom::Start(sector_size))?;
+    disk.read_exact(&mut header)?;
+    let mut entries = BTreeMap::new();
+    if header.starts_with(GPT_SIGNATURE) {
+        let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+        let count = u32::from_le_bytes(header[80..84].try_into().unwrap());
+        let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as usize;
+        // The UEFI specification requires 128 * 2^n byte entries
+        if entry_size == 0 || entry_size % 128 != 0 || entry_size > 4096 || count > 1024 {
+            bail!("Invalid GPT header");
+        }
+        let len = entry_size
+            .checked_mul(count as usize)
+            .ok_or_else(|| anyhow::anyhow!("Invalid GPT header"))?;
+        let offset = entries_lba
+            .checked_mul(sector_size)
+            .ok_or_else(|| anyhow::anyhow!("Invalid GPT partition entries LBA"))?;
+        let mut data = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut data)?;
+        for (i, entry) in data.chunks_exact(entry_size).enumerate() {
+            let parttype = &entry[0..16];
+            if parttype.iter().any(|&b| b != 0) {
+                let attrs = u64::from_le_bytes(entry[48..56].try_into().unwrap());
+                let entry = TableEntry {
+                    parttype: format_guid(parttype),
+                    bootable: attrs & GPT_LEGACY_BIOS_BOOTABLE != 0,
+                };
+                entries.insert(i as u32 + 1, entry);
+            }
+        }
+        return Ok(PartitionTable { gpt: true, entries });
+    }
+    let mut mbr = [0u8; 512];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut mbr)?;
+    if mbr[510..512] != [0x55, 0xaa] {
+        bail!("No partition table found");
+    }
+    for i in 0..MBR_PARTITIONS {
+        let entry = &mbr[446 + i as usize * 16..][..16];
+        if entry[4] != 0 {
+            let entry = TableEntry {
+                parttype: format!("{:x}", entry[4]),
+                bootable: entry[0] == MBR_ACTIVE,
+            };
+            entries.insert(i + 1, entry);
+        }
+    }
+    Ok(PartitionTable {
+        gpt: false,
+        entries,
+    })
+}
+
+/// The logical sector size of the disk of sysfs directory `dir`.
+fn sector_size(dir: &Path) -> Result<u64> {
+    Ok(read_attr(&dir.join("queue/logical_block_size"))?
+        .map(|s| s.parse())
+        .transpose()?
+        .unwrap_or(SYSFS_SECTOR_SIZE))
+}
+
+/// Read the partitions of `device` from `sysfs` and its partition table.
+fn read_partitions(sysfs: &Path, device: &str) -> Result<Vec<Partition>> {
+    let dir = sysfs_dir(sysfs, device)?;
+    let mut partitions = Vec::new();
+    for entry in std::fs::read_dir(&dir).with_context(|| format!("reading {dir:?}"))? {
+        let path = entry?.path();
+        let Some(number) = read_attr(&path.join("partition"))? else {
+            continue;
+        };
+        let number: u32 = number.parse().context("parsing partition number")?;
+        let attr = |name| -> Result<u64> {
+            read_attr(&path.join(name))?
+                .ok_or_else(|| anyhow::anyhow!("Missing {name} of {path:?}"))?
+                .parse()
+                .with_context(|| format!("parsing {name} of {path:?}"))
+        };
+        let (start, size) = (attr("start")?, attr("size")?);
+        // SAFETY: read_dir() returns paths with a file name
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        partitions.push((number, name, start, size));
+    }
+    if partitions.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut f = std::fs::File::open(device).with_context(|| format!("opening {device}"))?;
+    let mut entries = read_partition_table(&mut f, sector_size(&dir)?)?.entries;
+    partitions.sort();
+    partitions
+        .into_iter()
+        .map(|(number, name, start, size)| {
+            // e.g. a logical partition of an extended MBR partition
+            let Some(entry) = entries.remove(&number) else {
+                bail!("Failed to find the type of partition {number}");
+            };
+            Ok(Partition {
+                node: format!("/dev/{name}"),
+                start,
+                size,
+                parttype: entry.parttype,
+            })
+        })
+        .collect()
+}
+
+/// List the partitions of the disk `device`, from sysfs and its partition
+/// table, or else with sfdisk.
+#[context("Listing partitions of {device}")]
+pub(crate) fn partitions_of(device: &str) -> Result<Vec<Partition>> {
+    match read_partitions(Path::new(SYSFS), device) {
+        Ok(r) => return Ok(r),
+        Err(e) => log::debug!("Falling back to sfdisk: {e:#}"),
+    }
+    let table = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
+    Ok(table
+        .partitions
+        .into_iter()
+        .map(|p| Partition {
+            node: p.node,
+            start: p.start,
+            size: p.size,
+            parttype: p.parttype,
+        })
+        .collect())
+}
+
+/// The disk (e.g. `/dev/sda`) and number of the partition `partition`.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+pub(crate) fn partition_location(partition: &str) -> Result<(String, u32)> {
+    read_partition_location(Path::new(SYSFS), partition)
+}
+
+fn read_partition_location(sysfs: &Path, partition: &str) -> Result<(String, u32)> {
+    let dir = sysfs_dir(sysfs, partition)?;
+    let Some(number) = read_attr(&dir.join("partition"))? else {
+        bail!("{partition} is not a partition");
+    };
+    let number = number.parse().context("parsing partition number")?;
+    let disk = dir
+        .parent()
+        .and_then(|p| p.file_name())
+        .ok_or_else(|| anyhow::anyhow!("Failed to find the disk of {partition}"))?;
+    Ok((format!("/dev/{}", disk.to_string_lossy()), number))
+}
+
+/// Read the partition table of the disk of `partition`, returning the disk,
+/// the table and the number of the partition.
+fn read_table_of(sysfs: &Path, partition: &str) -> Result<(String, PartitionTable, u32)> {
+    let (disk, number) = read_partition_location(sysfs, partition)?;
+    let sector_size = sector_size(&sysfs_dir(sysfs, &disk)?)?;
+    let mut f = std::fs::File::open(&disk).with_context(|| format!("opening {disk}"))?;
+    let table = read_partition_table(&mut f, sector_size)?;
+    Ok((disk, table, number))
+}
+
+/// Returns `true` if `partition` is legacy BIOS bootable on GPT, or the
+/// active partition on MBR: the one booted by the MBR boot code of syslinux.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+#[context("Checking whether {partition} is bootable")]
+pub(crate) fn is_legacy_bootable(partition: &str) -> Result<bool> {
+    let (_, table, number) = read_table_of(Path::new(SYSFS), partition)?;
+    Ok(table.entries.get(&number).is_some_and(|e| e.bootable))
+}
+
+/// Mark `partition` legacy BIOS bootable on GPT, or make it the active
+/// partition on MBR.
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
+#[context("Marking {partition} bootable")]
+pub(crate) fn set_legacy_bootable(partition: &str) -> Result<()> {
+    let (disk, table, number) = read_table_of(Path::new(SYSFS), partition)?;
+    if table.entries.get(&number).is_some_and(|e| e.bootable) {
+        return Ok(());
+    }
+    let number = number.to_string();
+    if !table.gpt {
+        return Command::new("sfdisk")
+            .args(["--activate", disk.as_str(), number.as_str()])
+            .run();
+    }
+    // Setting the attributes replaces them all
+    let out = Command::new("sfdisk")
+        .args(["--part-attrs", disk.as_str(), number.as_str()])
+        .output()?;
+    if !out.status.success() {
+        bail!(
+            "Failed to read the attributes of {partition}: {}",
+            out.status
+        );
+    }
+    let mut attrs: Vec<String> = String::from_utf8(out.stdout)?
+        .split([',', ' ', '\n'])
+        .filter(|a| !a.is_empty())
+        .map(String::from)
+        .collect();
+    attrs.push("LegacyBIOSBootable".into());
+    Command::new("sfdisk")
+        .args([
+            "--part-attrs",
+            disk.as_str(),
+            number.as_str(),
+            &attrs.join(","),
+        ])
+        .run()
+}
+
+/// GPT partition type of the EFI System Partition
+pub(crate) const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+
 /// Find esp partition on the same device
 /// using sfdisk to get partitiontable
-#[allow(dead_code)]
 pub fn get_esp_partition(device: &str) -> Result<Option<String>> {
-    const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
-    let device_info: PartitionTable = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
-    let esp = device_info
-        .partitions
+    Ok(get_esp_partitions(device)?.into_iter().next())
+}
+
+/// Find all the esp partitions on a device
+pub fn get_esp_partitions(device: &str) -> Result<Vec<String>> {
+    let esps = partitions_of(device)?
         .into_iter()
-        .find(|p| p.parttype.as_str() == ESP_TYPE_GUID);
-    if let Some(esp) = esp {
-        return Ok(Some(esp.node));
+        .filter(|p| p.parttype.eq_ignore_ascii_case(ESP_TYPE_GUID))
+        .map(|p| p.node)
+        .collect();
+    Ok(esps)
+}
+
+/// Find the ESPs, by GPT partition type, on all the disks backing the root,
+/// `/sysroot` and `/boot` filesystems of the target root.
+#[context("Finding ESPs")]
+pub fn find_esps<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
+    let target_root = target_root.as_ref();
+    let mounts = crate::filesystem::mountinfo()?;
+    let mut devices = Vec::new();
+    for dir in [None, Some("sysroot"), Some("boot")] {
+        let mountpoint = dir.map_or_else(|| target_root.to_owned(), |d| target_root.join(d));
+        let Some(mount) = crate::filesystem::mount_at(&mounts, &mountpoint.to_string_lossy())
+        else {
+            continue;
+        };
+        // e.g. overlayfs or composefs for the root
+        if !mount.source.starts_with("/dev/") {
+            continue;
+        }
+        let parents = find_parent_disks(&mount.source)
+            .with_context(|| format!("while looking for backing devices of {}", mount.source))?;
+        devices.extend(parents);
     }
-    Ok(None)
+    devices.sort();
+    devices.dedup();
+    let mut esps = Vec::new();
+    for device in devices {
+        esps.extend(get_esp_partitions(&device)?);
+    }
+    log::debug!("Found ESPs: {esps:?}");
+    Ok(esps)
 }
 
 /// Find all ESP partitions on the devices with mountpoint boot
@@ -68,12 +626,12 @@ pub fn find_colocated_esps<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>
     Ok(esps)
 }
 
+/// GPT partition type of the BIOS boot partition
+pub(crate) const BIOS_BOOT_TYPE_GUID: &str = "21686148-6449-6E6F-744E-656564454649";
+
 /// Find bios_boot partition on the same device
 pub fn get_bios_boot_partition(device: &str) -> Result<Option<String>> {
-    const BIOS_BOOT_TYPE_GUID: &str = "21686148-6449-6E6F-744E-656564454649";
-    let device_info = bootc_blockdev::partitions_of(Utf8Path::new(device))?;
-    let bios_boot = device_info
-        .partitions
+    let bios_boot = partitions_of(device)?
         .into_iter()
         .find(|p| p.parttype.as_str() == BIOS_BOOT_TYPE_GUID);
     if let Some(bios_boot) = bios_boot {
@@ -83,7 +641,7 @@ pub fn get_bios_boot_partition(device: &str) -> Result<Option<String>> {
 }
 
 /// Find all bios_boot partitions on the devices with mountpoint boot
-#[allow(dead_code)]
+#[cfg_attr(not(target_arch = "x86_64"), allow(dead_code))]
 pub fn find_colocated_bios_boot<P: AsRef<Path>>(target_root: P) -> Result<Vec<String>> {
     // first, get the parent device
     let devices =
@@ -99,3 +657,547 @@ pub fn find_colocated_bios_boot<P: AsRef<Path>>(target_root: P) -> Result<Vec<St
     log::debug!("Find bios_boot partitions: {bios_boots:?}");
     Ok(bios_boots)
 }
+
+/// Returns `true` if the partition `partition` is an ESP.
+#[context("Checking partition type of {partition}")]
+pub(crate) fn is_esp(partition: &str) -> Result<bool> {
+    for device in find_parent_disks(partition)? {
+        if partitions_of(&device)?
+            .iter()
+            .any(|p| p.node == partition && p.parttype.eq_ignore_ascii_case(ESP_TYPE_GUID))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// GPT partition type of the Extended Boot Loader partition (XBOOTLDR)
+pub(crate) const XBOOTLDR_TYPE_GUID: &str = "BC13C2FF-59E6-4262-A352-B275FD6F7172";
+
+/// Returns `true` if the partition `partition` is an XBOOTLDR partition.
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )),
+    allow(dead_code)
+)]
+#[context("Checking partition type of {partition}")]
+pub(crate) fn is_xbootldr(partition: &str) -> Result<bool> {
+    for device in find_parent_disks(partition)? {
+        if partitions_of(&device)?
+            .iter()
+            .any(|p| p.node == partition && p.parttype.eq_ignore_ascii_case(XBOOTLDR_TYPE_GUID))
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The identifiers a disk can be referred to by in the configuration.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct DeviceIds {
+    /// The canonical path, e.g. `/dev/sda`
+    pub(crate) path: String,
+    pub(crate) wwn: Option<String>,
+    pub(crate) serial: Option<String>,
+}
+
+/// Convert a sysfs `wwid` to the WWN format of udev and lsblk, e.g.
+/// `naa.5000c500a1b2c3d4` to `0x5000c500a1b2c3d4`.
+fn wwid_to_wwn(wwid: &str) -> String {
+    match wwid.strip_prefix("naa.") {
+        Some(naa) => format!("0x{}", naa.to_ascii_lowercase()),
+        None => wwid.to_string(),
+    }
+}
+
+/// Query the WWN and serial number of `device` from the udev database in
+/// `udev_data`, or else from `sysfs`.
+fn read_device_ids(sysfs: &Path, udev_data: &Path, device: &str) -> Result<DeviceIds> {
+    let path = Path::new(device)
+        .canonicalize()
+        .with_context(|| format!("canonicalizing {device}"))?;
+    let dir = sysfs_dir(sysfs, device)?;
+    let mut ids = DeviceIds {
+        path: path.to_string_lossy().into_owned(),
+        ..Default::default()
+    };
+    if let Some(mut props) = udev_properties(udev_data, &dir)? {
+        ids.wwn = props
+            .remove("ID_WWN_WITH_EXTENSION")
+            .or_else(|| props.remove("ID_WWN"));
+        ids.serial = props.remove("ID_SERIAL_SHORT");
+    }
+    if ids.wwn.is_none() {
+        for attr in ["wwid", "device/wwid"] {
+            if let Some(wwid) = read_attr(&dir.join(attr))? {
+                ids.wwn = Some(wwid_to_wwn(&wwid));
+                break;
+            }
+        }
+    }
+    if ids.serial.is_none() {
+        ids.serial = read_attr(&dir.join("device/serial"))?;
+    }
+    Ok(ids)
+}
+
+/// Query the path, WWN and serial number of `device`, with lsblk if it
+/// isn't in sysfs.
+#[cfg_attr(
+    not(any(target_arch = "x86_64", target_arch = "powerpc64")),
+    allow(dead_code)
+)]
+#[context("Querying identifiers of {device}")]
+pub(crate) fn device_ids(device: &str) -> Result<DeviceIds> {
+    #[derive(serde::Deserialize)]
+    struct Device {
+        wwn: Option<String>,
+        serial: Option<String>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Devices {
+        blockdevices: Vec<Device>,
+    }
+    match read_device_ids(Path::new(SYSFS), Path::new(UDEV_DATA), device) {
+        Ok(ids) => return Ok(ids),
+        Err(e) => log::debug!("Falling back to lsblk: {e:#}"),
+    }
+    let path = Path::new(device)
+        .canonicalize()
+        .with_context(|| format!("canonicalizing {device}"))?;
+    let output = std::process::Command::new("lsblk")
+        .args(["--json", "--nodeps", "--output", "WWN,SERIAL"])
+        .arg(&path)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "lsblk failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let devices: Devices = serde_json::from_slice(&output.stdout)?;
+    let Some(dev) = devices.blockdevices.into_iter().next() else {
+        bail!("lsblk returned no device");
+    };
+    let non_empty = |s: Option<String>| s.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    Ok(DeviceIds {
+        path: path.to_string_lossy().into_owned(),
+        wwn: non_empty(dev.wwn),
+        serial: non_empty(dev.serial),
+    })
+}
+
+/// Returns `true` if `device` is an eMMC hardware boot partition, e.g.
+/// `/dev/mmcblk0boot0`.
+pub(crate) fn is_emmc_boot_partition(device: &str) -> bool {
+    let name = device.rsplit('/').next().unwrap_or_default();
+    let Some(rest) = name.strip_prefix("mmcblk") else {
+        return false;
+    };
+    let Some((disk, part)) = rest.split_once("boot") else {
+        return false;
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    is_number(disk) && is_number(part)
+}
+
+/// An eMMC hardware boot partition made writable; when dropped, it is
+/// synced and made read-only again if it was.
+#[derive(Debug)]
+pub(crate) struct EmmcBootPartition {
+    /// The device path, e.g. `/dev/mmcblk0boot0`
+    pub(crate) path: String,
+    force_ro: std::path::PathBuf,
+    was_ro: bool,
+}
+
+impl EmmcBootPartition {
+    /// Make the eMMC boot partition `device` writable, by clearing its
+    /// `force_ro` flag in `sysfs`.
+    fn unlock_in(sysfs: &Path, device: &str) -> Result<Self> {
+        if !is_emmc_boot_partition(device) {
+            bail!("{device} is not an eMMC boot partition");
+        }
+        let name = device.rsplit('/').next().unwrap_or_default();
+        let force_ro = sysfs.join("class/block").join(name).join("force_ro");
+        let was_ro = std::fs::read_to_string(&force_ro)
+            .with_context(|| format!("reading {force_ro:?}"))?
+            .trim()
+            == "1";
+        if was_ro {
+            log::debug!("Unlocking {device}");
+            std::fs::write(&force_ro, "0").with_context(|| format!("writing {force_ro:?}"))?;
+        }
+        Ok(Self {
+            path: device.to_string(),
+            force_ro,
+            was_ro,
+        })
+    }
+
+    /// Make the eMMC boot partition `device` writable.
+    #[context("Unlocking {device}")]
+    pub(crate) fn unlock(device: &str) -> Result<Self> {
+        Self::unlock_in(Path::new(SYSFS), device)
+    }
+}
+
+impl Drop for EmmcBootPartition {
+    fn drop(&mut self) {
+        // Flush the block device cache before making it read-only
+        match std::fs::File::open(&self.path).and_then(|f| f.sync_all()) {
+            Ok(()) => {}
+            Err(e) => log::warn!("Failed to sync {}: {e}", self.path),
+        }
+        if !self.was_ro {
+            return;
+        }
+        log::debug!("Locking {}", self.path);
+        if let Err(e) = std::fs::write(&self.force_ro, "1") {
+            log::warn!("Failed to make {} read-only again: {e}", self.path);
+        }
+    }
+}
+
+/// A loop device backed by a disk image file, with its partitions scanned,
+/// detached when dropped.
+#[derive(Debug)]
+pub(crate) struct LoopDevice {
+    /// The device path, e.g. `/dev/loop0`
+    pub(crate) path: String,
+}
+
+impl LoopDevice {
+    /// Attach the disk image `image` to a free loop device.
+    #[context("Attaching loop device for {image:?}")]
+    pub(crate) fn attach(image: &Path) -> Result<Self> {
+        let output = std::process::Command::new("losetup")
+            .args(["--find", "--show", "--partscan"])
+            .arg(image)
+            .output()?;
+        if !output.status.success() {
+            bail!(
+                "losetup failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let path = String::from_utf8(output.stdout)
+            .context("Invalid UTF-8 losetup output")?
+            .trim()
+            .to_string();
+        if path.is_empty() {
+            bail!("losetup returned no device");
+        }
+        log::debug!("Attached {image:?} to {path}");
+        Ok(Self { path })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let r = std::process::Command::new("losetup")
+            .arg("--detach")
+            .arg(&self.path)
+            .status();
+        match r {
+            Ok(st) if st.success() => {}
+            Ok(st) => log::warn!("Failed to detach {}: {st}", self.path),
+            Err(e) => log::warn!("Failed to detach {}: {e}", self.path),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Add the block device `devpath` (relative to `devices/`) to the fake
+    /// `sysfs`.
+    fn add_block(sysfs: &Path, devpath: &str, partition: bool) -> Result<()> {
+        let dir = sysfs.join("devices").join(devpath);
+        std::fs::create_dir_all(&dir)?;
+        if partition {
+            std::fs::write(dir.join("partition"), "2\n")?;
+        }
+        let name = Path::new(devpath).file_name().unwrap();
+        std::fs::create_dir_all(sysfs.join("class/block"))?;
+        symlink(&dir, sysfs.join("class/block").join(name))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk_parent_disks() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        add_block(sysfs, "pci0/block/sda", false)?;
+        add_block(sysfs, "pci0/block/sda/sda2", true)?;
+        add_block(sysfs, "pci1/block/nvme0n1/nvme0n1p3", true)?;
+        // A LVM volume group spanning both disks
+        add_block(sysfs, "virtual/block/dm-0", false)?;
+        for slave in ["sda2", "nvme0n1p3"] {
+            std::fs::create_dir_all(sysfs.join("devices/virtual/block/dm-0/slaves"))?;
+            symlink(
+                sysfs.join("class/block").join(slave),
+                sysfs.join("devices/virtual/block/dm-0/slaves").join(slave),
+            )?;
+        }
+        // A logical volume in a LUKS volume on top of it
+        add_block(sysfs, "virtual/block/dm-1", false)?;
+        std::fs::create_dir_all(sysfs.join("devices/virtual/block/dm-1/slaves"))?;
+        symlink(
+            sysfs.join("class/block/dm-0"),
+            sysfs.join("devices/virtual/block/dm-1/slaves/dm-0"),
+        )?;
+
+        let walk = |name| -> Result<BlockStack> {
+            let mut stack = BlockStack::default();
+            walk_block_stack(sysfs, name, &mut stack)?;
+            Ok(stack)
+        };
+        assert_eq!(walk("sda")?.disks, ["/dev/sda"]);
+        assert_eq!(walk("sda2")?.disks, ["/dev/sda"]);
+        assert_eq!(walk("dm-0")?.disks, ["/dev/nvme0n1", "/dev/sda"]);
+        assert_eq!(walk("dm-1")?.disks, ["/dev/nvme0n1", "/dev/sda"]);
+        assert!(walk("sdb").is_err());
+
+        let partitions = |name| -> Result<Vec<String>> {
+            let mut r = Vec::new();
+            walk_partitions(
+                &sysfs.join("class/block").join(name).canonicalize()?,
+                &mut r,
+            )?;
+            Ok(r)
+        };
+        assert_eq!(partitions("sda2")?, ["/dev/sda2"]);
+        assert_eq!(partitions("dm-1")?, ["/dev/nvme0n1p3", "/dev/sda2"]);
+        assert!(partitions("sda")?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_stack_dm_crypt() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        add_block(sysfs, "pci0/block/vda/vda3", true)?;
+        // /dev/mapper/luks-<uuid> on the partition
+        add_block(sysfs, "virtual/block/dm-0", false)?;
+        let dm0 = sysfs.join("devices/virtual/block/dm-0");
+        std::fs::create_dir_all(dm0.join("dm"))?;
+        std::fs::write(dm0.join("dm/uuid"), "CRYPT-LUKS2-0123-luks-0123\n")?;
+        std::fs::create_dir_all(dm0.join("slaves"))?;
+        symlink(sysfs.join("class/block/vda3"), dm0.join("slaves/vda3"))?;
+        let mut stack = BlockStack::default();
+        walk_block_stack(sysfs, "dm-0", &mut stack)?;
+        assert_eq!(stack.disks, ["/dev/vda"]);
+        assert!(stack.is_encrypted());
+        assert_eq!(stack.grub_modules(), ["cryptodisk", "luks2"]);
+
+        let lvm = BlockStack {
+            disks: vec!["/dev/vda".into()],
+            dm_uuids: vec!["LVM-abc".into(), "CRYPT-LUKS1-0123-luks".into()],
+        };
+        assert!(lvm.is_encrypted());
+        assert_eq!(lvm.grub_modules(), ["lvm", "cryptodisk", "luks"]);
+        assert!(BlockStack::default().grub_modules().is_empty());
+        Ok(())
+    }
+
+    /// Build a disk image with a GPT of 512 byte sectors, with partitions of
+    /// the given types.
+    fn gpt_image(types: &[&str]) -> Vec<u8> {
+        let mut data = vec![0u8; 512 * 34];
+        data[512..520].copy_from_slice(GPT_SIGNATURE);
+        data[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+        data[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+        data[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+        for (i, t) in types.iter().enumerate() {
+            let u = uuid_bytes(t);
+            data[1024 + i * 128..1024 + i * 128 + 16].copy_from_slice(&u);
+        }
+        data
+    }
+
+    /// The mixed-endian on-disk form of the GUID `s`.
+    fn uuid_bytes(s: &str) -> [u8; 16] {
+        let b = hex::decode(s.replace('-', "")).unwrap();
+        let mut r = [0u8; 16];
+        r[..4].copy_from_slice(&[b[3], b[2], b[1], b[0]]);
+        r[4..6].copy_from_slice(&[b[5], b[4]]);
+        r[6..8].copy_from_slice(&[b[7], b[6]]);
+        r[8..].copy_from_slice(&b[8..]);
+        r
+    }
+
+    #[test]
+    fn test_read_partition_table() -> Result<()> {
+        let mut data = gpt_image(&[BIOS_BOOT_TYPE_GUID, ESP_TYPE_GUID]);
+        // Mark the ESP legacy BIOS bootable
+        data[1024 + 128 + 48] = 1 << 2;
+        let table = read_partition_table(&mut std::io::Cursor::new(data), 512)?;
+        assert!(table.gpt);
+        assert_eq!(table.entries.len(), 2);
+        assert_eq!(table.entries[&1].parttype, BIOS_BOOT_TYPE_GUID);
+        assert!(!table.entries[&1].bootable);
+        assert_eq!(table.entries[&2].parttype, ESP_TYPE_GUID);
+        assert!(table.entries[&2].bootable);
+
+        let mut mbr = vec![0u8; 1024];
+        mbr[446 + 4] = 0x41;
+        mbr[446 + 16] = MBR_ACTIVE;
+        mbr[446 + 16 + 4] = 0x83;
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        let table = read_partition_table(&mut std::io::Cursor::new(mbr), 512)?;
+        assert!(!table.gpt);
+        assert_eq!(
+            table
+                .entries
+                .into_iter()
+                .map(|(n, e)| (n, e.parttype, e.bootable))
+                .collect::<Vec<_>>(),
+            [(1, "41".to_string(), false), (2, "83".to_string(), true)]
+        );
+        assert!(read_partition_table(&mut std::io::Cursor::new(vec![0u8; 1024]), 512).is_err());
+
+        // Corrupted headers
+        for (offset, value) in [(84, 0u64), (84, 200), (84, 8192), (72, u64::MAX)] {
+            let mut data = gpt_image(&[ESP_TYPE_GUID]);
+            let len = if offset == 84 { 4 } else { 8 };
+            data[512 + offset..512 + offset + len].copy_from_slice(&value.to_le_bytes()[..len]);
+            assert!(read_partition_table(&mut std::io::Cursor::new(data), 512).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_partitions() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        add_block(sysfs, "pci0/block/vda", false)?;
+        for (n, start, size) in [(1, 2048, 2048), (2, 4096, 1024000)] {
+            let devpath = format!("pci0/block/vda/vda{n}");
+            add_block(sysfs, &devpath, true)?;
+            let dir = sysfs.join("devices").join(devpath);
+            std::fs::write(dir.join("partition"), format!("{n}\n"))?;
+            std::fs::write(dir.join("start"), format!("{start}\n"))?;
+            std::fs::write(dir.join("size"), format!("{size}\n"))?;
+        }
+        let disk = td.path().join("vda");
+        std::fs::write(&disk, gpt_image(&[BIOS_BOOT_TYPE_GUID, ESP_TYPE_GUID]))?;
+        let partitions = read_partitions(sysfs, disk.to_str().unwrap())?;
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].node, "/dev/vda1");
+        assert_eq!(partitions[0].parttype, BIOS_BOOT_TYPE_GUID);
+        assert_eq!(partitions[1].start, 4096);
+        assert_eq!(partitions[1].size, 1024000);
+        assert_eq!(partitions[1].parttype, ESP_TYPE_GUID);
+
+        let partition = td.path().join("vda2");
+        std::fs::write(&partition, "")?;
+        assert_eq!(
+            read_partition_location(sysfs, partition.to_str().unwrap())?,
+            ("/dev/vda".to_string(), 2)
+        );
+        assert!(read_partition_location(sysfs, disk.to_str().unwrap()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_device_ids() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path().join("sys");
+        let udev = td.path().join("udev");
+        std::fs::create_dir(&udev)?;
+        add_block(&sysfs, "host0/block/sda", false)?;
+        let dir = sysfs.join("devices/host0/block/sda");
+        std::fs::write(dir.join("dev"), "8:0\n")?;
+        std::fs::create_dir(dir.join("device"))?;
+        std::fs::write(dir.join("device/wwid"), "naa.5000C500A1B2C3D4\n")?;
+        let disk = td.path().join("sda");
+        std::fs::write(&disk, "")?;
+        let disk = disk.to_str().unwrap();
+        // Without udev, e.g. in a container
+        let ids = read_device_ids(&sysfs, &udev, disk)?;
+        assert_eq!(ids.wwn.as_deref(), Some("0x5000c500a1b2c3d4"));
+        assert_eq!(ids.serial, None);
+        std::fs::write(
+            udev.join("b8:0"),
+            "S:disk/by-id/wwn-0x5000c500a1b2c3d4\nE:ID_WWN=0x5000c500a1b2c3d4\n\
+E:ID_WWN_WITH_EXTENSION=0x5000c500a1b2c3d4ffff\nE:ID_SERIAL_SHORT=ZA1B2C3D\n",
+        )?;
+        let ids = read_device_ids(&sysfs, &udev, disk)?;
+        assert_eq!(ids.wwn.as_deref(), Some("0x5000c500a1b2c3d4ffff"));
+        assert_eq!(ids.serial.as_deref(), Some("ZA1B2C3D"));
+        assert_eq!(wwid_to_wwn("eui.0025388b91b2c3d4"), "eui.0025388b91b2c3d4");
+        Ok(())
+    }
+
+    #[test]
+    fn test_emmc_boot_partition() -> Result<()> {
+        assert!(is_emmc_boot_partition("/dev/mmcblk0boot0"));
+        assert!(is_emmc_boot_partition("mmcblk12boot1"));
+        assert!(!is_emmc_boot_partition("/dev/mmcblk0"));
+        assert!(!is_emmc_boot_partition("/dev/mmcblk0p1"));
+        assert!(!is_emmc_boot_partition("/dev/mmcblkboot0"));
+        assert!(!is_emmc_boot_partition("/dev/mmcblk0boot"));
+
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        add_block(sysfs, "mmc0/block/mmcblk0/mmcblk0boot0", false)?;
+        let force_ro = sysfs.join("class/block/mmcblk0boot0/force_ro");
+        std::fs::write(&force_ro, "1\n")?;
+        // Stands in for the device node, which is synced when dropped
+        let device = td.path().join("mmcblk0boot0");
+        std::fs::write(&device, "")?;
+        let device = device.to_str().unwrap();
+        let part = EmmcBootPartition::unlock_in(sysfs, device)?;
+        assert_eq!(std::fs::read_to_string(&force_ro)?, "0");
+        drop(part);
+        assert_eq!(std::fs::read_to_string(&force_ro)?, "1");
+        // Left writable if it was
+        std::fs::write(&force_ro, "0\n")?;
+        drop(EmmcBootPartition::unlock_in(sysfs, device)?);
+        assert_eq!(std::fs::read_to_string(&force_ro)?, "0\n");
+        assert!(EmmcBootPartition::unlock_in(sysfs, "/dev/mmcblk0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_stack_multipath() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        // Two paths to the same LUN
+        add_block(sysfs, "host0/block/sda", false)?;
+        add_block(sysfs, "host1/block/sdb", false)?;
+        let add_dm = |name: &str, dm_name: &str, uuid: &str, slaves: &[&str]| -> Result<()> {
+            add_block(sysfs, &format!("virtual/block/{name}"), false)?;
+            let dir = sysfs.join("devices/virtual/block").join(name);
+            std::fs::create_dir_all(dir.join("dm"))?;
+            std::fs::write(dir.join("dm/uuid"), format!("{uuid}\n"))?;
+            std::fs::write(dir.join("dm/name"), format!("{dm_name}\n"))?;
+            std::fs::create_dir_all(dir.join("slaves"))?;
+            for slave in slaves {
+                symlink(
+                    sysfs.join("class/block").join(slave),
+                    dir.join("slaves").join(slave),
+                )?;
+            }
+            Ok(())
+        };
+        add_dm("dm-0", "mpatha", "mpath-3600a0b80", &["sda", "sdb"])?;
+        // The partition created by kpartx on the map
+        add_dm("dm-1", "mpatha3", "part3-mpath-3600a0b80", &["dm-0"])?;
+        add_dm("dm-2", "vg-root", "LVM-abc", &["dm-1"])?;
+        let mut stack = BlockStack::default();
+        walk_block_stack(sysfs, "dm-2", &mut stack)?;
+        assert_eq!(stack.disks, ["/dev/mapper/mpatha"]);
+        assert_eq!(stack.dm_uuids, ["LVM-abc", "part3-mpath-3600a0b80"]);
+        assert_eq!(stack.grub_modules(), ["lvm"]);
+        Ok(())
+    }
+}
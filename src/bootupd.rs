@@ -1,12 +1,57 @@
 #[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
 use crate::bios;
+use crate::bootverify;
 use crate::component;
-use crate::component::{Component, ValidationResult};
+use crate::component::{Component, GenerateOptions, InstallComponentOptions, ValidationResult};
+use crate::config::{AutoUpdatePolicy, Config};
 use crate::coreos;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::dbx;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use crate::dtb;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use crate::efi;
-use crate::model::{ComponentStatus, ComponentUpdatable, ContentMetadata, SavedState, Status};
-use crate::util;
+use crate::grubenv;
+use crate::history::{self, HistoryAction, HistoryEntry};
+use crate::hooks;
+use crate::journal;
+use crate::model::{
+    ComponentStatus, ComponentUpdatable, ComponentValidation, ContentMetadata, InstalledContent,
+    SavedState, Status, UpdateEstimate, ValidationReport, ValidationVerdict,
+};
+use crate::parallel;
+use crate::payloadsig;
+use crate::progress::{Progress, ProgressBar, ProgressFn};
+use crate::quarantine;
+use crate::sdnotify;
+#[cfg(target_arch = "x86_64")]
+use crate::syslinux;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::systemdboot;
+use crate::transaction::{self, Transaction};
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+use crate::uboot;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::uki;
+use crate::util::{self, CommandRunExt};
+#[cfg(target_arch = "s390x")]
+use crate::zipl;
 use anyhow::{anyhow, Context, Result};
 use clap::crate_version;
 use fn_error_context::context;
@@ -19,6 +64,8 @@ use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 pub(crate) enum ConfigMode {
     None,
@@ -39,24 +86,45 @@ impl ConfigMode {
 pub(crate) fn install(
     source_root: &str,
     dest_root: &str,
-    device: Option<&str>,
+    devices: &[String],
     configs: ConfigMode,
-    update_firmware: bool,
+    component_opts: &InstallComponentOptions,
     target_components: Option<&[String]>,
     auto_components: bool,
 ) -> Result<()> {
-    // TODO: Change this to an Option<&str>; though this probably balloons into having
-    // DeviceComponent and FileBasedComponent
-    let device = device.unwrap_or("");
+    #[cfg_attr(target_arch = "s390x", allow(unused_variables))]
+    let source_path = Path::new(source_root);
     let source_root = openat::Dir::open(source_root).context("Opening source root")?;
+    // The target isn't booted, but its disks may be shared with the host
+    let _lock = crate::backend::lock_operation()?;
     SavedState::ensure_not_present(dest_root)
         .context("failed to install, invalid re-install attempted")?;
 
+    // Installing to disk images: attach them to loop devices, detached last
+    let mut loopdevs = Vec::new();
+    let mut target_devices = Vec::new();
+    for device in devices {
+        if Path::new(device).is_file() {
+            if component_opts.update_firmware {
+                anyhow::bail!(
+                    "Updating the firmware is not supported when installing to a disk image"
+                );
+            }
+            let loopdev = crate::blockdev::LoopDevice::attach(Path::new(device))?;
+            target_devices.push(loopdev.path.clone());
+            loopdevs.push(loopdev);
+        } else {
+            target_devices.push(device.clone());
+        }
+    }
+    let devices = target_devices.as_slice();
+
     let all_components = get_components_impl(auto_components);
     if all_components.is_empty() {
         println!("No components available for this platform.");
         return Ok(());
     }
+    let explicit_components = target_components.is_some();
     let target_components = if let Some(target_components) = target_components {
         // Checked by CLI parser
         assert!(!auto_components);
@@ -75,22 +143,62 @@ pub(crate) fn install(
     if target_components.is_empty() && !auto_components {
         anyhow::bail!("No components specified");
     }
+    for component in target_components.iter() {
+        let Some(other) = conflicting_component(component.name()) else {
+            continue;
+        };
+        if explicit_components && target_components.iter().any(|c| c.name() == other) {
+            anyhow::bail!(
+                "Components {} and {other} are mutually exclusive",
+                component.name()
+            );
+        }
+    }
 
     let mut state = SavedState::default();
     let mut installed_efi_vendor = None;
     for &component in target_components.iter() {
-        // skip for BIOS if device is empty
-        if component.name() == "BIOS" && device.is_empty() {
+        // skip for BIOS, syslinux and U-Boot without target device
+        if matches!(component.name(), "BIOS" | "syslinux" | "u-boot") && devices.is_empty() {
             println!(
                 "Skip installing component {} without target device",
                 component.name()
             );
             continue;
         }
+        // systemd-boot conflicts with EFI, so only install the former if
+        // explicitly requested; UKIs are only useful with systemd-boot; dbx updates are adopted on the booted system;
+        // syslinux and BIOS both own the MBR boot code
+        if matches!(
+            component.name(),
+            "systemd-boot" | "UKI" | "dbx" | "syslinux"
+        ) && !explicit_components
+        {
+            println!(
+                "Skip installing component {} unless explicitly requested",
+                component.name()
+            );
+            continue;
+        }
 
-        let meta = component
-            .install(&source_root, dest_root, device, update_firmware)
-            .with_context(|| format!("installing component {}", component.name()))?;
+        let r = component
+            .install(&source_root, dest_root, devices, component_opts)
+            .with_context(|| format!("installing component {}", component.name()));
+        let new_version = r.as_ref().ok().map(|m| m.meta.version.as_str());
+        let entry = HistoryEntry::new(
+            HistoryAction::Install,
+            component.name(),
+            None,
+            new_version,
+            &r,
+        );
+        history::record(Path::new(dest_root), entry);
+        let mut meta = r?;
+        // The loop devices won't exist on the booted system; the checksums of
+        // its disks are recorded by the next update.
+        if !loopdevs.is_empty() {
+            meta.raw_checksums = None;
+        }
         log::info!("Installed {} {}", component.name(), meta.meta.version);
         state.installed.insert(component.name().into(), meta);
         // Yes this is a hack...the Component thing just turns out to be too generic.
@@ -103,19 +211,25 @@ pub(crate) fn install(
 
     match configs.enabled_with_uuid() {
         Some(uuid) => {
-            let self_bin_meta =
-                std::fs::metadata("/proc/self/exe").context("Querying self meta")?;
-            let self_meta = ContentMetadata {
-                timestamp: self_bin_meta.modified()?.into(),
-                version: crate_version!().into(),
-            };
-            state.static_configs = Some(self_meta);
+            state.static_configs = Some(static_configs_meta()?);
             #[cfg(any(
                 target_arch = "x86_64",
                 target_arch = "aarch64",
-                target_arch = "powerpc64"
+                target_arch = "powerpc64",
+                target_arch = "riscv64"
             ))]
-            crate::grubconfigs::install(sysroot, installed_efi_vendor.as_deref(), uuid)?;
+            {
+                let generated = crate::grubconfigs::GeneratedConfigs::new(
+                    source_path,
+                    &Config::load(source_path)?.grub,
+                )?;
+                crate::grubconfigs::install(
+                    sysroot,
+                    installed_efi_vendor.as_deref(),
+                    uuid,
+                    &generated,
+                )?;
+            }
             // On other architectures, assume that there's nothing to do.
         }
         None => {}
@@ -133,6 +247,97 @@ pub(crate) fn install(
     Ok(())
 }
 
+/// The version of the static GRUB configs shipped with this binary.
+fn static_configs_meta() -> Result<ContentMetadata> {
+    let self_bin_meta = std::fs::metadata("/proc/self/exe").context("Querying self meta")?;
+    Ok(ContentMetadata {
+        timestamp: self_bin_meta.modified()?.into(),
+        version: crate_version!().into(),
+        signing_keys: Vec::new(),
+    })
+}
+
+/// The files of the static GRUB configs generated from the configuration
+/// of the booted system.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "riscv64"
+))]
+fn static_configs_generated() -> Result<crate::grubconfigs::GeneratedConfigs> {
+    let root = Path::new("/");
+    crate::grubconfigs::GeneratedConfigs::new(root, &Config::load(root)?.grub)
+}
+
+/// The version the installed static GRUB configs would be updated to, if
+/// they were installed by a different version of bootupd or the files
+/// generated from the configuration changed.
+#[cfg_attr(target_arch = "s390x", allow(unused_variables))]
+fn query_static_configs_update(
+    state: &SavedState,
+    sysroot: &openat::Dir,
+) -> Result<Option<ContentMetadata>> {
+    let Some(installed) = state.static_configs.as_ref() else {
+        return Ok(None);
+    };
+    let meta = static_configs_meta()?;
+    if installed.version != meta.version {
+        return Ok(Some(meta));
+    }
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        target_arch = "riscv64"
+    ))]
+    if static_configs_generated()?.changed(sysroot)? {
+        return Ok(Some(meta));
+    }
+    Ok(None)
+}
+
+/// daemon implementation of the update of the static GRUB configs, so that
+/// they follow the ones shipped with bootupd; returns the new version if
+/// they were updated.
+pub(crate) fn update_static_configs() -> Result<Option<ContentMetadata>> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let sysroot = openat::Dir::open("/")?;
+    if query_static_configs_update(&state, &sysroot)?.is_none() {
+        return Ok(None);
+    }
+    ensure_writable_boot()?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    // Reload under the lock, as the components may have been updated since
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(meta) = query_static_configs_update(&state, &state_guard.sysroot)? else {
+        return Ok(None);
+    };
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        target_arch = "riscv64"
+    ))]
+    {
+        let sysroot = &state_guard.sysroot;
+        let mut vendor = None;
+        for name in state.installed.keys() {
+            let component = component::new_from_name(name)?;
+            if let Some(v) = component.get_efi_vendor(sysroot)? {
+                vendor = Some(v);
+                break;
+            }
+        }
+        let generated = static_configs_generated()?;
+        crate::grubconfigs::update(sysroot, vendor.as_deref(), &generated)?;
+    }
+    state.static_configs = Some(meta.clone());
+    state_guard.update_state(&state)?;
+    Ok(Some(meta))
+}
+
 type Components = BTreeMap<&'static str, Box<dyn Component>>;
 
 #[allow(clippy::box_default)]
@@ -163,12 +368,75 @@ pub(crate) fn get_components_impl(auto: bool) -> Components {
             insert_component(&mut components, Box::new(efi::Efi::default()));
         }
     }
-    #[cfg(target_arch = "aarch64")]
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
     insert_component(&mut components, Box::new(efi::Efi::default()));
 
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if systemdboot::is_available(Path::new("/")) {
+        insert_component(
+            &mut components,
+            Box::new(systemdboot::SystemdBoot::default()),
+        );
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if uki::is_available(Path::new("/")) {
+        insert_component(&mut components, Box::new(uki::Uki::default()));
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if dbx::is_available(Path::new("/")) {
+        insert_component(&mut components, Box::new(dbx::Dbx::default()));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    if syslinux::is_available(Path::new("/")) {
+        insert_component(&mut components, Box::new(syslinux::Syslinux::default()));
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    if uboot::is_available(Path::new("/")) {
+        insert_component(&mut components, Box::new(uboot::UBoot::default()));
+    }
+
+    #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+    if dtb::is_available(Path::new("/")) {
+        insert_component(&mut components, Box::new(dtb::Dtb::default()));
+    }
+
     #[cfg(target_arch = "powerpc64")]
     insert_component(&mut components, Box::new(bios::Bios::default()));
 
+    #[cfg(target_arch = "s390x")]
+    insert_component(&mut components, Box::new(zipl::Zipl::default()));
+
+    #[cfg(all(
+        feature = "testing",
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    ))]
+    if crate::testing::MockComponent::is_available(Path::new("/")) {
+        insert_component(
+            &mut components,
+            Box::new(crate::testing::MockComponent::default()),
+        );
+    }
+
     components
 }
 
@@ -176,19 +444,37 @@ pub(crate) fn get_components() -> Components {
     get_components_impl(false)
 }
 
-pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
+pub(crate) fn generate_update_metadata(sysroot_path: &str, opts: &GenerateOptions) -> Result<()> {
+    // Compressed payloads have no files left to store by digest
+    if opts.compress && opts.dedup {
+        anyhow::bail!("Update payloads can't be both compressed and deduplicated");
+    }
     // create bootupd update dir which will save component metadata files for both components
     let updates_dir = Path::new(sysroot_path).join(crate::model::BOOTUPD_UPDATES_DIR);
     std::fs::create_dir_all(&updates_dir)
         .with_context(|| format!("Failed to create updates dir {:?}", &updates_dir))?;
+    let sign_key = opts
+        .sign_key
+        .as_deref()
+        .map(payloadsig::load_signing_key)
+        .transpose()?;
     for component in get_components().values() {
-        let v = component.generate_update_metadata(sysroot_path)?;
+        let v = component.generate_update_metadata(sysroot_path, opts)?;
+        if let Some(key) = sign_key.as_ref() {
+            payloadsig::sign_payload(sysroot_path, component.as_ref(), key)?;
+        }
         println!(
             "Generated update layout for {}: {}",
             component.name(),
             v.version,
         );
     }
+    if opts.compress {
+        crate::payloadzstd::compress(sysroot_path)?;
+    }
+    if opts.dedup {
+        crate::payloadstore::pack(sysroot_path)?;
+    }
 
     Ok(())
 }
@@ -196,7 +482,7 @@ pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
 /// Return value from daemon → client for component update
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdateResult {
+pub enum ComponentUpdateResult {
     AtLatestVersion,
     Updated {
         previous: ContentMetadata,
@@ -209,45 +495,566 @@ fn ensure_writable_boot() -> Result<()> {
     util::ensure_writable_mount("/boot")
 }
 
-/// daemon implementation of component update
-pub(crate) fn update(name: &str) -> Result<ComponentUpdateResult> {
-    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+/// Returns the component which can't be installed alongside `name`:
+/// systemd-boot and EFI both own the removable media path.
+fn conflicting_component(name: &str) -> Option<&'static str> {
+    match name {
+        "EFI" => Some("systemd-boot"),
+        "systemd-boot" => Some("EFI"),
+        _ => None,
+    }
+}
+
+/// Refuse to touch components disabled by the administrator.
+fn ensure_enabled(name: &str) -> Result<()> {
+    if Config::load(Path::new("/"))?.is_disabled(name) {
+        anyhow::bail!(
+            "Component {} is disabled in /{}",
+            name,
+            crate::config::CONFIG_PATH
+        );
+    }
+    Ok(())
+}
+
+/// daemon implementation of component update; `progress` is called as the
+/// files of the update are written.
+pub(crate) fn update(name: &str, progress: ProgressFn) -> Result<ComponentUpdateResult> {
+    let names = [name.to_string()];
+    let mut results = update_components(&names, false, &|_, p| progress(p))?;
+    results.remove(name).expect("update result")
+}
+
+/// A callback receiving the progress of the update of a component.
+pub(crate) type ComponentProgressFn<'a> = &'a (dyn Fn(&str, &Progress) + Sync);
+
+/// An update found by `update_components`.
+struct PlannedUpdate<'a> {
+    name: &'a str,
+    inst: InstalledContent,
+    update: ContentMetadata,
+    interrupted: Option<ContentMetadata>,
+}
+
+impl PlannedUpdate<'_> {
+    fn hook_context(&self, success: Option<bool>) -> hooks::HookContext<'_> {
+        hooks::HookContext {
+            component: self.name,
+            old_version: &self.inst.meta.version,
+            new_version: &self.update.version,
+            success,
+        }
+    }
+
+    /// The devices locked while updating: those with raw bootloader data
+    /// if any, otherwise the filesystems of /boot and the ESP, which the
+    /// components without raw data (e.g. EFI and systemd-boot) share.
+    fn devices(&self) -> Vec<String> {
+        let devices = self.inst.devices();
+        if devices.is_empty() {
+            vec!["/boot".to_string()]
+        } else {
+            devices
+        }
+    }
+
+    fn run(
+        &self,
+        sysroot: &openat::Dir,
+        progress: ComponentProgressFn,
+    ) -> Result<InstalledContent> {
+        let devices = self.inst.devices();
+        let event = journal::UpdateEvent {
+            component: self.name,
+            old_version: Some(&self.inst.meta.version),
+            new_version: &self.update.version,
+            devices: &devices,
+        };
+        let estimate = component::new_from_name(self.name)
+            .ok()
+            .and_then(|c| estimate_update(sysroot, c.as_ref(), &self.inst, None));
+        event.started();
+        sdnotify::status(&format!("Updating {}", self.name));
+        let start = Instant::now();
+        let progress = |p: &Progress| {
+            sdnotify::update_progress(self.name, p);
+            progress(self.name, p)
+        };
+        let r = component::new_from_name(self.name)
+            .and_then(|c| c.run_update(sysroot, &self.inst, &progress))
+            .with_context(|| format!("Failed to update {}", self.name));
+        let elapsed = start.elapsed();
+        match &r {
+            Ok(_) => event.succeeded(),
+            Err(e) => event.failed(e),
+        }
+        let mut entry = HistoryEntry::new(
+            HistoryAction::Update,
+            self.name,
+            Some(&self.inst.meta.version),
+            Some(&self.update.version),
+            &r,
+        );
+        // Measured over the whole update, so that it predicts its duration
+        if r.is_ok() {
+            entry.write_throughput = estimate.filter(|e| e.bytes > 0).map(|e| {
+                let ms = elapsed.as_millis().max(1);
+                (u128::from(e.bytes) * 1000 / ms) as u64
+            });
+        }
+        history::record(Path::new("/"), entry);
+        r
+    }
+}
+
+/// What the update of `component` would write over `current`, taking the
+/// expected duration at `throughput` bytes per second, if known; `None` if
+/// it can't be estimated.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn estimate_update(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    current: &InstalledContent,
+    throughput: Option<u64>,
+) -> Option<UpdateEstimate> {
+    let mut estimate = component::estimate_filetree_update(sysroot, component, current)
+        .unwrap_or_else(|e| {
+            log::debug!(
+                "Failed to estimate the update of {}: {e:#}",
+                component.name()
+            );
+            None
+        })?;
+    estimate.seconds = throughput.map(|t| estimate.bytes.div_ceil(t));
+    Some(estimate)
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn estimate_update(
+    _sysroot: &openat::Dir,
+    _component: &dyn Component,
+    _current: &InstalledContent,
+    _throughput: Option<u64>,
+) -> Option<UpdateEstimate> {
+    None
+}
+
+/// Find the update of the installed component `name`, if any.
+fn plan_component_update<'a>(
+    state: &SavedState,
+    sysroot: &openat::Dir,
+    name: &'a str,
+    allow_downgrade: bool,
+) -> Result<Option<PlannedUpdate<'a>>> {
     let component = component::new_from_name(name)?;
-    let inst = if let Some(inst) = state.installed.get(name) {
-        inst.clone()
-    } else {
+    let Some(inst) = state.installed.get(name) else {
         anyhow::bail!("Component {} is not installed", name);
     };
-    let sysroot = openat::Dir::open("/")?;
-    let update = component.query_update(&sysroot)?;
-    let update = match update.as_ref() {
-        Some(p) if inst.meta.can_upgrade_to(p) => p,
-        _ => return Ok(ComponentUpdateResult::AtLatestVersion),
+    let update = match component.query_update(sysroot)? {
+        Some(p) if inst.meta.can_install(&p, allow_downgrade) => p,
+        _ => return Ok(None),
     };
+    ensure_enabled(name)?;
+    let interrupted = state.pending.as_ref().and_then(|p| p.get(name)).cloned();
+    Ok(Some(PlannedUpdate {
+        name,
+        inst: inst.clone(),
+        update,
+        interrupted,
+    }))
+}
 
-    ensure_writable_boot()?;
+/// daemon implementation of the update of several components; the
+/// components writing to different devices (e.g. BIOS and EFI) are updated
+/// concurrently; older payloads are only installed with `allow_downgrade`.
+/// Returns the result of each component.
+pub(crate) fn update_components<'a>(
+    names: &'a [String],
+    allow_downgrade: bool,
+    progress: ComponentProgressFn,
+) -> Result<BTreeMap<&'a str, Result<ComponentUpdateResult>>> {
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let sysroot = openat::Dir::open("/")?;
+    let mut results = BTreeMap::new();
+    let mut planned = Vec::new();
+    for name in names {
+        match plan_component_update(&state, &sysroot, name, allow_downgrade) {
+            Ok(Some(p)) => planned.push(p),
+            Ok(None) => {
+                results.insert(name.as_str(), Ok(ComponentUpdateResult::AtLatestVersion));
+            }
+            Err(e) => {
+                results.insert(name.as_str(), Err(e));
+            }
+        }
+    }
+    // Interrupted updates are rolled forward by updating again, or rolled
+    // back if no update is available anymore
+    let mut transactions = transaction::load(&sysroot, &state)?;
+    let rollbacks: Vec<&str> = names
+        .iter()
+        .map(String::as_str)
+        .filter(|n| transactions.contains_key(*n) && !planned.iter().any(|p| p.name == *n))
+        .collect();
+    if planned.is_empty() && rollbacks.is_empty() {
+        return Ok(results);
+    }
 
-    let mut pending_container = state.pending.take().unwrap_or_default();
-    let interrupted = pending_container.get(component.name()).cloned();
-    pending_container.insert(component.name().into(), update.clone());
+    ensure_writable_boot()?;
     let mut state_guard =
         SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    if !rollbacks.is_empty() {
+        for name in rollbacks {
+            let tx = &transactions[name];
+            match roll_back_interrupted(&state_guard.sysroot, &mut state, name, tx) {
+                Ok(()) => {
+                    transactions.remove(name);
+                }
+                Err(e) => {
+                    results.insert(name, Err(e));
+                }
+            }
+        }
+        state_guard.update_state(&state)?;
+        transaction::save(&state_guard.sysroot, &transactions)?;
+    }
+    if planned.is_empty() {
+        return Ok(results);
+    }
+    let config = Config::load(Path::new("/"))?;
+    let hooks_timeout = Duration::from_secs(config.hooks.timeout);
+    let mut runnable = Vec::new();
+    for p in planned {
+        let verified = component::new_from_name(p.name)
+            .and_then(|c| payloadsig::verify_payload(&state_guard.sysroot, c.as_ref()));
+        if let Err(e) = verified {
+            results.insert(p.name, Err(e));
+            continue;
+        }
+        let hook_ctx = p.hook_context(None);
+        match hooks::run(
+            Path::new("/"),
+            hooks::Phase::PreUpdate,
+            &hook_ctx,
+            hooks_timeout,
+        ) {
+            Ok(()) => runnable.push(p),
+            Err(e) => {
+                results.insert(p.name, Err(e));
+            }
+        }
+    }
+    if runnable.is_empty() {
+        return Ok(results);
+    }
+    let mut pending = state.pending.take().unwrap_or_default();
+    for p in runnable.iter() {
+        pending.insert(p.name.into(), p.update.clone());
+    }
+    state.pending = Some(pending.clone());
     state_guard
         .update_state(&state)
         .context("Failed to update state")?;
+    for p in runnable.iter() {
+        let files = component::new_from_name(p.name)
+            .and_then(|c| c.plan_update(&state_guard.sysroot, &p.inst))
+            .unwrap_or_else(|e| {
+                log::debug!("Failed to plan the update of {}: {e:#}", p.name);
+                Vec::new()
+            });
+        let tx = Transaction {
+            from: p.inst.meta.clone(),
+            to: p.update.clone(),
+            files,
+            phase: transaction::Phase::Writing,
+        };
+        transactions.insert(p.name.into(), tx);
+    }
+    transaction::save(&state_guard.sysroot, &transactions)?;
 
-    let newinst = component
-        .run_update(&state_guard.sysroot, &inst)
-        .with_context(|| format!("Failed to update {}", component.name()))?;
-    state.installed.insert(component.name().into(), newinst);
-    pending_container.remove(component.name());
+    let sysroot = &state_guard.sysroot;
+    let jobs = runnable
+        .iter()
+        .map(|p| parallel::Job::new(p.devices(), move || p.run(sysroot, progress)))
+        .collect();
+    let newinsts = parallel::run(jobs);
+
+    let mut updated = Vec::new();
+    for (p, r) in runnable.into_iter().zip(newinsts) {
+        let hook_ctx = p.hook_context(Some(r.is_ok()));
+        if let Err(e) = hooks::run(
+            Path::new("/"),
+            hooks::Phase::PostUpdate,
+            &hook_ctx,
+            hooks_timeout,
+        ) {
+            log::warn!("{e:#}");
+        }
+        pending.remove(p.name);
+        let r = r.map(|mut newinst| {
+            newinst.rebuilt = false;
+            updated.push(p.name);
+            state.installed.insert(p.name.into(), newinst);
+            ComponentUpdateResult::Updated {
+                previous: p.inst.meta,
+                interrupted: p.interrupted,
+                new: p.update,
+            }
+        });
+        results.insert(p.name, r);
+    }
+    // Failed updates stay in the journal, to be resolved by the next one
+    for name in updated.iter() {
+        if let Some(tx) = transactions.get_mut(*name) {
+            tx.phase = transaction::Phase::Recording;
+        }
+    }
+    transaction::save(&state_guard.sysroot, &transactions)?;
+    state.pending = (!pending.is_empty()).then_some(pending);
     state_guard.update_state(&state)?;
+    for name in updated.iter() {
+        transactions.remove(*name);
+    }
+    transaction::save(&state_guard.sysroot, &transactions)?;
+    if config.update.verify_boot && !updated.is_empty() {
+        if let Err(e) = bootverify::mark(Path::new("/"), &updated) {
+            log::warn!("Not verifying the next boot: {e:#}");
+        }
+    }
 
-    Ok(ComponentUpdateResult::Updated {
-        previous: inst.meta,
-        interrupted,
-        new: update.clone(),
-    })
+    Ok(results)
+}
+
+/// Roll back the interrupted update `tx` of `name`, for which no update is
+/// available anymore, e.g. after a rollback of the OS: the files of the
+/// version recorded as installed are repaired from the current update
+/// payload, which is expected to hold that version again.
+fn roll_back_interrupted(
+    sysroot: &openat::Dir,
+    state: &mut SavedState,
+    name: &str,
+    tx: &Transaction,
+) -> Result<()> {
+    let Some(inst) = state.installed.get(name) else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    log::warn!(
+        "Rolling back the interrupted update of {name} from {} to {}",
+        inst.meta.version,
+        tx.to.version
+    );
+    let r = component::new_from_name(name).and_then(|c| c.repair(sysroot, inst));
+    let entry = HistoryEntry::new(
+        HistoryAction::Rollback,
+        name,
+        Some(&tx.to.version),
+        Some(&inst.meta.version),
+        &r,
+    );
+    history::record(Path::new("/"), entry);
+    let r = r.with_context(|| format!("Rolling back the interrupted update of {name}"))?;
+    state.installed.insert(name.into(), r.inst);
+    if let Some(pending) = state.pending.as_mut() {
+        pending.remove(name);
+    }
+    Ok(())
+}
+
+/// daemon implementation of `bootupctl rollback`; returns the restored
+/// version.
+pub(crate) fn rollback(name: &str) -> Result<ContentMetadata> {
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(inst) = state.installed.get(name).cloned() else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let r: Result<InstalledContent> = match name {
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        "EFI" => efi::Efi::default().rollback(&state_guard.sysroot, &inst),
+        _ => return Err(component::Unsupported(format!("Rolling back {name}")).into()),
+    };
+    let entry = HistoryEntry::new(
+        HistoryAction::Rollback,
+        name,
+        Some(&inst.meta.version),
+        r.as_ref().ok().map(|i| i.meta.version.as_str()),
+        &r,
+    );
+    history::record(Path::new("/"), entry);
+    let newinst = r?;
+    let meta = newinst.meta.clone();
+    state.installed.insert(name.into(), newinst);
+    state_guard.update_state(&state)?;
+    Ok(meta)
+}
+
+/// daemon implementation of `bootupctl validate --prune`: remove the
+/// extraneous files of the vendor directories of the EFI component, if
+/// `confirm` accepts them; returns the removed files.
+pub(crate) fn prune(confirm: &dyn Fn(&[String]) -> Result<bool>) -> Result<Vec<String>> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(inst) = state.installed.get("EFI") else {
+        anyhow::bail!("Component EFI is not installed");
+    };
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let _state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    return efi::Efi::default().prune(inst, confirm);
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )))]
+    {
+        let _ = (inst, confirm);
+        Err(component::Unsupported("Pruning on this architecture".into()).into())
+    }
+}
+
+/// daemon implementation of `bootupctl validate --fix`: restore the missing
+/// or modified content of the installed components from their update
+/// payloads; returns the repaired files of each component.
+pub(crate) fn repair_all() -> Result<BTreeMap<String, Vec<String>>> {
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut results = BTreeMap::new();
+    for (name, inst) in state.installed.clone() {
+        let component = component::new_from_name(&name)?;
+        if !matches!(component.validate(&inst)?, ValidationResult::Errors(_)) {
+            continue;
+        }
+        payloadsig::verify_payload(&state_guard.sysroot, component.as_ref())?;
+        let r = component.repair(&state_guard.sysroot, &inst);
+        let entry = HistoryEntry::new(
+            HistoryAction::Repair,
+            &name,
+            Some(&inst.meta.version),
+            Some(&inst.meta.version),
+            &r,
+        );
+        history::record(Path::new("/"), entry);
+        let r = r.with_context(|| format!("Repairing {name}"))?;
+        if !r.repaired.is_empty() {
+            state.installed.insert(name.clone(), r.inst);
+            results.insert(name, r.repaired);
+        }
+    }
+    state_guard.update_state(&state)?;
+    Ok(results)
+}
+
+/// What an update of a component would do
+#[derive(Debug)]
+pub(crate) struct UpdatePlan {
+    pub(crate) previous: ContentMetadata,
+    pub(crate) new: ContentMetadata,
+    pub(crate) actions: Vec<String>,
+}
+
+/// daemon implementation of component update in dry-run mode; returns
+/// `None` if the component is at the latest version, or if the update would
+/// downgrade it without `allow_downgrade`.
+pub(crate) fn plan_update(name: &str, allow_downgrade: bool) -> Result<Option<UpdatePlan>> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let component = component::new_from_name(name)?;
+    let Some(inst) = state.installed.get(name) else {
+        anyhow::bail!("Component {} is not installed", name);
+    };
+    let sysroot = openat::Dir::open("/")?;
+    let update = match component.query_update(&sysroot)? {
+        Some(p) if inst.meta.can_install(&p, allow_downgrade) => p,
+        _ => return Ok(None),
+    };
+    let actions = component.plan_update(&sysroot, inst)?;
+    Ok(Some(UpdatePlan {
+        previous: inst.meta.clone(),
+        new: update,
+        actions,
+    }))
+}
+
+/// daemon implementation of `bootupctl diff`: print the files that the
+/// update payloads of `components` (or of all the installed components
+/// tracking files) would add, remove or change.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_diff(components: &[String]) -> Result<()> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    for name in components {
+        if !state.installed.contains_key(name) {
+            anyhow::bail!("Component {} is not installed", name);
+        }
+    }
+    let sysroot = openat::Dir::open("/")?;
+    let config = Config::load(Path::new("/"))?;
+    for (name, inst) in state.installed.iter() {
+        if !components.is_empty() && !components.contains(name) {
+            continue;
+        }
+        let Some(currentf) = inst.filetree.as_ref() else {
+            if !components.is_empty() {
+                println!("Component {}: Not tracking files", name);
+            }
+            continue;
+        };
+        let component = component::new_from_name(name)?;
+        let updatedir = component::component_updatedirname(component.as_ref());
+        let Some(updated) = sysroot.sub_dir_optional(&updatedir)? else {
+            println!("Component {}: No update available", name);
+            continue;
+        };
+        let updatef = crate::filetree::FileTree::new_from_dir(&updated, config.update.digest)?;
+        let diff = currentf.diff(&updatef)?;
+        println!("Component {}", name);
+        println!("  Installed: {}", inst.meta.version);
+        if let Some(update) = component.query_update(&sysroot)? {
+            println!("  Update: {}", update.version);
+        }
+        let changes = diff.describe(currentf, &updatef);
+        if changes.is_empty() {
+            println!("  No file changes");
+        }
+        for change in changes {
+            println!("  {}", change);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_diff(_components: &[String]) -> Result<()> {
+    anyhow::bail!("No component tracking files on this architecture")
 }
 
 /// daemon implementation of component adoption
@@ -258,39 +1065,121 @@ pub(crate) fn adopt_and_update(name: &str) -> Result<ContentMetadata> {
     if state.installed.contains_key(name) {
         anyhow::bail!("Component {} is already installed", name);
     };
+    if let Some(other) = conflicting_component(name).filter(|c| state.installed.contains_key(*c)) {
+        anyhow::bail!("Component {name} can't be adopted, {other} is installed");
+    }
 
+    ensure_enabled(name)?;
     ensure_writable_boot()?;
 
     let Some(update) = component.query_update(&sysroot)? else {
         anyhow::bail!("Component {} has no available update", name);
     };
+    let Some(adoptable) = component.query_adopt()? else {
+        anyhow::bail!("Component {} is not adoptable", name);
+    };
+    payloadsig::verify_payload(&sysroot, component.as_ref())?;
+    let hooks_timeout = Duration::from_secs(Config::load(Path::new("/"))?.hooks.timeout);
     let mut state_guard =
         SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
 
-    let inst = component
-        .adopt_update(&state_guard.sysroot, &update)
-        .context("Failed adopt and update")?;
+    let event = journal::UpdateEvent {
+        component: component.name(),
+        old_version: None,
+        new_version: &update.version,
+        devices: &[],
+    };
+    event.started();
+    sdnotify::status(&format!("Adopting {name}"));
+    let hook_ctx = hooks::HookContext {
+        component: component.name(),
+        old_version: &adoptable.version.version,
+        new_version: &update.version,
+        success: None,
+    };
+    let r = hooks::run_around(Path::new("/"), &hook_ctx, hooks_timeout, || {
+        component.adopt_update(&state_guard.sysroot, &update)
+    })
+    .context("Failed adopt and update");
+    match &r {
+        Ok(_) => event.succeeded(),
+        Err(e) => event.failed(e),
+    }
+    let entry = HistoryEntry::new(
+        HistoryAction::Adopt,
+        component.name(),
+        None,
+        Some(&update.version),
+        &r,
+    );
+    history::record(Path::new("/"), entry);
+    let inst = r?;
     state.installed.insert(component.name().into(), inst);
 
     state_guard.update_state(&state)?;
     Ok(update)
 }
 
-/// daemon implementation of component validate
-pub(crate) fn validate(name: &str) -> Result<ValidationResult> {
+/// daemon implementation of component validate; `strict` also reports the
+/// untracked content of the managed locations.
+pub(crate) fn validate(name: &str, strict: bool) -> Result<ValidationResult> {
     let state = SavedState::load_from_disk("/")?.unwrap_or_default();
     let component = component::new_from_name(name)?;
     let Some(inst) = state.installed.get(name) else {
         anyhow::bail!("Component {} is not installed", name);
     };
-    component.validate(inst)
+    let r = component.validate(inst)?;
+    if !strict {
+        return Ok(r);
+    }
+    let mut errs = match r {
+        ValidationResult::Skip => return Ok(r),
+        ValidationResult::Valid => Vec::new(),
+        ValidationResult::Errors(errs) => errs,
+    };
+    for e in component.validate_strict(inst)? {
+        if !errs.contains(&e) {
+            errs.push(e);
+        }
+    }
+    if errs.is_empty() {
+        Ok(ValidationResult::Valid)
+    } else {
+        Ok(ValidationResult::Errors(errs))
+    }
 }
 
 pub(crate) fn status() -> Result<Status> {
-    let mut ret: Status = Default::default();
+    let mut ret = Status {
+        config: Config::load(Path::new("/"))?,
+        drift: crate::driftwatch::load_recorded().unwrap_or_else(|e| {
+            log::warn!("{e:#}");
+            Vec::new()
+        }),
+        ..Default::default()
+    };
     let mut known_components = get_components();
     let sysroot = openat::Dir::open("/")?;
     let state = SavedState::load_from_disk("/")?;
+    #[cfg_attr(
+        not(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )),
+        allow(unused_variables)
+    )]
+    let esp_versions = state
+        .as_ref()
+        .and_then(|s| s.installed.get("EFI"))
+        .and_then(|ic| ic.esps.clone())
+        .unwrap_or_default();
+    let throughput = history::load(Path::new("/"))
+        .map(|h| history::write_throughput(&h))
+        .unwrap_or_else(|e| {
+            log::debug!("{e:#}");
+            None
+        });
     if let Some(state) = state {
         for (name, ic) in state.installed.iter() {
             log::trace!("Gathering status for installed component: {}", name);
@@ -299,17 +1188,36 @@ pub(crate) fn status() -> Result<Status> {
                 .ok_or_else(|| anyhow!("Unknown component installed: {}", name))?;
             let component = component.as_ref();
             let interrupted = state.pending.as_ref().and_then(|p| p.get(name.as_str()));
+            let staged = state.staged.as_ref().and_then(|s| s.get(name.as_str()));
             let update = component.query_update(&sysroot)?;
             let updatable = ComponentUpdatable::from_metadata(&ic.meta, update.as_ref());
+            let update_estimate = match updatable {
+                ComponentUpdatable::Upgradable => {
+                    estimate_update(&sysroot, component, ic, throughput)
+                }
+                _ => None,
+            };
             let adopted_from = ic.adopted_from.clone();
+            let efi_vendor = component.get_efi_vendor(&sysroot).unwrap_or_else(|e| {
+                log::debug!("Failed to get EFI vendor for {name}: {e}");
+                None
+            });
             ret.components.insert(
                 name.to_string(),
                 ComponentStatus {
                     installed: ic.meta.clone(),
                     interrupted: interrupted.cloned(),
+                    staged: staged.cloned(),
                     update,
                     updatable,
                     adopted_from,
+                    efi_vendor,
+                    devices: ic.devices(),
+                    pcr4: ic.pcr4.clone(),
+                    key_rotation: ic.rotation.as_ref().map(|r| r.previous.clone()),
+                    pending_nvram: ic.pending_nvram.clone(),
+                    rebuilt: ic.rebuilt,
+                    update_estimate,
                 },
             );
         }
@@ -317,9 +1225,47 @@ pub(crate) fn status() -> Result<Status> {
         log::trace!("No saved state");
     }
 
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if ret.components.contains_key("EFI") {
+        let efi = efi::Efi::default();
+        ret.efi_boot = efi.boot_status(&sysroot).unwrap_or_else(|e| {
+            log::warn!("{e:#}");
+            None
+        });
+        ret.sbat = efi
+            .sbat_status(&sysroot)
+            .map_err(|e| log::warn!("{e:#}"))
+            .ok();
+        ret.secure_boot = efi.secure_boot_status(&sysroot).unwrap_or_else(|e| {
+            log::warn!("{e:#}");
+            None
+        });
+        ret.esps = efi::esps_status(Path::new("/")).unwrap_or_else(|e| {
+            log::warn!("{e:#}");
+            Vec::new()
+        });
+        for esp in ret.esps.iter_mut() {
+            esp.installed = esp_versions.get(&esp.device).cloned();
+        }
+    }
+
     // Process the remaining components not installed
     log::trace!("Remaining known components: {}", known_components.len());
     for (name, component) in known_components {
+        if ret.config.is_disabled(name) {
+            log::trace!("Not adoptable: {} is disabled", name);
+            continue;
+        }
+        if let Some(other) = conflicting_component(name)
+            .filter(|c| ret.components.contains_key(*c) || ret.adoptable.contains_key(*c))
+        {
+            log::trace!("Not adoptable: {} conflicts with {}", name, other);
+            continue;
+        }
         if let Some(adopt_ver) = component.query_adopt()? {
             ret.adoptable.insert(name.to_string(), adopt_ver);
         } else {
@@ -330,7 +1276,32 @@ pub(crate) fn status() -> Result<Status> {
     Ok(ret)
 }
 
-pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
+/// What is left to do after `status --print-if-available` or
+/// `update --dry-run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Pending {
+    Nothing,
+    /// Updates, or confident adoptions, are available
+    Updates,
+    /// Some components can only be adopted with `adopt-and-update`
+    Adoption,
+}
+
+impl Pending {
+    fn new(updates: bool, status: &Status) -> Self {
+        if updates {
+            Pending::Updates
+        } else if status.adoptable.values().any(|a| !a.confident) {
+            Pending::Adoption
+        } else {
+            Pending::Nothing
+        }
+    }
+}
+
+/// Print the components with an available update, if any; with `summary`,
+/// print a line in any case.
+pub(crate) fn print_status_avail(status: &Status, summary: bool) -> Result<Pending> {
     let mut avail = Vec::new();
     for (name, component) in status.components.iter() {
         if let ComponentUpdatable::Upgradable = component.updatable {
@@ -342,10 +1313,25 @@ pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
             avail.push(name.as_str());
         }
     }
+    let pending = Pending::new(!avail.is_empty(), status);
     if !avail.is_empty() {
         println!("Updates available: {}", avail.join(" "));
+    } else if summary {
+        let adopt: Vec<_> = status
+            .adoptable
+            .iter()
+            .filter(|(_, a)| !a.confident)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        match pending {
+            Pending::Adoption => println!(
+                "No updates available; adopt with adopt-and-update: {}",
+                adopt.join(" ")
+            ),
+            _ => println!("All components are up to date"),
+        }
     }
-    Ok(())
+    Ok(pending)
 }
 
 pub(crate) fn print_status(status: &Status) -> Result<()> {
@@ -355,6 +1341,9 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
     for (name, component) in status.components.iter() {
         println!("Component {}", name);
         println!("  Installed: {}", component.installed.version);
+        if component.rebuilt {
+            println!("  WARNING: Rebuilt by `bootupctl state repair`, until the next update");
+        }
 
         if let Some(i) = component.interrupted.as_ref() {
             println!(
@@ -372,11 +1361,41 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
             )),
         };
         println!("  Update: {}", msg);
+        if let Some(e) = component.update_estimate.as_ref() {
+            let duration = e
+                .seconds
+                .map(|s| format!(", taking ~{s}s"))
+                .unwrap_or_default();
+            println!(
+                "  Update will write ~{:.1} MiB across {} files{duration}",
+                e.bytes as f64 / (1024.0 * 1024.0),
+                e.files
+            );
+        }
+        if let Some(s) = component.staged.as_ref() {
+            println!("  Staged: {} (applied at shutdown)", s.version);
+        }
+        if let Some(pcr4) = component.pcr4.as_ref() {
+            println!("  Predicted PCR 4: {}", pcr4.sha256);
+        }
+        if let Some(previous) = component.key_rotation.as_ref() {
+            println!(
+                "  Key rotation: previous boot chain {} kept; see finalize-rotation",
+                previous.version
+            );
+        }
+        if let Some(pending) = component.pending_nvram.as_ref() {
+            let ops: Vec<_> = pending.iter().map(|op| op.to_string()).collect();
+            println!("  Pending EFI variable writes: {}", ops.join(", "));
+        }
+        if status.config.is_disabled(name) {
+            println!("  Disabled in configuration");
+        }
+    }
+
+    if status.adoptable.is_empty() {
+        println!("No components are adoptable.");
     }
-
-    if status.adoptable.is_empty() {
-        println!("No components are adoptable.");
-    }
     for (name, adopt) in status.adoptable.iter() {
         let ver = &adopt.version.version;
         if adopt.confident {
@@ -386,11 +1405,104 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
         }
     }
 
+    for d in status.drift.iter() {
+        println!(
+            "WARNING: {} was modified out of band ({})",
+            d.path,
+            d.time.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+
+    for esp in status.esps.iter() {
+        match esp.mountpoint.as_deref() {
+            Some(mnt) => println!("ESP: {} (mounted at {mnt})", esp.device),
+            None => println!("ESP: {}", esp.device),
+        }
+        let efi = status.components.get("EFI").map(|c| &c.installed);
+        if let (Some(installed), Some(efi)) = (esp.installed.as_ref(), efi) {
+            if installed.version != efi.version {
+                println!("  WARNING: At {}", installed.version);
+            }
+        }
+        if !esp.leftovers.is_empty() {
+            println!(
+                "  WARNING: Temporary files of an interrupted update, removed by the next update: {}",
+                esp.leftovers.join(", ")
+            );
+        }
+    }
+
+    if let Some(efi_boot) = status.efi_boot.as_ref() {
+        match efi_boot.entry {
+            Some(n) if efi_boot.is_first() => println!("EFI boot entry: Boot{n:04X}"),
+            Some(n) => println!("WARNING: EFI boot entry Boot{n:04X} is not first in BootOrder"),
+            None => println!("WARNING: No EFI boot entry found"),
+        }
+        for n in efi_boot.dangling.iter() {
+            println!("WARNING: EFI boot entry Boot{n:04X} references a missing file");
+        }
+        if !efi_boot.is_ok() {
+            println!("Run `bootupctl fix-bootorder` to repair the EFI boot entries");
+        }
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    if let Some(sbat) = status.sbat.as_ref() {
+        if let Some(level) = sbat.level.as_ref() {
+            println!("SBAT level: {}", crate::sbat::format(level));
+        }
+        for (path, generations) in sbat.installed.iter() {
+            println!("SBAT: {path}: {}", crate::sbat::format(generations));
+        }
+        for r in sbat.revoked.iter() {
+            println!("WARNING: Update revoked by SBAT level: {r}");
+        }
+    }
+
+    if let Some(sb) = status.secure_boot.as_ref() {
+        let state = match (sb.enabled, sb.setup_mode) {
+            (true, _) => "enabled",
+            (false, true) => "disabled (setup mode)",
+            (false, false) => "disabled",
+        };
+        println!("Secure Boot: {state}");
+        if let Some(v) = sb.shim_version.as_ref() {
+            println!("Installed shim: {v}");
+        }
+        for p in sb.problems.iter() {
+            println!("WARNING: {p}");
+        }
+    }
+
     if let Some(coreos_aleph) = coreos::get_aleph_version(Path::new("/"))? {
         println!("CoreOS aleph version: {}", coreos_aleph.aleph.version);
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let config = &status.config;
+    let auto = serde_json::to_value(config.update.auto)?;
+    println!("Automatic updates: {}", auto.as_str().unwrap_or_default());
+    if !config.components.disabled.is_empty() {
+        let disabled: Vec<_> = config
+            .components
+            .disabled
+            .iter()
+            .map(String::as_str)
+            .collect();
+        println!("Disabled components: {}", disabled.join(" "));
+    }
+    if let Some(vendor) = config.efi.vendor.as_deref() {
+        println!("EFI vendor: {vendor} (configured)");
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     {
         let boot_method = if efi::is_efi_booted()? { "EFI" } else { "BIOS" };
         println!("Boot method: {}", boot_method);
@@ -399,29 +1511,122 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn client_run_update() -> Result<()> {
+/// Print the plan for updating `components` (or all components)
+fn client_run_update_dry_run(
+    status: &Status,
+    components: &[String],
+    allow_downgrade: bool,
+) -> Result<Pending> {
+    let selected = |name: &str| components.is_empty() || components.iter().any(|c| c == name);
+    let mut updatable = false;
+    let mut adoption = false;
+    for (name, cstatus) in status.components.iter() {
+        if !selected(name) {
+            continue;
+        }
+        match cstatus.updatable {
+            ComponentUpdatable::Upgradable => {}
+            ComponentUpdatable::WouldDowngrade if allow_downgrade => {}
+            ComponentUpdatable::WouldDowngrade => {
+                println!("Component {}: {}", name, refuse_downgrade_msg(cstatus));
+                continue;
+            }
+            _ => {
+                println!("Component {}: No update available", name);
+                continue;
+            }
+        }
+        let Some(plan) = plan_update(name, allow_downgrade)? else {
+            println!("Component {}: No update available", name);
+            continue;
+        };
+        println!("Component {}", name);
+        println!("  Previous: {}", plan.previous.version);
+        println!("  New: {}", plan.new.version);
+        for action in plan.actions.iter() {
+            println!("  {}", action);
+        }
+        updatable = true;
+    }
+    for (name, adoptable) in status.adoptable.iter() {
+        if !selected(name) {
+            continue;
+        }
+        if adoptable.confident {
+            println!(
+                "Would adopt and update: {}: {}",
+                name, adoptable.version.version
+            );
+            updatable = true;
+        } else {
+            println!("Component {} requires explicit adopt-and-update", name);
+            adoption = true;
+        }
+    }
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let sysroot = openat::Dir::open("/")?;
+    if let Some(meta) = query_static_configs_update(&state, &sysroot)? {
+        println!("Would update static GRUB configs: {}", meta.version);
+        updatable = true;
+    }
+    if !updatable {
+        println!("No update available for any component.");
+    }
+    Ok(match (updatable, adoption) {
+        (true, _) => Pending::Updates,
+        (false, true) => Pending::Adoption,
+        (false, false) => Pending::Nothing,
+    })
+}
+
+fn refuse_downgrade_msg(cstatus: &ComponentStatus) -> String {
+    let update = cstatus.update.as_ref().expect("update");
+    format!(
+        "Refusing to downgrade from {} to {}; use --allow-downgrade",
+        cstatus.installed.version, update.version
+    )
+}
+
+/// Update all components, or only those listed in `components`.  With
+/// `dry_run`, only print what would be done and return what is pending;
+/// otherwise `Pending::Nothing` is returned.  Components whose available
+/// payload is older than the installed one are only downgraded with
+/// `allow_downgrade`; explicitly listing them is an error otherwise.
+pub(crate) fn client_run_update(
+    components: &[String],
+    dry_run: bool,
+    allow_downgrade: bool,
+) -> Result<Pending> {
     crate::try_fail_point!("update");
     let status: Status = status()?;
     if status.components.is_empty() && status.adoptable.is_empty() {
         println!("No components installed.");
-        return Ok(());
+        return Ok(Pending::Nothing);
     }
-    let mut updated = false;
-    for (name, cstatus) in status.components.iter() {
-        match cstatus.updatable {
-            ComponentUpdatable::Upgradable => {}
-            _ => continue,
-        };
-        match update(name)? {
-            ComponentUpdateResult::AtLatestVersion => {
-                // Shouldn't happen unless we raced with another client
-                eprintln!(
-                    "warning: Expected update for {}, raced with a different client?",
-                    name
-                );
-                continue;
+    for name in components {
+        if !status.components.contains_key(name) && !status.adoptable.contains_key(name) {
+            anyhow::bail!("Component {} is not installed", name);
+        }
+    }
+    if dry_run {
+        return client_run_update_dry_run(&status, components, allow_downgrade);
+    }
+    let bars: Mutex<BTreeMap<String, ProgressBar>> = Mutex::default();
+    let summary = run_update(&status, components, allow_downgrade, &|event| {
+        let mut bars = bars.lock().unwrap();
+        match event {
+            UpdateEvent::Updating(name) => {
+                let bar = ProgressBar::new(format!("Updating {name}"));
+                bars.insert(name.to_string(), bar);
             }
-            ComponentUpdateResult::Updated {
+            UpdateEvent::Progress(name, p) => {
+                if let Some(bar) = bars.get(name) {
+                    bar.update(p);
+                }
+            }
+            UpdateEvent::Written => bars.clear(),
+            UpdateEvent::Updated {
+                name,
                 previous,
                 interrupted,
                 new,
@@ -435,24 +1640,640 @@ pub(crate) fn client_run_update() -> Result<()> {
                 println!("Previous {}: {}", name, previous.version);
                 println!("Updated {}: {}", name, new.version);
             }
+            UpdateEvent::Adopted(name, new) => {
+                println!("Adopted and updated: {}: {}", name, new.version)
+            }
+            UpdateEvent::StaticConfigs(meta) => {
+                println!("Updated static GRUB configs: {}", meta.version)
+            }
+            UpdateEvent::Skipped(_, reason) => println!("{reason}"),
+            UpdateEvent::Warning(_, message) => eprintln!("warning: {message}"),
+            UpdateEvent::Failed(_, e) => eprintln!("error: {e:#}"),
         }
-        updated = true;
+    })?;
+    if !summary.changed {
+        println!("No update available for any component.");
+    }
+    Ok(Pending::Nothing)
+}
+
+/// What `run_update` reports as it goes, printed by `bootupctl update` or
+/// emitted as signals by the D-Bus daemon.
+pub(crate) enum UpdateEvent<'a> {
+    /// The update of a component starts
+    Updating(&'a str),
+    /// How far the update of a component is
+    Progress(&'a str, &'a Progress),
+    /// All the updates were written
+    Written,
+    Updated {
+        name: &'a str,
+        previous: &'a ContentMetadata,
+        interrupted: Option<&'a ContentMetadata>,
+        new: &'a ContentMetadata,
+    },
+    /// A component was adopted, then updated
+    Adopted(&'a str, &'a ContentMetadata),
+    /// The static GRUB configs were updated
+    StaticConfigs(&'a ContentMetadata),
+    /// A component isn't updated, and why
+    Skipped(&'a str, String),
+    Warning(&'a str, String),
+    /// The update of a component failed; the others still are
+    Failed(&'a str, &'a anyhow::Error),
+}
+
+/// A callback receiving the events of `run_update`; it may be called from
+/// several threads.
+pub(crate) type UpdateEventFn<'a> = &'a (dyn Fn(UpdateEvent) + Sync);
+
+/// What `run_update` did.
+#[derive(Debug, Default)]
+pub(crate) struct UpdateSummary {
+    /// The new version of each updated or adopted component
+    pub(crate) versions: BTreeMap<String, String>,
+    /// Whether anything was modified, including the static GRUB configs
+    /// and the deferred EFI variable writes
+    pub(crate) changed: bool,
+}
+
+/// Update the components of `status`, or only those listed in
+/// `components`, adopt those which can reliably be, then apply the deferred
+/// EFI variable writes and update the static GRUB configs, reporting the
+/// progress to `report`.  This is shared by `bootupctl update` and the
+/// D-Bus daemon, so that both skip the disabled components and refuse the
+/// same downgrades; it blocks.
+pub(crate) fn run_update(
+    status: &Status,
+    components: &[String],
+    allow_downgrade: bool,
+    report: UpdateEventFn,
+) -> Result<UpdateSummary> {
+    let selected = |name: &str| components.is_empty() || components.iter().any(|c| c == name);
+    let mut summary = UpdateSummary::default();
+    let mut to_update = Vec::new();
+    for (name, cstatus) in status.components.iter() {
+        if !selected(name) {
+            continue;
+        }
+        match cstatus.updatable {
+            ComponentUpdatable::Upgradable => {}
+            ComponentUpdatable::WouldDowngrade if allow_downgrade => {}
+            ComponentUpdatable::WouldDowngrade if !components.is_empty() => {
+                anyhow::bail!("Component {}: {}", name, refuse_downgrade_msg(cstatus));
+            }
+            ComponentUpdatable::WouldDowngrade => {
+                report(UpdateEvent::Warning(
+                    name,
+                    format!("{}: {}", name, refuse_downgrade_msg(cstatus)),
+                ));
+                continue;
+            }
+            _ => continue,
+        };
+        if status.config.is_disabled(name) {
+            report(UpdateEvent::Skipped(
+                name,
+                format!("Skipping {}: disabled in configuration", name),
+            ));
+            continue;
+        }
+        to_update.push(name.clone());
+    }
+    for name in to_update.iter() {
+        report(UpdateEvent::Updating(name));
+    }
+    let results = update_components(&to_update, allow_downgrade, &|name, p| {
+        report(UpdateEvent::Progress(name, p))
+    })?;
+    report(UpdateEvent::Written);
+    let mut failed = Vec::new();
+    for (name, r) in results {
+        match r {
+            Err(e) => {
+                report(UpdateEvent::Failed(name, &e));
+                failed.push(name);
+                continue;
+            }
+            Ok(ComponentUpdateResult::AtLatestVersion) => {
+                // Shouldn't happen unless we raced with another client
+                report(UpdateEvent::Warning(
+                    name,
+                    format!(
+                        "Expected update for {}, raced with a different client?",
+                        name
+                    ),
+                ));
+                continue;
+            }
+            Ok(ComponentUpdateResult::Updated {
+                previous,
+                interrupted,
+                new,
+            }) => {
+                report(UpdateEvent::Updated {
+                    name,
+                    previous: &previous,
+                    interrupted: interrupted.as_ref(),
+                    new: &new,
+                });
+                summary.versions.insert(name.to_string(), new.version);
+            }
+        }
+        summary.changed = true;
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to update {}", failed.join(" "));
     }
     for (name, adoptable) in status.adoptable.iter() {
+        if !selected(name) {
+            continue;
+        }
         if adoptable.confident {
             let r: ContentMetadata = adopt_and_update(name)?;
-            println!("Adopted and updated: {}: {}", name, r.version);
-            updated = true;
+            report(UpdateEvent::Adopted(name, &r));
+            summary.versions.insert(name.clone(), r.version);
+            summary.changed = true;
         } else {
-            println!("Component {} requires explicit adopt-and-update", name);
+            report(UpdateEvent::Skipped(
+                name,
+                format!("Component {} requires explicit adopt-and-update", name),
+            ));
+        }
+    }
+    if apply_pending_nvram()? {
+        summary.changed = true;
+    }
+    if let Some(meta) = update_static_configs()? {
+        report(UpdateEvent::StaticConfigs(&meta));
+        summary.changed = true;
+    }
+    Ok(summary)
+}
+
+/// Retry the writes to the firmware variables deferred by the install or
+/// previous updates, now that efivarfs may be writable; failures are only
+/// warned about.  Returns `true` if any was applied.
+fn apply_pending_nvram() -> Result<bool> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    if !state.installed.values().any(|i| i.pending_nvram.is_some()) {
+        return Ok(false);
+    }
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let mut applied = false;
+    for (name, inst) in state.installed.iter_mut() {
+        if inst.pending_nvram.is_none() {
+            continue;
+        }
+        match component::new_from_name(name)
+            .and_then(|c| c.apply_pending_nvram(&state_guard.sysroot, inst))
+        {
+            Ok(Some(newinst)) => {
+                println!("Applied the deferred EFI variable writes of {name}");
+                *inst = newinst;
+                applied = true;
+            }
+            Ok(None) => log::debug!("{name}: EFI variable writes still deferred"),
+            Err(e) => eprintln!("warning: {name}: {e:#}"),
+        }
+    }
+    if applied {
+        state_guard.update_state(&state)?;
+    }
+    Ok(applied)
+}
+
+/// The unit applying the updates staged by `update --stage` when stopped,
+/// at shutdown
+const FINALIZE_STAGED_UNIT: &str = "bootupd-finalize-staged.service";
+
+/// Record the available updates of `components` (or all components) in the
+/// state, and start the unit applying them at shutdown, when no other
+/// workload touches the disks.
+pub(crate) fn client_run_stage_update(components: &[String]) -> Result<()> {
+    let status: Status = status()?;
+    for name in components {
+        if !status.components.contains_key(name) {
+            anyhow::bail!("Component {} is not installed", name);
         }
     }
-    if !updated {
+    let selected = |name: &str| components.is_empty() || components.iter().any(|c| c == name);
+    let mut staged = BTreeMap::new();
+    for (name, cstatus) in status.components.iter() {
+        if !selected(name) || status.config.is_disabled(name) {
+            continue;
+        }
+        if let (ComponentUpdatable::Upgradable, Some(update)) =
+            (&cstatus.updatable, cstatus.update.as_ref())
+        {
+            staged.insert(name.clone(), update.clone());
+        }
+    }
+    if staged.is_empty() {
         println!("No update available for any component.");
+        return Ok(());
+    }
+    ensure_writable_boot()?;
+    {
+        let sysroot = openat::Dir::open("/")?;
+        let mut state_guard =
+            SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+        let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+        state.staged = Some(staged.clone());
+        state_guard.update_state(&state)?;
+    }
+    std::process::Command::new("systemctl")
+        .args(["start", FINALIZE_STAGED_UNIT])
+        .run()?;
+    for (name, update) in staged {
+        println!("Staged {}: {}", name, update.version);
+    }
+    println!("The update will be applied at shutdown");
+    Ok(())
+}
+
+/// Apply the updates staged by `update --stage`; run by
+/// bootupd-finalize-staged.service at shutdown.
+pub(crate) fn client_run_finalize_staged() -> Result<()> {
+    let staged = {
+        ensure_writable_boot()?;
+        let sysroot = openat::Dir::open("/")?;
+        let mut state_guard =
+            SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+        let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+        let Some(staged) = state.staged.take() else {
+            println!("No staged update.");
+            return Ok(());
+        };
+        // Not retried at the next shutdown if it fails
+        state_guard.update_state(&state)?;
+        staged
+    };
+    let names: Vec<String> = staged.into_keys().collect();
+    let results = update_components(&names, false, &|_, _| {})?;
+    let mut failed = Vec::new();
+    for (name, r) in results {
+        match r {
+            Ok(ComponentUpdateResult::Updated { new, .. }) => {
+                println!("Updated {}: {}", name, new.version);
+            }
+            Ok(ComponentUpdateResult::AtLatestVersion) => {
+                println!("{} is at the latest version", name);
+            }
+            Err(e) => {
+                eprintln!("error: {e:#}");
+                failed.push(name);
+            }
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to update {}", failed.join(" "));
+    }
+    Ok(())
+}
+
+/// Update components according to the configured automatic update policy
+pub(crate) fn client_run_auto_update() -> Result<()> {
+    let config = Config::load(Path::new("/"))?;
+    match config.update.auto {
+        AutoUpdatePolicy::None => {
+            println!("Automatic updates are disabled.");
+            Ok(())
+        }
+        AutoUpdatePolicy::Update => {
+            let installed: Vec<String> = status()?.components.into_keys().collect();
+            if installed.is_empty() {
+                println!("No components installed.");
+                return Ok(());
+            }
+            client_run_update(&installed, false, false).map(drop)
+        }
+        AutoUpdatePolicy::All => client_run_update(&[], false, false).map(drop),
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_fix_bootorder() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    efi::Efi::default().fix_boot_order(&sysroot)
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_fix_bootorder() -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+/// Remove the firmware boot entries of files missing from their ESP; with
+/// `dry_run`, only list them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_prune_boot_entries(dry_run: bool) -> Result<()> {
+    let entries = efi::prune_boot_entries(dry_run)?;
+    if entries.is_empty() {
+        println!("No stale boot entry found.");
+    }
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for entry in entries {
+        println!("{verb} stale boot entry {entry}");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_prune_boot_entries(_dry_run: bool) -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+/// End the Secure Boot key rotation of the EFI component, removing the
+/// previous boot chain kept by the updates since it started.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_finalize_rotation() -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let Some(inst) = state.installed.get_mut("EFI") else {
+        anyhow::bail!("Component EFI is not installed");
+    };
+    let Some(rotation) = inst.rotation.take() else {
+        println!("No key rotation in progress.");
+        return Ok(());
+    };
+    efi::Efi::default().finalize_rotation(&rotation)?;
+    state_guard.update_state(&state)?;
+    println!(
+        "Removed the previous boot chain {} from EFI/{}",
+        rotation.previous.version, rotation.dir
+    );
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_finalize_rotation() -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+pub(crate) fn client_run_rollback(component: &str) -> Result<()> {
+    let status: Status = status()?;
+    let Some(cstatus) = status.components.get(component) else {
+        anyhow::bail!("Component {} is not installed", component);
+    };
+    let restored = rollback(component)?;
+    println!(
+        "Rolled back {}: {} -> {}",
+        component, cstatus.installed.version, restored.version
+    );
+    if cstatus.update.is_some() {
+        println!(
+            "Note: the next update will install {} again",
+            cstatus.installed.version
+        );
+    }
+    Ok(())
+}
+
+/// Print the recorded history
+pub(crate) fn client_run_history() -> Result<()> {
+    let entries = history::load(Path::new("/"))?;
+    history::print(&entries);
+    Ok(())
+}
+
+/// daemon implementation of `bootupctl setenv`: remove the variables
+/// `unset` from the GRUB environment block, then set those of `set`.
+pub(crate) fn set_grubenv(set: &[(String, String)], unset: &[String]) -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    // Serialize with updates, which may rewrite the ESP the block is on
+    let _state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let root = Path::new("/");
+    let mut env = grubenv::load(root)?;
+    for name in unset {
+        env.unset(name);
+    }
+    for (name, value) in set {
+        env.set(name, value)?;
+    }
+    grubenv::save(root, &env)
+}
+
+/// daemon implementation of `bootupctl verify-boot`: count a boot attempt
+/// after updates marked for boot verification, rolling the components back
+/// after more than `max_attempts`, or with `complete`, record that the
+/// boot completed.
+pub(crate) fn client_run_verify_boot(complete: bool, max_attempts: u32) -> Result<()> {
+    ensure_writable_boot()?;
+    let root = Path::new("/");
+    let rollbacks = {
+        let sysroot = openat::Dir::open("/")?;
+        let _state_guard =
+            SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+        let mut env = grubenv::load(root)?;
+        let orig = env.clone();
+        let rollbacks = if complete {
+            let verified = bootverify::complete(&mut env);
+            if !verified.is_empty() {
+                println!("Boot verified after updating {}", verified.join(" "));
+            }
+            Vec::new()
+        } else {
+            bootverify::attempt(&mut env, max_attempts)?
+        };
+        if env != orig {
+            grubenv::save(root, &env)?;
+        }
+        rollbacks
+    };
+    for name in rollbacks {
+        eprintln!("Boot did not complete {max_attempts} times after updating {name}");
+        match rollback(&name) {
+            Ok(meta) => println!("Rolled back {name}: {}", meta.version),
+            Err(e) => eprintln!("error: Rolling back {name}: {e:#}"),
+        }
     }
     Ok(())
 }
 
+/// Print the variables `names` of the GRUB environment block, or all of
+/// them as `name=value` if none are given.
+pub(crate) fn client_run_getenv(names: &[String]) -> Result<()> {
+    let env = grubenv::load(Path::new("/"))?;
+    if names.is_empty() {
+        for (name, value) in env.vars() {
+            println!("{name}={value}");
+        }
+        return Ok(());
+    }
+    for name in names {
+        let Some(value) = env.get(name) else {
+            anyhow::bail!("Variable {name} is not set");
+        };
+        println!("{value}");
+    }
+    Ok(())
+}
+
+/// daemon implementation of `bootupctl state migrate`: rewrite the state
+/// file in the current schema version, and its copies if they differ.
+pub(crate) fn client_run_state_migrate() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let Some(loaded) = SavedState::load_migrated(&state_guard.sysroot)? else {
+        println!("No state file found");
+        return Ok(());
+    };
+    if !loaded.migrations.is_empty() || loaded.needs_sync {
+        ensure_writable_boot()?;
+        state_guard.update_state(&loaded.state)?;
+        for m in loaded.migrations {
+            println!("Migrated: {m}");
+        }
+        if loaded.needs_sync {
+            println!("Synchronized the copies of the state file");
+        }
+    }
+    println!(
+        "State file at schema version {}",
+        crate::model::STATE_SCHEMA_VERSION
+    );
+    Ok(())
+}
+
+/// daemon implementation of `bootupctl state repair`: if the state file
+/// can't be loaded, set it aside and rebuild it from what adoption finds on
+/// the system.
+pub(crate) fn client_run_state_repair() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    match SavedState::load_migrated(&state_guard.sysroot) {
+        Ok(Some(_)) => {
+            println!("The state file is valid, nothing to repair");
+            return Ok(());
+        }
+        Ok(None) => anyhow::bail!("No state file found; use adopt-and-update"),
+        Err(e) => println!("Rebuilding the state file: {e:#}"),
+    }
+    ensure_writable_boot()?;
+    let dir = state_guard.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+    let aside = format!("{}.corrupted", SavedState::STATEFILE_NAME);
+    if dir.exists(SavedState::STATEFILE_NAME)? {
+        dir.local_rename(SavedState::STATEFILE_NAME, aside.as_str())
+            .with_context(|| format!("renaming to {aside}"))?;
+        println!(
+            "Moved the invalid state file to /{}/{aside}",
+            SavedState::STATEFILE_DIR
+        );
+    }
+
+    let config = Config::load(Path::new("/"))?;
+    let mut state = SavedState::default();
+    for (name, component) in get_components() {
+        if config.is_disabled(name) {
+            continue;
+        }
+        match component.rebuild_state(&state_guard.sysroot) {
+            Ok(Some(inst)) => {
+                println!("Rebuilt {name}: {}", inst.meta.version);
+                state.installed.insert(name.to_string(), inst);
+            }
+            Ok(None) => {}
+            Err(e) => println!("Failed to rebuild {name}: {e:#}"),
+        }
+    }
+    if state.installed.is_empty() {
+        anyhow::bail!("No installed component found");
+    }
+    state_guard.update_state(&state)?;
+    println!("Run `bootupctl update` to record the installed versions exactly");
+    Ok(())
+}
+
+pub(crate) fn client_run_backup(dest: &Path) -> Result<()> {
+    crate::backup::backup(dest)?;
+    println!("Backed up bootloaders to {}", dest.display());
+    Ok(())
+}
+
+pub(crate) fn client_run_restore(src: &Path) -> Result<()> {
+    let restored = crate::backup::restore(src)?;
+    for (name, meta) in restored.iter() {
+        println!("Restored {}: {}", name, meta.version);
+    }
+    Ok(())
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_efivars_backup(dest: &Path) -> Result<()> {
+    let n = crate::bootvars::backup(dest)?;
+    println!("Backed up {n} EFI variables to {}", dest.display());
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_efivars_backup(_dest: &Path) -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_efivars_restore(src: &Path) -> Result<()> {
+    for name in crate::bootvars::restore(src)? {
+        println!("Restored EFI variable {name}");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_efivars_restore(_src: &Path) -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
 pub(crate) fn client_run_adopt_and_update() -> Result<()> {
     let status: Status = status()?;
     if status.adoptable.is_empty() {
@@ -466,33 +2287,190 @@ pub(crate) fn client_run_adopt_and_update() -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn client_run_validate() -> Result<()> {
+/// Validate all installed components, see `validate`
+pub(crate) fn validate_all(strict: bool) -> Result<ValidationReport> {
     let status: Status = status()?;
-    if status.components.is_empty() {
+    let mut components = BTreeMap::new();
+    for (name, _) in status.components.iter() {
+        let (verdict, errors) = match validate(name, strict)? {
+            ValidationResult::Valid => (ValidationVerdict::Valid, Vec::new()),
+            ValidationResult::Skip => (ValidationVerdict::Skip, Vec::new()),
+            ValidationResult::Errors(errs) => (ValidationVerdict::Errors, errs),
+        };
+        components.insert(name.clone(), ComponentValidation { verdict, errors });
+    }
+    Ok(ValidationReport::new(components))
+}
+
+/// Ask on the terminal whether to remove the extraneous `files`, unless
+/// `assume_yes`.
+fn confirm_prune(files: &[String], assume_yes: bool) -> Result<bool> {
+    use std::io::{BufRead, IsTerminal, Write};
+    for f in files {
+        println!("Extraneous: {f}");
+    }
+    if assume_yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Not removing extraneous files without a terminal; pass --yes");
+    }
+    print!("Remove {} files? [y/N] ", files.len());
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Remove the extraneous files of the ESP after confirmation.
+pub(crate) fn client_run_prune(assume_yes: bool) -> Result<()> {
+    let removed = prune(&|files| confirm_prune(files, assume_yes))?;
+    if removed.is_empty() {
+        println!("No extraneous files removed.");
+    }
+    for f in removed {
+        println!("Removed: {f}");
+    }
+    Ok(())
+}
+
+/// The quarantines of the files removed by `validate --prune` on the ESPs.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn quarantines() -> Result<Vec<quarantine::Quarantine>> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    if !state.installed.contains_key("EFI") {
+        return Ok(Vec::new());
+    }
+    efi::Efi::default().quarantines()
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn quarantines() -> Result<Vec<quarantine::Quarantine>> {
+    Ok(Vec::new())
+}
+
+/// Move the files of the quarantine `id` back to the ESPs.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_quarantine_restore(id: &str) -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let _state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    for f in efi::Efi::default().restore_quarantine(id)? {
+        println!("Restored: {f}");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_quarantine_restore(_id: &str) -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+/// Remove the quarantines `ids`, or all of them, or those older than
+/// `efi.quarantine-days` if `expired`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn client_run_quarantine_purge(ids: &[String], all: bool, expired: bool) -> Result<()> {
+    ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let _state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let days = Config::load(Path::new("/"))?.efi.quarantine_days();
+    let now = chrono::Utc::now();
+    let select = |q: &quarantine::Quarantine| {
+        all || ids.contains(&q.id)
+            || (expired && days.is_some_and(|days| quarantine::is_expired(q, days, now)))
+    };
+    let purged = efi::Efi::default().purge_quarantines(&select)?;
+    if purged.is_empty() {
+        println!("No quarantine purged.");
+    }
+    for q in purged.iter() {
+        match q.device.as_deref() {
+            Some(device) => println!("Purged: {} (ESP {device})", q.id),
+            None => println!("Purged: {}", q.id),
+        }
+    }
+    if let Some(id) = ids.iter().find(|id| !purged.iter().any(|q| q.id == **id)) {
+        anyhow::bail!("No quarantine {id}");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn client_run_quarantine_purge(
+    _ids: &[String],
+    _all: bool,
+    _expired: bool,
+) -> Result<()> {
+    anyhow::bail!("EFI is not supported on this architecture")
+}
+
+/// Restore the content of the installed components which failed validation.
+pub(crate) fn client_run_repair() -> Result<()> {
+    let repaired = repair_all()?;
+    if repaired.is_empty() {
+        println!("Nothing to repair.");
+    }
+    for (name, files) in repaired {
+        for f in files {
+            println!("Repaired {name}: {f}");
+        }
+    }
+    Ok(())
+}
+
+/// Print the validation errors of the installed components, see `validate`;
+/// returns the overall verdict.
+pub(crate) fn client_run_validate(strict: bool) -> Result<ValidationVerdict> {
+    let report = validate_all(strict)?;
+    if report.components.is_empty() {
         println!("No components installed.");
-        return Ok(());
+        return Ok(report.verdict);
     }
-    let mut caught_validation_error = false;
-    for (name, _) in status.components.iter() {
-        match validate(name)? {
-            ValidationResult::Valid => {
+    for (name, c) in report.components.iter() {
+        match c.verdict {
+            ValidationVerdict::Valid => {
                 println!("Validated: {}", name);
             }
-            ValidationResult::Skip => {
+            ValidationVerdict::Skip => {
                 println!("Skipped: {}", name);
             }
-            ValidationResult::Errors(errs) => {
-                for err in errs {
+            ValidationVerdict::Errors => {
+                for err in c.errors.iter() {
                     eprintln!("{}", err);
                 }
-                caught_validation_error = true;
             }
         }
     }
-    if caught_validation_error {
-        anyhow::bail!("Caught validation errors");
+    if report.verdict == ValidationVerdict::Errors {
+        eprintln!("Caught validation errors");
     }
-    Ok(())
+    Ok(report.verdict)
 }
 
 #[context("Migrating to a static GRUB config")]
@@ -647,7 +2625,7 @@ mod tests {
     fn test_failpoint_update() {
         let guard = fail::FailScenario::setup();
         fail::cfg("update", "return").unwrap();
-        let r = client_run_update();
+        let r = client_run_update(&[], false, false);
         assert_eq!(r.is_err(), true);
         guard.teardown();
     }
@@ -0,0 +1,152 @@
+//! Backups of the EFI boot variables, so that the boot entries can be
+//! recovered with the ESP content after a motherboard replacement or a
+//! firmware reset.
+//!
+//! A backup is a JSON file holding the data of the `Boot####`, `BootOrder`,
+//! `Driver####`, `DriverOrder` and `Timeout` variables, in hex.  Restoring
+//! writes them back, replacing the variables of the same name; the entries
+//! created by the firmware since then are kept, after those of the backup
+//! in `BootOrder`.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::efi;
+use crate::efivars;
+
+/// The version of the backup format
+const FORMAT_VERSION: u32 = 1;
+/// The load options, `<prefix>####`, and the variables ordering them,
+/// `<prefix>Order`
+const LOAD_OPTION_PREFIXES: &[&str] = &["Boot", "Driver"];
+/// The other variables backed up
+const OTHER_VARS: &[&str] = &["Timeout"];
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Backup {
+    version: u32,
+    timestamp: DateTime<Utc>,
+    /// The variable data, in hex, keyed by name
+    variables: BTreeMap<String, String>,
+}
+
+/// Returns `true` if the global variable `name` is backed up.
+fn is_backed_up(name: &str) -> bool {
+    if OTHER_VARS.contains(&name) {
+        return true;
+    }
+    LOAD_OPTION_PREFIXES.iter().any(|prefix| {
+        name.strip_prefix(prefix).is_some_and(|rest| {
+            rest == "Order" || (rest.len() == 4 && rest.chars().all(|c| c.is_ascii_hexdigit()))
+        })
+    })
+}
+
+/// Parse an `Order` variable, a list of 16-bit entry numbers.
+fn parse_order(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect()
+}
+
+/// An `Order` variable after restoring `backup`: the entries of the backup,
+/// then the current ones it doesn't have.
+fn merge_order(backup: &[u16], current: &[u16]) -> Vec<u8> {
+    let mut r = backup.to_vec();
+    r.extend(current.iter().filter(|n| !backup.contains(n)));
+    r.iter().flat_map(|n| n.to_le_bytes()).collect()
+}
+
+fn ensure_efi_booted() -> Result<()> {
+    if !efi::is_efi_booted()? {
+        bail!("Not booted via EFI");
+    }
+    Ok(())
+}
+
+/// Write the boot variables to `dest`.
+#[context("Backing up EFI variables to {}", dest.display())]
+pub(crate) fn backup(dest: &Path) -> Result<usize> {
+    ensure_efi_booted()?;
+    let mut variables = BTreeMap::new();
+    for name in efivars::var_names()? {
+        if !is_backed_up(&name) {
+            continue;
+        }
+        if let Some(data) = efivars::read_var(&name)? {
+            variables.insert(name, hex::encode(data));
+        }
+    }
+    let backup = Backup {
+        version: FORMAT_VERSION,
+        timestamp: Utc::now(),
+        variables,
+    };
+    let n = backup.variables.len();
+    std::fs::write(dest, serde_json::to_vec_pretty(&backup)?)?;
+    Ok(n)
+}
+
+/// Write back the boot variables backed up to `src`; returns their names.
+#[context("Restoring EFI variables from {}", src.display())]
+pub(crate) fn restore(src: &Path) -> Result<Vec<String>> {
+    let backup: Backup = serde_json::from_slice(&std::fs::read(src)?)
+        .context("Not a bootupd EFI variables backup")?;
+    if backup.version != FORMAT_VERSION {
+        bail!("Unsupported backup format version {}", backup.version);
+    }
+    ensure_efi_booted()?;
+    if !efi::efivars_writable()? {
+        bail!("EFI variables are not writable");
+    }
+    let mut restored = Vec::new();
+    // In name order, `Boot####` is written before `BootOrder`
+    for (name, data) in backup.variables.iter() {
+        if !is_backed_up(name) {
+            bail!("Unexpected variable {name} in backup");
+        }
+        let mut data = hex::decode(data).with_context(|| format!("decoding {name}"))?;
+        if name.ends_with("Order") {
+            let current = efivars::read_var(name)?.unwrap_or_default();
+            data = merge_order(&parse_order(&data), &parse_order(&current));
+        }
+        efivars::write_var(name, &data)?;
+        restored.push(name.clone());
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_backed_up() {
+        for name in [
+            "Boot0001",
+            "Boot00AF",
+            "BootOrder",
+            "DriverOrder",
+            "Timeout",
+        ] {
+            assert!(is_backed_up(name), "{name}");
+        }
+        for name in ["BootCurrent", "BootNext", "Boot1", "BootXYZW", "SecureBoot"] {
+            assert!(!is_backed_up(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_merge_order() {
+        let backup = parse_order(&[0x01, 0x00, 0x03, 0x00]);
+        assert_eq!(backup, [1, 3]);
+        assert_eq!(parse_order(&merge_order(&backup, &[0, 3])), [1, 3, 0]);
+        assert_eq!(merge_order(&backup, &[]), [0x01, 0x00, 0x03, 0x00]);
+    }
+}
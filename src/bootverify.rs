@@ -0,0 +1,114 @@
+//! Boot verification of risky updates, with greenboot or any other user of
+//! `boot-complete.target`.
+//!
+//! With `verify-boot = true` in the `[update]` section of the
+//! configuration, updated components are listed in the `bootupd_verify`
+//! variable of the GRUB environment block.  Early at each boot,
+//! `bootupd-boot-verify.service` counts the attempt in
+//! `bootupd_boot_attempts`, and once `boot-complete.target` is reached,
+//! `bootupd-boot-complete.service` clears both.  If too many boots in a
+//! row didn't complete, the components are rolled back to the version
+//! backed up by their update.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::grubenv::{self, GrubEnv};
+
+/// The components updated since the last verified boot
+const VERIFY_VAR: &str = "bootupd_verify";
+/// The boots since the update, including the current one
+const ATTEMPTS_VAR: &str = "bootupd_boot_attempts";
+
+/// Mark the updated `components` as needing boot verification, in addition
+/// to those not verified yet.
+pub(crate) fn mark(root: &Path, components: &[&str]) -> Result<()> {
+    let mut env = grubenv::load(root)?;
+    mark_env(&mut env, components)?;
+    grubenv::save(root, &env)
+}
+
+fn mark_env(env: &mut GrubEnv, components: &[&str]) -> Result<()> {
+    let mut names: BTreeSet<String> = pending(env).into_iter().collect();
+    names.extend(components.iter().map(|c| c.to_string()));
+    let names: Vec<_> = names.into_iter().collect();
+    env.set(VERIFY_VAR, &names.join(" "))?;
+    env.set(ATTEMPTS_VAR, "0")
+}
+
+/// The components needing boot verification.
+fn pending(env: &GrubEnv) -> Vec<String> {
+    env.get(VERIFY_VAR)
+        .unwrap_or_default()
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// Clear the verification variables.
+fn clear(env: &mut GrubEnv) {
+    env.unset(VERIFY_VAR);
+    env.unset(ATTEMPTS_VAR);
+}
+
+/// Count a boot attempt; returns the components to roll back, once more
+/// than `max_attempts` boots didn't complete, which are then no longer
+/// verified.
+pub(crate) fn attempt(env: &mut GrubEnv, max_attempts: u32) -> Result<Vec<String>> {
+    let components = pending(env);
+    if components.is_empty() {
+        return Ok(Vec::new());
+    }
+    let attempts = env
+        .get(ATTEMPTS_VAR)
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or_default()
+        + 1;
+    if attempts > max_attempts {
+        clear(env);
+        return Ok(components);
+    }
+    env.set(ATTEMPTS_VAR, &attempts.to_string())?;
+    Ok(Vec::new())
+}
+
+/// Record a completed boot; returns the verified components.
+pub(crate) fn complete(env: &mut GrubEnv) -> Vec<String> {
+    let components = pending(env);
+    clear(env);
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_boot_verification() -> Result<()> {
+        let mut env = GrubEnv::default();
+        assert!(attempt(&mut env, 3)?.is_empty());
+        assert_eq!(env, GrubEnv::default());
+
+        mark_env(&mut env, &["EFI"])?;
+        assert_eq!(env.get(VERIFY_VAR), Some("EFI"));
+        for n in 1..=3 {
+            assert!(attempt(&mut env, 3)?.is_empty());
+            assert_eq!(env.get(ATTEMPTS_VAR), Some(n.to_string().as_str()));
+        }
+        assert_eq!(attempt(&mut env, 3)?, ["EFI"]);
+        assert_eq!(env.get(VERIFY_VAR), None);
+        assert_eq!(env.get(ATTEMPTS_VAR), None);
+
+        mark_env(&mut env, &["EFI"])?;
+        attempt(&mut env, 3)?;
+        mark_env(&mut env, &["BIOS", "EFI"])?;
+        assert_eq!(env.get(VERIFY_VAR), Some("BIOS EFI"));
+        assert_eq!(env.get(ATTEMPTS_VAR), Some("0"));
+        assert_eq!(complete(&mut env), ["BIOS", "EFI"]);
+        assert_eq!(env.get(VERIFY_VAR), None);
+        assert!(complete(&mut env).is_empty());
+        Ok(())
+    }
+}
@@ -1,11 +1,90 @@
-use crate::bootupd;
+use crate::backend::StateLocked;
+use crate::bootupd::{self, Pending};
+use crate::component::Unsupported;
+use crate::model::{HealthVerdict, ValidationVerdict};
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use log::LevelFilter;
 
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
+/// Exit code of `validate` if validation errors were found
+const EXIT_VALIDATION_ERRORS: i32 = 2;
+/// Exit code of `validate` if no component could be validated
+const EXIT_VALIDATION_SKIPPED: i32 = 3;
+/// Exit code of `status --print-if-available` and `update --dry-run` if
+/// updates are available
+const EXIT_UPDATES_PENDING: i32 = 4;
+/// Exit code of `status --print-if-available` and `update --dry-run` if
+/// components need `adopt-and-update`, and no updates are available
+const EXIT_ADOPTION_NEEDED: i32 = 5;
+/// Exit code if another bootupd process holds the lock
+const EXIT_LOCKED: i32 = 6;
+/// Exit code if the operation isn't supported by the component or platform
+const EXIT_UNSUPPORTED: i32 = 7;
+/// Exit code of `health` if some checks warn, and none fail
+const EXIT_HEALTH_WARN: i32 = 8;
+/// Exit code of `health` if some checks fail
+const EXIT_HEALTH_FAIL: i32 = 9;
+/// Exit code if the command line is invalid, like `EX_USAGE` of
+/// sysexits.h; clap uses 2, which is `EXIT_VALIDATION_ERRORS`
+const EXIT_USAGE: i32 = 64;
+
+/// The exit codes of `bootupctl`, printed by `--help-exit-codes`; scripts
+/// may rely on these staying stable.
+const EXIT_CODES: &[(i32, &str, &str)] = &[
+    (libc::EXIT_SUCCESS, "success", "The command succeeded"),
+    (
+        libc::EXIT_FAILURE,
+        "failure",
+        "Any other error; see the error message",
+    ),
+    (
+        EXIT_VALIDATION_ERRORS,
+        "validation-failed",
+        "validate: validation errors were found",
+    ),
+    (
+        EXIT_VALIDATION_SKIPPED,
+        "validation-skipped",
+        "validate: no component could be validated",
+    ),
+    (
+        EXIT_UPDATES_PENDING,
+        "updates-pending",
+        "status --print-if-available/--check-pending, update --dry-run: updates are available",
+    ),
+    (
+        EXIT_ADOPTION_NEEDED,
+        "adoption-needed",
+        "status --print-if-available/--check-pending, update --dry-run: no updates are \
+available, but components must be adopted with adopt-and-update",
+    ),
+    (
+        EXIT_LOCKED,
+        "locked-by-another-process",
+        "Another bootupd operation (e.g. an update) is in progress; see --wait",
+    ),
+    (
+        EXIT_UNSUPPORTED,
+        "unsupported-platform",
+        "The operation isn't supported by the component or on this platform",
+    ),
+    (
+        EXIT_HEALTH_WARN,
+        "health-warn",
+        "health: some checks warn, and none fail",
+    ),
+    (EXIT_HEALTH_FAIL, "health-fail", "health: some checks fail"),
+    (
+        EXIT_USAGE,
+        "usage",
+        "The command line is invalid; see --help",
+    ),
+];
+
 static SYSTEMD_ARGS_BOOTUPD: &[&str] = &["--unit", "bootupd", "--pipe"];
 
 /// Keep these properties (isolation/runtime state) in sync with
@@ -17,19 +96,35 @@ static SYSTEMD_PROPERTIES: &[&str] = &[
     // want systemd to send it to other processes.
     "KillMode=mixed",
     "MountFlags=slave",
+    // Progress and timeout extensions, see sdnotify.rs
+    "NotifyAccess=main",
 ];
 
 /// `bootupctl` sub-commands.
 #[derive(Debug, Parser)]
-#[clap(name = "bootupctl", about = "Bootupd client application", version)]
+#[clap(
+    name = "bootupctl",
+    about = "Bootupd client application",
+    version,
+    arg_required_else_help = true
+)]
 pub struct CtlCommand {
     /// Verbosity level (higher is more verbose).
     #[clap(short = 'v', action = clap::ArgAction::Count, global = true)]
     verbosity: u8,
 
+    /// Print the exit codes of bootupctl and exit
+    #[clap(long, exclusive = true)]
+    help_exit_codes: bool,
+
+    /// Wait for another bootupd operation in progress to finish, rather
+    /// than fail
+    #[clap(long, global = true)]
+    wait: bool,
+
     /// CLI sub-command.
     #[clap(subcommand)]
-    pub cmd: CtlVerb,
+    pub cmd: Option<CtlVerb>,
 }
 
 impl CtlCommand {
@@ -54,11 +149,82 @@ pub enum CtlVerb {
     #[clap(name = "status", about = "Show components status")]
     Status(StatusOpts),
     #[clap(name = "update", about = "Update all components")]
-    Update,
+    Update(UpdateOpts),
     #[clap(name = "adopt-and-update", about = "Update all adoptable components")]
     AdoptAndUpdate,
     #[clap(name = "validate", about = "Validate system state")]
-    Validate,
+    Validate(ValidateOpts),
+    #[clap(
+        name = "health",
+        about = "Check the health of the bootloaders, for monitoring agents"
+    )]
+    Health(HealthOpts),
+    #[clap(
+        name = "metrics",
+        about = "Print the metrics of the bootloaders in the Prometheus text format"
+    )]
+    Metrics(MetricsOpts),
+    #[clap(
+        name = "fix-bootorder",
+        about = "Move the EFI boot entry first and remove dangling entries"
+    )]
+    FixBootOrder,
+    #[clap(
+        name = "prune-boot-entries",
+        about = "Remove the EFI boot entries of files missing from their ESP"
+    )]
+    PruneBootEntries(PruneBootEntriesOpts),
+    #[clap(
+        name = "rollback",
+        about = "Restore the version of a component replaced by the last update"
+    )]
+    Rollback(RollbackOpts),
+    #[clap(
+        name = "finalize-rotation",
+        about = "Remove the boot chain kept since the start of a Secure Boot key rotation"
+    )]
+    FinalizeRotation,
+    #[clap(
+        name = "backup",
+        about = "Archive the installed bootloaders and their state"
+    )]
+    Backup(BackupOpts),
+    #[clap(
+        name = "restore",
+        about = "Restore the bootloaders archived by the backup command"
+    )]
+    Restore(RestoreOpts),
+    #[clap(
+        name = "efi-vars",
+        about = "Back up or restore the EFI boot variables",
+        subcommand
+    )]
+    EfiVars(EfiVarsVerb),
+    #[clap(
+        name = "getenv",
+        about = "Print variables of the GRUB environment block"
+    )]
+    GetEnv(GetEnvOpts),
+    #[clap(name = "setenv", about = "Set variables of the GRUB environment block")]
+    SetEnv(SetEnvOpts),
+    #[clap(
+        name = "verify-boot",
+        about = "Count a boot attempt after updates marked for boot verification"
+    )]
+    VerifyBoot(VerifyBootOpts),
+    #[clap(
+        name = "diff",
+        about = "Compare the installed files with the update payloads"
+    )]
+    Diff(DiffOpts),
+    #[clap(name = "state", about = "Manage the state file", subcommand)]
+    State(StateVerb),
+    #[clap(
+        name = "quarantine",
+        about = "Manage the files moved out of the ESPs by validate --prune",
+        subcommand
+    )]
+    Quarantine(QuarantineVerb),
     #[clap(
         name = "migrate-static-grub-config",
         hide = true,
@@ -75,6 +241,73 @@ pub enum CtlBackend {
     Install(super::bootupd::InstallOpts),
 }
 
+#[derive(Debug, Parser)]
+pub enum EfiVarsVerb {
+    #[clap(
+        name = "backup",
+        about = "Write the boot entries, BootOrder and Timeout to a JSON file"
+    )]
+    Backup(EfiVarsBackupOpts),
+    #[clap(
+        name = "restore",
+        about = "Write back the variables saved by the backup command"
+    )]
+    Restore(EfiVarsRestoreOpts),
+}
+
+#[derive(Debug, Parser)]
+pub enum StateVerb {
+    #[clap(
+        name = "migrate",
+        about = "Rewrite the state file in the current schema version, and repair its copies"
+    )]
+    Migrate,
+    #[clap(
+        name = "repair",
+        about = "Rebuild a state file which can't be loaded from the installed bootloaders"
+    )]
+    Repair,
+}
+
+#[derive(Debug, Parser)]
+pub enum QuarantineVerb {
+    #[clap(name = "list", about = "List the quarantined files")]
+    List(QuarantineListOpts),
+    #[clap(
+        name = "restore",
+        about = "Move the files of a quarantine back to the ESPs"
+    )]
+    Restore(QuarantineRestoreOpts),
+    #[clap(name = "purge", about = "Delete quarantined files")]
+    Purge(QuarantinePurgeOpts),
+}
+
+/// Output format for commands that support machine-readable output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Human,
+    /// JSON, following a stable schema
+    Json,
+    /// YAML, following the same schema as JSON
+    Yaml,
+}
+
+impl OutputFormat {
+    /// Print `value` in this machine-readable format.
+    fn print<T: serde::Serialize>(self, value: &T) -> Result<()> {
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        match self {
+            OutputFormat::Human => unreachable!("not a machine-readable format"),
+            OutputFormat::Json => serde_json::to_writer_pretty(&mut stdout, value)?,
+            OutputFormat::Yaml => serde_yaml::to_writer(&mut stdout, value)?,
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Parser)]
 pub struct StatusOpts {
     /// If there are updates available, output `Updates available: ` to standard output;
@@ -83,19 +316,306 @@ pub struct StatusOpts {
     #[clap(long, action)]
     print_if_available: bool,
 
-    /// Output JSON
-    #[clap(long, action)]
+    /// Print a one-line summary of the pending updates, and exit with code 4
+    /// if there are any, or 5 if components must be adopted, like
+    /// `dnf check-update`
+    #[clap(long, action, conflicts_with_all = ["print_if_available", "history", "json", "format"])]
+    check_pending: bool,
+
+    /// Output JSON (same as `--format=json`)
+    #[clap(long, action, conflicts_with = "format")]
     json: bool,
+
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Show the installs, adoptions, updates and rollbacks of the
+    /// components recorded in /boot/bootupd/history.json
+    #[clap(long, action, conflicts_with = "print_if_available")]
+    history: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct UpdateOpts {
+    /// Only update these components
+    #[clap(long = "component")]
+    components: Vec<String>,
+
+    /// Print the files that would be written (or the commands that would
+    /// be run) without modifying anything
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Update according to the automatic update policy configured in
+    /// /etc/bootupd/config.toml; used by bootupd-update.timer
+    #[clap(long, conflicts_with_all = ["components", "dry_run"])]
+    auto: bool,
+
+    /// Take the update payloads from this container image (e.g.
+    /// "quay.io/fedora/fedora-bootc:41", or with a skopeo transport such as
+    /// "containers-storage:") instead of the booted deployment
+    #[clap(long, value_name = "IMAGE", conflicts_with = "auto")]
+    from_image: Option<String>,
+
+    /// Record the available updates, to be applied at shutdown by
+    /// bootupd-finalize-staged.service, when no other workload touches
+    /// the disks
+    #[clap(long, conflicts_with_all = ["dry_run", "auto", "from_image"])]
+    stage: bool,
+
+    /// Apply the staged updates; used by bootupd-finalize-staged.service
+    #[clap(long, hide = true, conflicts_with_all = ["components", "dry_run", "auto", "from_image", "stage"])]
+    finalize_staged: bool,
+
+    /// Install the available payloads even if they are older than the
+    /// installed ones, e.g. after rolling back to an older deployment
+    #[clap(long, conflicts_with_all = ["auto", "stage", "finalize_staged"])]
+    allow_downgrade: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    after_help = "The exit code is 0 if the system is valid, 2 if validation errors \
+were found and 3 if no component could be validated; see --help-exit-codes."
+)]
+pub struct ValidateOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Remove the extraneous files found in the vendor directories of the
+    /// ESP, after confirmation, keeping them in quarantine
+    #[clap(long, conflicts_with = "format")]
+    prune: bool,
+
+    /// Don't ask for confirmation before pruning
+    #[clap(long, short = 'y', requires = "prune")]
+    yes: bool,
+
+    /// Restore the missing or modified files from the update payloads of
+    /// the installed versions (and re-run grub2-install for BIOS)
+    #[clap(long, conflicts_with_all = ["format", "prune"])]
+    fix: bool,
+
+    /// Also fail on any file of the managed locations (e.g. the whole
+    /// `EFI` directory of the ESP, besides protected directories) which
+    /// isn't part of the installed content
+    #[clap(long, conflicts_with_all = ["prune", "fix"])]
+    strict: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(
+    after_help = "The exit code is 0 if all checks pass, 8 if some warn and 9 if some \
+fail; see --help-exit-codes."
+)]
+pub struct HealthOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+
+    /// Fail if an installed version is older than the available update by
+    /// more than this many days
+    #[clap(long, value_name = "DAYS", default_value_t = 90)]
+    max_update_age: u32,
+}
+
+#[derive(Debug, Parser)]
+pub struct MetricsOpts {
+    /// Atomically write them to this file instead, e.g. for the textfile
+    /// collector of the node exporter
+    #[clap(long, value_name = "PATH")]
+    textfile: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct DiffOpts {
+    /// Only compare these components
+    #[clap(long = "component")]
+    components: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct PruneBootEntriesOpts {
+    /// Only print the entries that would be removed
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct RollbackOpts {
+    /// The component to roll back; only EFI is supported
+    #[clap(long, default_value = "EFI")]
+    component: String,
+}
+
+#[derive(Debug, Parser)]
+pub struct BackupOpts {
+    /// The archive to write
+    #[clap(long, value_name = "PATH")]
+    to: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct RestoreOpts {
+    /// The archive to restore
+    #[clap(long, value_name = "PATH")]
+    from: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct QuarantineListOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Parser)]
+pub struct QuarantineRestoreOpts {
+    /// The quarantine, e.g. `20250102T030405Z`
+    #[clap(value_name = "ID")]
+    id: String,
+}
+
+#[derive(Debug, Parser)]
+#[clap(group(clap::ArgGroup::new("which").required(true)))]
+pub struct QuarantinePurgeOpts {
+    /// The quarantines to delete
+    #[clap(value_name = "ID", group = "which")]
+    ids: Vec<String>,
+
+    /// Delete all the quarantines
+    #[clap(long, group = "which")]
+    all: bool,
+
+    /// Delete the quarantines older than `efi.quarantine-days`
+    #[clap(long, group = "which")]
+    expired: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct EfiVarsBackupOpts {
+    /// The file to write
+    #[clap(long, value_name = "PATH")]
+    to: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct EfiVarsRestoreOpts {
+    /// The backup to restore
+    #[clap(long, value_name = "PATH")]
+    from: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct GetEnvOpts {
+    /// The variables to print the value of; all of them are printed as
+    /// `NAME=VALUE` if none are given
+    #[clap(value_name = "NAME")]
+    names: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+#[clap(group(clap::ArgGroup::new("vars").required(true).multiple(true)))]
+pub struct SetEnvOpts {
+    /// The variables to set
+    #[clap(value_name = "NAME=VALUE", value_parser = parse_var, group = "vars")]
+    set: Vec<(String, String)>,
+
+    /// Remove this variable
+    #[clap(long, value_name = "NAME", group = "vars")]
+    unset: Vec<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct VerifyBootOpts {
+    /// Record that the boot completed instead, e.g. after
+    /// boot-complete.target
+    #[clap(long)]
+    complete: bool,
+
+    /// Roll back the updated components once more boots than this didn't
+    /// complete
+    #[clap(long, default_value_t = 3, conflicts_with = "complete")]
+    max_attempts: u32,
+}
+
+/// Parse a `NAME=VALUE` argument.
+fn parse_var(s: &str) -> std::result::Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected NAME=VALUE, got {s:?}")),
+    }
+}
+
+impl StatusOpts {
+    fn format(&self) -> OutputFormat {
+        if self.json {
+            OutputFormat::Json
+        } else {
+            self.format
+        }
+    }
 }
 
 impl CtlCommand {
-    /// Run CLI application.
-    pub fn run(self) -> Result<()> {
-        match self.cmd {
-            CtlVerb::Status(opts) => Self::run_status(opts),
-            CtlVerb::Update => Self::run_update(),
+    /// Run CLI application, returning the process exit code.
+    pub fn run(self) -> Result<i32> {
+        let Some(cmd) = self.cmd else {
+            if !self.help_exit_codes {
+                anyhow::bail!("A subcommand is required; see --help");
+            }
+            print_exit_codes();
+            return Ok(libc::EXIT_SUCCESS);
+        };
+        crate::backend::set_wait_for_lock(self.wait);
+        match Self::run_verb(cmd) {
+            Ok(code) => Ok(code),
+            Err(e) => {
+                let code = if e.downcast_ref::<StateLocked>().is_some() {
+                    EXIT_LOCKED
+                } else if e.downcast_ref::<Unsupported>().is_some() {
+                    EXIT_UNSUPPORTED
+                } else {
+                    return Err(e);
+                };
+                eprintln!("error: {e:#}");
+                if code == EXIT_LOCKED {
+                    eprintln!("Retry with --wait to wait for it to finish");
+                }
+                Ok(code)
+            }
+        }
+    }
+
+    fn run_verb(cmd: CtlVerb) -> Result<i32> {
+        match cmd {
+            CtlVerb::Status(opts) => return Self::run_status(opts),
+            CtlVerb::Update(opts) => return Self::run_update(opts),
             CtlVerb::AdoptAndUpdate => Self::run_adopt_and_update(),
-            CtlVerb::Validate => Self::run_validate(),
+            CtlVerb::Validate(opts) => return Self::run_validate(opts),
+            CtlVerb::Health(opts) => return Self::run_health(opts),
+            CtlVerb::Metrics(opts) => Self::run_metrics(opts),
+            CtlVerb::FixBootOrder => Self::run_fix_bootorder(),
+            CtlVerb::PruneBootEntries(opts) => Self::run_prune_boot_entries(opts),
+            CtlVerb::Rollback(opts) => Self::run_rollback(opts),
+            CtlVerb::FinalizeRotation => Self::run_finalize_rotation(),
+            CtlVerb::Backup(opts) => Self::run_backup(opts),
+            CtlVerb::Restore(opts) => Self::run_restore(opts),
+            CtlVerb::EfiVars(EfiVarsVerb::Backup(opts)) => Self::run_efivars_backup(opts),
+            CtlVerb::EfiVars(EfiVarsVerb::Restore(opts)) => Self::run_efivars_restore(opts),
+            CtlVerb::GetEnv(opts) => Self::run_getenv(opts),
+            CtlVerb::SetEnv(opts) => Self::run_setenv(opts),
+            CtlVerb::VerifyBoot(opts) => Self::run_verify_boot(opts),
+            CtlVerb::Diff(opts) => Self::run_diff(opts),
+            CtlVerb::State(StateVerb::Migrate) => Self::run_state_migrate(),
+            CtlVerb::State(StateVerb::Repair) => Self::run_state_repair(),
+            CtlVerb::Quarantine(QuarantineVerb::List(opts)) => Self::run_quarantine_list(opts),
+            CtlVerb::Quarantine(QuarantineVerb::Restore(opts)) => {
+                Self::run_quarantine_restore(opts)
+            }
+            CtlVerb::Quarantine(QuarantineVerb::Purge(opts)) => Self::run_quarantine_purge(opts),
             CtlVerb::Backend(CtlBackend::Generate(opts)) => {
                 super::bootupd::DCommand::run_generate_meta(opts)
             }
@@ -103,45 +623,246 @@ impl CtlCommand {
                 super::bootupd::DCommand::run_install(opts)
             }
             CtlVerb::MigrateStaticGrubConfig => Self::run_migrate_static_grub_config(),
-        }
+        }?;
+        Ok(libc::EXIT_SUCCESS)
     }
 
     /// Runner for `status` verb.
-    fn run_status(opts: StatusOpts) -> Result<()> {
+    fn run_status(opts: StatusOpts) -> Result<i32> {
+        let format = opts.format();
+        if opts.history {
+            if format == OutputFormat::Human {
+                bootupd::client_run_history()?;
+            } else {
+                format.print(&crate::history::load(std::path::Path::new("/"))?)?;
+            }
+            return Ok(libc::EXIT_SUCCESS);
+        }
         if crate::util::running_in_container() {
-            return run_status_in_container(opts.json);
+            run_status_in_container(format)?;
+            return Ok(libc::EXIT_SUCCESS);
         }
         ensure_running_in_systemd()?;
         let r = bootupd::status()?;
-        if opts.json {
-            let stdout = std::io::stdout();
-            let mut stdout = stdout.lock();
-            serde_json::to_writer_pretty(&mut stdout, &r)?;
-        } else if opts.print_if_available {
-            bootupd::print_status_avail(&r)?;
+        if format != OutputFormat::Human {
+            format.print(&r)?;
+        } else if opts.print_if_available || opts.check_pending {
+            return bootupd::print_status_avail(&r, opts.check_pending).map(pending_exit_code);
         } else {
             bootupd::print_status(&r)?;
         }
 
-        Ok(())
+        Ok(libc::EXIT_SUCCESS)
     }
 
     /// Runner for `update` verb.
-    fn run_update() -> Result<()> {
+    fn run_update(opts: UpdateOpts) -> Result<i32> {
+        // The update runs without network access, so pull the image first
+        if let Some(imgref) = opts.from_image.as_deref() {
+            if !running_in_systemd() {
+                require_root_permission()?;
+                crate::updatesource::fetch(imgref)?;
+            }
+        }
         ensure_running_in_systemd()?;
-        bootupd::client_run_update()
+        // Those of the image are activated with it
+        if opts.from_image.is_none() {
+            crate::payloadview::activate(std::path::Path::new("/"))?;
+        }
+        if opts.auto {
+            bootupd::client_run_auto_update()?;
+            return Ok(libc::EXIT_SUCCESS);
+        }
+        if opts.stage {
+            bootupd::client_run_stage_update(&opts.components)?;
+            return Ok(libc::EXIT_SUCCESS);
+        }
+        if opts.finalize_staged {
+            bootupd::client_run_finalize_staged()?;
+            return Ok(libc::EXIT_SUCCESS);
+        }
+        let _image = opts
+            .from_image
+            .as_deref()
+            .map(crate::updatesource::ImageUpdates::activate)
+            .transpose()?;
+        bootupd::client_run_update(&opts.components, opts.dry_run, opts.allow_downgrade)
+            .map(pending_exit_code)
     }
 
     /// Runner for `update` verb.
     fn run_adopt_and_update() -> Result<()> {
         ensure_running_in_systemd()?;
+        crate::payloadview::activate(std::path::Path::new("/"))?;
         bootupd::client_run_adopt_and_update()
     }
 
     /// Runner for `validate` verb.
-    fn run_validate() -> Result<()> {
+    fn run_validate(opts: ValidateOpts) -> Result<i32> {
+        ensure_running_in_systemd()?;
+        if opts.prune {
+            bootupd::client_run_prune(opts.yes)?;
+            return Ok(libc::EXIT_SUCCESS);
+        }
+        if opts.fix {
+            // The files are restored from the update payload
+            crate::payloadview::activate(std::path::Path::new("/"))?;
+            bootupd::client_run_repair()?;
+            return Ok(libc::EXIT_SUCCESS);
+        }
+        let verdict = if opts.format == OutputFormat::Human {
+            bootupd::client_run_validate(opts.strict)?
+        } else {
+            let report = bootupd::validate_all(opts.strict)?;
+            opts.format.print(&report)?;
+            report.verdict
+        };
+        let r = match verdict {
+            ValidationVerdict::Valid => libc::EXIT_SUCCESS,
+            ValidationVerdict::Errors => EXIT_VALIDATION_ERRORS,
+            ValidationVerdict::Skip => EXIT_VALIDATION_SKIPPED,
+        };
+        Ok(r)
+    }
+
+    /// Runner for `health` verb.
+    fn run_health(opts: HealthOpts) -> Result<i32> {
+        ensure_running_in_systemd()?;
+        let max_update_age = chrono::Duration::try_days(opts.max_update_age.into())
+            .ok_or_else(|| anyhow::anyhow!("Invalid --max-update-age"))?;
+        let report = crate::health::health(max_update_age)?;
+        match opts.format {
+            OutputFormat::Human => crate::health::print_health(&report),
+            format => format.print(&report)?,
+        }
+        let r = match report.verdict {
+            HealthVerdict::Pass => libc::EXIT_SUCCESS,
+            HealthVerdict::Warn => EXIT_HEALTH_WARN,
+            HealthVerdict::Fail => EXIT_HEALTH_FAIL,
+        };
+        Ok(r)
+    }
+
+    /// Runner for `metrics` verb.
+    fn run_metrics(opts: MetricsOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        match opts.textfile.as_deref() {
+            Some(path) => crate::metrics::write_textfile(path),
+            None => {
+                print!("{}", crate::metrics::collect()?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runner for `fix-bootorder` verb.
+    fn run_fix_bootorder() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_fix_bootorder()
+    }
+
+    /// Runner for `prune-boot-entries` verb.
+    fn run_prune_boot_entries(opts: PruneBootEntriesOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_prune_boot_entries(opts.dry_run)
+    }
+
+    /// Runner for `rollback` verb.
+    fn run_rollback(opts: RollbackOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_rollback(&opts.component)
+    }
+
+    /// Runner for `finalize-rotation` verb.
+    fn run_finalize_rotation() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_finalize_rotation()
+    }
+
+    /// Runner for `backup` verb.
+    fn run_backup(opts: BackupOpts) -> Result<()> {
+        // Not run via systemd-run, as the archive is usually under /root,
+        // which is hidden by ProtectHome.
+        require_root_permission()?;
+        bootupd::client_run_backup(&opts.to)
+    }
+
+    /// Runner for `restore` verb.
+    fn run_restore(opts: RestoreOpts) -> Result<()> {
+        require_root_permission()?;
+        bootupd::client_run_restore(&opts.from)
+    }
+
+    /// Runner for `efi-vars backup` verb.
+    fn run_efivars_backup(opts: EfiVarsBackupOpts) -> Result<()> {
+        // Like `backup`, the file is usually under /root
+        require_root_permission()?;
+        bootupd::client_run_efivars_backup(&opts.to)
+    }
+
+    /// Runner for `efi-vars restore` verb.
+    fn run_efivars_restore(opts: EfiVarsRestoreOpts) -> Result<()> {
+        require_root_permission()?;
+        bootupd::client_run_efivars_restore(&opts.from)
+    }
+
+    /// Runner for `getenv` verb.
+    fn run_getenv(opts: GetEnvOpts) -> Result<()> {
+        require_root_permission()?;
+        bootupd::client_run_getenv(&opts.names)
+    }
+
+    /// Runner for `setenv` verb.
+    fn run_setenv(opts: SetEnvOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::set_grubenv(&opts.set, &opts.unset)
+    }
+
+    /// Runner for `verify-boot` verb.
+    fn run_verify_boot(opts: VerifyBootOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_verify_boot(opts.complete, opts.max_attempts)
+    }
+
+    /// Runner for `state migrate` verb.
+    fn run_state_migrate() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_state_migrate()
+    }
+
+    /// Runner for `diff` verb.
+    fn run_diff(opts: DiffOpts) -> Result<()> {
         ensure_running_in_systemd()?;
-        bootupd::client_run_validate()
+        bootupd::client_run_diff(&opts.components)
+    }
+
+    /// Runner for `state repair` verb.
+    fn run_state_repair() -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_state_repair()
+    }
+
+    /// Runner for `quarantine list` verb.
+    fn run_quarantine_list(opts: QuarantineListOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        let quarantines = bootupd::quarantines()?;
+        match opts.format {
+            OutputFormat::Human => crate::quarantine::print(&quarantines),
+            format => format.print(&quarantines)?,
+        }
+        Ok(())
+    }
+
+    /// Runner for `quarantine restore` verb.
+    fn run_quarantine_restore(opts: QuarantineRestoreOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_quarantine_restore(&opts.id)
+    }
+
+    /// Runner for `quarantine purge` verb.
+    fn run_quarantine_purge(opts: QuarantinePurgeOpts) -> Result<()> {
+        ensure_running_in_systemd()?;
+        bootupd::client_run_quarantine_purge(&opts.ids, opts.all, opts.expired)
     }
 
     /// Runner for `migrate-static-grub-config` verb.
@@ -151,6 +872,34 @@ impl CtlCommand {
     }
 }
 
+/// Returns the exit code for what `status --print-if-available` or
+/// `update --dry-run` found pending.
+fn pending_exit_code(pending: Pending) -> i32 {
+    match pending {
+        Pending::Nothing => libc::EXIT_SUCCESS,
+        Pending::Updates => EXIT_UPDATES_PENDING,
+        Pending::Adoption => EXIT_ADOPTION_NEEDED,
+    }
+}
+
+/// Exit after failing to parse the command line, like clap does but with
+/// `EXIT_USAGE` for invalid command lines.
+pub(crate) fn exit_parse_error(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        // --help or --version
+        e.exit()
+    }
+    let _ = e.print();
+    std::process::exit(EXIT_USAGE)
+}
+
+/// Print the table of exit codes for `--help-exit-codes`.
+fn print_exit_codes() {
+    for (code, name, description) in EXIT_CODES {
+        println!("{code:>3}  {name:<26} {description}");
+    }
+}
+
 /// Checks if the current process is (apparently at least)
 /// running under systemd.
 fn running_in_systemd() -> bool {
@@ -179,37 +928,70 @@ fn ensure_running_in_systemd() -> Result<()> {
             .stderr(Stdio::null())
             .spawn()?
             .wait()?;
-        let r = Command::new("systemd-run")
-            .args(SYSTEMD_ARGS_BOOTUPD)
-            .args(
-                SYSTEMD_PROPERTIES
-                    .into_iter()
-                    .flat_map(|&v| ["--property", v]),
-            )
-            .args(std::env::args())
-            .exec();
+        // systemd-run would fail to start a second instance of the unit
+        while Command::new("systemctl")
+            .args(["is-active", "--quiet", "bootupd.service"])
+            .status()?
+            .success()
+        {
+            if !crate::backend::waits_for_lock() {
+                return Err(StateLocked.into());
+            }
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+        let mut cmd = Command::new("systemd-run");
+        cmd.args(SYSTEMD_ARGS_BOOTUPD).args(
+            SYSTEMD_PROPERTIES
+                .into_iter()
+                .flat_map(|&v| ["--property", v]),
+        );
+        // The failpoints of test builds, e.g. for crash-safety testing
+        #[cfg(feature = "testing")]
+        if let Some(failpoints) = std::env::var_os("FAILPOINTS") {
+            let mut setenv = std::ffi::OsString::from("--setenv=FAILPOINTS=");
+            setenv.push(failpoints);
+            cmd.arg(setenv);
+        }
+        let r = cmd.args(std::env::args()).exec();
         // If we got here, it's always an error
         return Err(r.into());
     }
-    Ok(())
+    // On the main thread, before any other is started
+    crate::util::enter_private_mount_namespace()
 }
 
 /// If running in container, just print the available payloads
-fn run_status_in_container(json_format: bool) -> Result<()> {
+fn run_status_in_container(format: OutputFormat) -> Result<()> {
     let all_components = crate::bootupd::get_components();
     if all_components.is_empty() {
         return Ok(());
     }
     let avail: Vec<_> = all_components.keys().cloned().collect();
-    if json_format {
-        let stdout = std::io::stdout();
-        let mut stdout = stdout.lock();
-        let output: serde_json::Value = serde_json::json!({
-            "components": avail
-        });
-        serde_json::to_writer(&mut stdout, &output)?;
-    } else {
-        println!("Available components: {}", avail.join(" "));
+    let output: serde_json::Value = serde_json::json!({
+        "components": avail
+    });
+    match format {
+        OutputFormat::Human => println!("Available components: {}", avail.join(" ")),
+        OutputFormat::Json => {
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            serde_json::to_writer(&mut stdout, &output)?;
+        }
+        OutputFormat::Yaml => format.print(&output)?,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_unique() {
+        let mut codes: Vec<i32> = EXIT_CODES.iter().map(|(code, _, _)| *code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), EXIT_CODES.len());
+        assert_eq!(pending_exit_code(Pending::Nothing), libc::EXIT_SUCCESS);
+    }
+}
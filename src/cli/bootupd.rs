@@ -1,7 +1,9 @@
 use crate::bootupd::{self, ConfigMode};
+use crate::component::{GenerateOptions, InstallComponentOptions};
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use log::LevelFilter;
+use std::path::PathBuf;
 
 /// `bootupd` sub-commands.
 #[derive(Debug, Parser)]
@@ -35,6 +37,67 @@ pub enum DVerb {
     GenerateUpdateMetadata(GenerateOpts),
     #[clap(name = "install", about = "Install components")]
     Install(InstallOpts),
+    #[clap(
+        name = "export-payload",
+        about = "Export the update payloads, e.g. to a registry"
+    )]
+    ExportPayload(ExportPayloadOpts),
+    #[cfg(feature = "dbus")]
+    #[clap(name = "daemon", about = "Serve the D-Bus API")]
+    Daemon(DaemonOpts),
+    #[clap(
+        name = "watch",
+        about = "Log the out-of-band modifications of the managed files"
+    )]
+    Watch(WatchOpts),
+    #[cfg(feature = "selftest")]
+    #[clap(
+        name = "selftest",
+        about = "Install, update, validate and adopt the components on a loop device"
+    )]
+    Selftest,
+}
+
+#[cfg(feature = "dbus")]
+#[derive(Debug, Parser)]
+pub struct DaemonOpts {
+    /// Also keep the metrics of `bootupctl metrics` up to date in this file,
+    /// e.g. for the textfile collector of the node exporter
+    #[clap(long, value_name = "PATH")]
+    metrics_textfile: Option<PathBuf>,
+
+    /// How often to refresh the metrics file
+    #[clap(long, value_name = "SECONDS", default_value_t = 300)]
+    metrics_interval: u64,
+}
+
+#[derive(Debug, Parser)]
+pub struct WatchOpts {
+    /// Also record the modifications in /run/bootupd/drift.json, for
+    /// `bootupctl status`
+    #[clap(long)]
+    record: bool,
+}
+
+/// Formats of `export-payload`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PayloadFormat {
+    /// An OCI artifact with a layer per component
+    #[default]
+    Oci,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportPayloadOpts {
+    /// Output format
+    #[clap(long, value_enum, default_value_t)]
+    format: PayloadFormat,
+
+    /// The directory of the OCI layout to write if starting with `/` or
+    /// `.`, otherwise the reference to push to (e.g.
+    /// "quay.io/example/bootloaders:41")
+    #[clap(long, value_name = "DIR|REF")]
+    output: String,
 }
 
 #[derive(Debug, Parser)]
@@ -46,9 +109,11 @@ pub struct InstallOpts {
     #[clap(value_parser)]
     dest_root: String,
 
-    /// Target device, used by bios bootloader installation
-    #[clap(long)]
-    device: Option<String>,
+    /// Target device, used by bios bootloader installation; may be a disk
+    /// image file, which is then attached to a loop device.  Repeat for
+    /// e.g. each member of a RAID1
+    #[clap(long = "device")]
+    devices: Vec<String>,
 
     /// Enable installation of the built-in static config files
     #[clap(long)]
@@ -59,10 +124,17 @@ pub struct InstallOpts {
     #[clap(long)]
     write_uuid: bool,
 
-    /// On EFI systems, invoke `efibootmgr` to update the firmware.
+    /// On EFI systems, invoke `efibootmgr` to update the firmware, and stage
+    /// the available firmware updates (with fwupd, or the capsules of the
+    /// image) for the next boot.
     #[clap(long)]
     update_firmware: bool,
 
+    /// GRUB modules to embed in the BIOS bootloader besides the configured
+    /// ones, e.g. "lvm luks"; they are kept on update
+    #[clap(long, value_name = "MODULES", value_delimiter = ' ')]
+    bios_modules: Vec<String>,
+
     #[clap(long = "component", conflicts_with = "auto")]
     /// Only install these components
     components: Option<Vec<String>>,
@@ -80,6 +152,37 @@ pub struct GenerateOpts {
     /// Physical root mountpoint
     #[clap(value_parser)]
     sysroot: Option<String>,
+
+    /// Use this version for the update payloads instead of querying the
+    /// package database (or hashing the payload content if there is none)
+    #[clap(long = "version")]
+    version_override: Option<String>,
+
+    /// Also install and update the EFI removable media path
+    /// (e.g. EFI/BOOT/BOOTX64.EFI), which some firmware boot exclusively
+    #[clap(long)]
+    with_efi_fallback: bool,
+
+    /// Accept update payloads owned by rpm packages which aren't signed by
+    /// a key imported in the rpm database
+    #[clap(long)]
+    allow_unsigned: bool,
+
+    /// Sign the update payloads with this ed25519 private key (PEM), for
+    /// verification with the keys of /etc/bootupd/trusted.d
+    #[clap(long, value_name = "PATH")]
+    sign_key: Option<PathBuf>,
+
+    /// Store the update payloads compressed with zstd, to be decompressed
+    /// when used
+    #[clap(long)]
+    compress: bool,
+
+    /// Store the files of the update payloads in an object store keyed by
+    /// their digest, so that those shared between components are stored
+    /// once
+    #[clap(long, conflicts_with = "compress")]
+    dedup: bool,
 }
 
 impl DCommand {
@@ -88,6 +191,17 @@ impl DCommand {
         match self.cmd {
             DVerb::Install(opts) => Self::run_install(opts),
             DVerb::GenerateUpdateMetadata(opts) => Self::run_generate_meta(opts),
+            DVerb::ExportPayload(opts) => match opts.format {
+                PayloadFormat::Oci => crate::payloadexport::export_oci(&opts.output),
+            },
+            #[cfg(feature = "dbus")]
+            DVerb::Daemon(opts) => crate::dbus::run(
+                opts.metrics_textfile.as_deref(),
+                std::time::Duration::from_secs(opts.metrics_interval),
+            ),
+            DVerb::Watch(opts) => crate::driftwatch::watch(opts.record),
+            #[cfg(feature = "selftest")]
+            DVerb::Selftest => crate::selftest::run(),
         }
     }
 
@@ -97,12 +211,23 @@ impl DCommand {
         if sysroot != "/" {
             anyhow::bail!("Using a non-default sysroot is not supported: {}", sysroot);
         }
-        bootupd::generate_update_metadata(sysroot).context("generating metadata failed")?;
+        let genopts = GenerateOptions {
+            version: opts.version_override,
+            efi_fallback: opts.with_efi_fallback,
+            allow_unsigned: opts.allow_unsigned,
+            sign_key: opts.sign_key,
+            compress: opts.compress,
+            dedup: opts.dedup,
+        };
+        bootupd::generate_update_metadata(sysroot, &genopts)
+            .context("generating metadata failed")?;
         Ok(())
     }
 
     /// Runner for `install` verb.
     pub(crate) fn run_install(opts: InstallOpts) -> Result<()> {
+        crate::util::enter_private_mount_namespace()?;
+        crate::payloadview::activate(std::path::Path::new(&opts.src_root))?;
         let configmode = if opts.write_uuid {
             ConfigMode::WithUUID
         } else if opts.with_static_configs {
@@ -113,9 +238,12 @@ impl DCommand {
         bootupd::install(
             &opts.src_root,
             &opts.dest_root,
-            opts.device.as_deref(),
+            &opts.devices,
             configmode,
-            opts.update_firmware,
+            &InstallComponentOptions {
+                update_firmware: opts.update_firmware,
+                bios_modules: opts.bios_modules,
+            },
             opts.components.as_deref(),
             opts.auto,
         )
@@ -26,18 +26,36 @@ impl MultiCall {
         };
         #[allow(clippy::wildcard_in_or_patterns)]
         match exe_name.as_bytes() {
-            b"bootupctl" => MultiCall::Ctl(bootupctl::CtlCommand::parse_from(args)),
+            b"bootupctl" => match bootupctl::CtlCommand::try_parse_from(args) {
+                Ok(cmd) => MultiCall::Ctl(cmd),
+                Err(e) => bootupctl::exit_parse_error(e),
+            },
             b"bootupd" | _ => MultiCall::D(bootupd::DCommand::parse_from(args)),
         }
     }
 
-    pub fn run(self) -> Result<()> {
+    /// Run the CLI, returning the process exit code.
+    pub fn run(self) -> Result<i32> {
         match self {
             MultiCall::Ctl(ctl_cmd) => ctl_cmd.run(),
-            MultiCall::D(d_cmd) => d_cmd.run(),
+            MultiCall::D(d_cmd) => d_cmd.run().map(|()| libc::EXIT_SUCCESS),
         }
     }
 
+    /// Set up logging: to the journal when running as a service, otherwise
+    /// to stderr.
+    pub fn init_logging(&self) {
+        let level = self.loglevel();
+        if crate::journal::init(level) {
+            return;
+        }
+        env_logger::Builder::from_default_env()
+            .format_timestamp(None)
+            .format_module_path(false)
+            .filter(Some(clap::crate_name!()), level)
+            .init();
+    }
+
     /// Return the log-level set via command-line flags.
     pub fn loglevel(&self) -> LevelFilter {
         match self {
@@ -89,6 +107,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_help_exit_codes() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        match MultiCall::from_args(args(&["bootupctl", "--help-exit-codes"])) {
+            MultiCall::Ctl(cmd) => assert!(cmd.cmd.is_none()),
+            MultiCall::D(cmd) => panic!("{:?}", cmd),
+        };
+        assert!(bootupctl::CtlCommand::try_parse_from(args(&[
+            "bootupctl",
+            "--help-exit-codes",
+            "status"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_compress_dedup_conflict() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(bootupd::DCommand::try_parse_from(args(&[
+            "bootupd",
+            "generate-update-metadata",
+            "--compress",
+            "--dedup"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_check_pending_conflicts() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        for option in ["--json", "--format=yaml"] {
+            assert!(bootupctl::CtlCommand::try_parse_from(args(&[
+                "bootupctl",
+                "status",
+                "--check-pending",
+                option
+            ]))
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_wait() {
+        let args = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        for argv in [
+            ["bootupctl", "--wait", "update"],
+            ["bootupctl", "update", "--wait"],
+        ] {
+            match MultiCall::from_args(args(&argv)) {
+                MultiCall::Ctl(cmd) => assert!(cmd.cmd.is_some()),
+                MultiCall::D(cmd) => panic!("{:?}", cmd),
+            };
+        }
+    }
+
     #[test]
     fn test_verbosity() {
         let default = MultiCall::from_args(vec![
@@ -10,14 +10,75 @@ use openat_ext::OpenatDirExt;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::config::Config;
 use crate::model::*;
+use crate::progress::ProgressFn;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) enum ValidationResult {
     Valid,
     Skip,
-    Errors(Vec<String>),
+    Errors(Vec<ValidationError>),
+}
+
+/// Options for `generate_update_metadata`.
+#[derive(Debug, Default, Clone)]
+pub struct GenerateOptions {
+    /// Use this version instead of the one derived from the payload
+    pub version: Option<String>,
+    /// Also ship the EFI removable media path (e.g. `EFI/BOOT/BOOTX64.EFI`)
+    pub efi_fallback: bool,
+    /// Accept payloads owned by rpm packages which aren't signed by a key
+    /// of the rpm keyring
+    pub allow_unsigned: bool,
+    /// Sign the generated payloads with this ed25519 private key, in PEM
+    /// format
+    pub sign_key: Option<PathBuf>,
+    /// Store the payload directories compressed with zstd
+    pub compress: bool,
+    /// Store the files of the payload directories once in an object store
+    /// keyed by their digest
+    pub dedup: bool,
+}
+
+/// Options for `Component::install`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct InstallComponentOptions {
+    /// Create the firmware boot entry on EFI systems, and stage the
+    /// available firmware updates
+    pub(crate) update_firmware: bool,
+    /// Modules to embed in the BIOS bootloader, in addition to those of the
+    /// configuration
+    pub(crate) bios_modules: Vec<String>,
+}
+
+/// The error for an operation which isn't supported by a component or on
+/// this platform; holds a description of the operation.
+#[derive(Debug)]
+pub(crate) struct Unsupported(pub(crate) String);
+
+impl std::fmt::Display for Unsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is not supported", self.0)
+    }
+}
+
+impl std::error::Error for Unsupported {}
+
+/// The outcome of `Component::repair`.
+#[derive(Debug)]
+pub(crate) struct Repaired {
+    /// The new installed state, e.g. with the checksums of rewritten raw
+    /// bootloader data
+    pub(crate) inst: InstalledContent,
+    /// The repaired files or devices
+    pub(crate) repaired: Vec<String>,
 }
 
 /// A component along with a possible update
@@ -43,14 +104,15 @@ pub(crate) trait Component {
     /// into the target root.  It is expected that sub-partitions (e.g. the ESP)
     /// are mounted at the expected place.  For operations that require a block device instead
     /// of a filesystem root, the component should query the mount point to
-    /// determine the block device.
+    /// determine the block device.  `devices` are the disks to write raw
+    /// bootloader data to, e.g. every member of a RAID1 for BIOS.
     /// This will be run during a disk image build process.
     fn install(
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
-        update_firmware: bool,
+        devices: &[String],
+        opts: &InstallComponentOptions,
     ) -> Result<InstalledContent>;
 
     /// Implementation of `bootupd generate-update-metadata` for a given component.
@@ -58,34 +120,335 @@ pub(crate) trait Component {
     /// this is an `rpm-ostree compose tree` for example.  For a dual-partition
     /// style updater, this would be run as part of a postprocessing step
     /// while the filesystem for the partition is mounted.
-    fn generate_update_metadata(&self, sysroot: &str) -> Result<ContentMetadata>;
+    fn generate_update_metadata(
+        &self,
+        sysroot: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata>;
 
     /// Used on the client to query for an update cached in the current booted OS.
     fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>>;
 
-    /// Used on the client to run an update.
+    /// Used on the client to run an update; `progress` is called as the
+    /// files of the update are written.
     fn run_update(
         &self,
         sysroot: &openat::Dir,
         current: &InstalledContent,
+        progress: ProgressFn,
     ) -> Result<InstalledContent>;
 
+    /// Used on the client to describe what `run_update` would do, without
+    /// modifying anything; returns one line per action.
+    fn plan_update(&self, sysroot: &openat::Dir, current: &InstalledContent)
+        -> Result<Vec<String>>;
+
     /// Used on the client to validate an installed version.
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult>;
 
+    /// Used on the client by `validate --strict`: the content found in the
+    /// locations managed by the component but not tracked in `current`,
+    /// besides what `validate` already reports.
+    fn validate_strict(&self, _current: &InstalledContent) -> Result<Vec<ValidationError>> {
+        Ok(Vec::new())
+    }
+
+    /// Used on the client to restore the content found missing or modified
+    /// by `validate` from the update payload, if it is still the installed
+    /// version.
+    fn repair(&self, _sysroot: &openat::Dir, _current: &InstalledContent) -> Result<Repaired> {
+        Err(Unsupported(format!("Repairing {}", self.name())).into())
+    }
+
+    /// Used on the client to rebuild a best-effort record of the installed
+    /// content, when the state file is lost, from what adoption finds on the
+    /// system, without modifying it; `None` if nothing is found.
+    fn rebuild_state(&self, _sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        Ok(self
+            .query_adopt()?
+            .map(|a| InstalledContent::rebuilt(a.version)))
+    }
+
+    /// Used on the client to retry the writes to the firmware variables
+    /// deferred by the install or an update; returns the new content, or
+    /// `None` if they still can't be written.
+    fn apply_pending_nvram(
+        &self,
+        _sysroot: &openat::Dir,
+        _current: &InstalledContent,
+    ) -> Result<Option<InstalledContent>> {
+        Ok(None)
+    }
+
     /// Locating efi vendor dir
     fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>>;
 }
 
+/// Copy the files of the installed `current` filetree which are missing or
+/// modified in `destdir` back from the update payload of a component, which
+/// must have the same content; returns the repaired files.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn repair_filetree(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    current: &InstalledContent,
+    destdir: &openat::Dir,
+) -> Result<Vec<String>> {
+    let currentf = current
+        .filetree
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No filetree for installed {} found!", component.name()))?;
+    let updated = sysroot
+        .sub_dir(&component_updatedirname(component))
+        .context("opening update dir")?;
+    let diff = currentf.relative_diff_to(destdir)?;
+    let mut broken: Vec<String> = diff.changes.union(&diff.removals).cloned().collect();
+    broken.sort();
+    for path in broken.iter() {
+        let expected = &currentf.children[path];
+        let found = match updated.metadata_optional(path.as_str())? {
+            Some(_) => Some(crate::filetree::FileMetadata::new_from_path(
+                &updated,
+                path,
+                expected.digest.algorithm(),
+            )?),
+            None => None,
+        };
+        if found.as_ref() != Some(expected) {
+            anyhow::bail!(
+                "The update payload doesn't have the installed version of {path}; run `bootupctl update`"
+            );
+        }
+    }
+    let fix = crate::filetree::FileTreeDiff {
+        additions: diff.removals,
+        removals: Default::default(),
+        changes: diff.changes,
+    };
+    crate::filetree::apply_diff(&updated, destdir, &fix, None)?;
+    Ok(broken)
+}
+
+/// Describe the file changes from the installed `current` filetree to the
+/// update payload of a component.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn plan_filetree_update(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    current: &InstalledContent,
+) -> Result<Vec<String>> {
+    let currentf = current
+        .filetree
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No filetree for installed {} found!", component.name()))?;
+    let updated = sysroot
+        .sub_dir(&component_updatedirname(component))
+        .context("opening update dir")?;
+    let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+    let updatef = crate::filetree::FileTree::new_from_dir(&updated, digest)?;
+    let diff = currentf.diff(&updatef)?;
+    let mut writes: Vec<_> = diff.additions.iter().chain(diff.changes.iter()).collect();
+    writes.sort();
+    let mut removals: Vec<_> = diff.removals.iter().collect();
+    removals.sort();
+    let r = writes
+        .into_iter()
+        .map(|f| format!("Write: {f}"))
+        .chain(removals.into_iter().map(|f| format!("Remove: {f}")))
+        .collect();
+    Ok(r)
+}
+
+/// The files the update payload of a component would write over the
+/// installed `current` filetree, without the expected duration; `None` if
+/// the component doesn't track its files.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn estimate_filetree_update(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    current: &InstalledContent,
+) -> Result<Option<UpdateEstimate>> {
+    let Some(currentf) = current.filetree.as_ref() else {
+        return Ok(None);
+    };
+    let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(component))? else {
+        return Ok(None);
+    };
+    let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+    let updatef = crate::filetree::FileTree::new_from_dir(&updated, digest)?;
+    let diff = currentf.diff(&updatef)?;
+    let writes: Vec<_> = diff.additions.iter().chain(diff.changes.iter()).collect();
+    let bytes = writes.iter().map(|f| updatef.children[*f].size).sum();
+    Ok(Some(UpdateEstimate {
+        bytes,
+        files: writes.len(),
+        seconds: None,
+    }))
+}
+
+/// Where the previous content of components is kept for `bootupctl rollback`,
+/// relative to the sysroot
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) const BACKUP_DIR: &str = "boot/bootupd-backup";
+/// The previous `InstalledContent`, in the backup of a component
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const BACKUP_STATE: &str = "installed.json";
+/// The previous files, in the backup of a component
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const BACKUP_CONTENT: &str = "content";
+
+/// Keep a copy of the files of `current` from `srcdir` (and of `current`
+/// itself), replacing the previous backup of the component.  The recorded
+/// filetree is the one of the copy, which may differ from `current` if the
+/// files were modified.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+#[context("Backing up {}", component.name())]
+pub(crate) fn backup_filetree(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    current: &InstalledContent,
+    srcdir: &openat::Dir,
+) -> Result<()> {
+    let Some(currentf) = current.filetree.as_ref() else {
+        return Ok(());
+    };
+    sysroot.ensure_dir_all(BACKUP_DIR, 0o700)?;
+    let backups = sysroot.sub_dir(BACKUP_DIR)?;
+    let name = component.name();
+    let tmp = format!(".{name}.tmp");
+    backups.remove_all(&tmp)?;
+    backups.create_dir(&tmp, 0o700)?;
+    let tmpd = backups.sub_dir(&tmp)?;
+    tmpd.create_dir(BACKUP_CONTENT, 0o700)?;
+    let content = tmpd.sub_dir(BACKUP_CONTENT)?;
+    currentf.copy_files(srcdir, &content)?;
+    let mut saved = current.clone();
+    let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+    saved.filetree = Some(crate::filetree::FileTree::new_from_dir(&content, digest)?);
+    tmpd.write_file_with_sync(BACKUP_STATE, 0o600, |w| -> Result<()> {
+        Ok(serde_json::to_writer(w, &saved)?)
+    })?;
+    backups.remove_all(name)?;
+    backups.local_rename(tmp.as_str(), name)?;
+    Ok(())
+}
+
+/// Returns the backup of a component made by `backup_filetree`, and the
+/// directory with its files.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+#[context("Loading backup of {}", component.name())]
+pub(crate) fn load_backup(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+) -> Result<Option<(InstalledContent, openat::Dir)>> {
+    let dir = Path::new(BACKUP_DIR).join(component.name());
+    let Some(f) = sysroot.open_file_optional(&dir.join(BACKUP_STATE))? else {
+        return Ok(None);
+    };
+    let saved = serde_json::from_reader(std::io::BufReader::new(f))?;
+    let content = sysroot.sub_dir(&dir.join(BACKUP_CONTENT))?;
+    Ok(Some((saved, content)))
+}
+
+/// Remove the backup of a component, once restored.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn remove_backup(sysroot: &openat::Dir, component: &dyn Component) -> Result<()> {
+    sysroot.remove_all(Path::new(BACKUP_DIR).join(component.name()))?;
+    Ok(())
+}
+
 /// Given a component name, create an implementation.
 pub(crate) fn new_from_name(name: &str) -> Result<Box<dyn Component>> {
     let r: Box<dyn Component> = match name {
-        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
         #[allow(clippy::box_default)]
         "EFI" => Box::new(crate::efi::Efi::default()),
         #[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
         #[allow(clippy::box_default)]
         "BIOS" => Box::new(crate::bios::Bios::default()),
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        #[allow(clippy::box_default)]
+        "systemd-boot" => Box::new(crate::systemdboot::SystemdBoot::default()),
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        #[allow(clippy::box_default)]
+        "UKI" => Box::new(crate::uki::Uki::default()),
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        #[allow(clippy::box_default)]
+        "dbx" => Box::new(crate::dbx::Dbx::default()),
+        #[cfg(target_arch = "x86_64")]
+        #[allow(clippy::box_default)]
+        "syslinux" => Box::new(crate::syslinux::Syslinux::default()),
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        #[allow(clippy::box_default)]
+        "u-boot" => Box::new(crate::uboot::UBoot::default()),
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        #[allow(clippy::box_default)]
+        "dtb" => Box::new(crate::dtb::Dtb::default()),
+        #[cfg(target_arch = "s390x")]
+        #[allow(clippy::box_default)]
+        "zipl" => Box::new(crate::zipl::Zipl::default()),
+        #[cfg(all(
+            feature = "testing",
+            any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ))]
+        #[allow(clippy::box_default)]
+        "mock" => Box::new(crate::testing::MockComponent::default()),
         _ => anyhow::bail!("No component {}", name),
     };
     Ok(r)
@@ -93,14 +456,22 @@ pub(crate) fn new_from_name(name: &str) -> Result<Box<dyn Component>> {
 
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn component_updatedirname(component: &dyn Component) -> PathBuf {
     Path::new(BOOTUPD_UPDATES_DIR).join(component.name())
 }
 
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn component_updatedir(sysroot: &str, component: &dyn Component) -> PathBuf {
     Path::new(sysroot).join(component_updatedirname(component))
 }
@@ -144,6 +515,9 @@ pub(crate) fn get_component_update(
     }
 }
 
+/// The version of an adoptable system of which nothing more is known
+pub(crate) const UNKNOWN_VERSION: &str = "unknown";
+
 #[context("Querying adoptable state")]
 pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
     // This would be extended with support for other operating systems later
@@ -151,6 +525,7 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
         let meta = ContentMetadata {
             timestamp: coreos_aleph.ts,
             version: coreos_aleph.aleph.version,
+            signing_keys: Vec::new(),
         };
         log::trace!("Adoptable: {:?}", &meta);
         return Ok(Some(Adoptable {
@@ -166,7 +541,8 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
         let timestamp = chrono::DateTime::from(btime);
         let meta = ContentMetadata {
             timestamp,
-            version: "unknown".to_string(),
+            version: UNKNOWN_VERSION.to_string(),
+            signing_keys: Vec::new(),
         };
         return Ok(Some(Adoptable {
             version: meta,
@@ -179,6 +555,7 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::digest::DigestAlgorithm;
 
     #[test]
     fn test_get_efi_vendor() -> Result<()> {
@@ -190,11 +567,15 @@ mod tests {
         std::fs::create_dir_all(tdp_updates.join("EFI/fedora"))?;
         std::fs::create_dir_all(tdp_updates.join("EFI/centos"))?;
         std::fs::write(
-            tdp_updates.join("EFI/fedora").join(crate::efi::SHIM),
+            tdp_updates
+                .join("EFI/fedora")
+                .join(crate::efi::VENDOR_LOADER),
             "shim data",
         )?;
         std::fs::write(
-            tdp_updates.join("EFI/centos").join(crate::efi::SHIM),
+            tdp_updates
+                .join("EFI/centos")
+                .join(crate::efi::VENDOR_LOADER),
             "shim data",
         )?;
 
@@ -213,4 +594,108 @@ mod tests {
         }
         Ok(())
     }
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn test_plan_filetree_update() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let tdp = td.path();
+        let component = crate::efi::Efi::default();
+        let updatedir = tdp.join(component_updatedirname(&component));
+        std::fs::create_dir_all(updatedir.join("fedora"))?;
+        std::fs::write(updatedir.join("fedora/grubx64.efi"), "grub data")?;
+        std::fs::write(updatedir.join("fedora/shimx64.efi"), "shim data")?;
+        let td = openat::Dir::open(tdp)?;
+        let filetree = crate::filetree::FileTree::new_from_dir(
+            &td.sub_dir(&component_updatedirname(&component))?,
+            DigestAlgorithm::default(),
+        )?;
+        let current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "v1".into(),
+                signing_keys: Vec::new(),
+            },
+            filetree: Some(filetree),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        };
+        assert!(plan_filetree_update(&td, &component, &current)?.is_empty());
+
+        std::fs::write(updatedir.join("fedora/shimx64.efi"), "new shim data")?;
+        std::fs::write(updatedir.join("fedora/mmx64.efi"), "mm data")?;
+        std::fs::remove_file(updatedir.join("fedora/grubx64.efi"))?;
+        assert_eq!(
+            plan_filetree_update(&td, &component, &current)?,
+            [
+                "Write: fedora/mmx64.efi",
+                "Write: fedora/shimx64.efi",
+                "Remove: fedora/grubx64.efi"
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn test_backup_filetree() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let tdp = td.path();
+        std::fs::create_dir_all(tdp.join("esp/fedora"))?;
+        std::fs::write(tdp.join("esp/fedora/shimx64.efi"), "shim data")?;
+        let sysroot = openat::Dir::open(tdp)?;
+        let esp = sysroot.sub_dir("esp")?;
+        let component = crate::efi::Efi::default();
+        let filetree = crate::filetree::FileTree::new_from_dir(&esp, DigestAlgorithm::default())?;
+        let current = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "v1".into(),
+                signing_keys: Vec::new(),
+            },
+            filetree: Some(filetree.clone()),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        };
+        assert!(load_backup(&sysroot, &component)?.is_none());
+        backup_filetree(&sysroot, &component, &current, &esp)?;
+        std::fs::write(tdp.join("esp/fedora/shimx64.efi"), "new shim data")?;
+        let (saved, content) = load_backup(&sysroot, &component)?.expect("backup");
+        assert_eq!(saved.meta.version, "v1");
+        assert_eq!(saved.filetree.as_ref(), Some(&filetree));
+        assert_eq!(
+            crate::filetree::FileTree::new_from_dir(&content, DigestAlgorithm::default())?,
+            filetree
+        );
+        remove_backup(&sysroot, &component)?;
+        assert!(load_backup(&sysroot, &component)?.is_none());
+        Ok(())
+    }
 }
@@ -0,0 +1,511 @@
+//! Administrator configuration, read from `/etc/bootupd/config.toml`.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::blockdev::DeviceIds;
+use crate::digest::DigestAlgorithm;
+
+/// The configuration file, relative to the root
+pub(crate) const CONFIG_PATH: &str = "etc/bootupd/config.toml";
+
+/// What `bootupctl update --auto` (i.e. `bootupd-update.timer`) does.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoUpdatePolicy {
+    /// Do nothing
+    None,
+    /// Update the installed components
+    #[default]
+    Update,
+    /// Also adopt the components that can be reliably updated, like
+    /// `bootupctl update`
+    All,
+}
+
+/// How the writes of updates to the ESP are made durable.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncPolicy {
+    /// fsync each written file and its directory, and each directory
+    /// changed by a rename or removal, as they are written: safest, each
+    /// write is durable before the next one
+    Fsync,
+    /// Sync the filesystem after staging the new files, after recording
+    /// the intent to swap them in, and after swapping them in
+    #[default]
+    Syncfs,
+    /// Sync the filesystem once, at the end of the update: fastest, but an
+    /// update interrupted by a power loss may leave a mix of old and new
+    /// files, and the written files can't be read back from the disk
+    End,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UpdateConfig {
+    #[serde(default)]
+    pub auto: AutoUpdatePolicy,
+    /// The digest of the files tracked in the state file
+    #[serde(default)]
+    pub digest: DigestAlgorithm,
+    /// Mark updates as needing boot verification: they are rolled back if
+    /// the following boots don't reach `boot-complete.target`
+    #[serde(default)]
+    pub verify_boot: bool,
+    /// How the writes to the ESP are made durable
+    #[serde(default)]
+    pub sync: SyncPolicy,
+}
+
+/// What to do with an EFI update containing binaries revoked by the SBAT
+/// policy of shim, which would then refuse to boot them.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SbatPolicy {
+    /// Refuse the update
+    #[default]
+    Enforce,
+    /// Log a warning and apply the update
+    Warn,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EfiConfig {
+    /// Recreate the firmware boot entry for the vendor loader on update if
+    /// it is missing
+    #[serde(default)]
+    pub ensure_boot_entry: bool,
+    /// Also ship the removable media path (e.g. `EFI/BOOT/BOOTX64.EFI`) in
+    /// the update payload, if the packages don't provide it; read when
+    /// generating the update metadata
+    #[serde(default)]
+    pub fallback: bool,
+    #[serde(default)]
+    pub sbat: SbatPolicy,
+    /// What to do with an EFI update lowering the SBAT generation of an
+    /// installed binary (e.g. an older shim), which would stop booting
+    /// once `SbatLevel` is raised
+    #[serde(default)]
+    pub sbat_rollback: SbatPolicy,
+    /// Use this directory of the ESP (e.g. `fedora`) for the boot entry
+    /// and the GRUB config instead of the one of the shim in the payload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<String>,
+    /// Directories of the ESP (relative to `EFI/`, e.g. `ubuntu`) which
+    /// updates must never modify, in addition to `Microsoft`, and `BOOT`
+    /// unless the payload ships it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protected: Vec<String>,
+    /// Remount the ESPs mounted by the system read-only once bootupd is
+    /// done writing to them, rather than leaving them read-write
+    #[serde(default)]
+    pub read_only: bool,
+    /// Keep the installed shim and GRUB in `<vendor>-previous` when
+    /// updating, e.g. across a Secure Boot CA rotation, until `bootupctl
+    /// finalize-rotation`
+    #[serde(default)]
+    pub key_rotation: bool,
+    /// Remove the firmware boot entries of files missing from their ESP
+    /// on update, as `bootupctl prune-boot-entries` does
+    #[serde(default)]
+    pub prune_boot_entries: bool,
+    /// How many days `bootupctl validate --prune` keeps the files it
+    /// removes in `bootupd-quarantine/` on the ESP (30 if unset); 0
+    /// deletes them outright
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine_days: Option<u32>,
+}
+
+impl EfiConfig {
+    /// How many days pruned files are kept in quarantine, if at all.
+    pub(crate) fn quarantine_days(&self) -> Option<u32> {
+        match self.quarantine_days {
+            Some(0) => None,
+            Some(days) => Some(days),
+            None => Some(30),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ComponentsConfig {
+    /// Components which are never updated nor adopted, e.g. `BIOS` on
+    /// EFI-only systems
+    #[serde(default)]
+    pub disabled: BTreeSet<String>,
+}
+
+/// Settings of grub2-install: the modules it embeds, and the disks it may
+/// write to, e.g. so that shared SAN LUNs are never touched.  Devices are
+/// given by path (e.g. `/dev/disk/by-path/...`), `wwn:<WWN>` or
+/// `serial:<serial number>`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BiosConfig {
+    /// If not empty, only these devices may be written to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_devices: Vec<String>,
+    /// These devices are never written to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_devices: Vec<String>,
+    /// GRUB modules to embed in addition to `mdraid1x` and `part_gpt`, e.g.
+    /// `lvm` for `/boot` on LVM or `mdraid09` for RAID with 0.90 metadata
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modules: Vec<String>,
+    /// Don't embed `mdraid1x` and `part_gpt`, only `modules`
+    #[serde(default)]
+    pub replace_default_modules: bool,
+}
+
+/// Whether the device list entry `entry` refers to the device `ids`.
+fn device_matches(entry: &str, ids: &DeviceIds) -> bool {
+    if let Some(wwn) = entry.strip_prefix("wwn:") {
+        ids.wwn
+            .as_deref()
+            .is_some_and(|w| w.eq_ignore_ascii_case(wwn))
+    } else if let Some(serial) = entry.strip_prefix("serial:") {
+        ids.serial.as_deref() == Some(serial)
+    } else {
+        let path = Path::new(entry);
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        path == Path::new(&ids.path)
+    }
+}
+
+impl BiosConfig {
+    /// Fail if writing the BIOS bootloader to the device `ids` is not
+    /// allowed.
+    #[cfg_attr(
+        not(any(target_arch = "x86_64", target_arch = "powerpc64")),
+        allow(dead_code)
+    )]
+    pub(crate) fn check_device(&self, ids: &DeviceIds) -> Result<()> {
+        if let Some(entry) = self.denied_devices.iter().find(|e| device_matches(e, ids)) {
+            bail!(
+                "Device {} is denied by {entry:?} in /{CONFIG_PATH}",
+                ids.path
+            );
+        }
+        if !self.allowed_devices.is_empty()
+            && !self.allowed_devices.iter().any(|e| device_matches(e, ids))
+        {
+            bail!("Device {} is not allowed in /{CONFIG_PATH}", ids.path);
+        }
+        Ok(())
+    }
+}
+
+/// The parity of a serial port.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SerialParity {
+    No,
+    Odd,
+    Even,
+}
+
+impl SerialParity {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            SerialParity::No => "no",
+            SerialParity::Odd => "odd",
+            SerialParity::Even => "even",
+        }
+    }
+}
+
+fn default_serial_speed() -> u32 {
+    115200
+}
+
+/// The settings of the GRUB `serial` command.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SerialConfig {
+    /// The serial port, e.g. 0 for `ttyS0`
+    #[serde(default)]
+    pub unit: u32,
+    #[serde(default = "default_serial_speed")]
+    pub speed: u32,
+    /// The number of data bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub word: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parity: Option<SerialParity>,
+    /// The number of stop bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop: Option<u8>,
+}
+
+/// The consoles of GRUB, rendered to `/boot/grub2/console.cfg` by the
+/// static GRUB configs.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConsoleConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<SerialConfig>,
+    /// The terminals GRUB reads from, e.g. `["serial", "console"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub terminal_input: Vec<String>,
+    /// The terminals GRUB writes to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub terminal_output: Vec<String>,
+}
+
+/// Settings of the static GRUB configs: the console, and passwords
+/// protecting the GRUB console and the editing of the boot entries.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GrubConfig {
+    /// The users allowed to edit the entries and use the console
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub superusers: Vec<String>,
+    /// A file only readable by root with a `NAME:HASH` line per user, the
+    /// hash being the output of `grub2-mkpasswd-pbkdf2`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub console: Option<ConsoleConfig>,
+}
+
+fn default_hook_timeout() -> u64 {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HooksConfig {
+    /// How long each hook of `/etc/bootupd/hooks.d` may run, in seconds
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_hook_timeout(),
+        }
+    }
+}
+
+fn default_uki_keep() -> usize {
+    3
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct UkiConfig {
+    /// How many UKIs to keep in `EFI/Linux` on update, including the ones
+    /// of the update and the booted one, which are never pruned
+    #[serde(default = "default_uki_keep")]
+    pub keep: usize,
+}
+
+impl Default for UkiConfig {
+    fn default() -> Self {
+        Self {
+            keep: default_uki_keep(),
+        }
+    }
+}
+
+/// Where the `dtb` component installs the device tree blobs.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DtbLocation {
+    /// `/boot/dtb-<version>`, with a `/boot/dtb` symlink to it
+    #[default]
+    Boot,
+    /// `dtb/` on the ESP
+    Esp,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DtbConfig {
+    #[serde(default)]
+    pub location: DtbLocation,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub update: UpdateConfig,
+    #[serde(default)]
+    pub efi: EfiConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub components: ComponentsConfig,
+    #[serde(default)]
+    pub bios: BiosConfig,
+    #[serde(default)]
+    pub uki: UkiConfig,
+    #[serde(default)]
+    pub grub: GrubConfig,
+    #[serde(default)]
+    pub dtb: DtbConfig,
+}
+
+impl Config {
+    /// Load the configuration of the target root; a missing file is the
+    /// default configuration.
+    #[context("Loading configuration")]
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join(CONFIG_PATH);
+        let s = match std::fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("reading {path:?}")),
+        };
+        toml::from_str(&s).with_context(|| format!("parsing {path:?}"))
+    }
+
+    /// Whether the component `name` was disabled by the administrator.
+    pub(crate) fn is_disabled(&self, name: &str) -> bool {
+        self.components.disabled.contains(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let config = Config::load(td.path())?;
+        assert_eq!(config.update.auto, AutoUpdatePolicy::Update);
+
+        let path = td.path().join(CONFIG_PATH);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, "[update]\nauto = \"all\"\n")?;
+        let config = Config::load(td.path())?;
+        assert_eq!(config.update.auto, AutoUpdatePolicy::All);
+        assert!(!config.efi.ensure_boot_entry);
+
+        std::fs::write(&path, "[efi]\nfallback = true\n")?;
+        let config = Config::load(td.path())?;
+        assert!(config.efi.fallback);
+        assert!(!config.efi.read_only);
+        assert!(!config.efi.key_rotation);
+        assert!(!config.efi.prune_boot_entries);
+        assert_eq!(config.efi.sbat, SbatPolicy::Enforce);
+        assert_eq!(config.efi.sbat_rollback, SbatPolicy::Enforce);
+        assert_eq!(config.efi.quarantine_days(), Some(30));
+        assert_eq!(config.update.auto, AutoUpdatePolicy::Update);
+        assert_eq!(config.hooks.timeout, 60);
+        assert_eq!(config.uki.keep, 3);
+        assert_eq!(config.dtb.location, DtbLocation::Boot);
+
+        assert_eq!(config.update.digest, DigestAlgorithm::Sha512);
+        assert!(!config.update.verify_boot);
+        assert_eq!(config.update.sync, SyncPolicy::Syncfs);
+
+        std::fs::write(&path, "[update]\ndigest = \"blake3\"\n")?;
+        assert_eq!(
+            Config::load(td.path())?.update.digest,
+            DigestAlgorithm::Blake3
+        );
+
+        std::fs::write(&path, "[update]\nsync = \"fsync\"\n")?;
+        assert_eq!(Config::load(td.path())?.update.sync, SyncPolicy::Fsync);
+
+        std::fs::write(&path, "[efi]\nquarantine-days = 0\n")?;
+        assert_eq!(Config::load(td.path())?.efi.quarantine_days(), None);
+
+        std::fs::write(&path, "[hooks]\ntimeout = 5\n")?;
+        assert_eq!(Config::load(td.path())?.hooks.timeout, 5);
+
+        std::fs::write(
+            &path,
+            "[grub]\nsuperusers = [\"root\"]\npassword-file = \"/etc/bootupd/grub-users\"\n",
+        )?;
+        let config = Config::load(td.path())?;
+        assert_eq!(config.grub.superusers, ["root"]);
+        assert_eq!(
+            config.grub.password_file.as_deref(),
+            Some(Path::new("/etc/bootupd/grub-users"))
+        );
+
+        std::fs::write(
+            &path,
+            "[grub.console]\nterminal-output = [\"serial\"]\n[grub.console.serial]\nunit = 1\n",
+        )?;
+        let config = Config::load(td.path())?;
+        let console = config.grub.console.unwrap();
+        assert_eq!(console.terminal_output, ["serial"]);
+        let serial = console.serial.unwrap();
+        assert_eq!((serial.unit, serial.speed), (1, 115200));
+
+        std::fs::write(&path, "[uki]\nkeep = 2\n")?;
+        assert_eq!(Config::load(td.path())?.uki.keep, 2);
+
+        std::fs::write(&path, "[dtb]\nlocation = \"esp\"\n")?;
+        assert_eq!(Config::load(td.path())?.dtb.location, DtbLocation::Esp);
+
+        std::fs::write(
+            &path,
+            "[components]\ndisabled = [\"BIOS\"]\n[efi]\nvendor = \"centos\"\n",
+        )?;
+        let config = Config::load(td.path())?;
+        assert!(config.is_disabled("BIOS"));
+        assert!(!config.is_disabled("EFI"));
+        assert_eq!(config.efi.vendor.as_deref(), Some("centos"));
+
+        std::fs::write(&path, "[update]\nauto = \"sometimes\"\n")?;
+        assert!(Config::load(td.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_device() -> Result<()> {
+        let sda = DeviceIds {
+            path: "/dev/sda".into(),
+            wwn: Some("0x5000c500a0b1c2d3".into()),
+            serial: Some("S3Z9NB0K".into()),
+        };
+        let sdb = DeviceIds {
+            path: "/dev/sdb".into(),
+            ..Default::default()
+        };
+        let config: Config = toml::from_str("")?;
+        config.bios.check_device(&sda)?;
+
+        let config: Config =
+            toml::from_str("[bios]\ndenied-devices = [\"wwn:0x5000C500A0B1C2D3\"]\n")?;
+        assert!(config.bios.check_device(&sda).is_err());
+        config.bios.check_device(&sdb)?;
+
+        let config: Config = toml::from_str("[bios]\nallowed-devices = [\"serial:S3Z9NB0K\"]\n")?;
+        config.bios.check_device(&sda)?;
+        assert!(config.bios.check_device(&sdb).is_err());
+
+        let config: Config = toml::from_str(
+            "[bios]\nallowed-devices = [\"/dev/sdb\"]\ndenied-devices = [\"/dev/sdb\"]\n",
+        )?;
+        assert!(config.bios.check_device(&sdb).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bios_modules() -> Result<()> {
+        let config: Config = toml::from_str("")?;
+        assert!(config.bios.modules.is_empty());
+        assert!(!config.bios.replace_default_modules);
+
+        let config: Config =
+            toml::from_str("[bios]\nmodules = [\"lvm\"]\nreplace-default-modules = true\n")?;
+        assert_eq!(config.bios.modules, ["lvm"]);
+        assert!(config.bios.replace_default_modules);
+        Ok(())
+    }
+}
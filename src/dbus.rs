@@ -0,0 +1,266 @@
+//! D-Bus API of the daemon, exposing the same operations as `bootupctl`
+//! as the system service `org.coreos.bootupd1`.
+//!
+//! The service is started on demand by the bus (see `dbus/` and
+//! `systemd/bootupd-dbus.service`), with the same isolation as the transient
+//! unit used by `bootupctl`.  Structured results (status, validation) are
+//! returned as the same JSON documents as `bootupctl status --json` and
+//! `bootupctl validate --format=json`.
+//!
+//! Callers other than root are authorized with polkit, using these actions
+//! (see `dbus/org.coreos.bootupd1.policy`):
+//!
+//! - `org.coreos.bootupd1.status`: `Status`, allowed for active local users
+//! - `org.coreos.bootupd1.validate`: `Validate`, allowed for active local users
+//! - `org.coreos.bootupd1.update`: `Update` and `AdoptAndUpdate`, requires
+//!   administrator authentication
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use zbus::message::Header;
+use zbus::names::BusName;
+use zbus::zvariant::{Type, Value};
+use zbus::{fdo, interface, Connection, SignalContext};
+
+use crate::bootupd;
+
+/// The well-known name of the service on the system bus
+pub(crate) const BUS_NAME: &str = "org.coreos.bootupd1";
+/// The path of the `Manager` object
+pub(crate) const OBJECT_PATH: &str = "/org/coreos/bootupd1";
+/// The interface of the `Manager` object
+const INTERFACE: &str = "org.coreos.bootupd1.Manager";
+/// The component of the `Progress` signals about the static GRUB configs
+const STATIC_CONFIGS: &str = "static-configs";
+
+/// polkit action for `Status`
+const ACTION_STATUS: &str = "org.coreos.bootupd1.status";
+/// polkit action for `Validate`
+const ACTION_VALIDATE: &str = "org.coreos.bootupd1.validate";
+/// polkit action for `Update` and `AdoptAndUpdate`
+const ACTION_UPDATE: &str = "org.coreos.bootupd1.update";
+
+/// `AllowUserInteraction` flag of `CheckAuthorization`
+const POLKIT_ALLOW_USER_INTERACTION: u32 = 1;
+
+fn to_fdo(e: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{e:#}"))
+}
+
+/// The reply of polkit's `CheckAuthorization`.
+#[derive(Debug, Deserialize, Type)]
+struct AuthorizationResult {
+    is_authorized: bool,
+    #[allow(dead_code)]
+    is_challenge: bool,
+    #[allow(dead_code)]
+    details: HashMap<String, String>,
+}
+
+/// Ensure the sender of the message is allowed to perform `action`; root
+/// is always allowed, everyone else is checked with polkit.
+async fn authorize(conn: &Connection, hdr: &Header<'_>, action: &str) -> fdo::Result<()> {
+    let sender = hdr
+        .sender()
+        .ok_or_else(|| fdo::Error::AccessDenied("Unknown sender".into()))?;
+    let uid = fdo::DBusProxy::new(conn)
+        .await?
+        .get_connection_unix_user(BusName::from(sender.to_owned()))
+        .await?;
+    if uid == 0 {
+        return Ok(());
+    }
+    let authority = zbus::Proxy::new(
+        conn,
+        "org.freedesktop.PolicyKit1",
+        "/org/freedesktop/PolicyKit1/Authority",
+        "org.freedesktop.PolicyKit1.Authority",
+    )
+    .await?;
+    let subject_details = HashMap::from([("name", Value::from(sender.as_str()))]);
+    let subject = ("system-bus-name", subject_details);
+    let details: HashMap<&str, &str> = HashMap::new();
+    let result: AuthorizationResult = authority
+        .call(
+            "CheckAuthorization",
+            &(subject, action, details, POLKIT_ALLOW_USER_INTERACTION, ""),
+        )
+        .await?;
+    if !result.is_authorized {
+        log::info!("Denied {action} for uid {uid}");
+        return Err(fdo::Error::AccessDenied(format!(
+            "Not authorized for {action}"
+        )));
+    }
+    Ok(())
+}
+
+struct Manager;
+
+#[interface(name = "org.coreos.bootupd1.Manager")]
+impl Manager {
+    /// Returns the status of all components, as JSON.
+    async fn status(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<String> {
+        authorize(conn, &hdr, ACTION_STATUS).await?;
+        let status = blocking::unblock(bootupd::status).await.map_err(to_fdo)?;
+        serde_json::to_string(&status).map_err(|e| to_fdo(e.into()))
+    }
+
+    /// Update all components, and adopt the ones which can be reliably
+    /// updated; returns the new version of each updated component.
+    async fn update(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<HashMap<String, String>> {
+        authorize(conn, &hdr, ACTION_UPDATE).await?;
+        let conn = zbus::blocking::Connection::from(conn.clone());
+        let summary = blocking::unblock(move || {
+            let status = bootupd::status()?;
+            let percents = Mutex::new(HashMap::new());
+            bootupd::run_update(&status, &[], false, &|event| {
+                report_update(&conn, &percents, event)
+            })
+        })
+        .await
+        .map_err(to_fdo)?;
+        Ok(summary.versions.into_iter().collect())
+    }
+
+    /// Adopt and update all adoptable components; returns the new version
+    /// of each adopted component.
+    async fn adopt_and_update(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<HashMap<String, String>> {
+        authorize(conn, &hdr, ACTION_UPDATE).await?;
+        let conn = zbus::blocking::Connection::from(conn.clone());
+        blocking::unblock(move || {
+            let status = bootupd::status()?;
+            let mut r = HashMap::new();
+            for name in status.adoptable.keys() {
+                emit_progress(&conn, name, "Adopting");
+                let new = bootupd::adopt_and_update(name)?;
+                emit_progress(
+                    &conn,
+                    name,
+                    &format!("Adopted and updated: {}", new.version),
+                );
+                r.insert(name.clone(), new.version);
+            }
+            Ok(r)
+        })
+        .await
+        .map_err(to_fdo)
+    }
+
+    /// Validate all installed components; returns the validation report,
+    /// as JSON.
+    async fn validate(
+        &self,
+        #[zbus(connection)] conn: &Connection,
+        #[zbus(header)] hdr: Header<'_>,
+    ) -> fdo::Result<String> {
+        authorize(conn, &hdr, ACTION_VALIDATE).await?;
+        let report = blocking::unblock(|| bootupd::validate_all(false))
+            .await
+            .map_err(to_fdo)?;
+        serde_json::to_string(&report).map_err(|e| to_fdo(e.into()))
+    }
+
+    /// Emitted while updating `component`, with a human readable message;
+    /// see `emit_progress`.
+    #[allow(dead_code)]
+    #[zbus(signal)]
+    async fn progress(ctxt: &SignalContext<'_>, component: &str, message: &str)
+        -> zbus::Result<()>;
+}
+
+/// Emit the `Progress` signal from the blocking threads running the
+/// operations, rather than from the executor of the handlers.
+fn emit_progress(conn: &zbus::blocking::Connection, component: &str, message: &str) {
+    let r = conn.emit_signal(
+        None::<BusName>,
+        OBJECT_PATH,
+        INTERFACE,
+        "Progress",
+        &(component, message),
+    );
+    if let Err(e) = r {
+        log::debug!("Failed to emit progress: {e}");
+    }
+}
+
+/// Report an event of `bootupd::run_update` as a `Progress` signal;
+/// `percents` records the last percentage reported for each component.
+fn report_update(
+    conn: &zbus::blocking::Connection,
+    percents: &Mutex<HashMap<String, u64>>,
+    event: bootupd::UpdateEvent,
+) {
+    use bootupd::UpdateEvent;
+    match event {
+        UpdateEvent::Updating(name) => emit_progress(conn, name, "Updating"),
+        UpdateEvent::Progress(name, p) => {
+            // One signal per percent is enough
+            let mut percents = percents.lock().unwrap();
+            if percents.insert(name.to_string(), p.percent()) != Some(p.percent()) {
+                emit_progress(conn, name, &format!("Writing: {p}"));
+            }
+        }
+        UpdateEvent::Written => {}
+        UpdateEvent::Updated { name, new, .. } => {
+            emit_progress(conn, name, &format!("Updated: {}", new.version))
+        }
+        UpdateEvent::Adopted(name, new) => {
+            emit_progress(conn, name, &format!("Adopted and updated: {}", new.version))
+        }
+        UpdateEvent::StaticConfigs(meta) => {
+            emit_progress(conn, STATIC_CONFIGS, &format!("Updated: {}", meta.version))
+        }
+        UpdateEvent::Skipped(name, message) | UpdateEvent::Warning(name, message) => {
+            emit_progress(conn, name, &message)
+        }
+        UpdateEvent::Failed(name, e) => emit_progress(conn, name, &format!("Failed: {e:#}")),
+    }
+}
+
+/// Serve the D-Bus API, refreshing `metrics_textfile` every
+/// `metrics_interval` if set; this only returns on error.
+pub(crate) fn run(metrics_textfile: Option<&Path>, metrics_interval: Duration) -> Result<()> {
+    // On the main thread, before zbus starts its own
+    crate::util::enter_private_mount_namespace()?;
+    crate::payloadview::activate(Path::new("/"))?;
+    if let Some(path) = metrics_textfile {
+        crate::metrics::spawn_refresh(path, metrics_interval)?;
+    }
+    let _conn = zbus::blocking::ConnectionBuilder::system()?
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Manager)?
+        .build()
+        .context("Connecting to the system bus")?;
+    log::info!("Serving {BUS_NAME}");
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorization_result_signature() {
+        assert_eq!(AuthorizationResult::signature(), "(bba{ss})");
+    }
+}
@@ -0,0 +1,343 @@
+//! The `dbx` component: updates of the UEFI forbidden signature database,
+//! which revokes the binaries the firmware must refuse to boot, such as
+//! vulnerable shims.  Leaving it stale undermines the Secure Boot chain
+//! maintained by the other components.
+//!
+//! The updates are authenticated variable writes signed with a KEK, like
+//! the `DBXUpdate.bin` published by Microsoft, shipped in
+//! `REVOCATION_LISTS_DIR/<arch>`.  `generate-update-metadata` copies them to
+//! the update payload, and they are appended to dbx through efivarfs, in
+//! name order; the applied updates are recorded in the state, so that each
+//! is only written once, or deferred to the next update while efivarfs
+//! isn't writable.  The firmware of the disk image build host mustn't
+//! be touched, so the component isn't installed with the others, but
+//! adopted on the booted system by `bootupctl update`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::component::*;
+use crate::efi;
+use crate::efivars;
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::sha512string::SHA512String;
+
+/// The revocation list updates shipped by the OS, one directory per
+/// architecture
+const REVOCATION_LISTS_DIR: &str = "usr/share/uefi-revocation-lists";
+#[cfg(target_arch = "x86_64")]
+const ARCH: &str = "x64";
+#[cfg(target_arch = "aarch64")]
+const ARCH: &str = "aa64";
+#[cfg(target_arch = "riscv64")]
+const ARCH: &str = "riscv64";
+
+/// The vendor GUID of db, dbx and the other signature databases
+const IMAGE_SECURITY_DATABASE: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+const DBX_VAR: &str = "dbx";
+
+/// An update of dbx in the update payload.
+#[derive(Debug)]
+struct DbxUpdate {
+    name: String,
+    data: Vec<u8>,
+    checksum: SHA512String,
+}
+
+/// Returns `true` if `name` is a dbx update file.
+fn is_update(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bin"))
+}
+
+/// The names of the dbx updates in `dir`, sorted.
+fn list_updates(dir: &Path) -> Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {dir:?}")),
+    };
+    let mut r = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_update(&name) {
+            r.push(name);
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Returns `true` if the target root ships revocation list updates.
+pub(crate) fn is_available(root: &Path) -> bool {
+    list_updates(&root.join(REVOCATION_LISTS_DIR).join(ARCH)).is_ok_and(|u| !u.is_empty())
+}
+
+fn checksum(data: &[u8]) -> Result<SHA512String> {
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    hasher.update(data)?;
+    Ok(SHA512String::from_hasher(&mut hasher))
+}
+
+/// The updates of the payload of `sysroot` whose content isn't in `applied`.
+#[context("Loading dbx updates")]
+fn pending_updates(
+    sysroot: &openat::Dir,
+    applied: Option<&BTreeMap<String, SHA512String>>,
+) -> Result<Vec<DbxUpdate>> {
+    let dir = sysroot.sub_dir(&component_updatedirname(&Dbx::default()))?;
+    let mut r = Vec::new();
+    for name in list_updates(&dir.recover_path()?)? {
+        let data = std::fs::read(dir.recover_path()?.join(&name))
+            .with_context(|| format!("reading {name}"))?;
+        let checksum = checksum(&data)?;
+        if applied.is_some_and(|a| a.values().any(|c| c == &checksum)) {
+            continue;
+        }
+        r.push(DbxUpdate {
+            name,
+            data,
+            checksum,
+        });
+    }
+    Ok(r)
+}
+
+fn ensure_efi_booted() -> Result<()> {
+    if !efi::is_efi_booted()? {
+        bail!("dbx can only be updated when booted via EFI");
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct Dbx {}
+
+impl Dbx {
+    /// Append the updates of the payload not in `applied` to dbx; returns
+    /// all the applied updates.
+    #[context("Updating dbx")]
+    fn apply(
+        &self,
+        sysroot: &openat::Dir,
+        applied: Option<&BTreeMap<String, SHA512String>>,
+    ) -> Result<BTreeMap<String, SHA512String>> {
+        ensure_efi_booted()?;
+        let mut r = applied.cloned().unwrap_or_default();
+        for update in pending_updates(sysroot, applied)? {
+            efivars::append_authenticated_var(DBX_VAR, IMAGE_SECURITY_DATABASE, &update.data)
+                .with_context(|| format!("applying {}", update.name))?;
+            log::info!("Applied dbx update {}", update.name);
+            r.insert(update.name, update.checksum);
+        }
+        Ok(r)
+    }
+
+    /// Like `apply`, but if the EFI variables are not writable, the
+    /// updates are deferred instead: returns the applied updates along
+    /// with the pending operations.
+    fn apply_or_defer(
+        &self,
+        sysroot: &openat::Dir,
+        applied: Option<&BTreeMap<String, SHA512String>>,
+    ) -> Result<(
+        BTreeMap<String, SHA512String>,
+        Option<BTreeSet<NvramOperation>>,
+    )> {
+        ensure_efi_booted()?;
+        if efi::efivars_writable()? {
+            return Ok((self.apply(sysroot, applied)?, None));
+        }
+        println!("EFI variables are not writable, deferring the dbx update");
+        let pending = BTreeSet::from([NvramOperation::DbxUpdate]);
+        Ok((applied.cloned().unwrap_or_default(), Some(pending)))
+    }
+}
+
+impl Component for Dbx {
+    fn name(&self) -> &'static str {
+        "dbx"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if !efi::is_efi_booted()? {
+            return Ok(None);
+        }
+        if efivars::read_var("KEK")?.is_none() {
+            log::debug!("No KEK enrolled, skip adopt");
+            return Ok(None);
+        }
+        // What was applied before is unknown; any update is newer
+        Ok(Some(Adoptable {
+            version: ContentMetadata {
+                timestamp: Default::default(),
+                version: UNKNOWN_VERSION.to_string(),
+                signing_keys: Vec::new(),
+            },
+            confident: true,
+        }))
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            bail!("Failed to find adoptable system")
+        };
+        let (applied, pending_nvram) = self.apply_or_defer(sysroot, None)?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: None,
+            adopted_from: Some(meta.version),
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: Some(applied),
+            rotation: None,
+            pending_nvram,
+        })
+    }
+
+    fn install(
+        &self,
+        _: &openat::Dir,
+        _: &str,
+        _: &[String],
+        _: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        bail!(
+            "{} is applied on the booted system, by `bootupctl update`",
+            self.name()
+        )
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path)
+            .join(REVOCATION_LISTS_DIR)
+            .join(ARCH);
+        let names = list_updates(&src)?;
+        if names.is_empty() {
+            bail!("Failed to find any dbx update in {src:?}");
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        let mut sources = Vec::new();
+        for name in names.iter() {
+            let path = src.join(name);
+            std::fs::copy(&path, dest.join(name)).with_context(|| format!("copying {path:?}"))?;
+            sources.push(path);
+        }
+        let meta = packagesystem::query_payload(sysroot_path, sources, &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        _: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let (applied, pending_nvram) = self.apply_or_defer(sysroot, current.dbx.as_ref())?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: Some(applied),
+            rotation: None,
+            pending_nvram,
+        })
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        let r = pending_updates(sysroot, current.dbx.as_ref())?
+            .into_iter()
+            .map(|u| format!("Append: {} to {DBX_VAR}", u.name))
+            .collect();
+        Ok(r)
+    }
+
+    fn apply_pending_nvram(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<InstalledContent>> {
+        if current.pending_nvram.is_none() || !efi::efivars_writable()? {
+            return Ok(None);
+        }
+        let applied = self.apply(sysroot, current.dbx.as_ref())?;
+        Ok(Some(InstalledContent {
+            dbx: Some(applied),
+            pending_nvram: None,
+            ..current.clone()
+        }))
+    }
+
+    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
+        // dbx only grows, and its entries can't be read back by update
+        Ok(ValidationResult::Skip)
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_updates() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let payload = td.path().join(BOOTUPD_UPDATES_DIR).join("dbx");
+        assert!(list_updates(&payload)?.is_empty());
+        std::fs::create_dir_all(&payload)?;
+        std::fs::write(payload.join("DBXUpdate-20250507.bin"), "second")?;
+        std::fs::write(payload.join("DBXUpdate-20230509.bin"), "first")?;
+        std::fs::write(payload.join("README"), "")?;
+        assert_eq!(
+            list_updates(&payload)?,
+            ["DBXUpdate-20230509.bin", "DBXUpdate-20250507.bin"]
+        );
+
+        let sysroot = openat::Dir::open(td.path())?;
+        assert_eq!(pending_updates(&sysroot, None)?.len(), 2);
+        // Applied under another name, e.g. by an older payload
+        let applied = BTreeMap::from([("DBXUpdate.bin".to_string(), checksum(b"first")?)]);
+        let pending = pending_updates(&sysroot, Some(&applied))?;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].name, "DBXUpdate-20250507.bin");
+        assert_eq!(pending[0].data, b"second");
+        Ok(())
+    }
+}
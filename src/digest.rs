@@ -0,0 +1,91 @@
+//! Content digests of the files tracked in filetrees.
+//!
+//! SHA-512 is the default, e.g. for FIPS-constrained deployments; BLAKE3 is
+//! much faster to validate large trees with.  Each file records the
+//! algorithm of its digest, so trees written with either (or by older
+//! versions, which only knew SHA-512) can be read back.
+
+use std::fmt;
+use std::io::Read;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::sha512string::SHA512String;
+
+/// The algorithm used for the digests of new filetrees.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha512,
+    Blake3,
+}
+
+/// The digest of the content of a file, serialized as a map entry named
+/// after its algorithm, e.g. `"sha512": "sha512:cf83..."`.
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Digest {
+    Sha512(SHA512String),
+    /// Prefixed with `blake3:`, like SHA-512 digests
+    Blake3(String),
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Digest::Sha512(s) => write!(f, "{s}"),
+            Digest::Blake3(s) => f.write_str(s),
+        }
+    }
+}
+
+impl Digest {
+    /// The algorithm of this digest.
+    pub(crate) fn algorithm(&self) -> DigestAlgorithm {
+        match self {
+            Digest::Sha512(_) => DigestAlgorithm::Sha512,
+            Digest::Blake3(_) => DigestAlgorithm::Blake3,
+        }
+    }
+
+    /// Compute the digest of the content of `r` with `algorithm`.
+    pub(crate) fn compute<R: Read>(algorithm: DigestAlgorithm, r: &mut R) -> Result<Self> {
+        match algorithm {
+            DigestAlgorithm::Sha512 => {
+                let mut hasher =
+                    openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha512())?;
+                std::io::copy(r, &mut hasher)?;
+                Ok(Digest::Sha512(SHA512String::from_hasher(&mut hasher)))
+            }
+            DigestAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(r, &mut hasher)?;
+                Ok(Digest::Blake3(format!(
+                    "blake3:{}",
+                    hasher.finalize().to_hex()
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute() -> Result<()> {
+        let sha512 = Digest::compute(DigestAlgorithm::Sha512, &mut &b""[..])?;
+        assert_eq!(sha512.algorithm(), DigestAlgorithm::Sha512);
+        assert!(sha512.to_string().starts_with("sha512:cf83e1357eef"));
+        let blake3 = Digest::compute(DigestAlgorithm::Blake3, &mut &b""[..])?;
+        assert_eq!(
+            blake3.to_string(),
+            "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_ne!(sha512, blake3);
+        Ok(())
+    }
+}
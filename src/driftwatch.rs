@@ -0,0 +1,356 @@
+//! Drift watch: report the out-of-band modifications of the files managed
+//! by bootupd (e.g. by rogue tooling, or tampering) as they happen, rather
+//! than at the next `bootupctl validate`.
+//!
+//! `bootupd watch` watches with inotify the directories of the files
+//! tracked for the EFI component and the GRUB configuration directory, and
+//! logs their modifications; with `--record`, they are also recorded in
+//! `/run/bootupd/drift.json` and shown by `bootupctl status`.
+//!
+//! Updates are not drift: once the watched directories have been quiet
+//! for a moment, the tracked files are compared to the (possibly just
+//! updated) state.  As the files of `/boot/grub2` aren't tracked, their
+//! modifications only count if no bootupd operation, which holds the
+//! operation lock, was in progress meanwhile.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::CString;
+use std::io::Read;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::model::DriftedFile;
+
+/// The modifications recorded by `bootupd watch --record`
+const DRIFT_PATH: &str = "/run/bootupd/drift.json";
+/// The GRUB configuration directory
+const GRUB_DIR: &str = "/boot/grub2";
+/// Files of the GRUB directory which legitimately change, e.g. for boot
+/// counting
+const GRUB_VOLATILE: &[&str] = &["grubenv"];
+/// How long the watched directories must be quiet before checking them
+const QUIET: Duration = Duration::from_secs(2);
+/// The events of the watched directories which may modify a file
+const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_DELETE
+    | libc::IN_ATTRIB;
+
+/// Load the modifications recorded by `bootupd watch --record`, if it runs.
+pub(crate) fn load_recorded() -> Result<Vec<DriftedFile>> {
+    match std::fs::read(DRIFT_PATH) {
+        Ok(buf) => serde_json::from_slice(&buf).with_context(|| format!("Parsing {DRIFT_PATH}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Reading {DRIFT_PATH}")),
+    }
+}
+
+/// Watch the managed files until killed, recording their modifications
+/// for `bootupctl status` if `record` is set.
+pub(crate) fn watch(record: bool) -> Result<()> {
+    let mut watcher = Watcher::new(record)?;
+    // Forget what a previous instance recorded
+    if record {
+        watcher.save()?;
+    }
+    watcher.watch_dirs()?;
+    log::info!("Watching {} directories", watcher.dirs.len());
+    // Catch up with what happened while not watching
+    let all = watcher.efidir.iter().cloned().collect();
+    watcher.check(&all, false)?;
+    watcher.run()
+}
+
+struct Watcher {
+    inotify: std::fs::File,
+    /// The watched directories, by watch descriptor
+    dirs: HashMap<i32, PathBuf>,
+    /// The `EFI` directory of the ESP, if the EFI component is installed
+    efidir: Option<PathBuf>,
+    record: bool,
+    /// The modified files, by path
+    drifted: BTreeMap<String, DriftedFile>,
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    efi: crate::efi::Efi,
+}
+
+impl Watcher {
+    fn new(record: bool) -> Result<Self> {
+        // SAFETY: inotify_init1() has no memory safety requirements
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error()).context("Creating inotify instance");
+        }
+        Ok(Self {
+            // SAFETY: the file descriptor was just created, and is ours
+            inotify: std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) }),
+            dirs: HashMap::new(),
+            efidir: None,
+            record,
+            drifted: BTreeMap::new(),
+            #[cfg(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            ))]
+            efi: Default::default(),
+        })
+    }
+
+    /// Watch the directories of the managed files, including the new ones
+    /// of an update.
+    #[context("Watching the managed files")]
+    fn watch_dirs(&mut self) -> Result<()> {
+        let mut dirs = BTreeSet::new();
+        if Path::new(GRUB_DIR).is_dir() {
+            dirs.insert(PathBuf::from(GRUB_DIR));
+        }
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        if let Some(tree) = efi_tree()? {
+            let efidir = self.efi.esp_path()?;
+            for name in tree.children.keys() {
+                match Path::new(name).parent() {
+                    Some(p) if !p.as_os_str().is_empty() => dirs.insert(efidir.join(p)),
+                    _ => dirs.insert(efidir.clone()),
+                };
+            }
+            self.efidir = Some(efidir);
+        }
+        for dir in dirs {
+            let cdir = CString::new(dir.as_os_str().as_bytes())?;
+            // SAFETY: the path is a valid C string
+            let wd = unsafe {
+                libc::inotify_add_watch(self.inotify.as_raw_fd(), cdir.as_ptr(), WATCH_MASK)
+            };
+            if wd < 0 {
+                // e.g. removed out of band, which the check reports
+                let e = std::io::Error::last_os_error();
+                log::warn!("Failed to watch {dir:?}: {e}");
+                continue;
+            }
+            self.dirs.insert(wd, dir);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut changed = BTreeSet::new();
+        let mut busy = false;
+        loop {
+            let timeout = if changed.is_empty() {
+                -1
+            } else {
+                QUIET.as_millis() as i32
+            };
+            let mut pfd = libc::pollfd {
+                fd: self.inotify.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: we pass a single valid pollfd
+            let r = unsafe { libc::poll(&mut pfd, 1, timeout) };
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                if e.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e).context("Waiting for inotify events");
+            }
+            if r > 0 {
+                let n = self.inotify.read(&mut buf)?;
+                for (wd, name) in parse_events(&buf[..n]) {
+                    if let Some(dir) = self.dirs.get(&wd) {
+                        changed.insert(dir.join(name));
+                    }
+                }
+                busy |= crate::backend::operation_in_progress()?;
+                continue;
+            }
+            // Quiet, but bootupd may still be at work
+            if crate::backend::operation_in_progress()? {
+                busy = true;
+                continue;
+            }
+            self.check(&changed, busy)?;
+            changed.clear();
+            busy = false;
+            self.watch_dirs()?;
+        }
+    }
+
+    /// Report the modifications of the `changed` files, which bootupd may
+    /// have made if `busy`.
+    fn check(&mut self, changed: &BTreeSet<PathBuf>, busy: bool) -> Result<()> {
+        let mut modified = BTreeSet::new();
+        let mut unmodified = BTreeSet::new();
+        for path in changed.iter().filter(|p| p.starts_with(GRUB_DIR)) {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            // Skip the temporary files of atomic writes
+            if name.starts_with('.') || GRUB_VOLATILE.contains(&name) {
+                continue;
+            }
+            let path = path.to_string_lossy().into_owned();
+            if busy {
+                unmodified.insert(path);
+            } else {
+                modified.insert(path);
+            }
+        }
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        ))]
+        if let Some(efidir) = self.efidir.as_ref() {
+            if changed.iter().any(|p| p.starts_with(efidir)) {
+                let esp_modified: BTreeSet<_> = match efi_tree()? {
+                    Some(tree) => {
+                        let diff = tree.relative_diff_to(&openat::Dir::open(efidir)?)?;
+                        diff.changes
+                            .into_iter()
+                            .chain(diff.removals)
+                            .map(|f| efidir.join(f).to_string_lossy().into_owned())
+                            .collect()
+                    }
+                    None => BTreeSet::new(),
+                };
+                unmodified.extend(
+                    self.drifted
+                        .keys()
+                        .filter(|p| Path::new(p).starts_with(efidir))
+                        .filter(|p| !esp_modified.contains(*p))
+                        .cloned(),
+                );
+                // Files already reported are reported again if modified since
+                modified.extend(
+                    esp_modified.into_iter().filter(|p| {
+                        !self.drifted.contains_key(p) || changed.contains(Path::new(p))
+                    }),
+                );
+            }
+        }
+        let mut updated = false;
+        for path in unmodified {
+            updated |= self.drifted.remove(&path).is_some();
+        }
+        for path in modified {
+            log::warn!("{path} was modified out of band");
+            let time = Utc::now();
+            self.drifted
+                .insert(path.clone(), DriftedFile { path, time });
+            updated = true;
+        }
+        if updated && self.record {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Record the modified files for `bootupctl status`.
+    #[context("Writing {DRIFT_PATH}")]
+    fn save(&self) -> Result<()> {
+        let path = Path::new(DRIFT_PATH);
+        let parent = path.parent().unwrap();
+        std::fs::create_dir_all(parent)?;
+        let drifted: Vec<_> = self.drifted.values().collect();
+        openat::Dir::open(parent)?.write_file_contents(
+            path.file_name().unwrap(),
+            0o644,
+            serde_json::to_vec(&drifted)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// The files tracked for the EFI component, relative to the `EFI` directory
+/// of the ESP.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn efi_tree() -> Result<Option<crate::filetree::FileTree>> {
+    let state = crate::model::SavedState::load_from_disk("/")?;
+    Ok(state
+        .and_then(|mut s| s.installed.remove("EFI"))
+        .and_then(|ic| ic.filetree))
+}
+
+/// Parse the `inotify_event` records read from an inotify instance into
+/// the watch descriptor and file name of each; events of the watched
+/// directories themselves, which have no name, are skipped.
+fn parse_events(buf: &[u8]) -> Vec<(i32, String)> {
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    let mut pos = 0;
+    while let Some(raw) = buf.get(pos..pos + header) {
+        // SAFETY: the buffer holds a complete (but maybe unaligned) record
+        let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(raw.as_ptr().cast()) };
+        let len = event.len as usize;
+        let Some(name) = buf.get(pos + header..pos + header + len) else {
+            break;
+        };
+        // The name is padded with NULs
+        let name = name.split(|&b| b == 0).next().unwrap_or_default();
+        if !name.is_empty() {
+            events.push((event.wd, String::from_utf8_lossy(name).into_owned()));
+        }
+        pos += header + len;
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(wd: i32, name: &str) -> Vec<u8> {
+        let len = name.len().next_multiple_of(16);
+        let event = libc::inotify_event {
+            wd,
+            mask: libc::IN_CLOSE_WRITE,
+            cookie: 0,
+            len: len as u32,
+        };
+        let header = std::mem::size_of::<libc::inotify_event>();
+        // SAFETY: inotify_event is plain old data
+        let mut buf =
+            unsafe { std::slice::from_raw_parts(&event as *const _ as *const u8, header) }.to_vec();
+        buf.extend(name.as_bytes());
+        buf.resize(header + len, 0);
+        buf
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut buf = event(1, "grub.cfg");
+        buf.extend(event(2, ""));
+        buf.extend(event(3, "shimx64.efi"));
+        assert_eq!(
+            parse_events(&buf),
+            [(1, "grub.cfg".to_string()), (3, "shimx64.efi".to_string())]
+        );
+        // A truncated record is ignored
+        buf.truncate(buf.len() - 1);
+        assert_eq!(parse_events(&buf), [(1, "grub.cfg".to_string())]);
+    }
+}
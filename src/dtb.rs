@@ -0,0 +1,481 @@
+//! The `dtb` component: the device tree blobs of the kernel, which many ARM
+//! and RISC-V boards need refreshed in lockstep with U-Boot or GRUB, as
+//! the firmware passes them to the kernel.
+//!
+//! The update payload holds the `dtb` directory of the kernel shipped in
+//! `/usr/lib/modules/<version>`, under `<version>/`.  Depending on the
+//! `location` of the `[dtb]` section of the configuration, the blobs are
+//! installed to `/boot/dtb-<version>`, with a `/boot/dtb` symlink to it, or
+//! to `dtb/` on the ESP, where U-Boot looks for them.  An update to another
+//! kernel version writes the new directory before switching the symlink,
+//! then removes the files of the previous one.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::config::{Config, DtbLocation};
+use crate::efi::{self, Efi};
+use crate::filetree::{self, FileTree, FileTreeDiff};
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::util::CommandRunExt;
+
+/// The directory of the kernels, one sub-directory per version
+const MODULES_DIR: &str = "usr/lib/modules";
+/// The directory of the blobs, in the directory of a kernel and on the ESP
+const DTB_DIR: &str = "dtb";
+/// The symlink to the directory of the installed blobs in `/boot`
+const BOOT_LINK: &str = "dtb";
+
+/// The newest kernel of `root` shipping device tree blobs, along with
+/// their directory.
+fn find_dtbs(root: &Path) -> Result<Option<(String, PathBuf)>> {
+    let modules = root.join(MODULES_DIR);
+    let entries = match std::fs::read_dir(&modules) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("reading {modules:?}")),
+    };
+    let mut r: Option<(String, PathBuf)> = None;
+    for entry in entries {
+        let entry = entry?;
+        let Some(kver) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        let dir = entry.path().join(DTB_DIR);
+        if !dir.is_dir() {
+            continue;
+        }
+        if r.as_ref()
+            .is_some_and(|(v, _)| crate::uki::version_cmp(v, &kver) != Ordering::Less)
+        {
+            continue;
+        }
+        r = Some((kver, dir));
+    }
+    Ok(r)
+}
+
+/// Returns `true` if the target root ships device tree blobs.
+pub(crate) fn is_available(root: &Path) -> bool {
+    find_dtbs(root).is_ok_and(|dtbs| dtbs.is_some())
+}
+
+/// The kernel version of the payload `updated`, its only directory.
+fn payload_version(updated: &openat::Dir) -> Result<String> {
+    let mut versions = Vec::new();
+    for entry in updated.list_dir(".")? {
+        let entry = entry?;
+        if !matches!(updated.get_file_type(&entry)?, openat::SimpleType::Dir) {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str() else {
+            bail!("Invalid UTF-8 filename: {:?}", entry.file_name())
+        };
+        versions.push(name.to_string());
+    }
+    match versions.as_slice() {
+        [kver] => Ok(kver.clone()),
+        _ => bail!(
+            "Expected a single kernel version in the dtb payload, found {}",
+            versions.len()
+        ),
+    }
+}
+
+/// The directory of the blobs of the kernel `kver`, relative to `/boot` or
+/// the ESP.
+fn target_dir_name(location: DtbLocation, kver: &str) -> String {
+    match location {
+        DtbLocation::Boot => format!("{DTB_DIR}-{kver}"),
+        DtbLocation::Esp => DTB_DIR.to_string(),
+    }
+}
+
+/// The directory the `/boot/dtb` symlink of `root` points to, if any.
+fn installed_boot_dir(root: &Path) -> Result<Option<String>> {
+    let link = root.join("boot").join(BOOT_LINK);
+    match std::fs::symlink_metadata(&link) {
+        Ok(m) if !m.is_symlink() => bail!("{link:?} is not a symlink"),
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("inspecting {link:?}")),
+    }
+    let target = std::fs::read_link(&link).with_context(|| format!("reading {link:?}"))?;
+    Ok(target.to_str().map(String::from))
+}
+
+/// Point the `dtb` symlink of `bootdir` to `target`, atomically.
+#[context("Updating /boot/{BOOT_LINK} symlink")]
+fn switch_link(bootdir: &openat::Dir, target: &str) -> Result<()> {
+    let tmp = format!("{BOOT_LINK}.tmp");
+    bootdir.remove_file_optional(tmp.as_str())?;
+    let bootpath = bootdir.recover_path()?;
+    std::os::unix::fs::symlink(target, bootpath.join(&tmp))?;
+    bootdir.local_rename(tmp.as_str(), BOOT_LINK)?;
+    Ok(())
+}
+
+/// Remove the files of `tree` from the directory `name` of `parent`, and
+/// then the directories left empty.
+#[context("Removing {name}")]
+fn remove_tree(parent: &openat::Dir, name: &str, tree: &FileTree) -> Result<()> {
+    let dir = parent.recover_path()?.join(name);
+    let mut dirs = BTreeSet::new();
+    for path in tree.children.keys() {
+        let path = dir.join(path);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("removing {path:?}")),
+        }
+        dirs.extend(
+            path.ancestors()
+                .skip(1)
+                .take_while(|p| p.starts_with(&dir))
+                .map(Path::to_path_buf),
+        );
+    }
+    // Children sort after their parent; directories with other files are
+    // kept
+    for d in dirs.iter().rev() {
+        let _ = std::fs::remove_dir(d);
+    }
+    Ok(())
+}
+
+/// An update of the installed blobs.
+struct DtbUpdate {
+    /// The blobs of the payload
+    srcdir: openat::Dir,
+    tree: FileTree,
+    /// Their directory, relative to `/boot` or the ESP
+    dirname: String,
+    /// The directory of the installed blobs, if they are moved
+    replaced: Option<String>,
+    diff: FileTreeDiff,
+}
+
+#[derive(Default)]
+pub(crate) struct Dtb {
+    esp: Efi,
+}
+
+impl Dtb {
+    /// The partition holding the blobs of `root`, `/boot` or the ESP,
+    /// mounting the latter if needed.
+    fn open_partition(&self, root: &Path, location: DtbLocation) -> Result<openat::Dir> {
+        let path = match location {
+            DtbLocation::Boot => root.join("boot"),
+            DtbLocation::Esp => self.esp.ensure_mounted_esp(root)?,
+        };
+        let dir = openat::Dir::open(&path).with_context(|| format!("opening {path:?}"))?;
+        if location == DtbLocation::Esp {
+            efi::validate_esp(&dir)?;
+        }
+        Ok(dir)
+    }
+
+    /// The directory of the installed blobs on the booted system, if any.
+    fn open_installed_optional(&self, location: DtbLocation) -> Result<Option<openat::Dir>> {
+        let root = Path::new("/");
+        let dirname = match location {
+            DtbLocation::Boot => installed_boot_dir(root)?,
+            DtbLocation::Esp => Some(DTB_DIR.to_string()),
+        };
+        let Some(dirname) = dirname else {
+            return Ok(None);
+        };
+        let partition = self.open_partition(root, location)?;
+        Ok(partition.sub_dir_optional(dirname.as_str())?)
+    }
+
+    /// The changes from the `current` blobs to the ones of the payload.
+    fn plan(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        location: DtbLocation,
+    ) -> Result<DtbUpdate> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed dtb found!"))?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let kver = payload_version(&updated)?;
+        let srcdir = updated.sub_dir(kver.as_str())?;
+        let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+        let tree = FileTree::new_from_dir(&srcdir, digest).context("reading update dir")?;
+        let dirname = target_dir_name(location, &kver);
+        let installed = match location {
+            DtbLocation::Boot => installed_boot_dir(Path::new("/"))?,
+            DtbLocation::Esp => Some(dirname.clone()),
+        };
+        let diff = if installed.as_deref() == Some(dirname.as_str()) {
+            currentf.diff(&tree)?
+        } else {
+            let empty = FileTree {
+                children: BTreeMap::new(),
+            };
+            empty.diff(&tree)?
+        };
+        Ok(DtbUpdate {
+            srcdir,
+            tree,
+            replaced: installed.filter(|d| d != &dirname),
+            dirname,
+            diff,
+        })
+    }
+}
+
+impl Component for Dtb {
+    fn name(&self) -> &'static str {
+        "dtb"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        // Blobs copied by e.g. the kernel package are managed by it
+        Ok(None)
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, _: &ContentMetadata) -> Result<InstalledContent> {
+        bail!("Adopting device tree blobs is not supported")
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+        log::debug!("Found metadata {}", meta.version);
+        let updated = src_root.sub_dir(&component_updatedirname(self))?;
+        let kver = payload_version(&updated)?;
+        let srcdir = updated.sub_dir(kver.as_str())?;
+        let config = Config::load(&src_root.recover_path()?)?;
+        let ft = FileTree::new_from_dir(&srcdir, config.update.digest)?;
+        let location = config.dtb.location;
+        let partition = self.open_partition(Path::new(dest_root), location)?;
+        let dirname = target_dir_name(location, &kver);
+        partition.ensure_dir_all(dirname.as_str(), 0o755)?;
+        let destd = partition.sub_dir(dirname.as_str())?;
+        let empty = FileTree {
+            children: BTreeMap::new(),
+        };
+        let diff = empty.diff(&ft)?;
+        filetree::apply_diff(&srcdir, &destd, &diff, None).context("copying device tree blobs")?;
+        if location == DtbLocation::Boot {
+            switch_link(&partition, &dirname)?;
+        }
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let Some((kver, src)) = find_dtbs(Path::new(sysroot_path))? else {
+            bail!("Failed to find device tree blobs in {sysroot_path}/{MODULES_DIR}");
+        };
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest).with_context(|| format!("creating {dest:?}"))?;
+        Command::new("cp")
+            .args(["-rp", "--reflink=auto"])
+            .arg(&src)
+            .arg(dest.join(&kver))
+            .run()?;
+
+        let meta = packagesystem::query_payload(sysroot_path, [&src], &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        progress: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let location = Config::load(Path::new("/"))?.dtb.location;
+        let update = self.plan(sysroot, current, location)?;
+        let partition = self.open_partition(Path::new("/"), location)?;
+        partition.ensure_dir_all(update.dirname.as_str(), 0o755)?;
+        let destdir = partition.sub_dir(update.dirname.as_str())?;
+        log::trace!("applying diff: {}", &update.diff);
+        let opts = filetree::ApplyUpdateOptions {
+            progress: Some(progress),
+            ..Default::default()
+        };
+        filetree::apply_diff(&update.srcdir, &destdir, &update.diff, Some(&opts))
+            .context("applying filesystem changes")?;
+        if location == DtbLocation::Boot {
+            switch_link(&partition, &update.dirname)?;
+        }
+        if let (Some(old), Some(currentf)) = (update.replaced.as_deref(), &current.filetree) {
+            remove_tree(&partition, old, currentf)?;
+        }
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(update.tree),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        let location = Config::load(Path::new("/"))?.dtb.location;
+        let update = self.plan(sysroot, current, location)?;
+        let mut writes: Vec<_> = update
+            .diff
+            .additions
+            .iter()
+            .chain(update.diff.changes.iter())
+            .map(|f| format!("Write: {}/{f}", update.dirname))
+            .collect();
+        writes.sort();
+        let mut removals: Vec<_> = match update.replaced.as_deref() {
+            Some(old) => vec![format!("Remove: {old}")],
+            None => update
+                .diff
+                .removals
+                .iter()
+                .map(|f| format!("Remove: {}/{f}", update.dirname))
+                .collect(),
+        };
+        removals.sort();
+        writes.extend(removals);
+        Ok(writes)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed dtb found!"))?;
+        let location = Config::load(Path::new("/"))?.dtb.location;
+        let Some(dir) = self.open_installed_optional(location)? else {
+            return Ok(ValidationResult::Skip);
+        };
+        let diff = currentf.relative_diff_to(&dir)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::DigestAlgorithm;
+
+    #[test]
+    fn test_find_dtbs() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(find_dtbs(td.path())?, None);
+        let modules = td.path().join(MODULES_DIR);
+        for kver in ["6.9.0", "6.10.0", "6.11.0"] {
+            std::fs::create_dir_all(modules.join(kver))?;
+        }
+        std::fs::create_dir_all(modules.join("6.9.0/dtb/rockchip"))?;
+        std::fs::create_dir_all(modules.join("6.10.0/dtb/allwinner"))?;
+        // 6.11.0 doesn't ship blobs
+        let (kver, dir) = find_dtbs(td.path())?.unwrap();
+        assert_eq!(kver, "6.10.0");
+        assert_eq!(dir, modules.join("6.10.0/dtb"));
+        assert_eq!(target_dir_name(DtbLocation::Boot, &kver), "dtb-6.10.0");
+        assert_eq!(target_dir_name(DtbLocation::Esp, &kver), "dtb");
+        Ok(())
+    }
+
+    #[test]
+    fn test_switch_and_remove() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boot = td.path().join("boot");
+        std::fs::create_dir_all(boot.join("dtb-6.9.0/rockchip"))?;
+        std::fs::write(boot.join("dtb-6.9.0/rockchip/rk3328-rock64.dtb"), "old")?;
+        std::fs::write(boot.join("dtb-6.9.0/README"), "not ours")?;
+        std::fs::create_dir_all(boot.join("dtb-6.10.0"))?;
+        let bootdir = openat::Dir::open(&boot)?;
+        assert_eq!(installed_boot_dir(td.path())?, None);
+        switch_link(&bootdir, "dtb-6.9.0")?;
+        assert_eq!(installed_boot_dir(td.path())?.as_deref(), Some("dtb-6.9.0"));
+        switch_link(&bootdir, "dtb-6.10.0")?;
+        assert_eq!(
+            installed_boot_dir(td.path())?.as_deref(),
+            Some("dtb-6.10.0")
+        );
+
+        let tree =
+            FileTree::new_from_dir(&bootdir.sub_dir("dtb-6.9.0")?, DigestAlgorithm::default())?;
+        std::fs::remove_file(boot.join("dtb-6.9.0/README"))?;
+        std::fs::create_dir_all(boot.join("dtb-6.9.0/overlays"))?;
+        std::fs::write(boot.join("dtb-6.9.0/overlays/local.dtbo"), "")?;
+        remove_tree(&bootdir, "dtb-6.9.0", &tree)?;
+        assert!(!boot.join("dtb-6.9.0/rockchip").exists());
+        assert!(boot.join("dtb-6.9.0/overlays/local.dtbo").exists());
+        Ok(())
+    }
+}
@@ -4,7 +4,22 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+//! The `EFI` component: shim and GRUB on the ESP.
+//!
+//! When the disks backing `/` and `/boot` have several ESPs, e.g. on RAID1
+//! installs, updates first stage the new content on every ESP, so that a
+//! failure leaves them all at the previous version, then swap it in on one
+//! ESP after the other, so that an interruption leaves at most one of them
+//! partially updated.  An ESP which isn't mounted is mounted in a private
+//! mount namespace for the duration of the operation only.
+//!
+//! Updates never modify `EFI/Microsoft`, nor `EFI/BOOT` unless the payload
+//! ships it, and are refused if they contain binaries that the SBAT policy
+//! of shim revokes, or whose SBAT generations are lower than the installed
+//! ones: once shim raises its level, they wouldn't boot anymore.
+
 use std::cell::RefCell;
+use std::collections::BTreeSet;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -19,9 +34,16 @@ use rustix::fd::BorrowedFd;
 use walkdir::WalkDir;
 use widestring::U16CString;
 
+use crate::config::{Config, EfiConfig, SbatPolicy};
+use crate::efivars;
 use crate::filetree;
+use crate::firmware;
 use crate::model::*;
 use crate::ostreeutil;
+use crate::progress::ProgressFn;
+use crate::quarantine;
+use crate::sbat;
+use crate::tpm;
 use crate::util::{self, CommandRunExt};
 use crate::{component::*, packagesystem};
 
@@ -36,6 +58,41 @@ pub(crate) const SHIM: &str = "shimaa64.efi";
 #[cfg(target_arch = "x86_64")]
 pub(crate) const SHIM: &str = "shimx64.efi";
 
+/// The binary in the vendor directory that the firmware loads first.
+/// There is no shim on riscv64, so GRUB is loaded directly.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub(crate) const VENDOR_LOADER: &str = SHIM;
+#[cfg(target_arch = "riscv64")]
+pub(crate) const VENDOR_LOADER: &str = "grubriscv64.efi";
+
+/// The GRUB binary in the vendor directory
+#[cfg(target_arch = "x86_64")]
+const GRUB_EFI: &str = "grubx64.efi";
+#[cfg(target_arch = "aarch64")]
+const GRUB_EFI: &str = "grubaa64.efi";
+#[cfg(target_arch = "riscv64")]
+const GRUB_EFI: &str = VENDOR_LOADER;
+
+/// The removable media path loader, relative to `EFI/`
+#[cfg(target_arch = "x86_64")]
+pub(crate) const FALLBACK_EFI: &str = "BOOT/BOOTX64.EFI";
+#[cfg(target_arch = "aarch64")]
+pub(crate) const FALLBACK_EFI: &str = "BOOT/BOOTAA64.EFI";
+#[cfg(target_arch = "riscv64")]
+pub(crate) const FALLBACK_EFI: &str = "BOOT/BOOTRISCV64.EFI";
+
+/// The shim fallback loader, which creates boot entries when booted from
+/// the removable media path
+#[cfg(target_arch = "x86_64")]
+const FALLBACK_LOADER: Option<&str> = Some("fbx64.efi");
+#[cfg(target_arch = "aarch64")]
+const FALLBACK_LOADER: Option<&str> = Some("fbaa64.efi");
+#[cfg(target_arch = "riscv64")]
+const FALLBACK_LOADER: Option<&str> = None;
+
+/// Where Debian derivatives ship the shim binaries
+const SHIM_LIBDIR: &str = "usr/lib/shim";
+
 /// The ESP partition label on Fedora CoreOS derivatives
 pub(crate) const COREOS_ESP_PART_LABEL: &str = "EFI-SYSTEM";
 pub(crate) const ANACONDA_ESP_PART_LABEL: &str = "EFI\\x20System\\x20Partition";
@@ -44,6 +101,9 @@ pub(crate) const ANACONDA_ESP_PART_LABEL: &str = "EFI\\x20System\\x20Partition";
 const LOADER_INFO_VAR_STR: &str = "LoaderInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
 const STUB_INFO_VAR_STR: &str = "StubInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
 
+/// The mount point of efivarfs
+pub(crate) const EFIVARS: &str = "/sys/firmware/efi/efivars";
+
 /// Return `true` if the system is booted via EFI
 pub(crate) fn is_efi_booted() -> Result<bool> {
     Path::new("/sys/firmware/efi")
@@ -51,19 +111,34 @@ pub(crate) fn is_efi_booted() -> Result<bool> {
         .map_err(Into::into)
 }
 
+/// Return `true` if EFI variables can be written.  Firmware such as U-Boot
+/// may not implement SetVariable() at runtime, in which case the kernel
+/// mounts efivarfs read-only.
+pub(crate) fn efivars_writable() -> Result<bool> {
+    let efivars = Path::new(EFIVARS);
+    if !efivars.try_exists()? {
+        return Ok(false);
+    }
+    let stat = rustix::fs::statvfs(efivars)?;
+    Ok(!stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY))
+}
+
 #[derive(Default)]
 pub(crate) struct Efi {
     mountpoint: RefCell<Option<PathBuf>>,
+    /// The mount of the system remounted read-write, to remount read-only
+    /// on drop
+    remount_ro: RefCell<Option<PathBuf>>,
 }
 
 impl Efi {
-    fn esp_path(&self) -> Result<PathBuf> {
+    pub(crate) fn esp_path(&self) -> Result<PathBuf> {
         self.ensure_mounted_esp(Path::new("/"))
             .map(|v| v.join("EFI"))
     }
 
-    fn open_esp_optional(&self) -> Result<Option<openat::Dir>> {
-        if !is_efi_booted()? && self.get_esp_device().is_none() {
+    pub(crate) fn open_esp_optional(&self) -> Result<Option<openat::Dir>> {
+        if !is_efi_booted()? && self.get_esp_device(Path::new("/")).is_none() {
             log::debug!("Skip EFI");
             return Ok(None);
         }
@@ -72,14 +147,26 @@ impl Efi {
         Ok(esp)
     }
 
-    fn open_esp(&self) -> Result<openat::Dir> {
+    pub(crate) fn open_esp(&self) -> Result<openat::Dir> {
         self.ensure_mounted_esp(Path::new("/"))?;
         let sysroot = openat::Dir::open("/")?;
         let esp = sysroot.sub_dir(&self.esp_path()?)?;
         Ok(esp)
     }
 
-    fn get_esp_device(&self) -> Option<PathBuf> {
+    /// Find the ESP by GPT partition type on the disks backing the root
+    /// and `/boot`, falling back to the well-known partition labels.
+    fn get_esp_device(&self, root: &Path) -> Option<PathBuf> {
+        match crate::blockdev::find_esps(root) {
+            Ok(esps) if !esps.is_empty() => {
+                if esps.len() > 1 {
+                    log::debug!("Found multiple ESPs, using {}", esps[0]);
+                }
+                return esps.into_iter().next().map(PathBuf::from);
+            }
+            Ok(_) => log::debug!("No ESP found by partition type"),
+            Err(e) => log::debug!("{e:#}"),
+        }
         let esp_devices = [COREOS_ESP_PART_LABEL, ANACONDA_ESP_PART_LABEL]
             .into_iter()
             .map(|p| Path::new("/dev/disk/by-partlabel/").join(p));
@@ -108,23 +195,28 @@ impl Efi {
             if st.f_type != libc::MSDOS_SUPER_MAGIC {
                 continue;
             }
-            util::ensure_writable_mount(&mnt)?;
+            self.make_writable(root, &mnt)?;
             log::debug!("Reusing existing {mnt:?}");
             return Ok(mnt);
         }
 
         let esp_device = self
-            .get_esp_device()
+            .get_esp_device(root)
             .ok_or_else(|| anyhow::anyhow!("Failed to find ESP device"))?;
+        // The ESP may already be mounted at an unusual location
+        if let Some(mnt) = esp_mountpoint(&esp_device)? {
+            if mnt.starts_with(root) {
+                self.make_writable(root, &mnt)?;
+                log::debug!("Reusing existing {mnt:?}");
+                return Ok(mnt);
+            }
+        }
         for &mnt in ESP_MOUNTS.iter() {
             let mnt = root.join(mnt);
             if !mnt.exists() {
                 continue;
             }
-            std::process::Command::new("mount")
-                .arg(&esp_device)
-                .arg(&mnt)
-                .run()
+            mount_esp(&esp_device, &mnt)
                 .with_context(|| format!("Failed to mount {:?}", esp_device))?;
             log::debug!("Mounted at {mnt:?}");
             *mountpoint = Some(mnt);
@@ -133,7 +225,21 @@ impl Efi {
         Ok(mountpoint.as_deref().unwrap().to_owned())
     }
 
+    /// Remount the existing ESP mount `mnt` read-write, to be remounted
+    /// read-only on drop if the configuration of `root` asks so.
+    fn make_writable(&self, root: &Path, mnt: &Path) -> Result<()> {
+        util::ensure_writable_mount(mnt)?;
+        if Config::load(root)?.efi.read_only {
+            *self.remount_ro.borrow_mut() = Some(mnt.to_owned());
+        }
+        Ok(())
+    }
+
     fn unmount(&self) -> Result<()> {
+        if let Some(mount) = self.remount_ro.borrow_mut().take() {
+            util::remount_read_only(&mount)?;
+            log::debug!("Remounted {mount:?} read-only");
+        }
         if let Some(mount) = self.mountpoint.borrow_mut().take() {
             Command::new("umount")
                 .arg(&mount)
@@ -144,11 +250,17 @@ impl Efi {
         Ok(())
     }
 
+    /// Create the boot entry of the vendor loader; returns `false` if it
+    /// must be deferred, as the EFI variables are not writable.
     #[context("Updating EFI firmware variables")]
-    fn update_firmware(&self, device: &str, espdir: &openat::Dir, vendordir: &str) -> Result<()> {
+    fn update_firmware(&self, device: &str, espdir: &openat::Dir, vendordir: &str) -> Result<bool> {
         if !is_efi_booted()? {
             log::debug!("Not booted via EFI, skipping firmware update");
-            return Ok(());
+            return Ok(true);
+        }
+        if !efivars_writable()? {
+            println!("EFI variables are not writable, relying on {FALLBACK_EFI} to boot");
+            return Ok(false);
         }
         let sysroot = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
         let product_name = get_product_name(&sysroot)?;
@@ -156,7 +268,523 @@ impl Efi {
         assert!(product_name.len() > 0);
         // clear all the boot entries that match the target name
         clear_efi_target(&product_name)?;
-        create_efi_boot_entry(device, espdir, vendordir, &product_name)
+        create_efi_boot_entry(device, espdir, vendordir, &product_name)?;
+        Ok(true)
+    }
+
+    /// Inspect the firmware boot entries relative to the entry for the vendor
+    /// loader; returns `None` if not booted via EFI.
+    #[context("Inspecting EFI boot entries")]
+    pub(crate) fn boot_status(&self, sysroot: &openat::Dir) -> Result<Option<EfiBootStatus>> {
+        if !is_efi_booted()? || !Path::new(EFIVARS).try_exists()? {
+            return Ok(None);
+        }
+        let Some(vendordir) = self.get_efi_vendor(sysroot)? else {
+            return Ok(None);
+        };
+        let esp = self.ensure_mounted_esp(Path::new("/"))?;
+        let espdir = openat::Dir::open(&esp)?;
+        let device = crate::filesystem::inspect_filesystem(&espdir, ".")?.source;
+        let hd = efivars::HardDrive::for_partition(&device)?;
+        let loader = format!("\\EFI\\{vendordir}\\{VENDOR_LOADER}");
+        let mut r = EfiBootStatus {
+            boot_current: efivars::read_u16_var("BootCurrent")?,
+            boot_order: efivars::boot_order()?,
+            ..Default::default()
+        };
+        for (num, option) in efivars::boot_entries()? {
+            if option.boots(&hd, &loader) {
+                r.entry = r.entry.or(Some(num));
+                continue;
+            }
+            if option.hard_drive().map(|h| h.guid) != Some(hd.guid) {
+                continue;
+            }
+            let Some(path) = option.file_path() else {
+                continue;
+            };
+            // vfat lookups are case-insensitive
+            let path = path.trim_start_matches('\\').replace('\\', "/");
+            if !esp.join(path).exists() {
+                r.dangling.push(num);
+            }
+        }
+        Ok(Some(r))
+    }
+
+    /// Ensure the boot entry for the vendor loader exists and is first in
+    /// `BootOrder`, and remove the entries on our ESP which reference files
+    /// that do not exist.
+    #[context("Fixing EFI boot order")]
+    pub(crate) fn fix_boot_order(&self, sysroot: &openat::Dir) -> Result<()> {
+        if !efivars_writable()? {
+            bail!("EFI variables are not writable");
+        }
+        let Some(mut status) = self.boot_status(sysroot)? else {
+            bail!("Not booted via EFI");
+        };
+        if status.entry.is_none() {
+            let espdir = self.open_esp()?;
+            self.ensure_boot_entry(sysroot, &espdir)?;
+            status = self.boot_status(sysroot)?.expect("EFI booted");
+        }
+        let entry = status
+            .entry
+            .ok_or_else(|| anyhow::anyhow!("Failed to create EFI boot entry"))?;
+        for &num in status.dangling.iter() {
+            efivars::delete_var(&efivars::boot_var_name(num))?;
+            println!("Removed dangling {}", efivars::boot_var_name(num));
+        }
+        let mut order: Vec<u16> = status
+            .boot_order
+            .iter()
+            .copied()
+            .filter(|n| *n != entry && !status.dangling.contains(n))
+            .collect();
+        order.insert(0, entry);
+        if order != status.boot_order {
+            efivars::set_boot_order(&order)?;
+            println!("Moved {} first in BootOrder", efivars::boot_var_name(entry));
+        } else {
+            println!("BootOrder is correct");
+        }
+        Ok(())
+    }
+
+    /// The device of the ESP of `root` we update first.
+    fn primary_esp_device(&self, root: &Path) -> Result<PathBuf> {
+        let esp = openat::Dir::open(&self.ensure_mounted_esp(root)?)?;
+        let source = crate::filesystem::inspect_filesystem(&esp, ".")?.source;
+        Path::new(&source)
+            .canonicalize()
+            .with_context(|| format!("canonicalizing {source}"))
+    }
+
+    /// Mount the ESPs of `root` other than the primary one, e.g. on the
+    /// other disks of a RAID1 setup, which are kept in sync with it.
+    #[context("Finding mirror ESPs")]
+    fn mirror_esps(&self, root: &Path) -> Result<Vec<MirrorEsp>> {
+        let esps = match crate::blockdev::find_esps(root) {
+            Ok(esps) if esps.len() > 1 => esps,
+            Ok(_) => return Ok(Vec::new()),
+            Err(e) => {
+                log::debug!("{e:#}");
+                return Ok(Vec::new());
+            }
+        };
+        let primary = self.primary_esp_device(root)?;
+        let read_only = Config::load(root)?.efi.read_only;
+        esps.iter()
+            .filter(|d| Path::new(d).canonicalize().ok().as_ref() != Some(&primary))
+            .map(|d| MirrorEsp::open(d, read_only))
+            .collect()
+    }
+
+    /// Apply `diff` to the primary ESP and the diff computed by `mirror_diff`
+    /// to each of the `mirrors`, all or nothing; returns the devices which
+    /// were updated, if there are mirrors.
+    fn apply_mirrored(
+        &self,
+        updated: &openat::Dir,
+        destdir: &openat::Dir,
+        diff: &filetree::FileTreeDiff,
+        mirrors: &[MirrorEsp],
+        progress: Option<ProgressFn>,
+        mirror_diff: impl Fn(&openat::Dir) -> Result<filetree::FileTreeDiff>,
+    ) -> Result<Vec<String>> {
+        let dirs = mirrors
+            .iter()
+            .map(|m| m.efidir())
+            .collect::<Result<Vec<_>>>()?;
+        let diffs = dirs.iter().map(&mirror_diff).collect::<Result<Vec<_>>>()?;
+        let mut targets = vec![(destdir, diff)];
+        for (dir, diff) in dirs.iter().zip(diffs.iter()) {
+            validate_esp(dir)?;
+            targets.push((dir, diff));
+        }
+        let opts = filetree::ApplyUpdateOptions {
+            progress,
+            ..Default::default()
+        };
+        filetree::apply_diffs(updated, &targets, Some(&opts))
+            .context("applying filesystem changes")?;
+        if mirrors.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut devices = vec![self
+            .primary_esp_device(Path::new("/"))?
+            .to_string_lossy()
+            .into_owned()];
+        devices.extend(mirrors.iter().map(|m| m.device.clone()));
+        for device in devices.iter().skip(1) {
+            println!("Updated mirror ESP {device}");
+        }
+        Ok(devices)
+    }
+
+    /// The mountpoints of the primary ESP and of the `mirrors`, with the
+    /// devices of the latter.
+    fn esp_mountpoints(&self, mirrors: &[MirrorEsp]) -> Result<Vec<(Option<String>, PathBuf)>> {
+        let mut r = vec![(None, self.ensure_mounted_esp(Path::new("/"))?)];
+        r.extend(
+            mirrors
+                .iter()
+                .map(|m| (Some(m.device.clone()), m.mountpoint.clone())),
+        );
+        Ok(r)
+    }
+
+    /// Remove the extraneous files reported by `validate` from all the
+    /// ESPs, if `confirm` accepts them; returns the removed files.  They
+    /// are moved to a quarantine unless `efi.quarantine-days` is 0, and
+    /// the expired quarantines are purged.
+    #[context("Pruning extraneous files")]
+    pub(crate) fn prune(
+        &self,
+        current: &InstalledContent,
+        confirm: &dyn Fn(&[String]) -> Result<bool>,
+    ) -> Result<Vec<String>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let config = Config::load(Path::new("/"))?;
+        let protected = ProtectedPaths::new(&config.efi, &[currentf]);
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let mut targets = Vec::new();
+        for (device, esp) in self.esp_mountpoints(&mirrors)? {
+            let efidir = esp.join("EFI");
+            if !efidir.is_dir() {
+                continue;
+            }
+            let files = extraneous_files(currentf, &efidir)?;
+            protected.check_paths(files.iter())?;
+            targets.push((device, esp, files));
+        }
+        let names: Vec<String> = targets
+            .iter()
+            .flat_map(|(device, _, files)| {
+                files.iter().map(move |f| match device {
+                    Some(device) => format!("{device}:{f}"),
+                    None => f.clone(),
+                })
+            })
+            .collect();
+        if names.is_empty() || !confirm(&names)? {
+            return Ok(Vec::new());
+        }
+        let Some(days) = config.efi.quarantine_days() else {
+            for (_, esp, files) in targets.iter() {
+                for f in files {
+                    let path = esp.join("EFI").join(f);
+                    std::fs::remove_file(&path).with_context(|| format!("removing {path:?}"))?;
+                }
+            }
+            return Ok(names);
+        };
+        let now = chrono::Utc::now();
+        let id = quarantine::new_id(now);
+        for (device, esp, files) in targets.iter() {
+            if !files.is_empty() {
+                quarantine::quarantine(esp, &id, files)?;
+            }
+            quarantine::purge(esp, device.as_deref(), &|q| {
+                quarantine::is_expired(q, days, now)
+            })?;
+        }
+        println!(
+            "Moved to {}/{id} for {days} days; undo with `bootupctl quarantine restore {id}`",
+            quarantine::QUARANTINE_DIR
+        );
+        Ok(names)
+    }
+
+    /// The quarantines of all the ESPs.
+    pub(crate) fn quarantines(&self) -> Result<Vec<quarantine::Quarantine>> {
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let mut r = Vec::new();
+        for (device, esp) in self.esp_mountpoints(&mirrors)? {
+            r.extend(quarantine::list(&esp, device.as_deref())?);
+        }
+        Ok(r)
+    }
+
+    /// Move the files of the quarantine `id` back on all the ESPs which
+    /// have it; returns them, prefixed with the device for the mirrors.
+    pub(crate) fn restore_quarantine(&self, id: &str) -> Result<Vec<String>> {
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let mut r = Vec::new();
+        for (device, esp) in self.esp_mountpoints(&mirrors)? {
+            if !quarantine::list(&esp, None)?.iter().any(|q| q.id == id) {
+                continue;
+            }
+            for f in quarantine::restore(&esp, id)? {
+                match device.as_deref() {
+                    Some(device) => r.push(format!("{device}:{f}")),
+                    None => r.push(f),
+                }
+            }
+        }
+        if r.is_empty() {
+            bail!("No quarantine {id}");
+        }
+        Ok(r)
+    }
+
+    /// Remove the quarantines selected by `select` from all the ESPs;
+    /// returns them.
+    pub(crate) fn purge_quarantines(
+        &self,
+        select: &dyn Fn(&quarantine::Quarantine) -> bool,
+    ) -> Result<Vec<quarantine::Quarantine>> {
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let mut r = Vec::new();
+        for (device, esp) in self.esp_mountpoints(&mirrors)? {
+            r.extend(quarantine::purge(&esp, device.as_deref(), select)?);
+        }
+        Ok(r)
+    }
+
+    /// End a Secure Boot key rotation: remove the previous boot chain kept
+    /// since it started from all the ESPs.
+    #[context("Finalizing key rotation")]
+    pub(crate) fn finalize_rotation(&self, rotation: &KeyRotation) -> Result<()> {
+        let destdir = self.open_esp()?;
+        validate_esp(&destdir)?;
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let mut dirs = vec![destdir];
+        for mirror in mirrors.iter() {
+            dirs.extend(mirror.efidir_optional()?);
+        }
+        for dir in dirs.iter() {
+            dir.remove_all(rotation.dir.as_str())?;
+            filetree::syncfs(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the content backed up by the last update, for when it breaks
+    /// boot on specific firmware; returns the restored content.
+    #[context("Rolling back EFI")]
+    pub(crate) fn rollback(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let Some((previous, content)) = load_backup(sysroot, self)? else {
+            bail!("No previous version of {} to roll back to", self.name());
+        };
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let previousf = previous
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree in the EFI backup"))?;
+        let diff = currentf.diff(previousf)?;
+        ProtectedPaths::load(&[currentf, previousf])?.check(previousf, &diff)?;
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let destdir = self.open_esp().context("opening EFI dir")?;
+        validate_esp(&destdir)?;
+        log::trace!("applying rollback diff: {}", &diff);
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let esps = self.apply_mirrored(&content, &destdir, &diff, &mirrors, None, |dir| {
+            mirror_diff(previousf, dir, diff.removals.clone())
+        })?;
+        remove_backup(sysroot, self)?;
+        Ok(InstalledContent {
+            esps: esps_state(esps, &previous.meta),
+            pcr4: predict_pcr4(previousf, &destdir.recover_path()?),
+            rotation: current.rotation.clone(),
+            pending_nvram: current.pending_nvram.clone(),
+            ..previous
+        })
+    }
+
+    /// Restore the ESP content archived by `bootupctl backup`: `content`
+    /// holds the files and `saved` their metadata, while `current` is what
+    /// is installed now, if anything; returns the restored content.
+    #[context("Restoring EFI")]
+    pub(crate) fn restore(
+        &self,
+        content: &openat::Dir,
+        saved: &InstalledContent,
+        current: Option<&InstalledContent>,
+    ) -> Result<InstalledContent> {
+        // Files missing at backup time were not archived, so what is
+        // restored is what the archive holds.
+        let digest = Config::load(Path::new("/"))?.update.digest;
+        let restoredf = filetree::FileTree::new_from_dir(content, digest)?;
+        let removals = match current.and_then(|c| c.filetree.as_ref()) {
+            Some(currentf) => currentf.diff(&restoredf)?.removals,
+            None => Default::default(),
+        };
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let destdir = self.open_esp().context("opening EFI dir")?;
+        validate_esp(&destdir)?;
+        let diff = mirror_diff(&restoredf, &destdir, removals.clone())?;
+        let mut trees = vec![&restoredf];
+        trees.extend(current.and_then(|c| c.filetree.as_ref()));
+        ProtectedPaths::load(&trees)?.check(&restoredf, &diff)?;
+        log::trace!("applying restore diff: {}", &diff);
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let esps = self.apply_mirrored(content, &destdir, &diff, &mirrors, None, |dir| {
+            mirror_diff(&restoredf, dir, removals.clone())
+        })?;
+        Ok(InstalledContent {
+            pcr4: predict_pcr4(&restoredf, &destdir.recover_path()?),
+            filetree: Some(restoredf),
+            esps: esps_state(esps, &saved.meta),
+            rotation: current.and_then(|c| c.rotation.clone()),
+            pending_nvram: current.and_then(|c| c.pending_nvram.clone()),
+            ..saved.clone()
+        })
+    }
+
+    /// Report the SBAT generations of the installed and updated binaries,
+    /// and the ones revoked by the current policy.
+    #[context("Reading SBAT metadata")]
+    pub(crate) fn sbat_status(&self, sysroot: &openat::Dir) -> Result<SbatStatus> {
+        let level = if is_efi_booted()? {
+            sbat::read_level()?
+        } else {
+            None
+        };
+        let installed = sbat::scan(&self.esp_path()?)?;
+        let updatedir = sysroot.recover_path()?.join(component_updatedirname(self));
+        let update = if updatedir.exists() {
+            sbat::scan(&updatedir)?
+        } else {
+            Default::default()
+        };
+        let revoked = level
+            .as_ref()
+            .map(|level| sbat_revocations(&update, level))
+            .unwrap_or_default();
+        Ok(SbatStatus {
+            level,
+            installed,
+            update,
+            revoked,
+        })
+    }
+
+    /// Report whether Secure Boot is enabled, and check that the installed
+    /// boot chain is consistent; returns `None` if not booted via EFI.
+    #[context("Inspecting Secure Boot state")]
+    pub(crate) fn secure_boot_status(
+        &self,
+        sysroot: &openat::Dir,
+    ) -> Result<Option<SecureBootStatus>> {
+        if !is_efi_booted()? {
+            return Ok(None);
+        }
+        let mut r = SecureBootStatus {
+            enabled: read_efi_var_bool("SecureBoot")?,
+            setup_mode: read_efi_var_bool("SetupMode")?,
+            ..Default::default()
+        };
+        let esp = self.esp_path()?;
+        if let Some(vendordir) = self.get_efi_vendor(sysroot)? {
+            let loader = format!("{vendordir}/{VENDOR_LOADER}");
+            match std::fs::read(esp.join(&loader)) {
+                Ok(data) => r.shim_version = shim_version(&data),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    r.problems.push(format!("Missing {loader}"));
+                }
+                Err(e) => return Err(e).with_context(|| format!("reading {loader}")),
+            }
+            if let (Some(version), Ok(data)) =
+                (&r.shim_version, std::fs::read(esp.join(FALLBACK_EFI)))
+            {
+                if let Some(fallback) = shim_version(&data).filter(|v| v != version) {
+                    r.problems.push(format!(
+                        "{FALLBACK_EFI} is shim {fallback}, {loader} is shim {version}"
+                    ));
+                }
+            }
+        }
+        if let Some(level) = sbat::read_level()? {
+            for revoked in sbat_revocations(&sbat::scan(&esp)?, &level) {
+                r.problems.push(format!("Revoked by SBAT level: {revoked}"));
+            }
+        }
+        Ok(Some(r))
+    }
+
+    /// Refuse, or warn about, an update containing binaries that shim would
+    /// refuse to load according to `SbatLevel`.
+    #[context("Checking SBAT revocations")]
+    fn check_sbat(&self, updated: &openat::Dir) -> Result<()> {
+        if !is_efi_booted()? {
+            return Ok(());
+        }
+        let Some(level) = sbat::read_level()? else {
+            log::debug!("No SBAT policy set");
+            return Ok(());
+        };
+        let revoked = sbat_revocations(&sbat::scan(&updated.recover_path()?)?, &level);
+        if revoked.is_empty() {
+            return Ok(());
+        }
+        let msg = format!(
+            "Update contains binaries revoked by SbatLevel: {}",
+            revoked.join(", ")
+        );
+        match Config::load(Path::new("/"))?.efi.sbat {
+            SbatPolicy::Enforce => bail!("{msg}"),
+            SbatPolicy::Warn => log::warn!("{msg}"),
+        }
+        Ok(())
+    }
+
+    /// Refuse, or warn about, an update lowering the SBAT generation of a
+    /// component of the `current` binaries to `update`: even if shim still
+    /// loads it, it would stop booting once `SbatLevel` is raised past it.
+    #[context("Checking SBAT generations")]
+    fn check_sbat_rollback(
+        &self,
+        update: &sbat::Generations,
+        current: &InstalledContent,
+    ) -> Result<()> {
+        let Some(installed) = current.sbat.as_ref() else {
+            return Ok(());
+        };
+        let lowered = sbat::revoked(update, installed);
+        if lowered.is_empty() {
+            return Ok(());
+        }
+        let msg = format!(
+            "Update lowers the SBAT generations of the installed binaries: {}",
+            lowered.join(", ")
+        );
+        match Config::load(Path::new("/"))?.efi.sbat_rollback {
+            SbatPolicy::Enforce => bail!("{msg}"),
+            SbatPolicy::Warn => log::warn!("{msg}"),
+        }
+        Ok(())
+    }
+
+    /// Recreate the boot entry for the vendor loader if it was lost, e.g.
+    /// after a firmware reset.
+    #[context("Ensuring EFI boot entry")]
+    fn ensure_boot_entry(&self, sysroot: &openat::Dir, espdir: &openat::Dir) -> Result<()> {
+        if !is_efi_booted()? || !efivars_writable()? {
+            log::debug!("EFI variables are not writable, skipping boot entry check");
+            return Ok(());
+        }
+        let Some(vendordir) = self.get_efi_vendor(sysroot)? else {
+            return Ok(());
+        };
+        let device = crate::filesystem::inspect_filesystem(espdir, ".")?.source;
+        let root = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+        let product_name = get_product_name(&root)?;
+        let loader = format!("\\EFI\\{vendordir}\\{VENDOR_LOADER}");
+        if let Some(num) = efivars::ensure_boot_entry(&device, &loader, product_name.trim())? {
+            println!("Created EFI boot entry {}", efivars::boot_var_name(num));
+        }
+        Ok(())
     }
 }
 
@@ -186,7 +814,7 @@ fn string_from_utf16_bytes(slice: &[u8]) -> String {
 
 /// Read a nul-terminated UTF-16 string from an EFI variable.
 fn read_efi_var_utf16_string(name: &str) -> Option<String> {
-    let efivars = Path::new("/sys/firmware/efi/efivars");
+    let efivars = Path::new(EFIVARS);
     if !efivars.exists() {
         log::trace!("No efivars mount at {:?}", efivars);
         return None;
@@ -243,8 +871,7 @@ impl Component for Efi {
     }
 
     fn query_adopt(&self) -> Result<Option<Adoptable>> {
-        let esp = self.open_esp_optional()?;
-        if esp.is_none() {
+        let Some(esp) = self.open_esp_optional()? else {
             log::trace!("No ESP detected");
             return Ok(None);
         };
@@ -254,7 +881,32 @@ impl Component for Efi {
         if skip_systemd_bootloaders() {
             return Ok(None);
         }
-        crate::component::query_adopt_state()
+        let Some(mut adoptable) = crate::component::query_adopt_state()? else {
+            return Ok(None);
+        };
+        // Compare the ESP with the update payload, if there is one
+        let sysroot = openat::Dir::open("/")?;
+        let vendor = match self.get_efi_vendor(&sysroot) {
+            Ok(Some(vendor)) => vendor,
+            Ok(None) => return Ok(Some(adoptable)),
+            Err(e) => {
+                log::debug!("Not fingerprinting the ESP: {e:#}");
+                return Ok(Some(adoptable));
+            }
+        };
+        let updated = sysroot.sub_dir(&component_updatedirname(self))?;
+        let found = EspFingerprint::new(&esp.recover_path()?, &vendor)?;
+        let expected = EspFingerprint::new(&updated.recover_path()?, &vendor)?;
+        if let Some(reason) = found.foreign(&expected, &vendor) {
+            log::info!("Not adopting the ESP, which looks foreign: {reason}");
+            return Ok(None);
+        }
+        if adoptable.version.version == UNKNOWN_VERSION {
+            if let Some(version) = found.version {
+                adoptable.version.version = version;
+            }
+        }
+        Ok(Some(adoptable))
     }
 
     /// Given an adoptable system and an update, perform the update.
@@ -272,15 +924,36 @@ impl Component for Efi {
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+        let updatef =
+            filetree::FileTree::new_from_dir(&updated, digest).context("reading update dir")?;
+        self.check_sbat(&updated)?;
+        let generations = sbat::highest(&sbat::scan(&updated.recover_path()?)?);
+        let authenticode = record_authenticode(&updatef, &updated.recover_path()?);
         // For adoption, we should only touch files that we know about.
         let diff = updatef.relative_diff_to(&esp)?;
+        ProtectedPaths::load(&[&updatef])?.check(&updatef, &diff)?;
         log::trace!("applying adoption diff: {}", &diff);
-        filetree::apply_diff(&updated, &esp, &diff, None).context("applying filesystem changes")?;
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        let esps = self.apply_mirrored(&updated, &esp, &diff, &mirrors, None, |dir| {
+            mirror_diff(&updatef, dir, Default::default())
+        })?;
+        let pcr4 = predict_pcr4(&updatef, &esp.recover_path()?);
         Ok(InstalledContent {
             meta: updatemeta.clone(),
             filetree: Some(updatef),
             adopted_from: Some(meta.version),
+            raw_checksums: None,
+            esps: esps_state(esps, updatemeta),
+            bios_modules: None,
+            pcr4,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: Some(generations),
+            authenticode,
         })
     }
 
@@ -289,15 +962,20 @@ impl Component for Efi {
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
-        update_firmware: bool,
+        devices: &[String],
+        opts: &InstallComponentOptions,
     ) -> Result<InstalledContent> {
         let Some(meta) = get_component_update(src_root, self)? else {
             anyhow::bail!("No update metadata for component {} found", self.name());
         };
         log::debug!("Found metadata {}", meta.version);
         let srcdir_name = component_updatedirname(self);
-        let ft = crate::filetree::FileTree::new_from_dir(&src_root.sub_dir(&srcdir_name)?)?;
+        let config = Config::load(&src_root.recover_path()?)?;
+        let ft = crate::filetree::FileTree::new_from_dir(
+            &src_root.sub_dir(&srcdir_name)?,
+            config.update.digest,
+        )?;
+        ProtectedPaths::new(&config.efi, &[&ft]).check_paths(ft.children.keys())?;
         let destdir = &self.ensure_mounted_esp(Path::new(dest_root))?;
 
         let destd = &openat::Dir::open(destdir)
@@ -312,15 +990,47 @@ impl Component for Efi {
             .arg(destdir)
             .current_dir(format!("/proc/self/fd/{}", src_root.as_raw_fd()))
             .run()?;
-        if update_firmware {
+        let mut pending_nvram = BTreeSet::new();
+        if opts.update_firmware {
             if let Some(vendordir) = self.get_efi_vendor(&src_root)? {
-                self.update_firmware(device, destd, &vendordir)?
+                // The boot entry points to the ESP of the first disk
+                let device = devices.first().map_or("", String::as_str);
+                if !self.update_firmware(device, destd, &vendordir)? {
+                    pending_nvram.insert(NvramOperation::BootEntry);
+                }
             }
         }
+        let firmware = if opts.update_firmware {
+            stage_firmware_updates(src_root, &destdir.join("EFI"))?
+        } else {
+            None
+        };
+        let capsules = firmware
+            .iter()
+            .flatten()
+            .any(|u| u.method == FirmwareUpdateMethod::Capsule && u.error.is_none());
+        if capsules && !efivars_writable()? {
+            pending_nvram.insert(NvramOperation::CapsuleDelivery);
+        }
+        let pcr4 = predict_pcr4(&ft, &destdir.join("EFI"));
+        let srcdir = src_root.recover_path()?.join(&srcdir_name);
+        let generations = sbat::highest(&sbat::scan(&srcdir)?);
+        let authenticode = record_authenticode(&ft, &srcdir);
         Ok(InstalledContent {
             meta,
             filetree: Some(ft),
             adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4,
+            firmware,
+            dbx: None,
+            rotation: None,
+            pending_nvram: (!pending_nvram.is_empty()).then_some(pending_nvram),
+            rebuilt: false,
+            sbat: Some(generations),
+            authenticode,
         })
     }
 
@@ -328,6 +1038,7 @@ impl Component for Efi {
         &self,
         sysroot: &openat::Dir,
         current: &InstalledContent,
+        progress: ProgressFn,
     ) -> Result<InstalledContent> {
         let currentf = current
             .filetree
@@ -337,23 +1048,79 @@ impl Component for Efi {
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let config = Config::load(Path::new("/"))?;
+        let updatef = filetree::FileTree::new_from_dir(&updated, config.update.digest)
+            .context("reading update dir")?;
         let diff = currentf.diff(&updatef)?;
+        ProtectedPaths::load(&[currentf, &updatef])?.check(&updatef, &diff)?;
+        self.check_sbat(&updated)?;
+        let generations = sbat::highest(&sbat::scan(&updated.recover_path()?)?);
+        self.check_sbat_rollback(&generations, current)?;
+        let authenticode = record_authenticode(&updatef, &updated.recover_path()?);
         self.ensure_mounted_esp(Path::new("/"))?;
         let destdir = self.open_esp().context("opening EFI dir")?;
         validate_esp(&destdir)?;
+        backup_filetree(sysroot, self, current, &destdir)?;
         log::trace!("applying diff: {}", &diff);
-        filetree::apply_diff(&updated, &destdir, &diff, None)
-            .context("applying filesystem changes")?;
+        let mirrors = self.mirror_esps(Path::new("/"))?;
+        // The boot chain from before the rotation is kept until finalized
+        let rotation = match current.rotation.as_ref() {
+            Some(r) => Some(r.clone()),
+            None if config.efi.key_rotation => keep_previous(current, &destdir, &mirrors)?,
+            None => None,
+        };
+        let esps =
+            self.apply_mirrored(&updated, &destdir, &diff, &mirrors, Some(progress), |dir| {
+                mirror_diff(&updatef, dir, diff.removals.clone())
+            })?;
+        let mut pending_nvram = current.pending_nvram.clone().unwrap_or_default();
+        if config.efi.ensure_boot_entry {
+            if is_efi_booted()? && !efivars_writable()? {
+                pending_nvram.insert(NvramOperation::BootEntry);
+            } else {
+                self.ensure_boot_entry(sysroot, &destdir)?;
+            }
+        }
+        if config.efi.prune_boot_entries && is_efi_booted()? && efivars_writable()? {
+            for entry in prune_boot_entries(false)? {
+                println!("Removed stale boot entry {entry}");
+            }
+        }
+        if let Some(days) = config.efi.quarantine_days() {
+            let now = chrono::Utc::now();
+            for (device, esp) in self.esp_mountpoints(&mirrors)? {
+                let expired = |q: &quarantine::Quarantine| quarantine::is_expired(q, days, now);
+                if let Err(e) = quarantine::purge(&esp, device.as_deref(), &expired) {
+                    log::warn!("{e:#}");
+                }
+            }
+        }
         let adopted_from = None;
+        let esps = esps_state(esps, &updatemeta);
+        let pcr4 = predict_pcr4(&updatef, &destdir.recover_path()?);
         Ok(InstalledContent {
             meta: updatemeta,
             filetree: Some(updatef),
             adopted_from,
+            raw_checksums: None,
+            esps,
+            bios_modules: None,
+            pcr4,
+            firmware: None,
+            dbx: None,
+            rotation,
+            pending_nvram: (!pending_nvram.is_empty()).then_some(pending_nvram),
+            rebuilt: false,
+            sbat: Some(generations),
+            authenticode,
         })
     }
 
-    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
         let ostreebootdir = Path::new(sysroot_path).join(ostreeutil::BOOT_PREFIX);
         let dest_efidir = component_updatedir(sysroot_path, self);
 
@@ -381,8 +1148,12 @@ impl Component for Efi {
             f.insert_str(0, "/boot/efi/EFI/");
             f
         });
+        // Added after listing the files, as these aren't owned by any package
+        if opts.efi_fallback || Config::load(Path::new(sysroot_path))?.efi.fallback {
+            add_fallback_loader(Path::new(sysroot_path), &dest_efidir)?;
+        }
 
-        let meta = packagesystem::query_files(sysroot_path, files)?;
+        let meta = packagesystem::query_payload(sysroot_path, files, &dest_efidir, opts)?;
         write_update_metadata(sysroot_path, self, &meta)?;
         Ok(meta)
     }
@@ -391,8 +1162,31 @@ impl Component for Efi {
         get_component_update(sysroot, self)
     }
 
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        plan_filetree_update(sysroot, self, current)
+    }
+
+    fn rebuild_state(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        let Some(adoptable) = self.query_adopt()? else {
+            return Ok(None);
+        };
+        let mut inst = InstalledContent::rebuilt(adoptable.version);
+        // Track the files of the payload found on the ESP, so that the next
+        // update replaces them
+        if let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(self))? {
+            let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+            let updatef = filetree::FileTree::new_from_dir(&updated, digest)?;
+            inst.filetree = Some(updatef.found_in(&self.open_esp()?)?);
+        }
+        Ok(Some(inst))
+    }
+
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
-        if !is_efi_booted()? && self.get_esp_device().is_none() {
+        if !is_efi_booted()? && self.get_esp_device(Path::new("/")).is_none() {
             return Ok(ValidationResult::Skip);
         }
         let currentf = current
@@ -404,12 +1198,61 @@ impl Component for Efi {
         let diff = currentf.relative_diff_to(&efidir)?;
         let mut errs = Vec::new();
         for f in diff.changes.iter() {
-            errs.push(format!("Changed: {}", f));
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
         }
         for f in diff.removals.iter() {
-            errs.push(format!("Removed: {}", f));
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
         }
         assert_eq!(diff.additions.len(), 0);
+        for f in extraneous_files(currentf, &efidir.recover_path()?)? {
+            errs.push(ValidationError::new(ValidationErrorKind::Extraneous, f));
+        }
+        if let Some(rotation) = current.rotation.as_ref() {
+            errs.extend(validate_previous(rotation, &efidir, "")?);
+        }
+        // Also comparing what the firmware measures, for the binaries whose
+        // file digests match
+        if let Some(digests) = current.authenticode.as_ref() {
+            for e in validate_authenticode(digests, &efidir.recover_path()?, "")? {
+                if !errs.contains(&e) {
+                    errs.push(e);
+                }
+            }
+        }
+        // The other ESPs must not have diverged from the primary one
+        for mirror in self.mirror_esps(Path::new("/"))? {
+            let Some(dir) = mirror.efidir_optional()? else {
+                errs.push(ValidationError::new(
+                    ValidationErrorKind::Missing,
+                    format!("{}:EFI", mirror.device),
+                ));
+                continue;
+            };
+            let diff = currentf.relative_diff_to(&dir)?;
+            for f in diff.changes.iter() {
+                let path = format!("{}:{f}", mirror.device);
+                errs.push(ValidationError::new(ValidationErrorKind::Modified, path));
+            }
+            for f in diff.removals.iter() {
+                let path = format!("{}:{f}", mirror.device);
+                errs.push(ValidationError::new(ValidationErrorKind::Missing, path));
+            }
+            for f in extraneous_files(currentf, &dir.recover_path()?)? {
+                let path = format!("{}:{f}", mirror.device);
+                errs.push(ValidationError::new(ValidationErrorKind::Extraneous, path));
+            }
+            let prefix = format!("{}:", mirror.device);
+            if let Some(rotation) = current.rotation.as_ref() {
+                errs.extend(validate_previous(rotation, &dir, &prefix)?);
+            }
+            if let Some(digests) = current.authenticode.as_ref() {
+                for e in validate_authenticode(digests, &dir.recover_path()?, &prefix)? {
+                    if !errs.contains(&e) {
+                        errs.push(e);
+                    }
+                }
+            }
+        }
         if !errs.is_empty() {
             Ok(ValidationResult::Errors(errs))
         } else {
@@ -417,25 +1260,111 @@ impl Component for Efi {
         }
     }
 
-    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
-        let updated = sysroot
-            .sub_dir(&component_updatedirname(self))
-            .context("opening update dir")?;
-        let shim_files = find_file_recursive(updated.recover_path()?, SHIM)?;
-
-        // Does not support multiple shim for efi
-        if shim_files.len() > 1 {
-            anyhow::bail!("Found multiple {SHIM} in the image");
+    fn validate_strict(&self, current: &InstalledContent) -> Result<Vec<ValidationError>> {
+        if !is_efi_booted()? && self.get_esp_device(Path::new("/")).is_none() {
+            return Ok(Vec::new());
         }
-        if let Some(p) = shim_files.first() {
-            let p = p
-                .parent()
-                .unwrap()
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("No file name found"))?;
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let protected = ProtectedPaths::load(&[currentf])?;
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let mut efidirs = vec![(String::new(), self.open_esp()?.recover_path()?)];
+        for mirror in self.mirror_esps(Path::new("/"))? {
+            // A missing EFI directory is reported by validate
+            if let Some(dir) = mirror.efidir_optional()? {
+                efidirs.push((format!("{}:", mirror.device), dir.recover_path()?));
+            }
+        }
+        let mut errs = Vec::new();
+        for (prefix, efidir) in efidirs {
+            for f in untracked_files(currentf, current.rotation.as_ref(), &protected, &efidir)? {
+                let path = format!("{prefix}{f}");
+                errs.push(ValidationError::new(ValidationErrorKind::Extraneous, path));
+            }
+        }
+        Ok(errs)
+    }
+
+    fn repair(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<Repaired> {
+        if let Some(currentf) = current.filetree.as_ref() {
+            ProtectedPaths::load(&[currentf])?.check_paths(currentf.children.keys())?;
+        }
+        self.ensure_mounted_esp(Path::new("/"))?;
+        let efidir = self.open_esp().context("opening EFI dir")?;
+        validate_esp(&efidir)?;
+        let mut repaired = repair_filetree(sysroot, self, current, &efidir)?;
+        for mirror in self.mirror_esps(Path::new("/"))? {
+            let Some(dir) = mirror.efidir_optional()? else {
+                log::warn!("No EFI directory on {}, not repairing it", mirror.device);
+                continue;
+            };
+            for f in repair_filetree(sysroot, self, current, &dir)? {
+                repaired.push(format!("{}:{f}", mirror.device));
+            }
+        }
+        Ok(Repaired {
+            inst: current.clone(),
+            repaired,
+        })
+    }
+
+    fn apply_pending_nvram(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<InstalledContent>> {
+        let Some(pending) = current.pending_nvram.as_ref() else {
+            return Ok(None);
+        };
+        if !is_efi_booted()? || !efivars_writable()? {
+            return Ok(None);
+        }
+        for op in pending {
+            match op {
+                NvramOperation::BootEntry => {
+                    self.ensure_mounted_esp(Path::new("/"))?;
+                    self.ensure_boot_entry(sysroot, &self.open_esp()?)?;
+                }
+                NvramOperation::CapsuleDelivery => firmware::request_capsule_delivery()?,
+                NvramOperation::DbxUpdate => {}
+            }
+        }
+        Ok(Some(InstalledContent {
+            pending_nvram: None,
+            ..current.clone()
+        }))
+    }
+
+    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let shim_files = find_file_recursive(updated.recover_path()?, VENDOR_LOADER)?;
+
+        if let Some(vendor) = Config::load(&sysroot.recover_path()?)?.efi.vendor {
+            let found = shim_files
+                .iter()
+                .any(|p| p.parent().and_then(|p| p.file_name()) == Some(vendor.as_ref()));
+            if !found {
+                anyhow::bail!("Configured vendor {vendor} has no {VENDOR_LOADER} in the image");
+            }
+            return Ok(Some(vendor));
+        }
+        // Does not support multiple shim for efi
+        if shim_files.len() > 1 {
+            anyhow::bail!("Found multiple {VENDOR_LOADER} in the image");
+        }
+        if let Some(p) = shim_files.first() {
+            let p = p
+                .parent()
+                .unwrap()
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("No file name found"))?;
             Ok(Some(p.to_string_lossy().into_owned()))
         } else {
-            anyhow::bail!("Failed to find {SHIM} in the image")
+            anyhow::bail!("Failed to find {VENDOR_LOADER} in the image")
         }
     }
 }
@@ -447,7 +1376,105 @@ impl Drop for Efi {
     }
 }
 
-fn validate_esp(dir: &openat::Dir) -> Result<()> {
+/// The `Boot####` entries booting a file missing from the ESP they
+/// reference, by number, with their description.  Entries without a
+/// partition (e.g. network boot or firmware applications), and those of
+/// partitions which aren't attached (e.g. removable media) or aren't ESPs,
+/// are kept.
+#[context("Finding stale EFI boot entries")]
+fn stale_boot_entries() -> Result<Vec<(u16, String)>> {
+    let read_only = Config::load(Path::new("/"))?.efi.read_only;
+    let mut esps: std::collections::HashMap<PathBuf, Option<MirrorEsp>> = Default::default();
+    let mut r = Vec::new();
+    for (num, option) in efivars::boot_entries()? {
+        let (Some(hd), Some(path)) = (option.hard_drive(), option.file_path()) else {
+            continue;
+        };
+        let Some(device) = hd.device()? else {
+            log::debug!(
+                "Keeping {}: partition not attached",
+                efivars::boot_var_name(num)
+            );
+            continue;
+        };
+        let esp = match esps.entry(device.clone()) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let device = device.to_string_lossy();
+                let esp = if crate::blockdev::is_esp(&device)? {
+                    Some(MirrorEsp::open(&device, read_only)?)
+                } else {
+                    None
+                };
+                e.insert(esp)
+            }
+        };
+        let Some(esp) = esp else {
+            continue;
+        };
+        // vfat lookups are case-insensitive
+        let path = path.trim_start_matches('\\').replace('\\', "/");
+        if !esp.mountpoint.join(path).exists() {
+            r.push((num, option.description));
+        }
+    }
+    Ok(r)
+}
+
+/// Remove the stale boot entries, as found by `stale_boot_entries`, except
+/// the one used for the current boot, and drop them from `BootOrder`;
+/// returns them, only listing them with `dry_run`.
+#[context("Pruning EFI boot entries")]
+pub(crate) fn prune_boot_entries(dry_run: bool) -> Result<Vec<String>> {
+    if !is_efi_booted()? {
+        bail!("Not booted via EFI");
+    }
+    if !dry_run && !efivars_writable()? {
+        bail!("EFI variables are not writable");
+    }
+    let current = efivars::read_u16_var("BootCurrent")?;
+    let stale: Vec<_> = stale_boot_entries()?
+        .into_iter()
+        .filter(|(num, _)| Some(*num) != current)
+        .collect();
+    let r = stale
+        .iter()
+        .map(|(num, desc)| format!("{} ({desc})", efivars::boot_var_name(*num)))
+        .collect();
+    if dry_run || stale.is_empty() {
+        return Ok(r);
+    }
+    for (num, _) in stale.iter() {
+        efivars::delete_var(&efivars::boot_var_name(*num))?;
+    }
+    let order = efivars::boot_order()?;
+    let pruned: Vec<u16> = order
+        .iter()
+        .copied()
+        .filter(|n| !stale.iter().any(|(num, _)| num == n))
+        .collect();
+    if pruned != order {
+        efivars::set_boot_order(&pruned)?;
+    }
+    Ok(r)
+}
+
+/// Mount the unmounted ESP `device` read-write at `mountpoint`, in the
+/// private mount namespace entered at startup: systems may deliberately
+/// keep the ESP unmounted, which the rest of the system then still sees.
+fn mount_esp(device: &Path, mountpoint: &Path) -> Result<()> {
+    // Entering it here could happen on any thread, e.g. of the D-Bus daemon
+    if !util::in_private_mount_namespace() {
+        bail!("Not mounting {device:?} outside of a private mount namespace");
+    }
+    Command::new("mount")
+        .args(["-o", "rw"])
+        .arg(device)
+        .arg(mountpoint)
+        .run()
+}
+
+pub(crate) fn validate_esp(dir: &openat::Dir) -> Result<()> {
     let dir = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
     let stat = rustix::fs::fstatfs(&dir)?;
     if stat.f_type != libc::MSDOS_SUPER_MAGIC {
@@ -526,11 +1553,11 @@ pub(crate) fn create_efi_boot_entry(
     let partition_path = format!("/sys/class/block/{devname}/partition");
     let partition_number = std::fs::read_to_string(&partition_path)
         .with_context(|| format!("Failed to read {partition_path}"))?;
-    let shim = format!("{vendordir}/{SHIM}");
+    let shim = format!("{vendordir}/{VENDOR_LOADER}");
     if espdir.exists(&shim)? {
-        anyhow::bail!("Failed to find {SHIM}");
+        anyhow::bail!("Failed to find {VENDOR_LOADER}");
     }
-    let loader = format!("\\EFI\\{}\\{SHIM}", vendordir);
+    let loader = format!("\\EFI\\{}\\{VENDOR_LOADER}", vendordir);
     log::debug!("Creating new EFI boot entry using '{target}'");
     let st = Command::new(EFIBOOTMGR)
         .args([
@@ -551,6 +1578,629 @@ pub(crate) fn create_efi_boot_entry(
     anyhow::Ok(())
 }
 
+/// An ESP other than the primary one; unmounted on drop if we mounted it,
+/// or remounted read-only if `read_only` is set.
+struct MirrorEsp {
+    device: String,
+    mountpoint: PathBuf,
+    tmpdir: Option<tempfile::TempDir>,
+    read_only: bool,
+}
+
+impl MirrorEsp {
+    #[context("Mounting ESP {device}")]
+    fn open(device: &str, read_only: bool) -> Result<Self> {
+        if let Some(mountpoint) = esp_mountpoint(Path::new(device))? {
+            util::ensure_writable_mount(&mountpoint)?;
+            return Ok(Self {
+                device: device.to_string(),
+                mountpoint,
+                tmpdir: None,
+                read_only,
+            });
+        }
+        let tmpdir = tempfile::tempdir()?;
+        mount_esp(Path::new(device), tmpdir.path())?;
+        log::debug!("Mounted {device} at {:?}", tmpdir.path());
+        Ok(Self {
+            device: device.to_string(),
+            mountpoint: tmpdir.path().to_owned(),
+            tmpdir: Some(tmpdir),
+            read_only: false,
+        })
+    }
+
+    /// Open the `EFI` directory, creating it on a blank ESP.
+    fn efidir(&self) -> Result<openat::Dir> {
+        let path = self.mountpoint.join("EFI");
+        std::fs::create_dir_all(&path)?;
+        openat::Dir::open(&path).with_context(|| format!("opening {path:?}"))
+    }
+
+    fn efidir_optional(&self) -> Result<Option<openat::Dir>> {
+        let path = self.mountpoint.join("EFI");
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(openat::Dir::open(&path)?))
+    }
+}
+
+impl Drop for MirrorEsp {
+    fn drop(&mut self) {
+        if self.tmpdir.is_some() {
+            if let Err(e) = Command::new("umount").arg(&self.mountpoint).run() {
+                log::warn!("Failed to unmount {}: {e:#}", self.device);
+            }
+        } else if self.read_only {
+            if let Err(e) = util::remount_read_only(&self.mountpoint) {
+                log::warn!("{e:#}");
+            }
+        }
+    }
+}
+
+/// Directories of the ESP, relative to `EFI/`, which are always protected
+const PROTECTED_DIRS: &[&str] = &["Microsoft"];
+/// The directory of the removable media path, relative to `EFI/`
+const BOOT_DIR: &str = "BOOT";
+
+/// Returns `true` if `path` is `dir` or below it, ignoring case as FAT does.
+fn is_below_dir(path: &str, dir: &str) -> bool {
+    path.get(..dir.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(dir))
+        && matches!(path.as_bytes().get(dir.len()), None | Some(b'/'))
+}
+
+/// The directories of the ESP which bootupd never modifies, e.g. the one
+/// of Windows on dual-boot systems, or the removable media path when it
+/// isn't part of the managed content.
+#[derive(Debug)]
+pub(crate) struct ProtectedPaths {
+    dirs: Vec<String>,
+}
+
+impl ProtectedPaths {
+    /// The protected directories when managing the content of `trees`.
+    pub(crate) fn new(config: &EfiConfig, trees: &[&filetree::FileTree]) -> Self {
+        let mut dirs: Vec<String> = PROTECTED_DIRS.iter().map(|d| d.to_string()).collect();
+        let boot_managed = trees
+            .iter()
+            .flat_map(|t| t.children.keys())
+            .any(|path| is_below_dir(path, BOOT_DIR));
+        if !boot_managed {
+            dirs.push(BOOT_DIR.to_string());
+        }
+        dirs.extend(
+            config
+                .protected
+                .iter()
+                .map(|d| d.trim_matches('/').to_string()),
+        );
+        Self { dirs }
+    }
+
+    /// Same as `new`, with the configuration of the booted system.
+    pub(crate) fn load(trees: &[&filetree::FileTree]) -> Result<Self> {
+        Ok(Self::new(&Config::load(Path::new("/"))?.efi, trees))
+    }
+
+    /// The protected directory of `path`, relative to `EFI/`, if any.
+    fn protecting(&self, path: &str) -> Option<&str> {
+        self.dirs
+            .iter()
+            .find(|dir| is_below_dir(path, dir))
+            .map(String::as_str)
+    }
+
+    /// Fail if any of `paths`, relative to `EFI/`, is protected.
+    pub(crate) fn check_paths<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a String>,
+    ) -> Result<()> {
+        for path in paths {
+            if let Some(dir) = self.protecting(path) {
+                bail!("Refusing to modify EFI/{path}: EFI/{dir} is protected");
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if writing `target` to an ESP with `diff` (or a subset of it, on
+    /// mirrors) would modify a protected directory.
+    pub(crate) fn check(
+        &self,
+        target: &filetree::FileTree,
+        diff: &filetree::FileTreeDiff,
+    ) -> Result<()> {
+        self.check_paths(target.children.keys())?;
+        self.check_paths(diff.removals.iter())
+    }
+}
+
+/// Files which are expected in a vendor directory without being part of the
+/// payload: the GRUB configuration and environment block, and the files
+/// staged by an interrupted update, which the next one takes care of.
+fn is_unmanaged_file(name: &str) -> bool {
+    name.ends_with(".cfg") || name == "grubenv" || name.starts_with(".btmp.")
+}
+
+/// The files of the vendor directories of `tree` (but not `BOOT`, which
+/// is shared) found in `efidir` but not in `tree`, e.g. leftovers of an
+/// older OS or of manual changes.
+fn extraneous_files(tree: &filetree::FileTree, efidir: &Path) -> Result<Vec<String>> {
+    let vendors: std::collections::BTreeSet<&str> = tree
+        .children
+        .keys()
+        .filter_map(|path| path.split_once('/'))
+        .map(|(dir, _)| dir)
+        .filter(|dir| !dir.eq_ignore_ascii_case("BOOT"))
+        .collect();
+    let mut r = Vec::new();
+    for vendor in vendors {
+        let dir = efidir.join(vendor);
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&dir).sort_by_file_name() {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path().strip_prefix(efidir)?.to_string_lossy();
+            let name = entry.file_name().to_string_lossy();
+            if tree.children.contains_key(path.as_ref()) || is_unmanaged_file(&name) {
+                continue;
+            }
+            r.push(path.into_owned());
+        }
+    }
+    Ok(r)
+}
+
+/// All the files of `efidir` which aren't in `tree` nor in the previous
+/// boot chain kept by `rotation`, outside of the `protected` directories,
+/// for `validate --strict`.
+fn untracked_files(
+    tree: &filetree::FileTree,
+    rotation: Option<&KeyRotation>,
+    protected: &ProtectedPaths,
+    efidir: &Path,
+) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for entry in WalkDir::new(efidir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(efidir)?.to_string_lossy();
+        let name = entry.file_name().to_string_lossy();
+        let previous = rotation.is_some_and(|rotation| {
+            path.strip_prefix(rotation.dir.as_str())
+                .and_then(|p| p.strip_prefix('/'))
+                .is_some_and(|p| rotation.filetree.children.contains_key(p))
+        });
+        if tree.children.contains_key(path.as_ref())
+            || previous
+            || protected.protecting(&path).is_some()
+            || is_unmanaged_file(&name)
+        {
+            continue;
+        }
+        r.push(path.into_owned());
+    }
+    Ok(r)
+}
+
+/// What the content of an ESP (or of the update payload) tells about the
+/// bootloader of the OS, used to check that an ESP is adoptable.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct EspFingerprint {
+    /// The vendor directories, e.g. `fedora` or `Microsoft`, but not `BOOT`
+    vendors: Vec<String>,
+    /// The packages and versions of the binaries of the vendor directory,
+    /// from their SBAT metadata, e.g. `shim-15.8-3 grub2-2.12-1.fc40`
+    version: Option<String>,
+    /// The SBAT components of the distribution of these binaries, e.g.
+    /// `shim.redhat`
+    sbat_vendors: std::collections::BTreeSet<String>,
+}
+
+impl EspFingerprint {
+    /// Inspect the `EFI` directory `efidir` for the vendor directory
+    /// `vendor`.
+    #[context("Inspecting {efidir:?}")]
+    fn new(efidir: &Path, vendor: &str) -> Result<Self> {
+        let mut r = Self::default();
+        let mut vendordir = None;
+        for entry in std::fs::read_dir(efidir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.eq_ignore_ascii_case("BOOT") {
+                continue;
+            }
+            // FAT is case insensitive
+            if name.eq_ignore_ascii_case(vendor) {
+                vendordir = Some(entry.path());
+            }
+            r.vendors.push(name);
+        }
+        r.vendors.sort();
+        let Some(vendordir) = vendordir else {
+            return Ok(r);
+        };
+        let mut binaries = Vec::new();
+        for entry in std::fs::read_dir(&vendordir)? {
+            let path = entry?.path();
+            let is_efi = path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("efi"));
+            if is_efi && path.is_file() {
+                binaries.push(path);
+            }
+        }
+        binaries.sort();
+        let mut versions = Vec::new();
+        for path in binaries {
+            let Some(entries) = sbat::read_entries(&path)? else {
+                continue;
+            };
+            r.sbat_vendors.extend(
+                entries
+                    .iter()
+                    .filter(|e| e.is_vendor())
+                    .map(|e| e.component.clone()),
+            );
+            if let Some(version) = sbat::package_version(&entries) {
+                if !versions.contains(&version) {
+                    versions.push(version);
+                }
+            }
+        }
+        r.version = (!versions.is_empty()).then(|| versions.join(" "));
+        Ok(r)
+    }
+
+    /// Why this ESP doesn't look like it was installed by the OS shipping
+    /// the update payload fingerprinted as `update`, if so.
+    fn foreign(&self, update: &Self, vendor: &str) -> Option<String> {
+        if !self.vendors.is_empty() && !self.vendors.iter().any(|v| v.eq_ignore_ascii_case(vendor))
+        {
+            return Some(format!(
+                "no {vendor} directory, found {}",
+                self.vendors.join(" ")
+            ));
+        }
+        if !self.sbat_vendors.is_empty()
+            && !update.sbat_vendors.is_empty()
+            && self.sbat_vendors.is_disjoint(&update.sbat_vendors)
+        {
+            let list = |s: &std::collections::BTreeSet<String>| {
+                s.iter().cloned().collect::<Vec<_>>().join(" ")
+            };
+            return Some(format!(
+                "binaries built by {} instead of {}",
+                list(&self.sbat_vendors),
+                list(&update.sbat_vendors)
+            ));
+        }
+        None
+    }
+}
+
+/// The diff bringing a mirror ESP from whatever it contains to `updatef`,
+/// also removing `removals`.
+fn mirror_diff(
+    updatef: &filetree::FileTree,
+    dir: &openat::Dir,
+    removals: std::collections::HashSet<String>,
+) -> Result<filetree::FileTreeDiff> {
+    // Relative to the update, files missing in dir are "removals"
+    let diff = updatef.relative_diff_to(dir)?;
+    Ok(filetree::FileTreeDiff {
+        additions: diff.removals,
+        removals,
+        changes: diff.changes,
+    })
+}
+
+/// The suffix of the directory of the boot chain kept during a key
+/// rotation, e.g. `EFI/fedora-previous`
+const ROTATION_SUFFIX: &str = "-previous";
+
+/// The vendor directory of `tree`, holding the vendor loader, and its
+/// files relative to it.
+fn vendor_subtree(tree: &filetree::FileTree) -> Option<(String, filetree::FileTree)> {
+    let (vendor, _) = tree
+        .children
+        .keys()
+        .filter_map(|path| path.split_once('/'))
+        .find(|(dir, name)| *name == VENDOR_LOADER && !dir.eq_ignore_ascii_case(BOOT_DIR))?;
+    let prefix = format!("{vendor}/");
+    let children = tree
+        .children
+        .iter()
+        .filter_map(|(path, meta)| Some((path.strip_prefix(&prefix)?.to_string(), meta.clone())))
+        .collect();
+    Some((vendor.to_string(), filetree::FileTree { children }))
+}
+
+/// Start a Secure Boot key rotation: copy the vendor directory of the
+/// installed `current` to `<vendor>-previous` on the primary ESP `destdir`
+/// and the `mirrors`, where shim still finds the GRUB next to it.  Returns
+/// `None` if `current` has no vendor loader.
+#[context("Keeping the previous boot chain")]
+fn keep_previous(
+    current: &InstalledContent,
+    destdir: &openat::Dir,
+    mirrors: &[MirrorEsp],
+) -> Result<Option<KeyRotation>> {
+    let Some((vendor, tree)) = current.filetree.as_ref().and_then(vendor_subtree) else {
+        log::warn!("No {VENDOR_LOADER} installed, not keeping the previous boot chain");
+        return Ok(None);
+    };
+    let dir = format!("{vendor}{ROTATION_SUFFIX}");
+    let mut mirror_dirs = Vec::new();
+    for mirror in mirrors {
+        mirror_dirs.extend(mirror.efidir_optional()?);
+    }
+    for efidir in std::iter::once(destdir).chain(mirror_dirs.iter()) {
+        let Some(src) = efidir.sub_dir_optional(vendor.as_str())? else {
+            continue;
+        };
+        efidir.remove_all(dir.as_str())?;
+        efidir.create_dir(dir.as_str(), 0o700)?;
+        tree.copy_files(&src, &efidir.sub_dir(dir.as_str())?)?;
+        filetree::syncfs(efidir)?;
+    }
+    println!(
+        "Keeping the boot chain of {} in EFI/{dir}",
+        current.meta.version
+    );
+    let digest = Config::load(Path::new("/"))?.update.digest;
+    let filetree = filetree::FileTree::new_from_dir(&destdir.sub_dir(dir.as_str())?, digest)?;
+    Ok(Some(KeyRotation {
+        previous: current.meta.clone(),
+        dir,
+        filetree,
+    }))
+}
+
+/// Check the previous boot chain kept during a key rotation in `efidir`,
+/// prefixing the reported paths with `prefix`.
+fn validate_previous(
+    rotation: &KeyRotation,
+    efidir: &openat::Dir,
+    prefix: &str,
+) -> Result<Vec<ValidationError>> {
+    let Some(dir) = efidir.sub_dir_optional(rotation.dir.as_str())? else {
+        let path = format!("{prefix}{}", rotation.dir);
+        return Ok(vec![ValidationError::new(
+            ValidationErrorKind::Missing,
+            path,
+        )]);
+    };
+    let diff = rotation.filetree.relative_diff_to(&dir)?;
+    let mut errs = Vec::new();
+    for f in diff.changes.iter() {
+        let path = format!("{prefix}{}/{f}", rotation.dir);
+        errs.push(ValidationError::new(ValidationErrorKind::Modified, path));
+    }
+    for f in diff.removals.iter() {
+        let path = format!("{prefix}{}/{f}", rotation.dir);
+        errs.push(ValidationError::new(ValidationErrorKind::Missing, path));
+    }
+    Ok(errs)
+}
+
+/// The per-ESP state to record, if the content was mirrored.
+fn esps_state(
+    devices: Vec<String>,
+    meta: &ContentMetadata,
+) -> Option<std::collections::BTreeMap<String, ContentMetadata>> {
+    (!devices.is_empty()).then(|| devices.into_iter().map(|d| (d, meta.clone())).collect())
+}
+
+/// Returns where the ESP `device` is currently mounted, if it is.
+pub(crate) fn esp_mountpoint(device: &Path) -> Result<Option<PathBuf>> {
+    let device = device.canonicalize()?;
+    let mounts = crate::filesystem::mountinfo()?;
+    let mnt = mounts.iter().rev().find(|m| {
+        m.fstype == "vfat" && Path::new(&m.source).canonicalize().ok().as_ref() == Some(&device)
+    });
+    Ok(mnt.map(|m| PathBuf::from(&m.mountpoint)))
+}
+
+/// The ESPs of `root`, and where they are mounted.
+#[context("Listing ESPs")]
+pub(crate) fn esps_status(root: &Path) -> Result<Vec<EspStatus>> {
+    crate::blockdev::find_esps(root)?
+        .into_iter()
+        .map(|device| {
+            let mountpoint =
+                esp_mountpoint(Path::new(&device))?.map(|p| p.to_string_lossy().into_owned());
+            let leftovers = match mountpoint.as_deref() {
+                Some(mnt) => filetree::find_leftovers(&openat::Dir::open(mnt)?)?,
+                None => Vec::new(),
+            };
+            Ok(EspStatus {
+                device,
+                mountpoint,
+                installed: None,
+                leftovers,
+            })
+        })
+        .collect()
+}
+
+/// Read a boolean (single byte) global variable, missing meaning false.
+fn read_efi_var_bool(name: &str) -> Result<bool> {
+    Ok(efivars::read_var(name)?.is_some_and(|v| v.first() == Some(&1)))
+}
+
+/// Returns the version shim embeds in its `.data.ident` section, e.g.
+/// `$Version: 15.8 $`.
+fn shim_version(data: &[u8]) -> Option<String> {
+    const PREFIX: &[u8] = b"$Version: ";
+    let start = data.windows(PREFIX.len()).position(|w| w == PREFIX)? + PREFIX.len();
+    let len = data[start..]
+        .iter()
+        .position(|&b| b == b'$' || b == b'\n')?;
+    let version = std::str::from_utf8(&data[start..start + len]).ok()?.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// Returns the SBAT entries of `files` revoked by `level`.
+fn sbat_revocations(
+    files: &std::collections::BTreeMap<String, sbat::Generations>,
+    level: &sbat::Generations,
+) -> Vec<String> {
+    files
+        .iter()
+        .flat_map(|(path, generations)| {
+            sbat::revoked(generations, level)
+                .into_iter()
+                .map(move |r| format!("{path}: {r}"))
+        })
+        .collect()
+}
+
+/// Stage the available firmware updates for the next boot, with the
+/// capsules of `src_root` copied to `efidir` if there is no fwupd; the
+/// installation goes on if this fails.
+fn stage_firmware_updates(
+    src_root: &openat::Dir,
+    efidir: &Path,
+) -> Result<Option<Vec<FirmwareUpdate>>> {
+    if !is_efi_booted()? {
+        log::debug!("Not booted via EFI, skipping firmware updates");
+        return Ok(None);
+    }
+    let updates = match firmware::stage_updates(&src_root.recover_path()?, efidir) {
+        Ok(updates) => updates,
+        Err(e) => {
+            log::warn!("{e:#}");
+            return Ok(None);
+        }
+    };
+    for update in updates.iter() {
+        match update.error.as_deref() {
+            None => println!("Staged firmware update: {}", update.name),
+            Some(e) => log::warn!("Failed to stage firmware update {}: {e}", update.name),
+        }
+    }
+    Ok(Some(updates))
+}
+
+/// Predict PCR 4 for the boot chain of the vendor directory tracked by
+/// `tree` and installed in `efidir`; this is informative only, so failures
+/// are just logged.
+fn predict_pcr4(tree: &filetree::FileTree, efidir: &Path) -> Option<Pcr4Prediction> {
+    let suffix = format!("/{VENDOR_LOADER}");
+    let vendors: Vec<&str> = tree
+        .children
+        .keys()
+        .filter_map(|p| p.strip_suffix(suffix.as_str()))
+        .filter(|v| !v.contains('/'))
+        .collect();
+    let [vendor] = vendors.as_slice() else {
+        log::debug!("Not predicting PCR 4 without a single {VENDOR_LOADER}");
+        return None;
+    };
+    let mut chain = vec![format!("{vendor}/{VENDOR_LOADER}")];
+    let grub = format!("{vendor}/{GRUB_EFI}");
+    if !chain.contains(&grub) && tree.children.contains_key(&grub) {
+        chain.push(grub);
+    }
+    tpm::predict_pcr4(efidir, &chain)
+        .map_err(|e| log::warn!("{e:#}"))
+        .ok()
+}
+
+/// The Authenticode digests of the binaries of `tree` in `dir`, for
+/// validation; failures are just logged, as for the PCR 4 prediction.
+fn record_authenticode(
+    tree: &filetree::FileTree,
+    dir: &Path,
+) -> Option<std::collections::BTreeMap<String, String>> {
+    tpm::authenticode_digests(dir, tree.children.keys())
+        .map_err(|e| log::warn!("{e:#}"))
+        .ok()
+}
+
+/// Check the Authenticode digests of the binaries in `dir` against the
+/// recorded `digests`, prefixing the paths of the errors with `prefix`;
+/// missing binaries are reported by the filetree comparison.
+fn validate_authenticode(
+    digests: &std::collections::BTreeMap<String, String>,
+    dir: &Path,
+    prefix: &str,
+) -> Result<Vec<ValidationError>> {
+    let mut r = Vec::new();
+    for (path, expected) in digests.iter() {
+        let data = match std::fs::read(dir.join(path)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+        };
+        let digest = tpm::authenticode_digest(&data).map(hex::encode);
+        if digest.ok().as_ref() != Some(expected) {
+            r.push(ValidationError::new(
+                ValidationErrorKind::Modified,
+                format!("{prefix}{path}"),
+            ));
+        }
+    }
+    Ok(r)
+}
+
+/// Populate the removable media path in the update payload from the vendor
+/// directory, unless the payload already has it.
+#[context("Adding the EFI removable media path")]
+fn add_fallback_loader(sysroot: &Path, efidir: &Path) -> Result<()> {
+    let fallback = efidir.join(FALLBACK_EFI);
+    if fallback.exists() {
+        log::debug!("Found {FALLBACK_EFI} in the update payload");
+        return Ok(());
+    }
+    let loaders = find_file_recursive(efidir, VENDOR_LOADER)?;
+    let [loader] = loaders.as_slice() else {
+        bail!("Expected exactly one {VENDOR_LOADER} in {efidir:?}");
+    };
+    let bootdir = fallback.parent().unwrap();
+    std::fs::create_dir_all(bootdir)?;
+    std::fs::copy(loader, &fallback).with_context(|| format!("copying {loader:?}"))?;
+    if let Some(fb) = FALLBACK_LOADER {
+        let src = [
+            loader.with_file_name(fb),
+            sysroot.join(SHIM_LIBDIR).join(fb),
+        ]
+        .into_iter()
+        .find(|p| p.exists());
+        match src {
+            Some(src) => {
+                std::fs::copy(&src, bootdir.join(fb))
+                    .with_context(|| format!("copying {src:?}"))?;
+            }
+            None => {
+                // Without the fallback loader, shim loads GRUB from its own
+                // directory
+                let grub = loader.with_file_name(GRUB_EFI);
+                if !grub.exists() {
+                    bail!("Failed to find {fb} or {GRUB_EFI} next to {loader:?}");
+                }
+                log::warn!("Failed to find {fb}, boot entries won't be recreated");
+                std::fs::copy(&grub, bootdir.join(GRUB_EFI))
+                    .with_context(|| format!("copying {grub:?}"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
 #[context("Find target file recursively")]
 fn find_file_recursive<P: AsRef<Path>>(dir: P, target_file: &str) -> Result<Vec<PathBuf>> {
     let mut result = Vec::new();
@@ -575,6 +2225,7 @@ mod tests {
     use cap_std_ext::dirext::CapStdExtDirExt;
 
     use super::*;
+    use crate::digest::DigestAlgorithm;
 
     #[test]
     fn test_parse_boot_entries() -> Result<()> {
@@ -681,4 +2332,264 @@ Boot0003* test";
         }
         Ok(())
     }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_add_fallback_loader() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path();
+        let efidir = sysroot.join("EFI");
+        std::fs::create_dir_all(efidir.join("fedora"))?;
+        std::fs::write(efidir.join("fedora").join(SHIM), "shim")?;
+        // Neither fbx64.efi nor GRUB
+        assert!(add_fallback_loader(sysroot, &efidir).is_err());
+        std::fs::remove_dir_all(efidir.join("BOOT"))?;
+
+        std::fs::write(efidir.join("fedora").join(GRUB_EFI), "grub")?;
+        add_fallback_loader(sysroot, &efidir)?;
+        assert_eq!(std::fs::read_to_string(efidir.join(FALLBACK_EFI))?, "shim");
+        assert_eq!(
+            std::fs::read_to_string(efidir.join("BOOT").join(GRUB_EFI))?,
+            "grub"
+        );
+        std::fs::remove_dir_all(efidir.join("BOOT"))?;
+
+        std::fs::write(efidir.join("fedora/fbx64.efi"), "fallback")?;
+        add_fallback_loader(sysroot, &efidir)?;
+        assert_eq!(
+            std::fs::read_to_string(efidir.join("BOOT/fbx64.efi"))?,
+            "fallback"
+        );
+        assert!(!efidir.join("BOOT").join(GRUB_EFI).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shim_version() {
+        let data = b"\0\0UEFI SHIM\n$Version: 15.8 $\n$BuildMachine: Linux x86_64 $\n";
+        assert_eq!(shim_version(data).as_deref(), Some("15.8"));
+        assert_eq!(shim_version(b"$Version: $"), None);
+        assert_eq!(shim_version(b"GRUB"), None);
+    }
+
+    #[test]
+    fn test_extraneous_files() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        std::fs::create_dir_all(efidir.join("fedora/fonts"))?;
+        std::fs::create_dir_all(efidir.join("BOOT"))?;
+        std::fs::write(efidir.join("fedora/shimx64.efi"), "shim")?;
+        std::fs::write(efidir.join("BOOT/BOOTX64.EFI"), "shim")?;
+        let tree = filetree::FileTree::new_from_dir(
+            &openat::Dir::open(efidir)?,
+            DigestAlgorithm::default(),
+        )?;
+        assert!(extraneous_files(&tree, efidir)?.is_empty());
+
+        std::fs::write(efidir.join("fedora/grub.cfg"), "configfile")?;
+        std::fs::write(efidir.join("fedora/grubenv"), "# GRUB Environment Block")?;
+        std::fs::write(efidir.join("fedora/.btmp.grubx64.efi"), "grub")?;
+        std::fs::write(efidir.join("fedora/gcdx64.efi"), "old grub")?;
+        std::fs::write(efidir.join("fedora/fonts/unicode.pf2"), "font")?;
+        std::fs::write(efidir.join("BOOT/fbx64.efi"), "fallback")?;
+        std::fs::create_dir_all(efidir.join("centos"))?;
+        std::fs::write(efidir.join("centos/shimx64.efi"), "shim")?;
+        assert_eq!(
+            extraneous_files(&tree, efidir)?,
+            ["fedora/fonts/unicode.pf2", "fedora/gcdx64.efi"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_untracked_files() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        std::fs::create_dir_all(efidir.join("fedora"))?;
+        std::fs::create_dir_all(efidir.join("BOOT"))?;
+        std::fs::write(efidir.join("fedora/shimx64.efi"), "shim")?;
+        std::fs::write(efidir.join("BOOT/BOOTX64.EFI"), "shim")?;
+        let tree = filetree::FileTree::new_from_dir(
+            &openat::Dir::open(efidir)?,
+            DigestAlgorithm::default(),
+        )?;
+        let config = EfiConfig {
+            protected: vec!["ubuntu".into()],
+            ..Default::default()
+        };
+        let protected = ProtectedPaths::new(&config, &[&tree]);
+        assert!(untracked_files(&tree, None, &protected, efidir)?.is_empty());
+
+        std::fs::write(efidir.join("fedora/grub.cfg"), "configfile")?;
+        std::fs::write(efidir.join("BOOT/fbx64.efi"), "fallback")?;
+        for dir in ["Microsoft/Boot", "ubuntu", "centos", "fedora-previous"] {
+            std::fs::create_dir_all(efidir.join(dir))?;
+        }
+        std::fs::write(efidir.join("Microsoft/Boot/bootmgfw.efi"), "windows")?;
+        std::fs::write(efidir.join("ubuntu/shimx64.efi"), "shim")?;
+        std::fs::write(efidir.join("centos/shimx64.efi"), "shim")?;
+        std::fs::write(efidir.join("fedora-previous/shimx64.efi"), "old shim")?;
+        std::fs::write(efidir.join("fedora-previous/grubx64.efi"), "old grub")?;
+        let rotation = KeyRotation {
+            previous: ContentMetadata {
+                timestamp: chrono::Utc::now(),
+                version: "1".into(),
+                signing_keys: Vec::new(),
+            },
+            dir: "fedora-previous".into(),
+            filetree: filetree::FileTree::new_from_dir(
+                &openat::Dir::open(&efidir.join("fedora-previous"))?,
+                DigestAlgorithm::default(),
+            )?,
+        };
+        std::fs::write(efidir.join("fedora-previous/mmx64.efi"), "mok")?;
+        assert_eq!(
+            untracked_files(&tree, Some(&rotation), &protected, efidir)?,
+            [
+                "BOOT/fbx64.efi",
+                "centos/shimx64.efi",
+                "fedora-previous/mmx64.efi"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_previous_boot_chain() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        std::fs::create_dir_all(efidir.join("fedora"))?;
+        std::fs::create_dir_all(efidir.join("BOOT"))?;
+        std::fs::write(efidir.join("fedora").join(VENDOR_LOADER), "shim")?;
+        std::fs::write(efidir.join("fedora/grub.efi"), "grub")?;
+        std::fs::write(efidir.join(FALLBACK_EFI), "shim")?;
+        let dir = openat::Dir::open(efidir)?;
+        let tree = filetree::FileTree::new_from_dir(&dir, DigestAlgorithm::default())?;
+        let (vendor, previous) = vendor_subtree(&tree).unwrap();
+        assert_eq!(vendor, "fedora");
+        let names: Vec<_> = previous.children.keys().map(String::as_str).collect();
+        assert_eq!(names, ["grub.efi", VENDOR_LOADER]);
+
+        let rotation = KeyRotation {
+            previous: ContentMetadata {
+                timestamp: Default::default(),
+                version: "grub2-2.12-1".into(),
+                signing_keys: Vec::new(),
+            },
+            dir: format!("{vendor}{ROTATION_SUFFIX}"),
+            filetree: previous,
+        };
+        let errs = validate_previous(&rotation, &dir, "")?;
+        assert_eq!(
+            errs,
+            [ValidationError::new(
+                ValidationErrorKind::Missing,
+                "fedora-previous"
+            )]
+        );
+        std::fs::create_dir_all(efidir.join("fedora-previous"))?;
+        let previous_dir = dir.sub_dir("fedora-previous")?;
+        rotation
+            .filetree
+            .copy_files(&dir.sub_dir("fedora")?, &previous_dir)?;
+        assert!(validate_previous(&rotation, &dir, "")?.is_empty());
+        std::fs::write(efidir.join("fedora-previous/grub.efi"), "modified")?;
+        let errs = validate_previous(&rotation, &dir, "/dev/vdb2:")?;
+        assert_eq!(
+            errs,
+            [ValidationError::new(
+                ValidationErrorKind::Modified,
+                "/dev/vdb2:fedora-previous/grub.efi"
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_protected_paths() -> Result<()> {
+        let tree = |paths: &[&str]| filetree::FileTree {
+            children: paths
+                .iter()
+                .map(|p| {
+                    let meta = filetree::FileMetadata {
+                        size: 0,
+                        digest: crate::digest::Digest::Sha512(crate::sha512string::SHA512String(
+                            "sha512:00".into(),
+                        )),
+                        xattrs: Default::default(),
+                    };
+                    (p.to_string(), meta)
+                })
+                .collect(),
+        };
+        let diff = |removals: &[&str]| filetree::FileTreeDiff {
+            additions: Default::default(),
+            removals: removals.iter().map(|p| p.to_string()).collect(),
+            changes: Default::default(),
+        };
+        let config = EfiConfig {
+            protected: vec!["ubuntu/".into()],
+            ..Default::default()
+        };
+        let fedora = tree(&["fedora/shimx64.efi", "fedora/grubx64.efi"]);
+        let protected = ProtectedPaths::new(&config, &[&fedora]);
+        protected.check(&fedora, &diff(&["fedora/mmx64.efi"]))?;
+        for path in [
+            "BOOT/BOOTX64.EFI",
+            "Boot/bootx64.efi",
+            "microsoft/Boot/bootmgfw.efi",
+            "ubuntu/shimx64.efi",
+        ] {
+            assert!(protected.check(&fedora, &diff(&[path])).is_err());
+            assert!(protected.check(&tree(&[path]), &diff(&[])).is_err());
+        }
+        // Only a prefix of the directory name
+        protected.check(&tree(&["ubuntu-old/shimx64.efi"]), &diff(&[]))?;
+        // The removable media path is managed if shipped
+        let fallback = tree(&["fedora/shimx64.efi", "BOOT/BOOTX64.EFI"]);
+        let protected = ProtectedPaths::new(&config, &[&fedora, &fallback]);
+        protected.check(&fallback, &diff(&["BOOT/fbx64.efi"]))?;
+        assert!(protected.check(&fallback, &diff(&["Microsoft/x"])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp_fingerprint() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        std::fs::create_dir_all(efidir.join("BOOT"))?;
+        std::fs::create_dir_all(efidir.join("Microsoft/Boot"))?;
+        std::fs::create_dir_all(efidir.join("ubuntu"))?;
+        std::fs::write(
+            efidir.join("ubuntu/grub.cfg"),
+            "configfile $prefix/grub.cfg",
+        )?;
+        let found = EspFingerprint::new(efidir, "fedora")?;
+        assert_eq!(found.vendors, ["Microsoft", "ubuntu"]);
+        assert_eq!(found.version, None);
+        let expected = EspFingerprint::new(efidir, "ubuntu")?;
+        assert_eq!(
+            found.foreign(&expected, "fedora").as_deref(),
+            Some("no fedora directory, found Microsoft ubuntu")
+        );
+        assert_eq!(found.foreign(&expected, "Ubuntu"), None);
+        // A blank ESP is adoptable
+        assert_eq!(EspFingerprint::default().foreign(&expected, "fedora"), None);
+
+        let vendor = |names: &[&str]| EspFingerprint {
+            vendors: vec!["fedora".into()],
+            version: None,
+            sbat_vendors: names.iter().map(|s| s.to_string()).collect(),
+        };
+        let expected = vendor(&["shim.redhat", "grub.rh"]);
+        assert_eq!(vendor(&["grub.rh"]).foreign(&expected, "fedora"), None);
+        assert_eq!(vendor(&[]).foreign(&expected, "fedora"), None);
+        assert_eq!(
+            vendor(&["shim.debian"])
+                .foreign(&expected, "fedora")
+                .as_deref(),
+            Some("binaries built by shim.debian instead of grub.rh shim.redhat")
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,535 @@
+//! Native manipulation of UEFI variables through efivarfs, in particular
+//! the `Boot####` load options and `BootOrder`.
+//!
+//! See the "Globally Defined Variables" and "Device Path Protocol" chapters
+//! of the UEFI specification for the formats.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fn_error_context::context;
+use rustix::fs::IFlags;
+
+use crate::efi::EFIVARS;
+
+/// The vendor GUID of the variables defined by the UEFI specification
+const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+/// NON_VOLATILE | BOOTSERVICE_ACCESS | RUNTIME_ACCESS
+const VAR_ATTRIBUTES: u32 = 0x7;
+/// VAR_ATTRIBUTES | TIME_BASED_AUTHENTICATED_WRITE_ACCESS | APPEND_WRITE
+const AUTHENTICATED_APPEND_ATTRIBUTES: u32 = 0x67;
+/// The load option is enabled
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+/// Device path node types and subtypes
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_HARDDRIVE_DP: u8 = 0x01;
+const MEDIA_FILEPATH_DP: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7f;
+const END_ENTIRE_DEVICE_PATH: u8 = 0xff;
+/// Hard drive node: GPT partition table, GUID signature
+const HD_MBR_TYPE_GPT: u8 = 0x02;
+const HD_SIGNATURE_GUID: u8 = 0x02;
+
+fn var_path(name: &str) -> PathBuf {
+    vendor_var_path(name, EFI_GLOBAL_VARIABLE)
+}
+
+fn vendor_var_path(name: &str, guid: &str) -> PathBuf {
+    Path::new(EFIVARS).join(format!("{name}-{guid}"))
+}
+
+/// The name of the `Boot####` variable for the entry `num`
+pub(crate) fn boot_var_name(num: u16) -> String {
+    format!("Boot{num:04X}")
+}
+
+/// Read a global variable, without its attributes.
+pub(crate) fn read_var(name: &str) -> Result<Option<Vec<u8>>> {
+    read_vendor_var(name, EFI_GLOBAL_VARIABLE)
+}
+
+/// Read the variable `name` of the vendor `guid`, without its attributes.
+#[context("Reading EFI variable {name}")]
+pub(crate) fn read_vendor_var(name: &str, guid: &str) -> Result<Option<Vec<u8>>> {
+    let path = vendor_var_path(name, guid);
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if buf.len() < 4 {
+        bail!("Read less than 4 bytes from {path:?}");
+    }
+    Ok(Some(buf[4..].to_vec()))
+}
+
+/// efivarfs marks variables immutable to protect against accidental
+/// removal; this needs to be cleared to modify or delete one.
+fn make_mutable(path: &Path) -> Result<()> {
+    let f = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let flags = rustix::fs::ioctl_getflags(&f)?;
+    if flags.contains(IFlags::IMMUTABLE) {
+        rustix::fs::ioctl_setflags(&f, flags - IFlags::IMMUTABLE)?;
+    }
+    Ok(())
+}
+
+/// Create or replace a non-volatile global variable.
+#[context("Writing EFI variable {name}")]
+pub(crate) fn write_var(name: &str, data: &[u8]) -> Result<()> {
+    let path = var_path(name);
+    make_mutable(&path)?;
+    let mut buf = VAR_ATTRIBUTES.to_le_bytes().to_vec();
+    buf.extend_from_slice(data);
+    let mut f = OpenOptions::new().write(true).create(true).open(&path)?;
+    // efivarfs requires the whole variable to be written at once
+    let n = f.write(&buf)?;
+    if n != buf.len() {
+        bail!("Short write to {path:?}");
+    }
+    Ok(())
+}
+
+/// Append the signed `data` (an `EFI_VARIABLE_AUTHENTICATION_2` descriptor
+/// followed by the new signature lists) to the authenticated variable
+/// `name` of the vendor `guid`; the firmware checks the signature.
+#[context("Appending to EFI variable {name}")]
+pub(crate) fn append_authenticated_var(name: &str, guid: &str, data: &[u8]) -> Result<()> {
+    let path = vendor_var_path(name, guid);
+    make_mutable(&path)?;
+    let mut buf = AUTHENTICATED_APPEND_ATTRIBUTES.to_le_bytes().to_vec();
+    buf.extend_from_slice(data);
+    let mut f = OpenOptions::new().write(true).create(true).open(&path)?;
+    let n = f.write(&buf)?;
+    if n != buf.len() {
+        bail!("Short write to {path:?}");
+    }
+    Ok(())
+}
+
+/// Delete a global variable.
+#[context("Deleting EFI variable {name}")]
+pub(crate) fn delete_var(name: &str) -> Result<()> {
+    let path = var_path(name);
+    make_mutable(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// Read a 16-bit variable, like `BootCurrent`.
+pub(crate) fn read_u16_var(name: &str) -> Result<Option<u16>> {
+    let Some(buf) = read_var(name)? else {
+        return Ok(None);
+    };
+    let Some(v) = buf.get(0..2) else {
+        bail!("Invalid EFI variable {name}");
+    };
+    Ok(Some(u16::from_le_bytes([v[0], v[1]])))
+}
+
+/// Read `BootOrder`.
+pub(crate) fn boot_order() -> Result<Vec<u16>> {
+    let buf = read_var("BootOrder")?.unwrap_or_default();
+    Ok(buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect())
+}
+
+/// Replace `BootOrder`.
+pub(crate) fn set_boot_order(order: &[u16]) -> Result<()> {
+    let buf: Vec<u8> = order.iter().flat_map(|n| n.to_le_bytes()).collect();
+    write_var("BootOrder", &buf)
+}
+
+/// The names of all the global variables, sorted.
+#[context("Listing EFI variables")]
+pub(crate) fn var_names() -> Result<Vec<String>> {
+    let suffix = format!("-{EFI_GLOBAL_VARIABLE}");
+    let mut r = Vec::new();
+    for entry in std::fs::read_dir(EFIVARS)? {
+        let name = entry?.file_name();
+        if let Some(name) = name.to_str().and_then(|n| n.strip_suffix(&suffix)) {
+            r.push(name.to_string());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// The number of the `Boot####` variable `name`, with 4 uppercase hex
+/// digits as required by the specification, if it is one.
+fn boot_var_number(name: &str) -> Option<u16> {
+    let num = name.strip_prefix("Boot")?;
+    if num.len() != 4 || !num.bytes().all(|b| matches!(b, b'0'..=b'9' | b'A'..=b'F')) {
+        return None;
+    }
+    u16::from_str_radix(num, 16).ok()
+}
+
+/// The numbers of all the `Boot####` variables, sorted, including those
+/// which can't be parsed.
+fn boot_numbers() -> Result<Vec<u16>> {
+    Ok(var_names()?
+        .iter()
+        .filter_map(|n| boot_var_number(n))
+        .collect())
+}
+
+/// Read all the `Boot####` load options, sorted by number; unparsable
+/// entries are skipped.
+#[context("Reading EFI boot entries")]
+pub(crate) fn boot_entries() -> Result<Vec<(u16, LoadOption)>> {
+    let mut r = Vec::new();
+    for name in var_names()? {
+        let name = name.as_str();
+        let Some(num) = boot_var_number(name) else {
+            continue;
+        };
+        let Some(buf) = read_var(name)? else {
+            continue;
+        };
+        match LoadOption::parse(&buf) {
+            Ok(o) => r.push((num, o)),
+            Err(e) => log::debug!("Skipping {name}: {e}"),
+        }
+    }
+    r.sort_by_key(|(num, _)| *num);
+    Ok(r)
+}
+
+/// A GPT partition, as referenced by a hard drive device path node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HardDrive {
+    pub(crate) number: u32,
+    /// Start, in logical blocks
+    pub(crate) start: u64,
+    /// Size, in logical blocks
+    pub(crate) size: u64,
+    /// The partition GUID, in its on-disk (mixed-endian) format
+    pub(crate) guid: [u8; 16],
+}
+
+impl HardDrive {
+    /// Query the partition `device` in sysfs.
+    #[context("Querying partition {device}")]
+    pub(crate) fn for_partition(device: &str) -> Result<Self> {
+        let dev = Path::new(device).canonicalize()?;
+        let name = dev
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("Invalid device {device}"))?;
+        let sys = Path::new("/sys/class/block").join(name).canonicalize()?;
+        let read = |p: &Path| -> Result<u64> {
+            let s = std::fs::read_to_string(p).with_context(|| format!("reading {p:?}"))?;
+            Ok(s.trim().parse()?)
+        };
+        let disk = sys
+            .parent()
+            .ok_or_else(|| anyhow!("Failed to find the disk of {device}"))?;
+        // sysfs uses 512 byte sectors, device paths use logical blocks
+        let blocks = read(&disk.join("queue/logical_block_size"))? / 512;
+        Ok(Self {
+            number: read(&sys.join("partition"))?.try_into()?,
+            start: read(&sys.join("start"))? / blocks,
+            size: read(&sys.join("size"))? / blocks,
+            guid: partition_guid(&dev)?,
+        })
+    }
+
+    fn to_node(&self) -> Vec<u8> {
+        let mut r = vec![MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP];
+        r.extend_from_slice(&42u16.to_le_bytes());
+        r.extend_from_slice(&self.number.to_le_bytes());
+        r.extend_from_slice(&self.start.to_le_bytes());
+        r.extend_from_slice(&self.size.to_le_bytes());
+        r.extend_from_slice(&self.guid);
+        r.extend_from_slice(&[HD_MBR_TYPE_GPT, HD_SIGNATURE_GUID]);
+        r
+    }
+
+    /// The partition on the attached disks, if any.
+    pub(crate) fn device(&self) -> Result<Option<PathBuf>> {
+        let link = Path::new("/dev/disk/by-partuuid").join(format_guid(&self.guid));
+        match link.canonicalize() {
+            Ok(dev) => Ok(Some(dev)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("resolving {link:?}")),
+        }
+    }
+
+    fn from_node(data: &[u8]) -> Option<Self> {
+        if data.len() != 38 || data[36] != HD_MBR_TYPE_GPT || data[37] != HD_SIGNATURE_GUID {
+            return None;
+        }
+        Some(Self {
+            number: u32::from_le_bytes(data[0..4].try_into().ok()?),
+            start: u64::from_le_bytes(data[4..12].try_into().ok()?),
+            size: u64::from_le_bytes(data[12..20].try_into().ok()?),
+            guid: data[20..36].try_into().ok()?,
+        })
+    }
+}
+
+/// Find the GPT partition GUID of `dev` from the udev symlinks.
+fn partition_guid(dev: &Path) -> Result<[u8; 16]> {
+    for entry in std::fs::read_dir("/dev/disk/by-partuuid")? {
+        let entry = entry?;
+        if entry.path().canonicalize()? == dev {
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("Invalid partition UUID {name:?}"))?;
+            return parse_guid(name);
+        }
+    }
+    bail!("Failed to find the partition UUID of {dev:?}")
+}
+
+/// Convert a textual GUID to its on-disk format, where the first three
+/// fields are little-endian.
+pub(crate) fn parse_guid(s: &str) -> Result<[u8; 16]> {
+    let hex: String = s.chars().filter(|&c| c != '-').collect();
+    let b = hex::decode(&hex).with_context(|| format!("Invalid GUID {s}"))?;
+    if b.len() != 16 {
+        bail!("Invalid GUID {s}");
+    }
+    let mut r = [0u8; 16];
+    r[0..4].copy_from_slice(&[b[3], b[2], b[1], b[0]]);
+    r[4..6].copy_from_slice(&[b[5], b[4]]);
+    r[6..8].copy_from_slice(&[b[7], b[6]]);
+    r[8..16].copy_from_slice(&b[8..16]);
+    Ok(r)
+}
+
+/// Convert an on-disk GUID to its textual form, as used by udev.
+pub(crate) fn format_guid(g: &[u8; 16]) -> String {
+    format!(
+        "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{}-{}",
+        g[3],
+        g[2],
+        g[1],
+        g[0],
+        g[5],
+        g[4],
+        g[7],
+        g[6],
+        hex::encode(&g[8..10]),
+        hex::encode(&g[10..16])
+    )
+}
+
+fn encode_utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// Decode a nul-terminated UTF-16 string, returning it and the number of
+/// bytes consumed.
+fn decode_utf16(buf: &[u8]) -> Result<(String, usize)> {
+    let units: Vec<u16> = buf
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&u| u != 0)
+        .collect();
+    let len = (units.len() + 1) * 2;
+    if len > buf.len() {
+        bail!("Unterminated UTF-16 string");
+    }
+    Ok((String::from_utf16(&units)?, len))
+}
+
+/// Iterate over the (type, subtype, data) nodes of a device path.
+fn device_path_nodes(mut buf: &[u8]) -> impl Iterator<Item = (u8, u8, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < 4 {
+            return None;
+        }
+        let len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        if len < 4 || len > buf.len() {
+            return None;
+        }
+        let node = (buf[0], buf[1], &buf[4..len]);
+        buf = &buf[len..];
+        (node.0 != END_DEVICE_PATH).then_some(node)
+    })
+}
+
+/// An `EFI_LOAD_OPTION`, the content of a `Boot####` variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LoadOption {
+    pub(crate) attributes: u32,
+    pub(crate) description: String,
+    pub(crate) file_path_list: Vec<u8>,
+    pub(crate) optional_data: Vec<u8>,
+}
+
+impl LoadOption {
+    /// A load option for the file `path` (e.g. `\EFI\fedora\shimx64.efi`)
+    /// on the partition `hd`.
+    pub(crate) fn new(description: &str, hd: &HardDrive, path: &str) -> Self {
+        let mut file_path_list = hd.to_node();
+        let path = encode_utf16(path);
+        file_path_list.extend_from_slice(&[MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP]);
+        file_path_list.extend_from_slice(&(path.len() as u16 + 4).to_le_bytes());
+        file_path_list.extend_from_slice(&path);
+        file_path_list.extend_from_slice(&[END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH, 4, 0]);
+        Self {
+            attributes: LOAD_OPTION_ACTIVE,
+            description: description.to_string(),
+            file_path_list,
+            optional_data: Vec::new(),
+        }
+    }
+
+    pub(crate) fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 6 {
+            bail!("Load option too short");
+        }
+        let attributes = u32::from_le_bytes(buf[0..4].try_into()?);
+        let fpl_len = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+        let (description, desc_len) = decode_utf16(&buf[6..])?;
+        let fpl_start = 6 + desc_len;
+        let Some(file_path_list) = buf.get(fpl_start..fpl_start + fpl_len) else {
+            bail!("Load option file path list too short");
+        };
+        Ok(Self {
+            attributes,
+            description,
+            file_path_list: file_path_list.to_vec(),
+            optional_data: buf[fpl_start + fpl_len..].to_vec(),
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut r = self.attributes.to_le_bytes().to_vec();
+        r.extend_from_slice(&(self.file_path_list.len() as u16).to_le_bytes());
+        r.extend_from_slice(&encode_utf16(&self.description));
+        r.extend_from_slice(&self.file_path_list);
+        r.extend_from_slice(&self.optional_data);
+        r
+    }
+
+    /// The partition the option boots from, if any.
+    pub(crate) fn hard_drive(&self) -> Option<HardDrive> {
+        device_path_nodes(&self.file_path_list)
+            .find(|&(t, s, _)| t == MEDIA_DEVICE_PATH && s == MEDIA_HARDDRIVE_DP)
+            .and_then(|(_, _, data)| HardDrive::from_node(data))
+    }
+
+    /// The file the option boots, if any.
+    pub(crate) fn file_path(&self) -> Option<String> {
+        device_path_nodes(&self.file_path_list)
+            .find(|&(t, s, _)| t == MEDIA_DEVICE_PATH && s == MEDIA_FILEPATH_DP)
+            .and_then(|(_, _, data)| decode_utf16(data).ok())
+            .map(|(s, _)| s)
+    }
+
+    /// Returns `true` if this option boots `path` from the partition `hd`;
+    /// paths in the ESP are case-insensitive.
+    pub(crate) fn boots(&self, hd: &HardDrive, path: &str) -> bool {
+        self.hard_drive().is_some_and(|h| h.guid == hd.guid)
+            && self
+                .file_path()
+                .is_some_and(|p| p.eq_ignore_ascii_case(path))
+    }
+}
+
+/// Ensure there is a `Boot####` entry for `loader` (a path in the ESP like
+/// `\EFI\fedora\shimx64.efi`) on the partition `esp_device`, and that it is
+/// in `BootOrder`; returns the number of the entry if it was created.
+#[context("Ensuring EFI boot entry for {loader}")]
+pub(crate) fn ensure_boot_entry(
+    esp_device: &str,
+    loader: &str,
+    label: &str,
+) -> Result<Option<u16>> {
+    let hd = HardDrive::for_partition(esp_device)?;
+    let entries = boot_entries()?;
+    let existing = entries
+        .iter()
+        .find(|(_, o)| o.boots(&hd, loader))
+        .map(|(num, _)| *num);
+    let (num, created) = match existing {
+        Some(num) => (num, false),
+        None => {
+            // Not from the parsed entries, which would overwrite an
+            // unparsable one
+            let used = boot_numbers()?;
+            let num = (0..=u16::MAX)
+                .find(|n| used.binary_search(n).is_err())
+                .ok_or_else(|| anyhow!("No free boot entry"))?;
+            let option = LoadOption::new(label, &hd, loader);
+            write_var(&boot_var_name(num), &option.to_bytes())?;
+            (num, true)
+        }
+    };
+    let mut order = boot_order()?;
+    if !order.contains(&num) {
+        order.insert(0, num);
+        set_boot_order(&order)?;
+    }
+    Ok(created.then_some(num))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_guid() -> Result<()> {
+        let guid = parse_guid("c12a7328-f81f-11d2-ba4b-00a0c93ec93b")?;
+        assert_eq!(hex::encode(guid), "28732ac11ff8d211ba4b00a0c93ec93b");
+        assert_eq!(format_guid(&guid), "c12a7328-f81f-11d2-ba4b-00a0c93ec93b");
+        assert!(parse_guid("c12a7328").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_var_number() {
+        assert_eq!(boot_var_number("Boot0000"), Some(0));
+        assert_eq!(boot_var_number("Boot00A1"), Some(0xa1));
+        for name in [
+            "BootOrder",
+            "BootNext",
+            "Boot00a1",
+            "Boot+001",
+            "Boot00001",
+            "Boot",
+        ] {
+            assert_eq!(boot_var_number(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_load_option() -> Result<()> {
+        let hd = HardDrive {
+            number: 2,
+            start: 4096,
+            size: 260096,
+            guid: parse_guid("68b2905b-df3e-4fb3-80fa-49d1e773aa33")?,
+        };
+        let o = LoadOption::new("Fedora", &hd, "\\EFI\\fedora\\shimx64.efi");
+        let parsed = LoadOption::parse(&o.to_bytes())?;
+        assert_eq!(parsed, o);
+        assert_eq!(parsed.description, "Fedora");
+        assert_eq!(parsed.hard_drive(), Some(hd.clone()));
+        assert_eq!(
+            parsed.file_path().as_deref(),
+            Some("\\EFI\\fedora\\shimx64.efi")
+        );
+        assert!(parsed.boots(&hd, "\\EFI\\FEDORA\\SHIMX64.EFI"));
+        assert!(!parsed.boots(&hd, "\\EFI\\centos\\shimx64.efi"));
+        assert!(LoadOption::parse(&[1, 0, 0, 0]).is_err());
+        Ok(())
+    }
+}
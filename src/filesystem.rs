@@ -38,3 +38,90 @@ pub(crate) fn inspect_filesystem(root: &openat::Dir, path: &str) -> Result<Files
         .next()
         .ok_or_else(|| anyhow::anyhow!("findmnt returned no data"))
 }
+
+/// An entry of `/proc/self/mountinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MountInfo {
+    pub(crate) mountpoint: String,
+    pub(crate) fstype: String,
+    pub(crate) source: String,
+}
+
+/// Undo the octal escaping of whitespace and backslashes in mountinfo.
+fn unescape_mountinfo(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        r.push_str(&rest[..i]);
+        let code = rest.get(i + 1..i + 4);
+        match code.and_then(|c| u8::from_str_radix(c, 8).ok()) {
+            Some(c) => {
+                r.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                r.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+fn parse_mountinfo(s: &str) -> Result<Vec<MountInfo>> {
+    s.lines()
+        .map(|line| {
+            // The optional fields are terminated by a single hyphen
+            let (mount, fs) = line
+                .split_once(" - ")
+                .ok_or_else(|| anyhow::anyhow!("Invalid mountinfo line: {line}"))?;
+            let mountpoint = mount.split(' ').nth(4);
+            let mut fs = fs.split(' ');
+            match (mountpoint, fs.next(), fs.next()) {
+                (Some(mountpoint), Some(fstype), Some(source)) => Ok(MountInfo {
+                    mountpoint: unescape_mountinfo(mountpoint),
+                    fstype: fstype.to_string(),
+                    source: unescape_mountinfo(source),
+                }),
+                _ => anyhow::bail!("Invalid mountinfo line: {line}"),
+            }
+        })
+        .collect()
+}
+
+/// The mounts of the current mount namespace, in mount order.
+#[context("Reading mountinfo")]
+pub(crate) fn mountinfo() -> Result<Vec<MountInfo>> {
+    parse_mountinfo(&std::fs::read_to_string("/proc/self/mountinfo")?)
+}
+
+/// The filesystem currently visible at `mountpoint`, if it is a mount point.
+pub(crate) fn mount_at<'a>(mounts: &'a [MountInfo], mountpoint: &str) -> Option<&'a MountInfo> {
+    // Later mounts hide earlier ones
+    mounts.iter().rev().find(|m| m.mountpoint == mountpoint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mountinfo() -> Result<()> {
+        let data = "\
+22 1 252:4 / / rw,relatime shared:1 - xfs /dev/vda4 rw,prjquota
+25 22 252:3 / /boot rw,relatime shared:2 - ext4 /dev/vda3 rw
+26 25 252:2 / /boot/efi rw,relatime shared:3 - vfat /dev/vda2 rw,fmask=0077
+27 22 0:40 / /mnt/my\\040esp rw - vfat /dev/vdb1 rw
+28 22 0:41 / /boot rw - tmpfs tmpfs rw
+";
+        let mounts = parse_mountinfo(data)?;
+        assert_eq!(mounts.len(), 5);
+        assert_eq!(mount_at(&mounts, "/boot/efi").unwrap().source, "/dev/vda2");
+        assert_eq!(mount_at(&mounts, "/mnt/my esp").unwrap().fstype, "vfat");
+        assert_eq!(mount_at(&mounts, "/boot").unwrap().source, "tmpfs");
+        assert!(mount_at(&mounts, "/efi").is_none());
+        assert!(parse_mountinfo("22 1 252:4 / /").is_err());
+        Ok(())
+    }
+}
@@ -4,46 +4,230 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+//! The files tracked for a component, and the updates of the directories
+//! holding them.
+//!
+//! An update first stages the changed files, syncs them, and records the
+//! pending swaps in `.btmp.intent.json` before swapping them in.  A vendor
+//! directory with several changed files is copied to `.btmp.<name>`,
+//! updated there and swapped in with a single directory exchange, so that
+//! e.g. shim and GRUB are never found at different versions; a single
+//! changed file is staged next to the one it replaces, which matters on
+//! slow USB or SD card ESPs.  An update interrupted before the intent is
+//! recorded is discarded by the next one, which otherwise completes the
+//! swaps first.
+//!
+//! Files are compared by content, so the 2 second timestamps of FAT never
+//! cause spurious changes, and as FAT is case-insensitive, a file whose name
+//! only changed case is updated in place rather than added then removed.
+//! The SELinux label of a replaced file is kept, rather than the `/usr`
+//! label of the payload.
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use anyhow::{bail, Context, Result};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use camino::{Utf8Path, Utf8PathBuf};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use openat_ext::OpenatDirExt;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-use openssl::hash::{Hasher, MessageDigest};
 use rustix::fd::BorrowedFd;
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::Display;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 use std::os::unix::io::AsRawFd;
-use std::os::unix::process::CommandExt;
-use std::process::Command;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use std::path::Path;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use std::sync::atomic::{AtomicU32, Ordering};
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use std::sync::Mutex;
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::config::SyncPolicy;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::parallel::Job;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+use crate::progress::{Progress, ProgressFn};
 
 /// The prefix we apply to our temporary files.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) const TMP_PREFIX: &str = ".btmp.";
 // This module doesn't handle modes right now, because
 // we're only targeting FAT filesystems for UEFI.
 // In FAT there are no unix permission bits, usually
 // they're set by mount options.
 // See also https://github.com/coreos/fedora-coreos-config/commit/8863c2b34095a2ae5eae6fbbd121768a5f592091
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 const DEFAULT_FILE_MODE: u32 = 0o700;
 
-use crate::sha512string::SHA512String;
+/// The extended attributes recorded for each file and restored on update,
+/// where the filesystems support them: unlike the FAT ESP, e.g. `/boot`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const TRACKED_XATTRS: &[&str] = &["security.selinux", "security.ima"];
+/// The tracked extended attributes kept from the file being replaced rather
+/// than taken from the update payload: the SELinux label of the payload is
+/// that of `/usr`, not of the destination.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const PRESERVED_XATTRS: &[&str] = &["security.selinux"];
 
-/// Metadata for a single file
-#[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq)]
+use crate::digest::{Digest, DigestAlgorithm};
+
+/// Metadata for a single file; files are compared by content only, so the
+/// coarse timestamps of FAT don't matter.
+#[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct FileMetadata {
     /// File size in bytes
     pub(crate) size: u64,
-    /// Content checksum; chose SHA-512 because there are not a lot of files here
-    /// and it's ok if the checksum is large.
-    pub(crate) sha512: SHA512String,
+    /// Content checksum; SHA-512 by default because there are not a lot of
+    /// files here and it's ok if the checksum is large.
+    #[serde(flatten)]
+    pub(crate) digest: Digest,
+    /// The tracked extended attributes, hex encoded; not part of the
+    /// comparisons, which are about the content.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) xattrs: BTreeMap<String, String>,
+}
+
+impl PartialEq for FileMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size && self.digest == other.digest
+    }
+}
+
+impl std::hash::Hash for FileMetadata {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.size.hash(state);
+        self.digest.hash(state);
+    }
+}
+
+/// Read the tracked extended attributes of `fd`; none are found on
+/// filesystems without support for them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn read_xattrs<Fd: rustix::fd::AsFd>(fd: Fd) -> Result<BTreeMap<String, String>> {
+    let mut r = BTreeMap::new();
+    for &name in TRACKED_XATTRS {
+        let mut buf = vec![0u8; 256];
+        let n = match rustix::fs::fgetxattr(&fd, name, &mut buf) {
+            Ok(n) => n,
+            Err(rustix::io::Errno::RANGE) => {
+                buf.resize(rustix::fs::fgetxattr(&fd, name, &mut [])?, 0);
+                rustix::fs::fgetxattr(&fd, name, &mut buf)?
+            }
+            Err(rustix::io::Errno::NODATA | rustix::io::Errno::NOTSUP) => continue,
+            Err(e) => return Err(e).with_context(|| format!("reading {name}")),
+        };
+        r.insert(name.to_string(), hex::encode(&buf[..n]));
+    }
+    Ok(r)
+}
+
+/// Set the extended attributes `xattrs` on `fd`, unless the filesystem
+/// doesn't support them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn write_xattrs<Fd: rustix::fd::AsFd>(fd: Fd, xattrs: &BTreeMap<String, String>) -> Result<()> {
+    for (name, value) in xattrs {
+        let value = hex::decode(value).with_context(|| format!("decoding {name}"))?;
+        match rustix::fs::fsetxattr(&fd, name, &value, rustix::fs::XattrFlags::empty()) {
+            Ok(()) => {}
+            Err(rustix::io::Errno::NOTSUP) => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("setting {name}")),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the extended attributes for the new version of a file, with
+/// `source` those of the update payload, and `replaced` those of the file
+/// it replaces, if any.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn merge_xattrs(
+    source: &BTreeMap<String, String>,
+    replaced: Option<&BTreeMap<String, String>>,
+) -> BTreeMap<String, String> {
+    let mut r: BTreeMap<_, _> = source
+        .iter()
+        .filter(|(k, _)| !PRESERVED_XATTRS.contains(&k.as_str()))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if let Some(replaced) = replaced {
+        for &name in PRESERVED_XATTRS {
+            if let Some(v) = replaced.get(name) {
+                r.insert(name.to_string(), v.clone());
+            }
+        }
+    }
+    r
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
@@ -71,7 +255,58 @@ impl Display for FileTreeDiff {
     }
 }
 
-#[cfg(test)]
+impl FileTreeDiff {
+    /// Describe each change from `current` to `updated`, sorted by path,
+    /// with the sizes and digests of the files.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn describe(&self, current: &FileTree, updated: &FileTree) -> Vec<String> {
+        // A file renamed to a different case is changed under its new name
+        let previous = |k: &str| {
+            current.children.get(k).or_else(|| {
+                let k = k.to_lowercase();
+                current
+                    .children
+                    .iter()
+                    .find(|(p, _)| p.to_lowercase() == k)
+                    .map(|(_, m)| m)
+            })
+        };
+        let mut r = Vec::new();
+        for k in self.additions.iter() {
+            let m = &updated.children[k];
+            r.push((k, format!("Add: {k} ({} bytes, {})", m.size, m.digest)));
+        }
+        for k in self.removals.iter() {
+            let m = &current.children[k];
+            r.push((k, format!("Remove: {k} ({} bytes, {})", m.size, m.digest)));
+        }
+        for k in self.changes.iter() {
+            let new = &updated.children[k];
+            let Some(old) = previous(k) else {
+                continue;
+            };
+            r.push((
+                k,
+                format!(
+                    "Change: {k} ({} -> {} bytes, {} -> {})",
+                    old.size, new.size, old.digest, new.digest
+                ),
+            ));
+        }
+        r.sort();
+        r.into_iter().map(|(_, s)| s).collect()
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 impl FileTreeDiff {
     pub(crate) fn count(&self) -> usize {
         self.additions.len() + self.removals.len() + self.changes.len()
@@ -79,28 +314,40 @@ impl FileTreeDiff {
 }
 
 impl FileMetadata {
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    /// The metadata of the file `name` of `dir`, with its digest computed
+    /// with `algorithm`.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     pub(crate) fn new_from_path<P: openat::AsPath>(
         dir: &openat::Dir,
         name: P,
+        algorithm: DigestAlgorithm,
     ) -> Result<FileMetadata> {
         let mut r = dir.open_file(name)?;
         let meta = r.metadata()?;
-        let mut hasher =
-            Hasher::new(MessageDigest::sha512()).expect("openssl sha512 hasher creation failed");
-        let _ = std::io::copy(&mut r, &mut hasher)?;
-        let digest = SHA512String::from_hasher(&mut hasher);
+        let digest = Digest::compute(algorithm, &mut r)?;
         Ok(FileMetadata {
             size: meta.len(),
-            sha512: digest,
+            digest,
+            xattrs: read_xattrs(&r)?,
         })
     }
 }
 
 impl FileTree {
     // Internal helper to generate a sub-tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    fn unsorted_from_dir(dir: &openat::Dir) -> Result<HashMap<String, FileMetadata>> {
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    fn unsorted_from_dir(
+        dir: &openat::Dir,
+        algorithm: DigestAlgorithm,
+    ) -> Result<HashMap<String, FileMetadata>> {
         let mut ret = HashMap::new();
         for entry in dir.list_dir(".")? {
             let entry = entry?;
@@ -112,12 +359,12 @@ impl FileTree {
             }
             match dir.get_file_type(&entry)? {
                 openat::SimpleType::File => {
-                    let meta = FileMetadata::new_from_path(dir, name)?;
+                    let meta = FileMetadata::new_from_path(dir, name, algorithm)?;
                     let _ = ret.insert(name.to_string(), meta);
                 }
                 openat::SimpleType::Dir => {
                     let child = dir.sub_dir(name)?;
-                    for (mut k, v) in FileTree::unsorted_from_dir(&child)?.drain() {
+                    for (mut k, v) in FileTree::unsorted_from_dir(&child, algorithm)?.drain() {
                         k.reserve(name.len() + 1);
                         k.insert(0, '/');
                         k.insert_str(0, name);
@@ -135,19 +382,48 @@ impl FileTree {
         Ok(ret)
     }
 
-    /// Create a FileTree from the target directory.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub(crate) fn new_from_dir(dir: &openat::Dir) -> Result<Self> {
+    /// Create a FileTree from the target directory, with the digests
+    /// computed with `algorithm`, usually `digest` of the `[update]`
+    /// section of the configuration.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn new_from_dir(dir: &openat::Dir, algorithm: DigestAlgorithm) -> Result<Self> {
         let mut children = BTreeMap::new();
-        for (k, v) in Self::unsorted_from_dir(dir)?.drain() {
+        for (k, v) in Self::unsorted_from_dir(dir, algorithm)?.drain() {
             children.insert(k, v);
         }
 
         Ok(Self { children })
     }
 
+    /// The files of this tree which are present in `dir`, with their
+    /// content there.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn found_in(&self, dir: &openat::Dir) -> Result<Self> {
+        let mut children = BTreeMap::new();
+        for (path, meta) in self.children.iter() {
+            if dir.exists(path.as_str())? {
+                let found =
+                    FileMetadata::new_from_path(dir, path.as_str(), meta.digest.algorithm())?;
+                children.insert(path.clone(), found);
+            }
+        }
+        Ok(Self { children })
+    }
+
     /// Determine the changes *from* self to the updated tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     pub(crate) fn diff(&self, updated: &Self) -> Result<FileTreeDiff> {
         self.diff_impl(updated, true)
     }
@@ -167,7 +443,11 @@ impl FileTree {
         current.diff_impl(self, false)
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     fn diff_impl(&self, updated: &Self, check_additions: bool) -> Result<FileTreeDiff> {
         let mut additions = HashSet::new();
         let mut removals = HashSet::new();
@@ -189,6 +469,22 @@ impl FileTree {
                 }
                 additions.insert(k.clone());
             }
+            // FAT is case-insensitive: a file renamed to a different case
+            // is the same file, which must not be removed after writing it
+            let renamed: HashMap<String, String> = removals
+                .iter()
+                .map(|k| (k.to_lowercase(), k.clone()))
+                .collect();
+            for k in additions.clone() {
+                let Some(old) = renamed.get(&k.to_lowercase()) else {
+                    continue;
+                };
+                removals.remove(old);
+                additions.remove(&k);
+                if self.children[old] != updated.children[&k] {
+                    changes.insert(k);
+                }
+            }
         }
         Ok(FileTreeDiff {
             additions,
@@ -199,7 +495,11 @@ impl FileTree {
 
     /// Create a diff from a target directory.  This will ignore
     /// any files or directories that are not part of the original tree.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
     pub(crate) fn relative_diff_to(&self, dir: &openat::Dir) -> Result<FileTreeDiff> {
         let mut removals = HashSet::new();
         let mut changes = HashSet::new();
@@ -210,7 +510,8 @@ impl FileTree {
             if let Some(meta) = dir.metadata_optional(path)? {
                 match meta.simple_type() {
                     openat::SimpleType::File => {
-                        let target_info = FileMetadata::new_from_path(dir, path)?;
+                        let target_info =
+                            FileMetadata::new_from_path(dir, path, info.digest.algorithm())?;
                         if info != &target_info {
                             changes.insert(path.clone());
                         }
@@ -230,10 +531,73 @@ impl FileTree {
             changes,
         })
     }
+
+    /// The files under `dir` which are not part of this tree, sorted.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn untracked_in(&self, dir: &openat::Dir) -> Result<Vec<String>> {
+        fn walk(
+            tree: &FileTree,
+            dir: &openat::Dir,
+            prefix: &str,
+            r: &mut Vec<String>,
+        ) -> Result<()> {
+            for entry in dir.list_dir(".")? {
+                let entry = entry?;
+                let Some(name) = entry.file_name().to_str() else {
+                    bail!("Invalid UTF-8 filename: {:?}", entry.file_name())
+                };
+                let path = format!("{prefix}{name}");
+                if matches!(dir.get_file_type(&entry)?, openat::SimpleType::Dir) {
+                    walk(tree, &dir.sub_dir(name)?, &format!("{path}/"), r)?;
+                } else if !tree.children.contains_key(&path) {
+                    r.push(path);
+                }
+            }
+            Ok(())
+        }
+        let mut r = Vec::new();
+        walk(self, dir, "", &mut r)?;
+        r.sort();
+        Ok(r)
+    }
+
+    /// Copy the files of this tree from `srcdir` to `destdir`, creating
+    /// the intermediate directories; files missing in `srcdir` are skipped.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    pub(crate) fn copy_files(&self, srcdir: &openat::Dir, destdir: &openat::Dir) -> Result<()> {
+        for path in self.children.keys() {
+            if !srcdir.exists(path.as_str())? {
+                log::debug!("Not copying missing {path}");
+                continue;
+            }
+            if let Some(parent) = Utf8Path::new(path)
+                .parent()
+                .filter(|p| !p.as_str().is_empty())
+            {
+                destdir.ensure_dir_all(parent.as_std_path(), 0o700)?;
+            }
+            srcdir
+                .copy_file_at(path.as_str(), destdir, path.as_str())
+                .with_context(|| format!("copying {path}"))?;
+        }
+        Ok(())
+    }
 }
 
 // Recursively remove all files/dirs in the directory that start with our TMP_PREFIX
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
     for entry in dir.list_dir(".")? {
         let entry = entry?;
@@ -263,18 +627,224 @@ fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
     Ok(())
 }
 
+/// The temporary files and directories (with our `TMP_PREFIX`) under
+/// `dir`, left by an interrupted update, sorted.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn find_leftovers(dir: &openat::Dir) -> Result<Vec<String>> {
+    fn walk(dir: &openat::Dir, prefix: &str, r: &mut Vec<String>) -> Result<()> {
+        for entry in dir.list_dir(".")? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            let path = format!("{prefix}{name}");
+            if name.starts_with(TMP_PREFIX) {
+                r.push(path);
+            } else if matches!(dir.get_file_type(&entry)?, openat::SimpleType::Dir) {
+                walk(&dir.sub_dir(name)?, &format!("{path}/"), r)?;
+            }
+        }
+        Ok(())
+    }
+    let mut r = Vec::new();
+    walk(dir, "", &mut r)?;
+    r.sort();
+    Ok(r)
+}
+
+/// Complete or roll back an update of `destdir` interrupted e.g. by a
+/// crash, removing its temporary files; returns them.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn clean_leftovers(destdir: &openat::Dir) -> Result<Vec<String>> {
+    let leftovers = find_leftovers(destdir)?;
+    if leftovers.is_empty() {
+        return Ok(leftovers);
+    }
+    recover_interrupted(destdir).context("recovering interrupted update")?;
+    cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    log::warn!(
+        "Removed the temporary files of an interrupted update: {}",
+        leftovers.join(", ")
+    );
+    Ok(leftovers)
+}
+
 #[derive(Default, Clone)]
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-pub(crate) struct ApplyUpdateOptions {
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) struct ApplyUpdateOptions<'a> {
     pub(crate) skip_removals: bool,
     pub(crate) skip_sync: bool,
+    /// How the writes are made durable, instead of the configured policy
+    pub(crate) sync_policy: Option<SyncPolicy>,
+    /// Called after each file written
+    pub(crate) progress: Option<ProgressFn<'a>>,
+    /// Faults to inject, instead of those of `FAULT_INJECTION_FAILPOINT`
+    pub(crate) faults: Option<&'a FaultInjection>,
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl ApplyUpdateOptions<'_> {
+    /// The sync policy, or `None` if the syncs are skipped.
+    fn policy(&self) -> Option<SyncPolicy> {
+        if self.skip_sync || self.faults.is_some_and(|f| f.skip_sync) {
+            return None;
+        }
+        Some(self.sync_policy.unwrap_or_default())
+    }
+
+    /// Make the writes durable before the next step of the update, with
+    /// the `syncfs` policy.
+    fn barrier(&self, destdir: &openat::Dir) -> Result<()> {
+        match self.policy() {
+            Some(SyncPolicy::Syncfs) => syncfs(destdir),
+            _ => Ok(()),
+        }
+    }
+
+    /// Make the writes durable at the end of the update; with the `fsync`
+    /// policy, they already are.
+    fn sync(&self, destdir: &openat::Dir) -> Result<()> {
+        match self.policy() {
+            Some(SyncPolicy::Syncfs | SyncPolicy::End) => syncfs(destdir),
+            _ => Ok(()),
+        }
+    }
+
+    /// With the `fsync` policy, make the file `path` of `destdir` durable,
+    /// and its entry in its directory.
+    fn fsync_file(&self, destdir: &openat::Dir, path: &str) -> Result<()> {
+        if self.policy() != Some(SyncPolicy::Fsync) {
+            return Ok(());
+        }
+        fsync_at(destdir, path)?;
+        self.fsync_parent(destdir, path)
+    }
+
+    /// With the `fsync` policy, make the directory entry of `path`, renamed
+    /// or removed, durable.
+    fn fsync_parent(&self, destdir: &openat::Dir, path: &str) -> Result<()> {
+        if self.policy() != Some(SyncPolicy::Fsync) {
+            return Ok(());
+        }
+        match Utf8Path::new(path).parent().map(Utf8Path::as_str) {
+            Some(parent) if !parent.is_empty() => fsync_at(destdir, parent),
+            _ => fsync_at(destdir, "."),
+        }
+    }
+
+    /// Count a file written, renamed or removed, for the injected faults.
+    fn written(&self) -> Result<()> {
+        match self.faults {
+            Some(faults) => faults.written(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The failpoint configuring the faults injected in the writes of updates,
+/// e.g. `FAILPOINTS='update::faults=return(abort-after=3,skip-sync)'`, for
+/// crash-safety testing.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const FAULT_INJECTION_FAILPOINT: &str = "update::faults";
+
+/// Faults injected in the writes of an update, to check that it can be
+/// interrupted at any point, as by a power loss.
+#[derive(Debug, Default)]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) struct FaultInjection {
+    /// Stop once this many files were written, renamed or removed
+    pub(crate) abort_after: Option<u32>,
+    /// Abort the process rather than fail, so that nothing is cleaned up
+    pub(crate) abort_process: bool,
+    /// Don't sync the filesystems, as if the writes were lost
+    pub(crate) skip_sync: bool,
+    writes: AtomicU32,
 }
 
-// syncfs() is a Linux-specific system call, which doesn't seem
-// to be bound in nix today.  I found https://github.com/XuShaohua/nc
-// but that's a nontrivial dependency with not a lot of code review.
-// Let's just fork off a helper process for now.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl FaultInjection {
+    /// Parse a comma-separated list of `abort-after=N` and `skip-sync`.
+    fn parse(s: &str) -> Result<Self> {
+        let mut r = Self {
+            abort_process: true,
+            ..Default::default()
+        };
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            match item.split_once('=') {
+                Some(("abort-after", n)) => {
+                    let n: u32 = n.parse().with_context(|| format!("parsing {item}"))?;
+                    if n == 0 {
+                        bail!("abort-after must be at least 1");
+                    }
+                    r.abort_after = Some(n);
+                }
+                None if item == "skip-sync" => r.skip_sync = true,
+                _ => bail!("Unknown fault {item}"),
+            }
+        }
+        Ok(r)
+    }
+
+    /// The faults configured with `FAULT_INJECTION_FAILPOINT`, if any.
+    fn from_failpoint() -> Result<Option<Self>> {
+        let Some(s) = fail::eval(FAULT_INJECTION_FAILPOINT, |arg| arg.unwrap_or_default()) else {
+            return Ok(None);
+        };
+        let r = Self::parse(&s)
+            .with_context(|| format!("parsing failpoint {FAULT_INJECTION_FAILPOINT}"))?;
+        log::warn!("Injecting faults in updates: {r:?}");
+        Ok(Some(r))
+    }
+
+    fn written(&self) -> Result<()> {
+        let n = self.writes.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.abort_after {
+            Some(max) if n >= max => {
+                if self.abort_process {
+                    eprintln!("Injected fault: aborting after {n} writes");
+                    std::process::abort();
+                }
+                bail!("Injected fault after {n} writes")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sync the filesystem holding `d`, with syncfs().
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
     use rustix::fs::{Mode, OFlags};
     let d = unsafe { BorrowedFd::borrow_raw(d.as_raw_fd()) };
@@ -283,134 +853,640 @@ pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
     rustix::fs::syncfs(d).map_err(Into::into)
 }
 
-/// Copy from src to dst at root dir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-fn copy_dir(root: &openat::Dir, src: &str, dst: &str) -> Result<()> {
-    use bootc_utils::CommandRunExt;
+/// fsync the file or directory `path` of `d`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn fsync_at(d: &openat::Dir, path: &str) -> Result<()> {
+    use rustix::fs::{Mode, OFlags};
+    let d = unsafe { BorrowedFd::borrow_raw(d.as_raw_fd()) };
+    let fd = rustix::fs::openat(d, path, OFlags::RDONLY | OFlags::CLOEXEC, Mode::empty())
+        .with_context(|| format!("opening {path}"))?;
+    rustix::fs::fsync(fd).with_context(|| format!("syncing {path}"))
+}
 
-    let rootfd = unsafe { BorrowedFd::borrow_raw(root.as_raw_fd()) };
-    unsafe {
-        Command::new("cp")
-            .args(["-a"])
-            .arg(src)
-            .arg(dst)
-            .pre_exec(move || rustix::process::fchdir(rootfd).map_err(Into::into))
-            .run()?
-    };
-    log::debug!("Copy {src} to {dst}");
-    Ok(())
+/// The sync policy configured with `sync` in the `[update]` section of
+/// `/etc/bootupd/config.toml`, loaded once.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn configured_sync_policy() -> SyncPolicy {
+    static POLICY: std::sync::OnceLock<SyncPolicy> = std::sync::OnceLock::new();
+    *POLICY.get_or_init(
+        || match crate::config::Config::load(std::path::Path::new("/")) {
+            Ok(config) => config.update.sync,
+            Err(e) => {
+                log::warn!("Using the default sync policy: {e:#}");
+                SyncPolicy::default()
+            }
+        },
+    )
 }
 
-/// Get first sub dir and tmp sub dir for the path
-/// "fedora/foo/bar" -> ("fedora", ".btmp.fedora")
-/// "foo" -> ("foo", ".btmp.foo")
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-fn get_first_dir(path: &Utf8Path) -> Result<(&Utf8Path, String)> {
-    let first = path
-        .iter()
-        .next()
+/// The temporary path a new file is staged at, next to the file it replaces:
+/// "fedora/foo/bar" -> "fedora/foo/.btmp.bar"
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn tmp_path(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let name = path
+        .file_name()
         .ok_or_else(|| anyhow::anyhow!("Invalid path: {path}"))?;
-    let mut tmp = first.to_owned();
-    tmp.insert_str(0, TMP_PREFIX);
-    Ok((first.into(), tmp))
+    Ok(path.with_file_name(format!("{TMP_PREFIX}{name}")))
 }
 
-/// Given two directories, apply a diff generated from srcdir to destdir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-pub(crate) fn apply_diff(
+/// Round `size` up to a multiple of the filesystem block size.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn round_up(size: u64, block: u64) -> u64 {
+    size.div_ceil(block) * block
+}
+
+/// Size of a regular file relative to `dir`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn file_size<P: rustix::path::Arg>(dir: &openat::Dir, path: P) -> Result<u64> {
+    let fd = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
+    let st = rustix::fs::statat(fd, path, rustix::fs::AtFlags::SYMLINK_NOFOLLOW)?;
+    Ok(st.st_size as u64)
+}
+
+/// Space allocated to the files under `dir`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn disk_usage(dir: &openat::Dir, block: u64) -> Result<u64> {
+    let mut r = 0;
+    for entry in dir.list_dir(".")? {
+        let entry = entry?;
+        match dir.get_file_type(&entry)? {
+            openat::SimpleType::Dir => r += disk_usage(&dir.sub_dir(entry.file_name())?, block)?,
+            openat::SimpleType::File => r += round_up(file_size(dir, entry.file_name())?, block),
+            _ => {}
+        }
+    }
+    Ok(r)
+}
+
+/// The space `apply_diff` needs on the filesystem of `destdir`: the new
+/// files are written next to the old ones, or into a copy of their
+/// directory (see `staged_dirs`), which are only removed once all of them
+/// are swapped in.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn required_space(
     srcdir: &openat::Dir,
     destdir: &openat::Dir,
     diff: &FileTreeDiff,
-    opts: Option<&ApplyUpdateOptions>,
+    opts: &ApplyUpdateOptions,
+    block: u64,
+) -> Result<u64> {
+    let mut r = 0;
+    for dir in staged_dirs(diff, opts) {
+        if destdir.exists(dir)? {
+            r += disk_usage(&destdir.sub_dir(dir)?, block)?;
+        }
+    }
+    for pathstr in diff.changes.iter().chain(diff.additions.iter()) {
+        r += round_up(file_size(srcdir, pathstr.as_str())?, block);
+    }
+    Ok(r)
+}
+
+/// Fail early if the filesystem of `destdir` doesn't have enough free space
+/// to apply `diff`, rather than leaving a partially written directory.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn check_free_space(
+    srcdir: &openat::Dir,
+    destdir: &openat::Dir,
+    diff: &FileTreeDiff,
+    opts: &ApplyUpdateOptions,
 ) -> Result<()> {
-    let default_opts = ApplyUpdateOptions {
-        ..Default::default()
-    };
-    let opts = opts.unwrap_or(&default_opts);
-    cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    const MIB: f64 = (1024 * 1024) as f64;
+    let fd = unsafe { BorrowedFd::borrow_raw(destdir.as_raw_fd()) };
+    let st = rustix::fs::fstatvfs(fd)?;
+    let block = st.f_frsize.max(1);
+    let needed = required_space(srcdir, destdir, diff, opts, block)?;
+    let available = st.f_bavail * st.f_frsize;
+    log::debug!("Update needs {needed} bytes, {available} available");
+    if needed > available {
+        bail!(
+            "Not enough free space: need {:.1} MiB free, have {:.1} MiB",
+            needed as f64 / MIB,
+            available as f64 / MIB
+        );
+    }
+    Ok(())
+}
 
-    let mut updates = HashMap::new();
-    // Handle removals in temp dir, or remove directly if file not in dir
-    if !opts.skip_removals {
-        for pathstr in diff.removals.iter() {
-            let path = Utf8Path::new(pathstr);
-            let (first_dir, first_dir_tmp) = get_first_dir(path)?;
-            let path_tmp;
-            if first_dir != path {
-                path_tmp = Utf8Path::new(&first_dir_tmp).join(path.strip_prefix(&first_dir)?);
-                // copy to temp dir and remember
-                // skip copying if dir not existed in dest
-                if !destdir.exists(&first_dir_tmp)? && destdir.exists(first_dir.as_std_path())? {
-                    copy_dir(destdir, first_dir.as_str(), &first_dir_tmp).with_context(|| {
-                        format!("copy {first_dir} to {first_dir_tmp} before removing {pathstr}")
-                    })?;
-                    updates.insert(first_dir, first_dir_tmp);
-                }
-            } else {
-                path_tmp = path.to_path_buf();
+/// Written to destdir once the new content is staged and synced, and
+/// removed once it is swapped in; if it exists, the update was interrupted
+/// while swapping and is completed by the next one.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const INTENT_FILE: &str = ".btmp.intent.json";
+/// Created in each staged copy of a directory, to tell whether it was
+/// swapped in.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+const STAGED_MARKER: &str = ".btmp.staged";
+
+/// The pending swaps of an update, see `INTENT_FILE`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+struct Intent {
+    /// Maps a file or top-level directory of destdir to its staged copy
+    exchanges: BTreeMap<String, String>,
+    /// The new content of the staged files, including those of the staged
+    /// directories
+    files: BTreeMap<String, FileMetadata>,
+    /// Files to remove
+    removals: Vec<String>,
+}
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl Intent {
+    fn write(&self, destdir: &openat::Dir, opts: &ApplyUpdateOptions) -> Result<()> {
+        destdir
+            .write_file_with(INTENT_FILE, DEFAULT_FILE_MODE, |w| -> Result<_> {
+                Ok(serde_json::to_writer(w, self)?)
+            })
+            .context("writing update intent")?;
+        opts.fsync_file(destdir, INTENT_FILE)?;
+        opts.written()
+    }
+
+    /// Re-read the staged files from the disk and check their content, to
+    /// catch silent write failures of e.g. flaky SD cards or USB sticks
+    /// before anything is swapped in.  They are first dropped from the page
+    /// cache, which only drops written back pages, so this must follow a
+    /// sync.
+    fn verify_staged(&self, destdir: &openat::Dir) -> Result<()> {
+        for (dst, expected) in self.files.iter() {
+            let Some(tmp) = self.staged_path(dst) else {
+                bail!("No staged copy of {dst}");
+            };
+            let tmp = tmp.as_str();
+            let f = destdir.open_file(tmp)?;
+            rustix::fs::fadvise(&f, 0, 0, rustix::fs::Advice::DontNeed)
+                .with_context(|| format!("dropping cached pages of {tmp}"))?;
+            let found = FileMetadata::new_from_path(destdir, tmp, expected.digest.algorithm())?;
+            if &found != expected {
+                bail!("Read-back of {dst} doesn't match what was written; failing storage?");
             }
+        }
+        Ok(())
+    }
+
+    /// Where the new content of the file `dst` is staged: next to it, or in
+    /// the staged copy of its top-level directory.
+    fn staged_path(&self, dst: &str) -> Option<String> {
+        if let Some(tmp) = self.exchanges.get(dst) {
+            return Some(tmp.clone());
+        }
+        let (first, rest) = dst.split_once('/')?;
+        let tmp = self.exchanges.get(first)?;
+        Some(format!("{tmp}/{rest}"))
+    }
+
+    /// Whether the staged copy of `dst` was already swapped in.
+    fn is_swapped(&self, destdir: &openat::Dir, dst: &str) -> Result<bool> {
+        if let Some(meta) = self.files.get(dst) {
+            if !destdir.exists(dst)? {
+                return Ok(false);
+            }
+            let found = FileMetadata::new_from_path(destdir, dst, meta.digest.algorithm())?;
+            return Ok(&found == meta);
+        }
+        destdir
+            .exists(&Path::new(dst).join(STAGED_MARKER))
+            .map_err(Into::into)
+    }
+
+    /// Swap in the staged content which is not yet.
+    fn complete(&self, destdir: &openat::Dir, opts: &ApplyUpdateOptions) -> Result<()> {
+        for path in self.removals.iter() {
             destdir
-                .remove_file_optional(path_tmp.as_std_path())
-                .with_context(|| format!("removing {:?}", path_tmp))?;
+                .remove_file_optional(path.as_str())
+                .with_context(|| format!("removing {:?}", path))?;
+            opts.fsync_parent(destdir, path)?;
+            opts.written()?;
+        }
+        for (dst, tmp) in self.exchanges.iter() {
+            if !destdir.exists(tmp.as_str())? || self.is_swapped(destdir, dst)? {
+                continue;
+            }
+            log::trace!("doing local exchange for {} and {:?}", tmp, dst);
+            if destdir.exists(dst.as_str())? {
+                destdir
+                    .local_exchange(tmp.as_str(), dst.as_str())
+                    .with_context(|| format!("exchange for {} and {:?}", tmp, dst))?;
+            } else {
+                destdir
+                    .local_rename(tmp.as_str(), dst.as_str())
+                    .with_context(|| format!("rename for {} and {:?}", tmp, dst))?;
+            }
+            opts.fsync_parent(destdir, dst)?;
+            crate::try_fail_point!("update::exchange");
+            opts.written()?;
+        }
+        Ok(())
+    }
+}
+
+/// Complete an update interrupted while swapping in the new content; its
+/// content was entirely staged and synced, so it is rolled forward.
+/// Updates interrupted earlier are rolled back by discarding the staged
+/// content in `cleanup_tmp`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn recover_interrupted(destdir: &openat::Dir) -> Result<()> {
+    let Some(f) = destdir.open_file_optional(INTENT_FILE)? else {
+        return Ok(());
+    };
+    let intent: Intent = match serde_json::from_reader(std::io::BufReader::new(f)) {
+        Ok(intent) => intent,
+        Err(e) => {
+            // The intent is written atomically, so this should not happen
+            log::warn!("Ignoring invalid update intent: {e}");
+            return Ok(());
+        }
+    };
+    log::info!("Completing interrupted update");
+    intent.complete(destdir, &ApplyUpdateOptions::default())?;
+    syncfs(destdir)?;
+    Ok(())
+}
+
+/// The top-level directories of `destdir` where `diff` modifies more than
+/// one file: they are staged as a whole and swapped in with a single
+/// exchange, so that e.g. shim and GRUB are never seen at different
+/// versions; the single-file deltas are staged next to the file.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn staged_dirs<'a>(diff: &'a FileTreeDiff, opts: &ApplyUpdateOptions) -> BTreeSet<&'a str> {
+    let removals = diff.removals.iter().filter(|_| !opts.skip_removals);
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for path in diff
+        .changes
+        .iter()
+        .chain(diff.additions.iter())
+        .chain(removals)
+    {
+        if let Some((first, _)) = path.split_once('/') {
+            *counts.entry(first).or_default() += 1;
         }
     }
-    // Write changed or new files to temp dir or temp file
+    counts
+        .into_iter()
+        .filter_map(|(dir, n)| (n > 1).then_some(dir))
+        .collect()
+}
+
+/// Copy the directory `src` of `destdir` to `dst`, except the files in
+/// `skip`, which are replaced or removed.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn copy_tree(
+    destdir: &openat::Dir,
+    src: &Utf8Path,
+    dst: &Utf8Path,
+    skip: &HashSet<&str>,
+    opts: &ApplyUpdateOptions,
+) -> Result<()> {
+    destdir.ensure_dir_all(dst.as_std_path(), DEFAULT_FILE_MODE)?;
+    let srcdir = destdir.sub_dir(src.as_std_path())?;
+    for entry in srcdir.list_dir(".")? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str() else {
+            bail!("Invalid UTF-8 filename: {:?}", entry.file_name());
+        };
+        let (src, dst) = (src.join(name), dst.join(name));
+        match srcdir.get_file_type(&entry)? {
+            openat::SimpleType::Dir => copy_tree(destdir, &src, &dst, skip, opts)?,
+            openat::SimpleType::File if !skip.contains(src.as_str()) => {
+                destdir
+                    .copy_file_at(src.as_std_path(), destdir, dst.as_std_path())
+                    .with_context(|| format!("copying {src} to {dst}"))?;
+                let xattrs = read_xattrs(&destdir.open_file(src.as_std_path())?)?;
+                write_xattrs(&destdir.open_file(dst.as_std_path())?, &xattrs)
+                    .with_context(|| format!("setting extended attributes of {dst}"))?;
+                opts.fsync_file(destdir, dst.as_str())?;
+                opts.written()?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The new files of a diff staged in `destdir`, ready to be exchanged with
+/// the originals.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+struct PreparedDiff {
+    intent: Intent,
+}
+/// Write the changed and added files of a diff next to the files they
+/// replace, or into a copy of their top-level directory if it has several
+/// of them, without modifying the existing content of destdir, which must
+/// have no interrupted update or temporary files left.  Single-file deltas
+/// don't copy the rest of their directory, which matters on slow media
+/// where e.g. only GRUB changes.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn prepare_diff(
+    srcdir: &openat::Dir,
+    destdir: &openat::Dir,
+    diff: &FileTreeDiff,
+    opts: &ApplyUpdateOptions,
+    progress: &Mutex<Progress>,
+) -> Result<PreparedDiff> {
+    check_free_space(srcdir, destdir, diff, opts).context("checking free space")?;
+
+    let staged = staged_dirs(diff, opts);
+    let in_staged_dir = |path: &str| {
+        path.split_once('/')
+            .is_some_and(|(first, _)| staged.contains(first))
+    };
+    let mut intent = Intent::default();
+    let mut skip: HashSet<&str> = diff.changes.iter().map(String::as_str).collect();
+    if !opts.skip_removals {
+        skip.extend(diff.removals.iter().map(String::as_str));
+        intent.removals = diff
+            .removals
+            .iter()
+            .filter(|p| !in_staged_dir(p))
+            .cloned()
+            .collect();
+        intent.removals.sort();
+    }
+    for &dir in staged.iter() {
+        let tmp = Utf8PathBuf::from(format!("{TMP_PREFIX}{dir}"));
+        if destdir.exists(dir)? {
+            copy_tree(destdir, Utf8Path::new(dir), &tmp, &skip, opts)
+                .with_context(|| format!("copying {dir} to {tmp}"))?;
+        } else {
+            destdir.ensure_dir_all(tmp.as_std_path(), DEFAULT_FILE_MODE)?;
+        }
+        let marker = tmp.join(STAGED_MARKER);
+        destdir.write_file(marker.as_std_path(), DEFAULT_FILE_MODE)?;
+        opts.fsync_file(destdir, marker.as_str())?;
+        intent.exchanges.insert(dir.to_string(), tmp.into_string());
+    }
     for pathstr in diff.changes.iter().chain(diff.additions.iter()) {
         let path = Utf8Path::new(pathstr);
-        let (first_dir, first_dir_tmp) = get_first_dir(path)?;
-        let mut path_tmp = Utf8PathBuf::from(&first_dir_tmp);
-        if first_dir != path {
-            if !destdir.exists(&first_dir_tmp)? && destdir.exists(first_dir.as_std_path())? {
-                // copy to temp dir if not exists
-                copy_dir(destdir, first_dir.as_str(), &first_dir_tmp).with_context(|| {
-                    format!("copy {first_dir} to {first_dir_tmp} before updating {pathstr}")
-                })?;
-            }
-            path_tmp = path_tmp.join(path.strip_prefix(&first_dir)?);
-            // ensure new additions dir exists
-            if let Some(parent) = path_tmp.parent() {
-                destdir.ensure_dir_all(parent.as_std_path(), DEFAULT_FILE_MODE)?;
-            }
-            // remove changed file before copying
-            destdir
-                .remove_file_optional(path_tmp.as_std_path())
-                .with_context(|| format!("removing {path_tmp} before copying"))?;
+        let path_tmp = match pathstr.split_once('/') {
+            Some((first, rest)) if staged.contains(first) => {
+                Utf8PathBuf::from(format!("{TMP_PREFIX}{first}")).join(rest)
+            }
+            _ => tmp_path(path)?,
+        };
+        // ensure new additions dir exists
+        if let Some(parent) = path_tmp.parent().filter(|p| !p.as_str().is_empty()) {
+            destdir.ensure_dir_all(parent.as_std_path(), DEFAULT_FILE_MODE)?;
         }
-        updates.insert(first_dir, first_dir_tmp);
         srcdir
             .copy_file_at(path.as_std_path(), destdir, path_tmp.as_std_path())
             .with_context(|| format!("copying {:?} to {:?}", path, path_tmp))?;
+        // The copy has the default SELinux label of the directory, and no
+        // IMA signature
+        let replaced = match destdir.open_file_optional(path.as_std_path())? {
+            Some(f) => Some(read_xattrs(&f)?),
+            None => None,
+        };
+        let source = read_xattrs(&srcdir.open_file(path.as_std_path())?)?;
+        let xattrs = merge_xattrs(&source, replaced.as_ref());
+        write_xattrs(&destdir.open_file(path_tmp.as_std_path())?, &xattrs)
+            .with_context(|| format!("setting extended attributes of {path}"))?;
+        opts.fsync_file(destdir, path_tmp.as_str())?;
+        if let Some(f) = opts.progress {
+            let size = file_size(srcdir, path.as_std_path())?;
+            let mut progress = progress.lock().unwrap();
+            progress.files_done += 1;
+            progress.bytes_done += size;
+            f(&progress);
+        }
+        // The source is faster to read back than e.g. an SD card; the
+        // digest is only compared with that of the staged copy
+        let meta = FileMetadata::new_from_path(srcdir, path.as_str(), DigestAlgorithm::default())?;
+        opts.written()?;
+        intent.files.insert(pathstr.clone(), meta);
+        if !in_staged_dir(pathstr) {
+            intent
+                .exchanges
+                .insert(pathstr.clone(), path_tmp.into_string());
+        }
     }
+    // Before any target is modified, so that a bad write leaves them all
+    // unchanged
+    opts.barrier(destdir)?;
+    intent
+        .verify_staged(destdir)
+        .context("verifying written files")?;
+    Ok(PreparedDiff { intent })
+}
 
-    // do local exchange or rename
-    for (dst, tmp) in updates.iter() {
-        let dst = dst.as_std_path();
-        log::trace!("doing local exchange for {} and {:?}", tmp, dst);
-        if destdir.exists(dst)? {
-            destdir
-                .local_exchange(tmp, dst)
-                .with_context(|| format!("exchange for {} and {:?}", tmp, dst))?;
-        } else {
-            destdir
-                .local_rename(tmp, dst)
-                .with_context(|| format!("rename for {} and {:?}", tmp, dst))?;
-        }
-        crate::try_fail_point!("update::exchange");
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+impl PreparedDiff {
+    /// Replace the original content of destdir with the prepared one.
+    fn commit(self, destdir: &openat::Dir, opts: &ApplyUpdateOptions) -> Result<()> {
+        // Ensure the staged content is on disk before recording the intent
+        // to swap it in, and the intent before swapping
+        opts.barrier(destdir)?;
+        self.intent.write(destdir, opts)?;
+        opts.barrier(destdir)?;
+        self.intent.complete(destdir, opts)?;
+        // Ensure all of the updates & changes are written persistently to disk
+        opts.barrier(destdir)?;
+
+        // finally remove the previous content, the markers and the intent
+        cleanup_tmp(destdir).context("clean up temp")?;
+        // A second full filesystem sync to narrow any races rather than
+        // waiting for writeback to kick in.
+        opts.sync(destdir)?;
+        Ok(())
     }
-    // Ensure all of the updates & changes are written persistently to disk
-    if !opts.skip_sync {
-        syncfs(destdir)?;
+}
+
+/// Fail if some of the files written by `diff` only differ by case, which
+/// would be the same file on FAT.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn check_case_collisions(diff: &FileTreeDiff) -> Result<()> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    let mut paths: Vec<_> = diff.changes.iter().chain(diff.additions.iter()).collect();
+    paths.sort();
+    for path in paths {
+        if let Some(other) = seen.insert(path.to_lowercase(), path) {
+            bail!("Files {other} and {path} only differ by case");
+        }
     }
+    Ok(())
+}
 
-    // finally remove the temp dir
-    for (_, tmp) in updates.iter() {
-        log::trace!("cleanup: {}", tmp);
-        destdir.remove_all(tmp).context("clean up temp")?;
+/// Given two directories, apply a diff generated from srcdir to destdir
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn apply_diff(
+    srcdir: &openat::Dir,
+    destdir: &openat::Dir,
+    diff: &FileTreeDiff,
+    opts: Option<&ApplyUpdateOptions>,
+) -> Result<()> {
+    apply_diffs(srcdir, &[(destdir, diff)], opts)
+}
+
+/// Apply diffs generated from srcdir to several directories, e.g. mirrored
+/// ESPs.  The new content is first written to all of them concurrently, so
+/// that a failure at this point (e.g. running out of space) leaves all of
+/// them unchanged; it is then swapped in sequentially, so that there is
+/// always at least one of them either entirely old or entirely new.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+pub(crate) fn apply_diffs(
+    srcdir: &openat::Dir,
+    targets: &[(&openat::Dir, &FileTreeDiff)],
+    opts: Option<&ApplyUpdateOptions>,
+) -> Result<()> {
+    let default_opts = ApplyUpdateOptions {
+        ..Default::default()
+    };
+    let opts = opts.unwrap_or(&default_opts);
+    let env_faults = match opts.faults {
+        Some(_) => None,
+        None => FaultInjection::from_failpoint()?,
+    };
+    let sync_policy = match opts.sync_policy {
+        Some(p) => Some(p),
+        None if opts.skip_sync => None,
+        None => Some(configured_sync_policy()),
+    };
+    let opts = &ApplyUpdateOptions {
+        faults: opts.faults.or(env_faults.as_ref()),
+        sync_policy,
+        ..opts.clone()
+    };
+    for (_, diff) in targets {
+        check_case_collisions(diff)?;
     }
-    // A second full filesystem sync to narrow any races rather than
-    // waiting for writeback to kick in.
-    if !opts.skip_sync {
-        syncfs(destdir)?;
+    let mut progress = Progress::default();
+    if opts.progress.is_some() {
+        for (_, diff) in targets {
+            for path in diff.changes.iter().chain(diff.additions.iter()) {
+                progress.files_total += 1;
+                progress.bytes_total += file_size(srcdir, path.as_str())?;
+            }
+        }
+    }
+    for (destdir, _) in targets {
+        clean_leftovers(destdir)?;
+    }
+    // The targets are distinct filesystems, written concurrently
+    let progress = Mutex::new(progress);
+    let jobs = targets
+        .iter()
+        .map(|&(destdir, diff)| {
+            let progress = &progress;
+            Job::new([], move || {
+                prepare_diff(srcdir, destdir, diff, opts, progress)
+            })
+        })
+        .collect();
+    let prepared = match crate::parallel::run(jobs)
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            for (destdir, _) in targets {
+                if let Err(e) = cleanup_tmp(destdir) {
+                    log::warn!("Failed to clean up temporary files: {e:#}");
+                }
+            }
+            return Err(e);
+        }
+    };
+    // Swapped in one target after the other, each durable before the next
+    // is touched, so that an interruption leaves at most one of them
+    // partially updated, completed by the next update
+    let mut prepared = prepared.into_iter().zip(targets);
+    while let Some((p, &(destdir, _))) = prepared.next() {
+        if let Err(e) = p.commit(destdir, opts) {
+            for (_, &(destdir, _)) in prepared {
+                if let Err(e) = cleanup_tmp(destdir) {
+                    log::warn!("Failed to clean up temporary files: {e:#}");
+                }
+            }
+            return Err(e);
+        }
+        if opts.policy().is_some() {
+            syncfs(destdir)?;
+        }
     }
     Ok(())
 }
@@ -418,13 +1494,14 @@ pub(crate) fn apply_diff(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sha512string::SHA512String;
     use std::fs;
     use std::io::Write;
     use std::path::Path;
 
     fn run_diff(a: &openat::Dir, b: &openat::Dir) -> Result<FileTreeDiff> {
-        let ta = FileTree::new_from_dir(a)?;
-        let tb = FileTree::new_from_dir(b)?;
+        let ta = FileTree::new_from_dir(a, DigestAlgorithm::default())?;
+        let tb = FileTree::new_from_dir(b, DigestAlgorithm::default())?;
         let diff = ta.diff(&tb)?;
         Ok(diff)
     }
@@ -448,15 +1525,15 @@ mod tests {
         let c = openat::Dir::open(&c)?;
         let da = openat::Dir::open(a)?;
         let db = openat::Dir::open(b)?;
-        let ta = FileTree::new_from_dir(&da)?;
-        let tb = FileTree::new_from_dir(&db)?;
+        let ta = FileTree::new_from_dir(&da, DigestAlgorithm::default())?;
+        let tb = FileTree::new_from_dir(&db, DigestAlgorithm::default())?;
         let diff = ta.diff(&tb)?;
         let rdiff = tb.diff(&ta)?;
         assert_eq!(diff.count(), rdiff.count());
         assert_eq!(diff.additions.len(), rdiff.removals.len());
         assert_eq!(diff.changes.len(), rdiff.changes.len());
         apply_diff(&db, &c, &diff, opts)?;
-        let tc = FileTree::new_from_dir(&c)?;
+        let tc = FileTree::new_from_dir(&c, DigestAlgorithm::default())?;
         let newdiff = tb.diff(&tc)?;
         let skip_removals = opts.map(|o| o.skip_removals).unwrap_or(false);
         if skip_removals {
@@ -508,8 +1585,8 @@ mod tests {
         let diff = run_diff(&a, &b)?;
         assert_eq!(diff.count(), 1);
         assert_eq!(diff.removals.len(), 1);
-        let ta = FileTree::new_from_dir(&a)?;
-        let tb = FileTree::new_from_dir(&b)?;
+        let ta = FileTree::new_from_dir(&a, DigestAlgorithm::default())?;
+        let tb = FileTree::new_from_dir(&b, DigestAlgorithm::default())?;
         let cdiff = ta.changes(&tb)?;
         assert_eq!(cdiff.count(), 1);
         assert_eq!(cdiff.removals.len(), 1);
@@ -534,7 +1611,7 @@ mod tests {
         let diff = run_diff(&a, &b)?;
         assert_eq!(diff.count(), 1);
         assert_eq!(diff.changes.len(), 1);
-        let ta = FileTree::new_from_dir(&a)?;
+        let ta = FileTree::new_from_dir(&a, DigestAlgorithm::default())?;
         let rdiff = ta.relative_diff_to(&b)?;
         assert_eq!(rdiff.count(), diff.count());
         assert_eq!(rdiff.changes.len(), diff.changes.len());
@@ -565,8 +1642,8 @@ mod tests {
         {
             let a = openat::Dir::open(&a)?;
             let b = openat::Dir::open(&b)?;
-            let ta = FileTree::new_from_dir(&a)?;
-            let tb = FileTree::new_from_dir(&b)?;
+            let ta = FileTree::new_from_dir(&a, DigestAlgorithm::default())?;
+            let tb = FileTree::new_from_dir(&b, DigestAlgorithm::default())?;
             let diff = ta.diff(&tb)?;
             assert_eq!(diff.changes.len(), 1);
             assert_eq!(diff.additions.len(), 1);
@@ -585,17 +1662,11 @@ mod tests {
         Ok(())
     }
     #[test]
-    fn test_get_first_dir() -> Result<()> {
-        // test path
+    fn test_tmp_path() -> Result<()> {
         let path = Utf8Path::new("foo/subdir/bar");
-        let (tp, tp_tmp) = get_first_dir(path)?;
-        assert_eq!(tp, Utf8Path::new("foo"));
-        assert_eq!(tp_tmp, ".btmp.foo");
-        // test file
+        assert_eq!(tmp_path(path)?, "foo/subdir/.btmp.bar");
         let path = Utf8Path::new("testfile");
-        let (tp, tp_tmp) = get_first_dir(path)?;
-        assert_eq!(tp, Utf8Path::new("testfile"));
-        assert_eq!(tp_tmp, ".btmp.testfile");
+        assert_eq!(tmp_path(path)?, ".btmp.testfile");
         Ok(())
     }
     #[test]
@@ -628,6 +1699,503 @@ mod tests {
         assert!(!dp.exists(".btmp.b")?);
         Ok(())
     }
+
+    #[test]
+    fn test_clean_leftovers() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("EFI/fedora"))?;
+        fs::create_dir_all(p.join(".btmp.old/sub"))?;
+        fs::write(p.join("EFI/fedora/grubx64.efi"), "grub")?;
+        fs::write(p.join("EFI/fedora/.btmp.grubx64.efi"), "new grub")?;
+        let dp = openat::Dir::open(p)?;
+        assert_eq!(
+            find_leftovers(&dp)?,
+            [".btmp.old", "EFI/fedora/.btmp.grubx64.efi"]
+        );
+        assert_eq!(clean_leftovers(&dp)?.len(), 2);
+        assert!(find_leftovers(&dp)?.is_empty());
+        assert_eq!(
+            fs::read_to_string(p.join("EFI/fedora/grubx64.efi"))?,
+            "grub"
+        );
+        assert!(clean_leftovers(&dp)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_diff() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("a/fedora"))?;
+        fs::create_dir_all(p.join("b/fedora"))?;
+        fs::write(p.join("a/fedora/shimx64.efi"), "shim")?;
+        fs::write(p.join("a/fedora/old.efi"), "old")?;
+        fs::write(p.join("b/fedora/shimx64.efi"), "new shim")?;
+        fs::write(p.join("b/fedora/grubx64.efi"), "grub")?;
+        let a = FileTree::new_from_dir(
+            &openat::Dir::open(&p.join("a"))?,
+            DigestAlgorithm::default(),
+        )?;
+        let b = FileTree::new_from_dir(
+            &openat::Dir::open(&p.join("b"))?,
+            DigestAlgorithm::default(),
+        )?;
+        let described = a.diff(&b)?.describe(&a, &b);
+        let digest = |t: &FileTree, k: &str| t.children[k].digest.to_string();
+        assert_eq!(
+            described,
+            [
+                format!(
+                    "Add: fedora/grubx64.efi (4 bytes, {})",
+                    digest(&b, "fedora/grubx64.efi")
+                ),
+                format!(
+                    "Remove: fedora/old.efi (3 bytes, {})",
+                    digest(&a, "fedora/old.efi")
+                ),
+                format!(
+                    "Change: fedora/shimx64.efi (4 -> 8 bytes, {} -> {})",
+                    digest(&a, "fedora/shimx64.efi"),
+                    digest(&b, "fedora/shimx64.efi")
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_found_in() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("update/fedora"))?;
+        fs::create_dir_all(p.join("esp/fedora"))?;
+        fs::write(p.join("update/fedora/shimx64.efi"), "new shim")?;
+        fs::write(p.join("update/fedora/grubx64.efi"), "grub")?;
+        fs::write(p.join("esp/fedora/shimx64.efi"), "old shim")?;
+        fs::write(p.join("esp/fedora/other.efi"), "other")?;
+        let update = FileTree::new_from_dir(
+            &openat::Dir::open(&p.join("update"))?,
+            DigestAlgorithm::default(),
+        )?;
+        let esp = openat::Dir::open(&p.join("esp"))?;
+        let found = update.found_in(&esp)?;
+        let current = FileTree::new_from_dir(&esp, DigestAlgorithm::default())?;
+        assert_eq!(
+            found.children.keys().collect::<Vec<_>>(),
+            ["fedora/shimx64.efi"]
+        );
+        // The content on the ESP is recorded, not the one of the update
+        assert_eq!(
+            found.children["fedora/shimx64.efi"],
+            current.children["fedora/shimx64.efi"]
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_required_space() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        fs::create_dir_all(p.join("a/fedora"))?;
+        fs::create_dir_all(p.join("b/fedora/fw"))?;
+        fs::write(p.join("a/fedora/shimx64.efi"), vec![0u8; 5000])?;
+        fs::write(p.join("a/BOOTX64.CSV"), "shim")?;
+        fs::write(p.join("b/fedora/shimx64.efi"), vec![0u8; 3000])?;
+        fs::write(p.join("b/fedora/fw/fw.bin"), vec![0u8; 100])?;
+        let a = openat::Dir::open(&p.join("a"))?;
+        let b = openat::Dir::open(&p.join("b"))?;
+        let diff = run_diff(&b, &a)?;
+        let opts = ApplyUpdateOptions::default();
+        // fedora/ is copied (at most 4096 + 4096), then shimx64.efi (8192)
+        // and BOOTX64.CSV (4096) are written
+        assert_eq!(required_space(&a, &b, &diff, &opts, 4096)?, 20480);
+        check_free_space(&a, &b, &diff, &opts)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_interrupted() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for d in ["src/fedora", "src/BOOT", "dest/fedora", "dest/BOOT"] {
+            fs::create_dir_all(p.join(d))?;
+        }
+        fs::write(p.join("src/fedora/grubx64.efi"), "new grub")?;
+        fs::write(p.join("src/BOOT/BOOTX64.EFI"), "new shim")?;
+        fs::write(p.join("src/BOOTX64.CSV"), "new csv")?;
+        fs::write(p.join("dest/fedora/grubx64.efi"), "old grub")?;
+        fs::write(p.join("dest/BOOT/BOOTX64.EFI"), "old shim")?;
+        fs::write(p.join("dest/BOOTX64.CSV"), "old csv")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let dest = openat::Dir::open(&p.join("dest"))?;
+        let diff = run_diff(&dest, &src)?;
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            ..Default::default()
+        };
+
+        // Interrupted before recording the intent: rolled back
+        let _ = prepare_diff(&src, &dest, &diff, &opts, &Mutex::default())?;
+        recover_interrupted(&dest)?;
+        cleanup_tmp(&dest)?;
+        assert_eq!(fs::read_to_string(p.join("dest/BOOTX64.CSV"))?, "old csv");
+
+        // Interrupted after swapping in some of the content: rolled forward
+        let prepared = prepare_diff(&src, &dest, &diff, &opts, &Mutex::default())?;
+        prepared.intent.write(&dest, &opts)?;
+        dest.local_exchange("fedora/.btmp.grubx64.efi", "fedora/grubx64.efi")?;
+        dest.local_exchange(".btmp.BOOTX64.CSV", "BOOTX64.CSV")?;
+        recover_interrupted(&dest)?;
+        cleanup_tmp(&dest)?;
+        assert_eq!(
+            FileTree::new_from_dir(&dest, DigestAlgorithm::default())?,
+            FileTree::new_from_dir(&src, DigestAlgorithm::default())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_staged() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for d in ["src/EFI", "dest/EFI"] {
+            fs::create_dir_all(p.join(d))?;
+        }
+        fs::write(p.join("src/EFI/grubx64.efi"), "new grub")?;
+        fs::write(p.join("dest/EFI/grubx64.efi"), "old grub")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let dest = openat::Dir::open(&p.join("dest"))?;
+        let diff = run_diff(&dest, &src)?;
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            ..Default::default()
+        };
+        let prepared = prepare_diff(&src, &dest, &diff, &opts, &Mutex::default())?;
+        prepared.intent.verify_staged(&dest)?;
+
+        // Corrupted by the storage after the write
+        fs::write(p.join("dest/EFI/.btmp.grubx64.efi"), "new grun")?;
+        let e = prepared.intent.verify_staged(&dest).unwrap_err();
+        assert!(e.to_string().contains("EFI/grubx64.efi"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_exchanges() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        let write_tree = |dir: &str, files: &[(&str, &str)]| -> Result<()> {
+            let _ = fs::remove_dir_all(p.join(dir));
+            fs::create_dir_all(p.join(dir))?;
+            for (path, content) in files {
+                let path = p.join(dir).join(path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, content)?;
+            }
+            Ok(())
+        };
+        let old_files = [
+            ("fedora/shimx64.efi", "old shim"),
+            ("fedora/grubx64.efi", "old grub"),
+            ("fedora/mmx64.efi", "mm"),
+            ("BOOT/BOOTX64.EFI", "old fallback"),
+            ("BOOT/fbx64.efi", "fb"),
+            ("BOOTX64.CSV", "old csv"),
+        ];
+        write_tree("old", &old_files)?;
+        write_tree(
+            "new",
+            &[
+                ("fedora/shimx64.efi", "new shim"),
+                ("fedora/grubx64.efi", "new grub"),
+                ("fedora/mmx64.efi", "mm"),
+                ("BOOT/BOOTX64.EFI", "new fallback"),
+                ("BOOT/fbx64.efi", "fb"),
+                ("centos/shimx64.efi", "centos shim"),
+                ("centos/grubx64.efi", "centos grub"),
+                ("BOOTX64.CSV", "new csv"),
+            ],
+        )?;
+        let oldtree = FileTree::new_from_dir(
+            &openat::Dir::open(&p.join("old"))?,
+            DigestAlgorithm::default(),
+        )?;
+        let new = openat::Dir::open(&p.join("new"))?;
+        let newtree = FileTree::new_from_dir(&new, DigestAlgorithm::default())?;
+        let diff = oldtree.diff(&newtree)?;
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            staged_dirs(&diff, &opts).into_iter().collect::<Vec<_>>(),
+            ["centos", "fedora"]
+        );
+        // Crash after each of the exchanges in turn: the content of each
+        // vendor directory is swapped in at once
+        for n in 1..=4 {
+            write_tree("dest", &old_files)?;
+            let dest = openat::Dir::open(&p.join("dest"))?;
+            let prepared = prepare_diff(&new, &dest, &diff, &opts, &Mutex::default())?;
+            assert_eq!(prepared.intent.exchanges.len(), 4);
+            prepared.intent.write(&dest, &opts)?;
+            let faults = FaultInjection {
+                abort_after: Some(n),
+                ..Default::default()
+            };
+            let crashing = ApplyUpdateOptions {
+                faults: Some(&faults),
+                ..opts.clone()
+            };
+            assert!(prepared.intent.complete(&dest, &crashing).is_err());
+            let shim = fs::read_to_string(p.join("dest/fedora/shimx64.efi"))?;
+            let grub = fs::read_to_string(p.join("dest/fedora/grubx64.efi"))?;
+            assert_eq!(shim.replace("shim", "grub"), grub);
+            assert_eq!(fs::read_to_string(p.join("dest/fedora/mmx64.efi"))?, "mm");
+            assert!(verify_recovered(&dest, &oldtree, &newtree)?);
+        }
+        Ok(())
+    }
+
+    /// Check that the update of `dest` from `old` to `new`, interrupted at
+    /// any point, leaves either of them once recovered by the next update.
+    fn verify_recovered(dest: &openat::Dir, old: &FileTree, new: &FileTree) -> Result<bool> {
+        recover_interrupted(dest)?;
+        cleanup_tmp(dest)?;
+        let found = FileTree::new_from_dir(dest, DigestAlgorithm::default())?;
+        if &found == new {
+            return Ok(true);
+        }
+        assert_eq!(&found, old, "neither the old nor the new content");
+        Ok(false)
+    }
+
+    #[test]
+    fn test_fault_injection() -> Result<()> {
+        assert!(FaultInjection::parse("abort-after=0").is_err());
+        assert!(FaultInjection::parse("abort-after=3,sync").is_err());
+        let faults = FaultInjection::parse("abort-after=3, skip-sync")?;
+        assert_eq!(faults.abort_after, Some(3));
+        assert!(faults.abort_process && faults.skip_sync);
+
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        let write_tree = |dir: &str, files: &[(&str, &str)]| -> Result<()> {
+            let _ = fs::remove_dir_all(p.join(dir));
+            for (path, content) in files {
+                let path = p.join(dir).join(path);
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::write(path, content)?;
+            }
+            Ok(())
+        };
+        let old_files = [
+            ("fedora/grubx64.efi", "old grub"),
+            ("fedora/shimx64.efi", "shim"),
+            ("fedora/mmx64.efi", "old mm"),
+        ];
+        write_tree("old", &old_files)?;
+        write_tree(
+            "new",
+            &[
+                ("fedora/grubx64.efi", "new grub"),
+                ("fedora/shimx64.efi", "shim"),
+                ("BOOT/BOOTX64.EFI", "new fallback"),
+            ],
+        )?;
+        let oldtree = FileTree::new_from_dir(
+            &openat::Dir::open(&p.join("old"))?,
+            DigestAlgorithm::default(),
+        )?;
+        let new = openat::Dir::open(&p.join("new"))?;
+        let newtree = FileTree::new_from_dir(&new, DigestAlgorithm::default())?;
+        let diff = oldtree.diff(&newtree)?;
+
+        // Interrupt the update after each of its writes in turn
+        let mut n = 1;
+        loop {
+            write_tree("dest", &old_files)?;
+            let dest = openat::Dir::open(&p.join("dest"))?;
+            let faults = FaultInjection {
+                abort_after: Some(n),
+                skip_sync: true,
+                ..Default::default()
+            };
+            let opts = ApplyUpdateOptions {
+                faults: Some(&faults),
+                ..Default::default()
+            };
+            let r = apply_diff(&new, &dest, &diff, Some(&opts));
+            let updated = verify_recovered(&dest, &oldtree, &newtree)?;
+            if r.is_ok() {
+                assert!(updated);
+                break;
+            }
+            assert!(n < 10, "update not completed");
+            n += 1;
+        }
+        // Only completed past its writes: shimx64.efi copied to the staged
+        // fedora/, 2 files staged, the intent and 2 exchanges
+        assert_eq!(n, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_case_insensitive() -> Result<()> {
+        let tree = |files: &[(&str, &str)]| FileTree {
+            children: files
+                .iter()
+                .map(|&(name, sha)| {
+                    let meta = FileMetadata {
+                        size: 1,
+                        digest: Digest::Sha512(SHA512String(format!("sha512:{sha}"))),
+                        xattrs: Default::default(),
+                    };
+                    (name.to_string(), meta)
+                })
+                .collect(),
+        };
+        let current = tree(&[("BOOT/BOOTX64.EFI", "a"), ("BOOT/fbx64.efi", "b")]);
+        let updated = tree(&[("BOOT/bootx64.efi", "c"), ("BOOT/FBX64.EFI", "b")]);
+        let diff = current.diff(&updated)?;
+        assert!(diff.additions.is_empty());
+        assert!(diff.removals.is_empty());
+        assert_eq!(
+            diff.changes.into_iter().collect::<Vec<_>>(),
+            ["BOOT/bootx64.efi"]
+        );
+
+        let collision = FileTreeDiff {
+            additions: ["BOOT/bootx64.efi".to_string()].into(),
+            removals: HashSet::new(),
+            changes: ["BOOT/BOOTX64.EFI".to_string()].into(),
+        };
+        assert!(check_case_collisions(&collision).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattrs() -> Result<()> {
+        let label = |l: &str| hex::encode(format!("system_u:object_r:{l}:s0\0"));
+        let source = BTreeMap::from([
+            ("security.selinux".to_string(), label("usr_t")),
+            ("security.ima".to_string(), "0302".to_string()),
+        ]);
+        let replaced = BTreeMap::from([("security.selinux".to_string(), label("boot_t"))]);
+        // The label of the replaced file is kept, the IMA signature updated
+        let merged = merge_xattrs(&source, Some(&replaced));
+        assert_eq!(merged["security.selinux"], label("boot_t"));
+        assert_eq!(merged["security.ima"], "0302");
+        // A new file gets the default label of its directory
+        let merged = merge_xattrs(&source, None);
+        assert!(!merged.contains_key("security.selinux"));
+        assert_eq!(merged.len(), 1);
+
+        // Older state files don't record extended attributes, which aren't
+        // compared
+        let old: FileMetadata = serde_json::from_str(r#"{"size": 4, "sha512": "sha512:abcd"}"#)?;
+        assert!(old.xattrs.is_empty());
+        let new = FileMetadata {
+            xattrs: source,
+            ..old.clone()
+        };
+        assert_eq!(old, new);
+        assert!(serde_json::to_string(&new)?.contains("security.ima"));
+        assert!(!serde_json::to_string(&old)?.contains("xattrs"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_algorithms() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        fs::write(tmpd.path().join("shimx64.efi"), "shim")?;
+        let d = openat::Dir::open(tmpd.path())?;
+        let sha512 = FileMetadata::new_from_path(&d, "shimx64.efi", DigestAlgorithm::Sha512)?;
+        let blake3 = FileMetadata::new_from_path(&d, "shimx64.efi", DigestAlgorithm::Blake3)?;
+        // Older trees only have SHA-512 digests, serialized the same way
+        let json = serde_json::to_string(&sha512)?;
+        assert!(json.contains(r#""sha512":"sha512:"#));
+        assert_eq!(serde_json::from_str::<FileMetadata>(&json)?, sha512);
+        let json = serde_json::to_string(&blake3)?;
+        assert!(json.contains(r#""blake3":"blake3:"#));
+        assert_eq!(serde_json::from_str::<FileMetadata>(&json)?, blake3);
+        // Digests of different algorithms never match
+        assert_ne!(sha512, blake3);
+        let tree = FileTree {
+            children: BTreeMap::from([("shimx64.efi".to_string(), blake3)]),
+        };
+        assert_eq!(tree.relative_diff_to(&d)?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_diffs() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for d in ["src/fedora", "esp1/fedora", "esp2"] {
+            fs::create_dir_all(p.join(d))?;
+        }
+        fs::write(p.join("src/fedora/grubx64.efi"), "new grub")?;
+        fs::write(p.join("esp1/fedora/grubx64.efi"), "old grub")?;
+        fs::write(p.join("esp1/fedora/mmx64.efi"), "mm")?;
+        let src = openat::Dir::open(&p.join("src"))?;
+        let esp1 = openat::Dir::open(&p.join("esp1"))?;
+        let esp2 = openat::Dir::open(&p.join("esp2"))?;
+        let current = FileTree::new_from_dir(&esp1, DigestAlgorithm::default())?;
+        let updated = FileTree::new_from_dir(&src, DigestAlgorithm::default())?;
+        let diff1 = current.diff(&updated)?;
+        // The second ESP is blank, so everything is an addition
+        let diff2 = FileTree::new_from_dir(&esp2, DigestAlgorithm::default())?.diff(&updated)?;
+        let last = Mutex::new(Progress::default());
+        let opts = ApplyUpdateOptions {
+            skip_sync: true,
+            progress: Some(&|p| *last.lock().unwrap() = *p),
+            ..Default::default()
+        };
+        apply_diffs(&src, &[(&esp1, &diff1), (&esp2, &diff2)], Some(&opts))?;
+        for esp in [&esp1, &esp2] {
+            assert_eq!(
+                FileTree::new_from_dir(esp, DigestAlgorithm::default())?,
+                updated
+            );
+        }
+        let last = last.into_inner().unwrap();
+        assert_eq!((last.files_done, last.files_total), (2, 2));
+        assert_eq!(last.bytes_done, 16);
+        assert_eq!(last.percent(), 100);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_policies() -> Result<()> {
+        for policy in [SyncPolicy::Fsync, SyncPolicy::Syncfs, SyncPolicy::End] {
+            let tmpd = tempfile::tempdir()?;
+            let p = tmpd.path();
+            for d in ["src/EFI/fedora", "dest/EFI/fedora", "dest/EFI/old"] {
+                fs::create_dir_all(p.join(d))?;
+            }
+            fs::write(p.join("src/EFI/fedora/grubx64.efi"), "new grub")?;
+            fs::write(p.join("src/EFI/fedora/shimx64.efi"), "shim")?;
+            fs::write(p.join("dest/EFI/fedora/grubx64.efi"), "old grub")?;
+            fs::write(p.join("dest/EFI/old/grubx64.efi"), "removed")?;
+            let src = openat::Dir::open(&p.join("src"))?;
+            let dest = openat::Dir::open(&p.join("dest"))?;
+            let updated = FileTree::new_from_dir(&src, DigestAlgorithm::default())?;
+            let diff = FileTree::new_from_dir(&dest, DigestAlgorithm::default())?.diff(&updated)?;
+            let opts = ApplyUpdateOptions {
+                sync_policy: Some(policy),
+                ..Default::default()
+            };
+            apply_diff(&src, &dest, &diff, Some(&opts))?;
+            assert_eq!(
+                FileTree::new_from_dir(&dest, DigestAlgorithm::default())?,
+                updated,
+                "{policy:?}"
+            );
+        }
+        Ok(())
+    }
+
     // Waiting on https://github.com/rust-lang/rust/pull/125692
     #[cfg(not(target_env = "musl"))]
     #[test]
@@ -696,7 +2264,7 @@ mod tests {
         }
         {
             b.remove_file(testfile)?;
-            let ta = FileTree::new_from_dir(&a)?;
+            let ta = FileTree::new_from_dir(&a, DigestAlgorithm::default())?;
             let diff = ta.relative_diff_to(&b)?;
             assert_eq!(diff.removals.len(), 1);
             apply_diff(&a, &b, &diff, None).context("test removed files with relative_diff")?;
@@ -0,0 +1,240 @@
+//! Staging of UEFI firmware updates, when installing with
+//! `--update-firmware`.
+//!
+//! Updates are staged by fwupd when it is available: it copies the capsules
+//! to the ESP and asks the firmware to apply them on the next boot.
+//! Otherwise, the capsules shipped by the image in `CAPSULES_DIR` are
+//! delivered on disk: copied to `EFI/UpdateCapsule` in the ESP, with file
+//! capsule delivery requested in `OsIndications`, or by the next update if
+//! efivarfs isn't writable yet.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::efi;
+use crate::efivars;
+use crate::model::{FirmwareUpdate, FirmwareUpdateMethod};
+use crate::util::CommandRunExt;
+
+/// The fwupd client
+const FWUPDMGR: &str = "fwupdmgr";
+/// The exit code of fwupdmgr when there is nothing to do
+const FWUPDMGR_NOTHING_TO_DO: i32 = 2;
+/// The capsules shipped by the image, used without fwupd
+const CAPSULES_DIR: &str = "usr/lib/bootupd/capsules";
+/// Where the firmware looks for capsules, relative to `EFI/`
+const UPDATE_CAPSULE_DIR: &str = "UpdateCapsule";
+/// The `OsIndications` bit requesting the delivery of capsules on disk
+const FILE_CAPSULE_DELIVERY_SUPPORTED: u64 = 0x4;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FwupdRelease {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FwupdDevice {
+    device_id: String,
+    name: String,
+    /// Newest first
+    #[serde(default)]
+    releases: Vec<FwupdRelease>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct FwupdUpdates {
+    #[serde(default)]
+    devices: Vec<FwupdDevice>,
+}
+
+/// Stage the updates fwupd knows about; returns `None` if fwupd isn't
+/// installed.
+#[context("Staging firmware updates with fwupd")]
+fn stage_fwupd() -> Result<Option<Vec<FirmwareUpdate>>> {
+    let output = match Command::new(FWUPDMGR)
+        .args([
+            "get-updates",
+            "--json",
+            "--no-unreported-check",
+            "--no-metadata-check",
+        ])
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if output.status.code() == Some(FWUPDMGR_NOTHING_TO_DO) {
+        return Ok(Some(Vec::new()));
+    }
+    if !output.status.success() {
+        bail!(
+            "{FWUPDMGR} get-updates failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let updates: FwupdUpdates =
+        serde_json::from_slice(&output.stdout).context("parsing fwupd updates")?;
+    let mut r = Vec::new();
+    for device in updates.devices {
+        let Some(release) = device.releases.into_iter().next() else {
+            continue;
+        };
+        // One device failing to stage doesn't prevent updating the others
+        let staged = Command::new(FWUPDMGR)
+            .args(["update", "--assume-yes", "--no-reboot-check"])
+            .arg(&device.device_id)
+            .run();
+        r.push(FirmwareUpdate {
+            name: device.name,
+            version: Some(release.version),
+            method: FirmwareUpdateMethod::Fwupd,
+            error: staged.err().map(|e| format!("{e:#}")),
+        });
+    }
+    Ok(Some(r))
+}
+
+/// Read a 64-bit variable, like `OsIndications`.
+fn read_u64_var(name: &str) -> Result<u64> {
+    Ok(efivars::read_var(name)?
+        .and_then(|v| Some(u64::from_le_bytes(v.get(..8)?.try_into().unwrap())))
+        .unwrap_or_default())
+}
+
+fn ensure_capsules_supported() -> Result<()> {
+    if read_u64_var("OsIndicationsSupported")? & FILE_CAPSULE_DELIVERY_SUPPORTED == 0 {
+        bail!("The firmware doesn't support capsules on disk");
+    }
+    Ok(())
+}
+
+/// Ask the firmware to apply the capsules in `EFI/UpdateCapsule` on the
+/// next boot.
+#[context("Requesting capsule delivery")]
+pub(crate) fn request_capsule_delivery() -> Result<()> {
+    ensure_capsules_supported()?;
+    let indications = read_u64_var("OsIndications")?;
+    efivars::write_var(
+        "OsIndications",
+        &(indications | FILE_CAPSULE_DELIVERY_SUPPORTED).to_le_bytes(),
+    )
+}
+
+/// Returns `true` if `name` is a capsule file.
+fn is_capsule(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cap"))
+}
+
+/// Copy the capsules of `src_root` to the `efidir` of the ESP for the
+/// firmware to apply them on the next boot.
+#[context("Staging firmware capsules")]
+fn stage_capsules(src_root: &Path, efidir: &Path) -> Result<Vec<FirmwareUpdate>> {
+    let src = src_root.join(CAPSULES_DIR);
+    if !src.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&src).with_context(|| format!("reading {src:?}"))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if is_capsule(&name) {
+            names.push(name);
+        }
+    }
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    names.sort();
+    // Without efivarfs, the delivery is requested by a later update
+    let deliver = efi::efivars_writable()?;
+    if deliver {
+        ensure_capsules_supported()?;
+    }
+    let dest = efidir.join(UPDATE_CAPSULE_DIR);
+    std::fs::create_dir_all(&dest).with_context(|| format!("creating {dest:?}"))?;
+    for name in names.iter() {
+        std::fs::copy(src.join(name), dest.join(name))
+            .with_context(|| format!("copying {name}"))?;
+    }
+    if deliver {
+        request_capsule_delivery()?;
+    } else {
+        println!("EFI variables are not writable, deferring the capsule delivery");
+    }
+    Ok(names
+        .into_iter()
+        .map(|name| FirmwareUpdate {
+            name,
+            version: None,
+            method: FirmwareUpdateMethod::Capsule,
+            error: None,
+        })
+        .collect())
+}
+
+/// Stage the available firmware updates, with fwupd if installed, or else
+/// from the capsules of `src_root` copied to `efidir`, the `EFI` directory
+/// of the target ESP.
+pub(crate) fn stage_updates(src_root: &Path, efidir: &Path) -> Result<Vec<FirmwareUpdate>> {
+    if let Some(r) = stage_fwupd()? {
+        return Ok(r);
+    }
+    log::debug!("{FWUPDMGR} not found, looking for capsules");
+    stage_capsules(src_root, efidir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fwupd_updates() -> Result<()> {
+        let data = r#"{
+            "Devices": [
+                {
+                    "Name": "System Firmware",
+                    "DeviceId": "6f5fd8a3b4b4d4f1c2b9e8d7a6c5b4a3f2e1d0c9",
+                    "Guid": ["230c8b18-8d9b-53ec-838b-6cfc0383493a"],
+                    "Releases": [{"Version": "1.2.3"}, {"Version": "1.2.2"}]
+                },
+                {"Name": "UEFI dbx", "DeviceId": "362301da643102b9f38477387e2193e57abaa590"}
+            ]
+        }"#;
+        let updates: FwupdUpdates = serde_json::from_str(data)?;
+        assert_eq!(updates.devices.len(), 2);
+        assert_eq!(updates.devices[0].name, "System Firmware");
+        assert_eq!(updates.devices[0].releases[0].version, "1.2.3");
+        assert!(updates.devices[1].releases.is_empty());
+        let none: FwupdUpdates = serde_json::from_str("{}")?;
+        assert!(none.devices.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_capsule() {
+        assert!(is_capsule("firmware.cap"));
+        assert!(is_capsule("FIRMWARE.CAP"));
+        assert!(!is_capsule("firmware.bin"));
+        assert!(!is_capsule("cap"));
+    }
+
+    #[test]
+    fn test_stage_capsules_none() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert!(stage_capsules(td.path(), &td.path().join("EFI"))?.is_empty());
+        std::fs::create_dir_all(td.path().join(CAPSULES_DIR))?;
+        std::fs::write(td.path().join(CAPSULES_DIR).join("README"), "")?;
+        assert!(stage_capsules(td.path(), &td.path().join("EFI"))?.is_empty());
+        assert!(!td.path().join("EFI").exists());
+        Ok(())
+    }
+}
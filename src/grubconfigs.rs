@@ -1,14 +1,194 @@
 use std::fmt::Write;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use fn_error_context::context;
 use openat_ext::OpenatDirExt;
 
+use crate::config::GrubConfig;
+
 /// The subdirectory of /boot we use
 const GRUB2DIR: &str = "grub2";
 const CONFIGDIR: &str = "/usr/lib/bootupd/grub2-static";
 const DROPINDIR: &str = "configs.d";
+/// The superusers and their passwords, generated from the configuration
+const USERS_CFG: &str = "users.cfg";
+/// The consoles, generated from the configuration; sourced by
+/// `grub-static-pre.cfg`
+const CONSOLE_CFG: &str = "console.cfg";
+/// The first line of the files generated from the configuration
+const GENERATED_HEADER: &str = "# Generated by bootupd from /etc/bootupd/config.toml\n";
+
+/// The files generated from the `[grub]` section of the configuration; `None`
+/// if the corresponding settings are unset.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct GeneratedConfigs {
+    users: Option<String>,
+    console: Option<String>,
+}
+
+impl GeneratedConfigs {
+    /// Generate the files for `config`, reading the files it refers to
+    /// relative to `root`.
+    pub(crate) fn new(root: &Path, config: &GrubConfig) -> Result<Self> {
+        Ok(Self {
+            users: users_cfg(root, config)?,
+            console: console_cfg(config)?,
+        })
+    }
+
+    /// The files with their name, mode, and whether `grub.cfg` must source
+    /// them.
+    fn files(&self) -> [(&'static str, Option<&str>, u32, bool); 2] {
+        [
+            (USERS_CFG, self.users.as_deref(), 0o600, true),
+            (CONSOLE_CFG, self.console.as_deref(), 0o644, false),
+        ]
+    }
+
+    /// Returns `true` if the files installed in `target_root` differ.
+    pub(crate) fn changed(&self, target_root: &openat::Dir) -> Result<bool> {
+        let bootdir = &target_root.sub_dir("boot").context("Opening /boot")?;
+        for (name, contents, _, _) in self.files() {
+            if read_generated(bootdir, name)?.as_deref() != contents {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Write the files to `bootdir`, adding the `source` commands to
+    /// `config`; the files generated before whose settings were removed
+    /// are deleted, but not those written by other means (e.g. a
+    /// `console.cfg` provided by the platform).
+    fn install(&self, bootdir: &openat::Dir, config: &mut String) -> Result<()> {
+        for (name, contents, mode, source) in self.files() {
+            let path = format!("{GRUB2DIR}/{name}");
+            if let Some(contents) = contents {
+                bootdir
+                    .write_file_contents(&path, mode, contents.as_bytes())
+                    .with_context(|| format!("Writing {name}"))?;
+                if source {
+                    writeln!(config, "source $prefix/{name}")?;
+                }
+                println!("Installed: {name}");
+            } else if read_generated(bootdir, name)?.is_some() {
+                bootdir.remove_file_optional(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The contents of `name` in the GRUB directory of `bootdir`, if it was
+/// generated from the configuration.
+fn read_generated(bootdir: &openat::Dir, name: &str) -> Result<Option<String>> {
+    let Some(mut f) = bootdir.open_file_optional(format!("{GRUB2DIR}/{name}"))? else {
+        return Ok(None);
+    };
+    let mut s = String::new();
+    std::io::Read::read_to_string(&mut f, &mut s).with_context(|| format!("Reading {name}"))?;
+    Ok(s.starts_with(GENERATED_HEADER).then_some(s))
+}
+
+/// Returns `true` if `name` is a valid GRUB terminal name, e.g.
+/// `serial_com0`.
+fn valid_terminal(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Generate the `console.cfg` for the console settings of `config`.
+#[context("Generating GRUB console config")]
+fn console_cfg(config: &GrubConfig) -> Result<Option<String>> {
+    let Some(console) = config.console.as_ref() else {
+        return Ok(None);
+    };
+    let mut r = String::from(GENERATED_HEADER);
+    if let Some(serial) = console.serial.as_ref() {
+        write!(r, "serial --unit={} --speed={}", serial.unit, serial.speed)?;
+        if let Some(word) = serial.word {
+            write!(r, " --word={word}")?;
+        }
+        if let Some(parity) = serial.parity {
+            write!(r, " --parity={}", parity.as_str())?;
+        }
+        if let Some(stop) = serial.stop {
+            write!(r, " --stop={stop}")?;
+        }
+        r.push('\n');
+    }
+    for (command, terminals) in [
+        ("terminal_input", &console.terminal_input),
+        ("terminal_output", &console.terminal_output),
+    ] {
+        if terminals.is_empty() {
+            continue;
+        }
+        if let Some(t) = terminals.iter().find(|t| !valid_terminal(t)) {
+            bail!("Invalid terminal {t:?}");
+        }
+        writeln!(r, "{command} {}", terminals.join(" "))?;
+    }
+    Ok(Some(r))
+}
+
+/// Generate the `users.cfg` setting the superusers and passwords of
+/// `config`, reading the password file relative to `root`; returns `None`
+/// if no superusers are configured.
+#[context("Generating GRUB users config")]
+fn users_cfg(root: &Path, config: &GrubConfig) -> Result<Option<String>> {
+    if config.superusers.is_empty() {
+        if config.password_file.is_some() {
+            bail!("password-file requires superusers");
+        }
+        return Ok(None);
+    }
+    let Some(password_file) = config.password_file.as_deref() else {
+        bail!("superusers requires a password-file");
+    };
+    let path = root.join(password_file.strip_prefix("/").unwrap_or(password_file));
+    let meta = std::fs::metadata(&path).with_context(|| format!("reading {path:?}"))?;
+    if meta.uid() != rustix::process::geteuid().as_raw() || meta.mode() & 0o077 != 0 {
+        bail!("{path:?} must be owned by root and not accessible by other users");
+    }
+    let contents = std::fs::read_to_string(&path).with_context(|| format!("reading {path:?}"))?;
+    let mut passwords = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((name, hash)) = line.split_once(':') else {
+            bail!("Invalid line in {path:?}, expected NAME:HASH");
+        };
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid_name {
+            bail!("Invalid user name {name:?} in {path:?}");
+        }
+        let valid_hash = hash.starts_with("grub.pbkdf2.")
+            && hash.chars().all(|c| c.is_ascii_alphanumeric() || c == '.');
+        if !valid_hash {
+            bail!("Invalid password of {name} in {path:?}, expected a grub.pbkdf2 hash");
+        }
+        passwords.push((name, hash));
+    }
+    for user in config.superusers.iter() {
+        if !passwords.iter().any(|(name, _)| name == user) {
+            bail!("No password for superuser {user} in {path:?}");
+        }
+    }
+    let mut r = String::from(GENERATED_HEADER);
+    writeln!(r, "set superusers=\"{}\"", config.superusers.join(" "))?;
+    writeln!(r, "export superusers")?;
+    for (name, hash) in passwords {
+        writeln!(r, "password_pbkdf2 {name} {hash}")?;
+    }
+    Ok(Some(r))
+}
 
 /// Install the static GRUB config files.
 #[context("Installing static GRUB configs")]
@@ -16,6 +196,7 @@ pub(crate) fn install(
     target_root: &openat::Dir,
     installed_efi_vendor: Option<&str>,
     write_uuid: bool,
+    generated: &GeneratedConfigs,
 ) -> Result<()> {
     let bootdir = &target_root.sub_dir("boot").context("Opening /boot")?;
     let boot_is_mount = {
@@ -54,6 +235,8 @@ pub(crate) fn install(
         println!("Installed {name}");
     }
 
+    generated.install(bootdir, &mut config)?;
+
     {
         let post = std::fs::read_to_string(Path::new(CONFIGDIR).join("grub-static-post.cfg"))?;
         config.push_str(post.as_str());
@@ -106,6 +289,18 @@ pub(crate) fn install(
     Ok(())
 }
 
+/// Rewrite the static GRUB config files installed by `install`, keeping
+/// the UUID of the boot filesystem if it was written.
+#[context("Updating static GRUB configs")]
+pub(crate) fn update(
+    target_root: &openat::Dir,
+    installed_efi_vendor: Option<&str>,
+    generated: &GeneratedConfigs,
+) -> Result<()> {
+    let write_uuid = target_root.exists(format!("boot/{GRUB2DIR}/bootuuid.cfg"))?;
+    install(target_root, installed_efi_vendor, write_uuid, generated)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,10 +315,105 @@ mod tests {
         std::fs::create_dir_all(tdp.join("boot/grub2"))?;
         std::fs::create_dir_all(tdp.join("boot/efi/EFI/BOOT"))?;
         std::fs::create_dir_all(tdp.join("boot/efi/EFI/fedora"))?;
-        install(&td, Some("fedora"), false).unwrap();
+        install(&td, Some("fedora"), false, &GeneratedConfigs::default()).unwrap();
 
         assert!(td.exists("boot/grub2/grub.cfg")?);
         assert!(td.exists("boot/efi/EFI/fedora/grub.cfg")?);
         Ok(())
     }
+
+    #[test]
+    fn test_users_cfg() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let mut config = GrubConfig::default();
+        assert_eq!(users_cfg(root, &config)?, None);
+
+        config.superusers = vec!["root".into()];
+        assert!(users_cfg(root, &config).is_err());
+        config.password_file = Some("/etc/bootupd/grub-users".into());
+        let path = root.join("etc/bootupd/grub-users");
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(
+            &path,
+            "# comment\nroot:grub.pbkdf2.sha512.10000.AB12.CD34\nops:grub.pbkdf2.sha512.10000.EF56.7890\n",
+        )?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        assert!(users_cfg(root, &config).is_err());
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        let users = users_cfg(root, &config)?.unwrap();
+        assert_eq!(
+            users,
+            "# Generated by bootupd from /etc/bootupd/config.toml\n\
+             set superusers=\"root\"\n\
+             export superusers\n\
+             password_pbkdf2 root grub.pbkdf2.sha512.10000.AB12.CD34\n\
+             password_pbkdf2 ops grub.pbkdf2.sha512.10000.EF56.7890\n"
+        );
+
+        config.superusers = vec!["admin".into()];
+        assert!(users_cfg(root, &config).is_err());
+        config.superusers = vec!["root".into()];
+        std::fs::write(&path, "root:plaintext\n")?;
+        assert!(users_cfg(root, &config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_cfg() -> Result<()> {
+        let mut config = GrubConfig::default();
+        assert_eq!(console_cfg(&config)?, None);
+
+        config.console = Some(toml::from_str(
+            "terminal-input = [\"serial\", \"console\"]\n\
+             terminal-output = [\"serial\", \"console\"]\n\
+             [serial]\nunit = 1\nspeed = 9600\nparity = \"even\"\n",
+        )?);
+        assert_eq!(
+            console_cfg(&config)?.unwrap(),
+            "# Generated by bootupd from /etc/bootupd/config.toml\n\
+             serial --unit=1 --speed=9600 --parity=even\n\
+             terminal_input serial console\n\
+             terminal_output serial console\n"
+        );
+
+        config.console = Some(toml::from_str("terminal-output = [\"serial; reboot\"]")?);
+        assert!(console_cfg(&config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generated_configs() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir_all(td.path().join("boot/grub2"))?;
+        let root = openat::Dir::open(td.path())?;
+        let bootdir = root.sub_dir("boot")?;
+        let console_path = td.path().join("boot/grub2/console.cfg");
+        let empty = GeneratedConfigs::default();
+        assert!(!empty.changed(&root)?);
+
+        // A console.cfg provided by other means is kept
+        std::fs::write(&console_path, "serial --unit=0\n")?;
+        assert!(!empty.changed(&root)?);
+        let mut config = String::new();
+        empty.install(&bootdir, &mut config)?;
+        assert!(console_path.exists());
+
+        let generated = GeneratedConfigs {
+            users: None,
+            console: Some(format!("{GENERATED_HEADER}terminal_output serial\n")),
+        };
+        assert!(generated.changed(&root)?);
+        generated.install(&bootdir, &mut config)?;
+        assert!(!generated.changed(&root)?);
+        assert!(config.is_empty());
+
+        // Once the settings are removed, the generated file is too
+        assert!(empty.changed(&root)?);
+        empty.install(&bootdir, &mut config)?;
+        assert!(!console_path.exists());
+        Ok(())
+    }
 }
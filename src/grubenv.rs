@@ -0,0 +1,234 @@
+//! The GRUB environment block, `/boot/grub2/grubenv`, used e.g. for boot
+//! counting (`boot_success`, `boot_indeterminate`) and to select the
+//! kernel to boot (`saved_entry`).
+//!
+//! The block is a file of (usually) 1024 bytes, starting with a header
+//! line, followed by `name=value` lines and padded with `#`.  GRUB writes
+//! it in place with `save_env`, so its size never changes; bootupd writes a
+//! complete new block of the same size and renames it over the old one, so
+//! that it is never seen half written.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+/// The environment block, relative to the root
+pub(crate) const GRUBENV_PATH: &str = "boot/grub2/grubenv";
+
+const HEADER: &str = "# GRUB Environment Block\n";
+/// The size of a new block, and the minimum size of a block
+const BLOCK_SIZE: usize = 1024;
+
+/// The variables of an environment block, in the order of the block.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct GrubEnv {
+    vars: Vec<(String, String)>,
+    /// The size of the block
+    size: usize,
+}
+
+/// Reject names GRUB couldn't parse back.
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains(['=', '\n', '\\']) || name.starts_with('#') {
+        bail!("Invalid variable name: {name:?}");
+    }
+    Ok(())
+}
+
+impl GrubEnv {
+    /// Parse the environment block `data`.
+    pub(crate) fn parse(data: &[u8]) -> Result<Self> {
+        let Some(data) = data.strip_prefix(HEADER.as_bytes()) else {
+            bail!("Invalid environment block: missing header");
+        };
+        let size = HEADER.len() + data.len();
+        let data = std::str::from_utf8(data).context("Invalid environment block")?;
+        let mut vars = Vec::new();
+        // Values may contain escaped newlines, so lines are split by hand
+        let mut chars = data.chars();
+        'lines: loop {
+            let mut line = String::new();
+            loop {
+                match chars.next() {
+                    None => break,
+                    Some('\n') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => line.push(c),
+                        None => break,
+                    },
+                    Some(c) => line.push(c),
+                }
+            }
+            if line.is_empty() && chars.as_str().is_empty() {
+                break 'lines;
+            }
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            // GRUB ignores malformed lines too
+            if let Some((name, value)) = line.split_once('=') {
+                vars.push((name.to_string(), value.to_string()));
+            }
+        }
+        Ok(Self { vars, size })
+    }
+
+    /// Serialize to a block of the same size as the parsed one (or of 1024
+    /// bytes for a new one).
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>> {
+        let size = self.size.max(BLOCK_SIZE);
+        let mut r = String::from(HEADER);
+        for (name, value) in self.vars.iter() {
+            r.push_str(name);
+            r.push('=');
+            for c in value.chars() {
+                if matches!(c, '\\' | '\n') {
+                    r.push('\\');
+                }
+                r.push(c);
+            }
+            r.push('\n');
+        }
+        if r.len() > size {
+            bail!("Environment block exceeds {size} bytes");
+        }
+        let mut r = r.into_bytes();
+        r.resize(size, b'#');
+        Ok(r)
+    }
+
+    /// The variables, in the order of the block.
+    pub(crate) fn vars(&self) -> &[(String, String)] {
+        &self.vars
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set the variable `name`, keeping its position if it was already set.
+    pub(crate) fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        validate_name(name)?;
+        match self.vars.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.vars.push((name.to_string(), value.to_string())),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unset(&mut self, name: &str) {
+        self.vars.retain(|(n, _)| n != name);
+    }
+}
+
+/// The path of the environment block of `root`; `/boot/grub2/grubenv` is
+/// a symbolic link to the ESP on some systems.
+fn resolve(root: &Path) -> Result<PathBuf> {
+    let path = root.join(GRUBENV_PATH);
+    if !path.is_symlink() {
+        return Ok(path);
+    }
+    let target = std::fs::read_link(&path).with_context(|| format!("reading {path:?}"))?;
+    // SAFETY: GRUBENV_PATH has a parent
+    Ok(path.parent().unwrap().join(target))
+}
+
+/// Read the environment block of `root`; a missing block is empty.
+#[context("Reading GRUB environment block")]
+pub(crate) fn load(root: &Path) -> Result<GrubEnv> {
+    let path = resolve(root)?;
+    match std::fs::read(&path) {
+        Ok(data) => GrubEnv::parse(&data).with_context(|| format!("parsing {path:?}")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(GrubEnv::default()),
+        Err(e) => Err(e).with_context(|| format!("reading {path:?}")),
+    }
+}
+
+/// Atomically replace the environment block of `root` with `env`.
+#[context("Writing GRUB environment block")]
+pub(crate) fn save(root: &Path, env: &GrubEnv) -> Result<()> {
+    let path = resolve(root)?;
+    let data = env.serialize()?;
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("Invalid path {path:?}");
+    };
+    let dir = openat::Dir::open(parent).with_context(|| format!("opening {parent:?}"))?;
+    let mode = match dir.metadata_optional(name)? {
+        Some(meta) => meta.stat().st_mode & 0o7777,
+        None => 0o600,
+    };
+    dir.write_file_with_sync(name, mode, |w| -> Result<()> {
+        use std::io::Write;
+        Ok(w.write_all(&data)?)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_serialize() -> Result<()> {
+        let mut block = format!("{HEADER}saved_entry=fedora-6.9\nboot_success=1\n").into_bytes();
+        block.resize(BLOCK_SIZE, b'#');
+        let mut env = GrubEnv::parse(&block)?;
+        assert_eq!(env.get("saved_entry"), Some("fedora-6.9"));
+        assert_eq!(env.get("boot_success"), Some("1"));
+        assert_eq!(env.get("missing"), None);
+        assert_eq!(env.serialize()?, block);
+
+        env.set("boot_success", "0")?;
+        env.set("menu_auto_hide", "1")?;
+        env.unset("saved_entry");
+        env.set("kernelopts", "a\\b\nc")?;
+        let data = env.serialize()?;
+        assert_eq!(data.len(), BLOCK_SIZE);
+        let parsed = GrubEnv::parse(&data)?;
+        assert_eq!(
+            parsed.vars(),
+            [
+                ("boot_success".to_string(), "0".to_string()),
+                ("menu_auto_hide".to_string(), "1".to_string()),
+                ("kernelopts".to_string(), "a\\b\nc".to_string()),
+            ]
+        );
+
+        assert!(env.set("a=b", "c").is_err());
+        env.set("big", &"x".repeat(BLOCK_SIZE))?;
+        assert!(env.serialize().is_err());
+
+        // Larger blocks keep their size
+        let mut block = HEADER.as_bytes().to_vec();
+        block.resize(2 * BLOCK_SIZE, b'#');
+        assert_eq!(GrubEnv::parse(&block)?.serialize()?.len(), 2 * BLOCK_SIZE);
+
+        assert!(GrubEnv::parse(b"saved_entry=foo\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_save() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let env = load(root)?;
+        assert!(env.vars().is_empty());
+
+        std::fs::create_dir_all(root.join("boot/efi/EFI/fedora"))?;
+        std::fs::create_dir_all(root.join("boot/grub2"))?;
+        std::os::unix::fs::symlink("../efi/EFI/fedora/grubenv", root.join(GRUBENV_PATH))?;
+        let mut env = load(root)?;
+        env.set("boot_success", "1")?;
+        save(root, &env)?;
+        let target = root.join("boot/efi/EFI/fedora/grubenv");
+        assert_eq!(std::fs::read(&target)?.len(), BLOCK_SIZE);
+        assert!(root.join(GRUBENV_PATH).is_symlink());
+        assert_eq!(load(root)?.get("boot_success"), Some("1"));
+        Ok(())
+    }
+}
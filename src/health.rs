@@ -0,0 +1,259 @@
+//! `bootupctl health`: a single pass/warn/fail verdict for node health
+//! frameworks and monitoring agents, combining validation, the staleness
+//! of the installed versions, the state of the ESPs and the consistency of
+//! the Secure Boot chain.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::bootupd;
+use crate::model::{
+    ComponentUpdatable, HealthCheck, HealthReport, HealthVerdict, Status, ValidationReport,
+    ValidationVerdict,
+};
+
+/// Run all the checks; installed versions older than the available update
+/// by more than `max_update_age` fail.
+pub(crate) fn health(max_update_age: chrono::Duration) -> Result<HealthReport> {
+    let status = bootupd::status()?;
+    let validation = bootupd::validate_all(false)?;
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "validation".to_string(),
+        check_validation(&status, &validation),
+    );
+    checks.insert(
+        "updates".to_string(),
+        check_updates(&status, max_update_age),
+    );
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    checks.insert("esp".to_string(), check_esps(&status));
+    checks.insert("secure-boot".to_string(), check_secure_boot(&status));
+    Ok(HealthReport::new(checks))
+}
+
+/// Validation errors fail, and out-of-band modifications seen by
+/// `bootupd watch` warn.
+fn check_validation(status: &Status, validation: &ValidationReport) -> HealthCheck {
+    let mut check = HealthCheck::default();
+    for (name, c) in validation.components.iter() {
+        for err in c.errors.iter() {
+            check.report(HealthVerdict::Fail, format!("{name}: {err}"));
+        }
+    }
+    if validation.verdict == ValidationVerdict::Skip && !validation.components.is_empty() {
+        check.report(HealthVerdict::Warn, "No component could be validated");
+    }
+    for d in status.drift.iter() {
+        check.report(
+            HealthVerdict::Warn,
+            format!("{} was modified out of band", d.path),
+        );
+    }
+    check
+}
+
+/// Pending updates, adoptions and deferred EFI variable writes warn, while
+/// interrupted updates and installed versions too old compared to the
+/// available update fail.
+fn check_updates(status: &Status, max_update_age: chrono::Duration) -> HealthCheck {
+    let mut check = HealthCheck::default();
+    for (name, c) in status.components.iter() {
+        if let Some(i) = c.interrupted.as_ref() {
+            check.report(
+                HealthVerdict::Fail,
+                format!("{name}: previous update to {} was interrupted", i.version),
+            );
+        }
+        let (ComponentUpdatable::Upgradable, Some(update)) = (&c.updatable, c.update.as_ref())
+        else {
+            continue;
+        };
+        let age = update.timestamp - c.installed.timestamp;
+        if age > max_update_age {
+            check.report(
+                HealthVerdict::Fail,
+                format!(
+                    "{name}: installed {} is {} days older than the available {}",
+                    c.installed.version,
+                    age.num_days(),
+                    update.version
+                ),
+            );
+        } else {
+            check.report(
+                HealthVerdict::Warn,
+                format!("{name}: update to {} available", update.version),
+            );
+        }
+    }
+    for (name, c) in status.components.iter() {
+        for op in c.pending_nvram.iter().flatten() {
+            check.report(
+                HealthVerdict::Warn,
+                format!("{name}: {op} deferred until EFI variables are writable"),
+            );
+        }
+    }
+    for name in status.adoptable.keys() {
+        check.report(
+            HealthVerdict::Warn,
+            format!("{name}: not managed by bootupd; see adopt-and-update"),
+        );
+    }
+    check
+}
+
+/// The primary ESP must be a FAT filesystem, where updates can be staged;
+/// the other ESPs should be at the installed version.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn check_esps(status: &Status) -> HealthCheck {
+    /// Warn below this fraction of free space, in percent
+    const MIN_FREE_PERCENT: u64 = 10;
+    let mut check = HealthCheck::default();
+    let Some(efi) = status.components.get("EFI") else {
+        return check;
+    };
+    let esp = crate::efi::Efi::default();
+    match esp.esp_path() {
+        Ok(path) => {
+            if let Err(e) = openat::Dir::open(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|dir| crate::efi::validate_esp(&dir))
+            {
+                check.report(HealthVerdict::Fail, format!("{e:#}"));
+            }
+            match rustix::fs::statvfs(&path) {
+                Ok(st) if st.f_bavail * 100 < st.f_blocks * MIN_FREE_PERCENT => {
+                    check.report(
+                        HealthVerdict::Warn,
+                        format!(
+                            "Only {} KiB free on the ESP",
+                            st.f_bavail * st.f_frsize / 1024
+                        ),
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => check.report(HealthVerdict::Warn, format!("statvfs on the ESP: {e}")),
+            }
+        }
+        Err(e) => check.report(HealthVerdict::Fail, format!("Opening the ESP: {e:#}")),
+    }
+    for s in status.esps.iter() {
+        let installed = s.installed.as_ref();
+        if let Some(v) = installed.filter(|v| v.version != efi.installed.version) {
+            check.report(
+                HealthVerdict::Warn,
+                format!("ESP {} is at {}", s.device, v.version),
+            );
+        }
+    }
+    check
+}
+
+/// Problems of the installed boot chain fail if the firmware enforces
+/// Secure Boot, and warn otherwise.
+fn check_secure_boot(status: &Status) -> HealthCheck {
+    let mut check = HealthCheck::default();
+    let Some(sb) = status.secure_boot.as_ref() else {
+        return check;
+    };
+    let verdict = if sb.enabled {
+        HealthVerdict::Fail
+    } else {
+        HealthVerdict::Warn
+    };
+    for p in sb.problems.iter() {
+        check.report(verdict, p.as_str());
+    }
+    if sb.setup_mode {
+        check.report(HealthVerdict::Warn, "The firmware is in setup mode");
+    }
+    for r in status.sbat.iter().flat_map(|s| s.revoked.iter()) {
+        check.report(
+            HealthVerdict::Warn,
+            format!("Update revoked by SBAT level: {r}"),
+        );
+    }
+    check
+}
+
+fn verdict_str(verdict: HealthVerdict) -> &'static str {
+    match verdict {
+        HealthVerdict::Pass => "pass",
+        HealthVerdict::Warn => "warn",
+        HealthVerdict::Fail => "fail",
+    }
+}
+
+pub(crate) fn print_health(report: &HealthReport) {
+    for (name, check) in report.checks.iter() {
+        println!("{name}: {}", verdict_str(check.verdict));
+        for d in check.details.iter() {
+            println!("  {d}");
+        }
+    }
+    println!("Health: {}", verdict_str(report.verdict));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{ComponentStatus, ContentMetadata};
+    use chrono::{DateTime, Duration, Utc};
+
+    fn meta(version: &str, days_ago: i64) -> ContentMetadata {
+        let now = "2025-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        ContentMetadata {
+            timestamp: now - Duration::try_days(days_ago).unwrap(),
+            version: version.into(),
+            signing_keys: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_check_updates() {
+        let max_age = Duration::try_days(90).unwrap();
+        let mut status = Status::default();
+        assert_eq!(check_updates(&status, max_age), HealthCheck::default());
+
+        let mut efi = ComponentStatus {
+            installed: meta("v1", 150),
+            interrupted: None,
+            staged: None,
+            update: Some(meta("v2", 100)),
+            updatable: ComponentUpdatable::Upgradable,
+            adopted_from: None,
+            efi_vendor: None,
+            devices: Vec::new(),
+            pcr4: None,
+            key_rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            update_estimate: None,
+        };
+        status.components.insert("EFI".into(), efi);
+        let check = check_updates(&status, max_age);
+        assert_eq!(check.verdict, HealthVerdict::Warn);
+        assert_eq!(check.details, ["EFI: update to v2 available"]);
+
+        efi = status.components.remove("EFI").unwrap();
+        efi.installed = meta("v1", 200);
+        status.components.insert("EFI".into(), efi);
+        let check = check_updates(&status, max_age);
+        assert_eq!(check.verdict, HealthVerdict::Fail);
+        assert_eq!(
+            check.details,
+            ["EFI: installed v1 is 100 days older than the available v2"]
+        );
+    }
+}
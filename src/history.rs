@@ -0,0 +1,263 @@
+//! Persistent history of the changes of the installed bootloaders, in
+//! `/boot/bootupd/history.json`.
+//!
+//! The file is append-only, with one JSON object per line for each install,
+//! adoption, update, rollback or repair of a component, whether it
+//! succeeded or not.  It is shown by `bootupctl status --history`.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// The history file, relative to the root
+pub(crate) const HISTORY_PATH: &str = "boot/bootupd/history.json";
+
+/// A change of a component.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HistoryAction {
+    Install,
+    Adopt,
+    Update,
+    Rollback,
+    Repair,
+}
+
+impl HistoryAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            HistoryAction::Install => "install",
+            HistoryAction::Adopt => "adopt",
+            HistoryAction::Update => "update",
+            HistoryAction::Rollback => "rollback",
+            HistoryAction::Repair => "repair",
+        }
+    }
+}
+
+/// An entry of the history.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HistoryEntry {
+    /// When the change was made
+    pub timestamp: DateTime<Utc>,
+    pub action: HistoryAction,
+    pub component: String,
+    /// The version before the change, if any
+    pub old_version: Option<String>,
+    /// The version after the change, if known
+    pub new_version: Option<String>,
+    /// The command line of the process which made the change
+    pub initiator: String,
+    pub success: bool,
+    /// Why the change failed
+    pub error: Option<String>,
+    /// The bytes written per second by a successful update, when measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_throughput: Option<u64>,
+}
+
+impl HistoryEntry {
+    /// An entry for the change of `component` with result `r`, made now by
+    /// this process.
+    pub(crate) fn new<T>(
+        action: HistoryAction,
+        component: &str,
+        old_version: Option<&str>,
+        new_version: Option<&str>,
+        r: &Result<T>,
+    ) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            action,
+            component: component.into(),
+            old_version: old_version.map(String::from),
+            new_version: new_version.map(String::from),
+            initiator: std::env::args().collect::<Vec<_>>().join(" "),
+            success: r.is_ok(),
+            error: r.as_ref().err().map(|e| format!("{e:#}")),
+            write_throughput: None,
+        }
+    }
+}
+
+/// How many of the latest measurements `write_throughput` averages
+const THROUGHPUT_SAMPLES: usize = 5;
+
+/// The write throughput of the updates in `entries`, in bytes per second,
+/// averaged over the latest ones which measured it.
+pub(crate) fn write_throughput(entries: &[HistoryEntry]) -> Option<u64> {
+    let samples: Vec<u64> = entries
+        .iter()
+        .rev()
+        .filter(|e| e.success && e.action == HistoryAction::Update)
+        .filter_map(|e| e.write_throughput)
+        .filter(|t| *t > 0)
+        .take(THROUGHPUT_SAMPLES)
+        .collect();
+    if samples.is_empty() {
+        return None;
+    }
+    Some(samples.iter().sum::<u64>() / samples.len() as u64)
+}
+
+/// Append `entry` to the history of `root`.
+fn append(root: &Path, entry: &HistoryEntry) -> Result<()> {
+    let path = root.join(HISTORY_PATH);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+    }
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    let mut f = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("opening {path:?}"))?;
+    // Terminate a line cut short by a crash, so the entry stays readable
+    if f.metadata()?.len() > 0 {
+        let mut last = [0u8];
+        f.seek(SeekFrom::End(-1))?;
+        f.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            line.insert(0, b'\n');
+        }
+    }
+    f.write_all(&line)?;
+    f.sync_all()?;
+    Ok(())
+}
+
+/// Append `entry` to the history of `root`; failures are only logged, as
+/// the change was already made.
+pub(crate) fn record(root: &Path, entry: HistoryEntry) {
+    if let Err(e) = append(root, &entry) {
+        log::warn!(
+            "Failed to record the {} of {}: {e:#}",
+            entry.action.as_str(),
+            entry.component
+        );
+    }
+}
+
+/// The history of `root`, oldest first.
+pub(crate) fn load(root: &Path) -> Result<Vec<HistoryEntry>> {
+    let path = root.join(HISTORY_PATH);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {path:?}")),
+    };
+    let mut r = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        // A line may have been cut short by a crash while it was written
+        match serde_json::from_str(line) {
+            Ok(entry) => r.push(entry),
+            Err(e) => log::warn!("Ignoring invalid entry at {path:?}:{}: {e}", i + 1),
+        }
+    }
+    Ok(r)
+}
+
+/// Print `entries` for humans.
+pub(crate) fn print(entries: &[HistoryEntry]) {
+    if entries.is_empty() {
+        println!("No history recorded.");
+    }
+    for entry in entries {
+        let versions = match (entry.old_version.as_deref(), entry.new_version.as_deref()) {
+            (Some(old), Some(new)) => format!("{old} -> {new}"),
+            (None, Some(new)) => new.to_string(),
+            (Some(old), None) => format!("{old} -> ?"),
+            (None, None) => "?".to_string(),
+        };
+        println!(
+            "{} {} {}: {}",
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            entry.action.as_str(),
+            entry.component,
+            versions
+        );
+        println!("  Initiator: {}", entry.initiator);
+        if let Some(e) = entry.error.as_deref() {
+            println!("  Failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_load() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        assert!(load(root)?.is_empty());
+
+        let ok: Result<()> = Ok(());
+        let update = HistoryEntry::new(HistoryAction::Update, "EFI", Some("1"), Some("2"), &ok);
+        record(root, update.clone());
+        let err: Result<()> = Err(anyhow::anyhow!("no space left"));
+        let rollback = HistoryEntry::new(HistoryAction::Rollback, "EFI", Some("2"), None, &err);
+        record(root, rollback.clone());
+        assert_eq!(load(root)?, [update.clone(), rollback.clone()]);
+        assert!(!rollback.success);
+        assert_eq!(rollback.error.as_deref(), Some("no space left"));
+
+        // A truncated line is skipped
+        let path = root.join(HISTORY_PATH);
+        let mut f = OpenOptions::new().append(true).open(&path)?;
+        f.write_all(b"{\"timestamp\":")?;
+        assert_eq!(load(root)?, [update.clone(), rollback.clone()]);
+        let install = HistoryEntry::new(HistoryAction::Install, "BIOS", None, Some("1"), &ok);
+        record(root, install.clone());
+        assert_eq!(load(root)?, [update, rollback, install]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_throughput() {
+        let ok: Result<()> = Ok(());
+        let err: Result<()> = Err(anyhow::anyhow!("I/O error"));
+        let mut entries = Vec::new();
+        assert_eq!(write_throughput(&entries), None);
+        let mut entry = HistoryEntry::new(HistoryAction::Update, "EFI", Some("1"), Some("2"), &ok);
+        entry.write_throughput = Some(1000);
+        entries.push(entry);
+        let mut entry = HistoryEntry::new(HistoryAction::Update, "EFI", Some("2"), Some("3"), &ok);
+        entry.write_throughput = Some(3000);
+        entries.push(entry);
+        // Only successful updates which measured it count
+        entries.push(HistoryEntry::new(
+            HistoryAction::Update,
+            "EFI",
+            Some("3"),
+            Some("4"),
+            &err,
+        ));
+        entries.push(HistoryEntry::new(
+            HistoryAction::Install,
+            "BIOS",
+            None,
+            Some("1"),
+            &ok,
+        ));
+        assert_eq!(write_throughput(&entries), Some(2000));
+        for _ in 0..THROUGHPUT_SAMPLES {
+            let mut entry =
+                HistoryEntry::new(HistoryAction::Update, "EFI", Some("4"), Some("5"), &ok);
+            entry.write_throughput = Some(500);
+            entries.push(entry);
+        }
+        assert_eq!(write_throughput(&entries), Some(500));
+    }
+}
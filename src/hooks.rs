@@ -0,0 +1,252 @@
+//! Site hooks run around component updates, from
+//! `/etc/bootupd/hooks.d/{pre-update,post-update}`.
+//!
+//! Hooks are the executable files of these directories, run in the
+//! lexical order of their names, with the update described by environment
+//! variables.  A failing pre-update hook vetoes the update; the failures of
+//! post-update hooks are only logged.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use rustix::fs::{Access, AtFlags};
+
+/// The hook directories, relative to the root
+pub(crate) const HOOKS_DIR: &str = "etc/bootupd/hooks.d";
+
+/// How often we check whether a hook exited
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When a hook is run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    PreUpdate,
+    PostUpdate,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::PreUpdate => "pre-update",
+            Phase::PostUpdate => "post-update",
+        }
+    }
+}
+
+/// The update a hook is run for.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HookContext<'a> {
+    pub(crate) component: &'a str,
+    pub(crate) old_version: &'a str,
+    pub(crate) new_version: &'a str,
+    /// Whether the update succeeded, for post-update hooks
+    pub(crate) success: Option<bool>,
+}
+
+impl HookContext<'_> {
+    fn env(&self, phase: Phase) -> Vec<(&'static str, &str)> {
+        let mut r = vec![
+            ("BOOTUPD_HOOK", phase.as_str()),
+            ("BOOTUPD_COMPONENT", self.component),
+            ("BOOTUPD_OLD_VERSION", self.old_version),
+            ("BOOTUPD_NEW_VERSION", self.new_version),
+        ];
+        if let Some(success) = self.success {
+            r.push((
+                "BOOTUPD_RESULT",
+                if success { "success" } else { "failure" },
+            ));
+        }
+        r
+    }
+}
+
+/// The hooks of `phase`, in the order they are run.
+fn list(root: &Path, phase: Phase) -> Result<Vec<PathBuf>> {
+    let dir = root.join(HOOKS_DIR).join(phase.as_str());
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {dir:?}")),
+    };
+    let mut r = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with('.') || name.ends_with('~') || !path.is_file() {
+            continue;
+        }
+        let executable =
+            rustix::fs::accessat(rustix::fs::CWD, &path, Access::EXEC_OK, AtFlags::empty());
+        if executable.is_err() {
+            log::debug!("Skipping non-executable hook {path:?}");
+            continue;
+        }
+        r.push(path);
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Run `hook`, killing it after `timeout`.
+fn run_one(hook: &Path, env: &[(&str, &str)], timeout: Duration) -> Result<ExitStatus> {
+    let mut child = Command::new(hook)
+        .envs(env.iter().copied())
+        .spawn()
+        .with_context(|| format!("running {hook:?}"))?;
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("{hook:?} timed out after {}s", timeout.as_secs());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Run the hooks of `phase`; pre-update hooks stop at the first failure,
+/// which is returned as an error.
+#[context("Running {} hooks", phase.as_str())]
+pub(crate) fn run(root: &Path, phase: Phase, ctx: &HookContext, timeout: Duration) -> Result<()> {
+    let env = ctx.env(phase);
+    for hook in list(root, phase)? {
+        log::debug!("Running hook {hook:?}");
+        let r = run_one(&hook, &env, timeout).and_then(|status| {
+            if !status.success() {
+                bail!("{hook:?} failed: {status}");
+            }
+            Ok(())
+        });
+        match (phase, r) {
+            (_, Ok(())) => {}
+            (Phase::PreUpdate, Err(e)) => {
+                return Err(e).context(format!("Update of {} vetoed", ctx.component))
+            }
+            (Phase::PostUpdate, Err(e)) => log::warn!("{e:#}"),
+        }
+    }
+    Ok(())
+}
+
+/// Run `f` between the pre-update hooks, which may veto it, and the
+/// post-update hooks, told whether it succeeded.
+pub(crate) fn run_around<T>(
+    root: &Path,
+    ctx: &HookContext,
+    timeout: Duration,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    run(root, Phase::PreUpdate, ctx, timeout)?;
+    let r = f();
+    let ctx = HookContext {
+        success: Some(r.is_ok()),
+        ..*ctx
+    };
+    if let Err(e) = run(root, Phase::PostUpdate, &ctx, timeout) {
+        log::warn!("{e:#}");
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_hook(root: &Path, phase: Phase, name: &str, script: &str) -> Result<()> {
+        let dir = root.join(HOOKS_DIR).join(phase.as_str());
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{script}\n"))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let out = root.join("out");
+        let ctx = HookContext {
+            component: "EFI",
+            old_version: "1",
+            new_version: "2",
+            success: None,
+        };
+        let timeout = Duration::from_secs(10);
+        // No hooks at all
+        run(root, Phase::PreUpdate, &ctx, timeout)?;
+
+        let script = format!(
+            "echo \"$0 $BOOTUPD_HOOK $BOOTUPD_COMPONENT $BOOTUPD_OLD_VERSION $BOOTUPD_NEW_VERSION\" >> {}",
+            out.display()
+        );
+        write_hook(root, Phase::PreUpdate, "20-b", &script)?;
+        write_hook(root, Phase::PreUpdate, "10-a", &script)?;
+        std::fs::write(root.join(HOOKS_DIR).join("pre-update/30-noexec"), "")?;
+        run(root, Phase::PreUpdate, &ctx, timeout)?;
+        let dir = root.join(HOOKS_DIR).join("pre-update");
+        assert_eq!(
+            std::fs::read_to_string(&out)?,
+            format!(
+                "{0}/10-a pre-update EFI 1 2\n{0}/20-b pre-update EFI 1 2\n",
+                dir.display()
+            )
+        );
+
+        write_hook(root, Phase::PreUpdate, "15-veto", "exit 1")?;
+        std::fs::remove_file(&out)?;
+        assert!(run(root, Phase::PreUpdate, &ctx, timeout).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&out)?,
+            format!("{}/10-a pre-update EFI 1 2\n", dir.display())
+        );
+
+        write_hook(root, Phase::PostUpdate, "10-fail", "exit 1")?;
+        let script = format!("echo $BOOTUPD_RESULT > {}", out.display());
+        write_hook(root, Phase::PostUpdate, "20-result", &script)?;
+        let ctx = HookContext {
+            success: Some(true),
+            ..ctx
+        };
+        run(root, Phase::PostUpdate, &ctx, timeout)?;
+        assert_eq!(std::fs::read_to_string(&out)?, "success\n");
+
+        write_hook(root, Phase::PreUpdate, "15-veto", "sleep 10")?;
+        let err = run(root, Phase::PreUpdate, &ctx, Duration::from_millis(200)).unwrap_err();
+        assert!(format!("{err:#}").contains("timed out"));
+        Ok(())
+    }
+    #[test]
+    fn test_run_around() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let out = root.join("out");
+        let ctx = HookContext {
+            component: "systemd-boot",
+            old_version: "255",
+            new_version: "256",
+            success: None,
+        };
+        let timeout = Duration::from_secs(10);
+        let script = format!("echo $BOOTUPD_RESULT >> {}", out.display());
+        write_hook(root, Phase::PostUpdate, "10-result", &script)?;
+        assert_eq!(run_around(root, &ctx, timeout, || Ok(1))?, 1);
+        assert!(run_around(root, &ctx, timeout, || -> Result<()> { bail!("failed") }).is_err());
+        assert_eq!(std::fs::read_to_string(&out)?, "success\nfailure\n");
+
+        // A vetoed update isn't run, nor are the post-update hooks
+        write_hook(root, Phase::PreUpdate, "10-veto", "exit 1")?;
+        let r = run_around(root, &ctx, timeout, || -> Result<()> { unreachable!() });
+        assert!(r.is_err());
+        assert_eq!(std::fs::read_to_string(&out)?, "success\nfailure\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+//! Structured logging to the systemd journal.
+//!
+//! When running as a service, log records are sent to the journal with
+//! their priority and source location instead of being written to stderr.
+//! The key events of updates are also recorded with a stable `MESSAGE_ID`
+//! and the `BOOTUPD_COMPONENT`, `BOOTUPD_OLD_VERSION`,
+//! `BOOTUPD_NEW_VERSION` and `BOOTUPD_DEVICE` fields, so they can be queried
+//! with e.g. `journalctl MESSAGE_ID=6225dcf49df34731ba1a7f817a802c4d`.
+
+use libsystemd::logging::{self, Priority};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// An update of a component started
+pub(crate) const MESSAGE_ID_UPDATE_STARTED: &str = "6225dcf49df34731ba1a7f817a802c4d";
+/// An update of a component succeeded
+pub(crate) const MESSAGE_ID_UPDATE_SUCCEEDED: &str = "37681a7b700745cdba6fe99cd5df5320";
+/// An update of a component failed
+pub(crate) const MESSAGE_ID_UPDATE_FAILED: &str = "b238c5c7edec4a0bb499217f98128932";
+
+/// A key event of an update.
+#[derive(Debug)]
+pub(crate) struct UpdateEvent<'a> {
+    pub(crate) component: &'a str,
+    pub(crate) old_version: Option<&'a str>,
+    pub(crate) new_version: &'a str,
+    /// The devices with raw bootloader data, if any
+    pub(crate) devices: &'a [String],
+}
+
+impl UpdateEvent<'_> {
+    fn fields(&self, message_id: &'static str) -> Vec<(&'static str, String)> {
+        let mut r = vec![
+            ("MESSAGE_ID", message_id.to_string()),
+            ("BOOTUPD_COMPONENT", self.component.to_string()),
+            ("BOOTUPD_NEW_VERSION", self.new_version.to_string()),
+        ];
+        if let Some(v) = self.old_version {
+            r.push(("BOOTUPD_OLD_VERSION", v.to_string()));
+        }
+        r.extend(self.devices.iter().map(|d| ("BOOTUPD_DEVICE", d.clone())));
+        r
+    }
+
+    fn send(&self, message_id: &'static str, priority: Priority, message: &str) {
+        if let Err(e) =
+            logging::journal_send(priority, message, self.fields(message_id).into_iter())
+        {
+            log::debug!("Failed to write to the journal: {e}");
+        }
+    }
+
+    pub(crate) fn started(&self) {
+        let message = format!(
+            "Updating {} from {} to {}",
+            self.component,
+            self.old_version.unwrap_or("unknown version"),
+            self.new_version
+        );
+        self.send(MESSAGE_ID_UPDATE_STARTED, Priority::Notice, &message);
+    }
+
+    pub(crate) fn succeeded(&self) {
+        let message = format!("Updated {} to {}", self.component, self.new_version);
+        self.send(MESSAGE_ID_UPDATE_SUCCEEDED, Priority::Notice, &message);
+    }
+
+    pub(crate) fn failed(&self, e: &anyhow::Error) {
+        let message = format!(
+            "Failed to update {} to {}: {e:#}",
+            self.component, self.new_version
+        );
+        self.send(MESSAGE_ID_UPDATE_FAILED, Priority::Error, &message);
+    }
+}
+
+/// A `log` backend writing to the journal.
+struct JournalLogger {
+    level: LevelFilter,
+}
+
+fn priority(level: Level) -> Priority {
+    match level {
+        Level::Error => Priority::Error,
+        Level::Warn => Priority::Warning,
+        Level::Info => Priority::Info,
+        Level::Debug | Level::Trace => Priority::Debug,
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level && metadata.target().starts_with(clap::crate_name!())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut fields = vec![("TARGET", record.target().to_string())];
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE", file.to_string()));
+        }
+        if let Some(line) = record.line() {
+            fields.push(("CODE_LINE", line.to_string()));
+        }
+        let message = record.args().to_string();
+        let _ = logging::journal_send(priority(record.level()), &message, fields.into_iter());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send log records to the journal if stderr is connected to it, as when
+/// running as a service; returns `false` otherwise.
+pub(crate) fn init(level: LevelFilter) -> bool {
+    if !logging::connected_to_journal() {
+        return false;
+    }
+    if log::set_boxed_logger(Box::new(JournalLogger { level })).is_err() {
+        return false;
+    }
+    log::set_max_level(level);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let devices = vec!["/dev/sda".to_string(), "/dev/sdb".to_string()];
+        let event = UpdateEvent {
+            component: "BIOS",
+            old_version: Some("grub2-2.06"),
+            new_version: "grub2-2.12",
+            devices: &devices,
+        };
+        let fields = event.fields(MESSAGE_ID_UPDATE_STARTED);
+        assert_eq!(fields[0], ("MESSAGE_ID", MESSAGE_ID_UPDATE_STARTED.into()));
+        assert!(fields.contains(&("BOOTUPD_OLD_VERSION", "grub2-2.06".into())));
+        let devices: Vec<_> = fields
+            .iter()
+            .filter(|(k, _)| *k == "BOOTUPD_DEVICE")
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(devices, ["/dev/sda", "/dev/sdb"]);
+        for (k, _) in fields.iter() {
+            assert!(k.chars().all(|c| c.is_ascii_uppercase() || c == '_'));
+        }
+    }
+}
@@ -0,0 +1,282 @@
+/*!
+**Boot**loader **upd**ater.
+
+Bootupd installs and updates the bootloaders of image-based systems
+(e.g. ostree), which the package system of the OS doesn't touch: the EFI
+system partition (shim and GRUB, or systemd-boot and UKIs), the BIOS boot
+code of GRUB or syslinux, the dbx of the firmware, device trees, u-boot
+on boards, and zipl on s390x, depending on the architecture.
+
+Besides the `bootupd` and `bootupctl` binaries, this crate can be used as
+a library by installers and update tools (e.g. coreos-installer or bootc),
+instead of running `bootupctl` and parsing its output.  Unless noted
+otherwise, the functions operate on the booted system (`/`) and must be
+run as root.
+
+Refs:
+ * <https://github.com/coreos/fedora-coreos-tracker/issues/510>
+!*/
+
+#![deny(unused_must_use)]
+// The style lints are more annoying than useful
+#![allow(clippy::style)]
+#![deny(clippy::dbg_macro)]
+
+mod backend;
+mod backup;
+#[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
+mod bios;
+mod blockdev;
+mod bootupd;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod bootvars;
+mod bootverify;
+#[doc(hidden)]
+pub mod cli;
+mod component;
+mod config;
+mod coreos;
+#[cfg(feature = "dbus")]
+mod dbus;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod dbx;
+mod digest;
+mod driftwatch;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+mod dtb;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod efi;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod efivars;
+mod failpoints;
+mod filesystem;
+mod filetree;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod firmware;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    target_arch = "riscv64"
+))]
+mod grubconfigs;
+mod grubenv;
+mod health;
+mod history;
+mod hooks;
+mod journal;
+mod metrics;
+mod model;
+mod model_legacy;
+mod ostreeutil;
+mod packagesystem;
+mod parallel;
+mod payloadexport;
+mod payloadsig;
+mod payloadstore;
+mod payloadview;
+mod payloadzstd;
+mod progress;
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )),
+    allow(dead_code)
+)]
+mod quarantine;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod sbat;
+mod sdnotify;
+#[cfg(feature = "selftest")]
+mod selftest;
+mod sha512string;
+#[cfg(target_arch = "x86_64")]
+mod syslinux;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod systemdboot;
+#[cfg(all(
+    feature = "testing",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+pub mod testing;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod tpm;
+mod transaction;
+#[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+mod uboot;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+mod uki;
+mod updatesource;
+mod util;
+#[cfg(target_arch = "s390x")]
+mod zipl;
+
+use std::path::Path;
+
+use anyhow::Result;
+
+pub use crate::bootupd::ComponentUpdateResult;
+pub use crate::component::GenerateOptions;
+pub use crate::config::{
+    AutoUpdatePolicy, BiosConfig, ComponentsConfig, Config, ConsoleConfig, EfiConfig, GrubConfig,
+    HooksConfig, SbatPolicy, SerialConfig, SerialParity, SyncPolicy, UkiConfig, UpdateConfig,
+};
+pub use crate::history::{HistoryAction, HistoryEntry};
+pub use crate::model::{
+    Adoptable, ComponentStatus, ComponentUpdatable, ComponentValidation, ContentMetadata,
+    EfiBootStatus, EspStatus, SbatStatus, SecureBootStatus, Status, ValidationError,
+    ValidationErrorKind, ValidationReport, ValidationVerdict,
+};
+pub use crate::progress::{Progress, ProgressFn};
+
+/// Options for [`install`].
+#[derive(Debug, Default, Clone)]
+pub struct InstallOptions {
+    /// The devices to write raw bootloader data to, e.g. for BIOS on each
+    /// member of a RAID1; disk image files are attached to loop devices
+    pub devices: Vec<String>,
+    /// Also install the built-in static GRUB configuration
+    pub with_static_configs: bool,
+    /// Implies `with_static_configs`, also writing the UUIDs of the target
+    /// filesystems
+    pub write_uuid: bool,
+    /// Create the firmware boot entry on EFI systems, and stage the
+    /// available firmware updates
+    pub update_firmware: bool,
+    /// GRUB modules to embed in the BIOS bootloader in addition to the
+    /// configured ones, also on update (e.g. `lvm`)
+    pub bios_modules: Vec<String>,
+    /// Only install these components, instead of all the available ones
+    pub components: Option<Vec<String>>,
+    /// Choose the components based on how the host was booted
+    pub auto: bool,
+}
+
+/// Install the bootloader components of the `source_root` deployment to
+/// the physical root mounted at `dest_root`.
+pub fn install(source_root: &str, dest_root: &str, opts: &InstallOptions) -> Result<()> {
+    let configs = if opts.write_uuid {
+        bootupd::ConfigMode::WithUUID
+    } else if opts.with_static_configs {
+        bootupd::ConfigMode::Static
+    } else {
+        bootupd::ConfigMode::None
+    };
+    bootupd::install(
+        source_root,
+        dest_root,
+        &opts.devices,
+        configs,
+        &component::InstallComponentOptions {
+            update_firmware: opts.update_firmware,
+            bios_modules: opts.bios_modules.clone(),
+        },
+        opts.components.as_deref(),
+        opts.auto,
+    )
+}
+
+/// Generate the update payloads from the deployment at `sysroot`, in
+/// `/usr/lib/bootupd/updates`.
+pub fn generate_update_metadata(sysroot: &str, opts: &GenerateOptions) -> Result<()> {
+    bootupd::generate_update_metadata(sysroot, opts)
+}
+
+/// The installed components, their available updates and the components
+/// which could be adopted.
+pub fn status() -> Result<Status> {
+    bootupd::status()
+}
+
+/// Update the component `name`, if an update is available.
+pub fn update(name: &str) -> Result<ComponentUpdateResult> {
+    bootupd::update(name, &progress::ignore)
+}
+
+/// Like [`update`], calling `progress` as the files of the update are
+/// written.
+pub fn update_with_progress(name: &str, progress: ProgressFn) -> Result<ComponentUpdateResult> {
+    bootupd::update(name, progress)
+}
+
+/// Start managing the component `name`, installed without bootupd, and
+/// update it; returns the installed version.
+pub fn adopt_and_update(name: &str) -> Result<ContentMetadata> {
+    bootupd::adopt_and_update(name)
+}
+
+/// The installs, adoptions, updates and rollbacks of the components,
+/// oldest first.
+pub fn history() -> Result<Vec<HistoryEntry>> {
+    history::load(Path::new("/"))
+}
+
+/// The variables of the GRUB environment block (`/boot/grub2/grubenv`),
+/// in the order of the block.
+pub fn grubenv() -> Result<Vec<(String, String)>> {
+    Ok(grubenv::load(Path::new("/"))?.vars().to_vec())
+}
+
+/// Atomically update the GRUB environment block, removing the variables
+/// `unset` then setting those of `set`.
+pub fn set_grubenv(set: &[(String, String)], unset: &[String]) -> Result<()> {
+    bootupd::set_grubenv(set, unset)
+}
+
+/// Check that the installed components weren't modified.
+pub fn validate() -> Result<ValidationReport> {
+    bootupd::validate_all(false)
+}
+
+/// The disks backing `/boot` of `target_root`, which a BIOS bootloader is
+/// installed to (several for e.g. RAID1).
+pub fn bootloader_devices(target_root: impl AsRef<Path>) -> Result<Vec<String>> {
+    blockdev::get_bootloader_devices(target_root)
+}
+
+/// The EFI System Partitions on the disks backing `target_root`.
+pub fn esp_devices(target_root: impl AsRef<Path>) -> Result<Vec<String>> {
+    blockdev::find_esps(target_root)
+}
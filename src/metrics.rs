@@ -0,0 +1,249 @@
+//! Metrics of the bootloaders in the Prometheus text format, for fleet
+//! dashboards.
+//!
+//! `bootupctl metrics --textfile` writes them for the textfile collector of
+//! the node exporter, and `bootupd daemon --metrics-textfile` keeps them
+//! fresh.  The file is replaced atomically, so that the collector never
+//! reads a partial one.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::bootupd;
+use crate::history::{self, HistoryAction, HistoryEntry};
+use crate::model::{ComponentUpdatable, Status, ValidationReport};
+
+/// The prefix of our metrics
+const PREFIX: &str = "bootupd";
+
+/// Escape `v` for a label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Accumulates the samples of each metric, to print them grouped.
+#[derive(Default)]
+struct Metrics {
+    /// Maps a metric name to its help and samples
+    metrics: BTreeMap<&'static str, (&'static str, Vec<String>)>,
+}
+
+impl Metrics {
+    fn gauge(&mut self, name: &'static str, help: &'static str, labels: &[(&str, &str)], v: i64) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+            .collect();
+        let sample = if labels.is_empty() {
+            format!("{PREFIX}_{name} {v}")
+        } else {
+            format!("{PREFIX}_{name}{{{}}} {v}", labels.join(","))
+        };
+        self.metrics
+            .entry(name)
+            .or_insert_with(|| (help, Vec::new()))
+            .1
+            .push(sample);
+    }
+
+    fn render(&self) -> String {
+        let mut r = String::new();
+        for (name, (help, samples)) in self.metrics.iter() {
+            // Unwrap safety: writing to a String can't fail
+            writeln!(r, "# HELP {PREFIX}_{name} {help}").unwrap();
+            writeln!(r, "# TYPE {PREFIX}_{name} gauge").unwrap();
+            for s in samples {
+                writeln!(r, "{s}").unwrap();
+            }
+        }
+        r
+    }
+}
+
+/// When each component was last successfully installed, adopted or
+/// updated, according to `history`.
+fn last_updates(history: &[HistoryEntry]) -> BTreeMap<&str, DateTime<Utc>> {
+    let mut r = BTreeMap::new();
+    for e in history.iter().filter(|e| {
+        e.success
+            && matches!(
+                e.action,
+                HistoryAction::Install | HistoryAction::Adopt | HistoryAction::Update
+            )
+    }) {
+        r.insert(e.component.as_str(), e.timestamp);
+    }
+    r
+}
+
+/// Render the metrics of `status`, `validation` and `history`.
+fn render(status: &Status, validation: &ValidationReport, history: &[HistoryEntry]) -> String {
+    let mut m = Metrics::default();
+    let last_updates = last_updates(history);
+    for (name, c) in status.components.iter() {
+        let labels = [("component", name.as_str())];
+        m.gauge(
+            "component_info",
+            "The installed version of the component",
+            &[("component", name), ("version", &c.installed.version)],
+            1,
+        );
+        let pending = matches!(c.updatable, ComponentUpdatable::Upgradable);
+        m.gauge(
+            "pending_update",
+            "Whether an update of the component is available",
+            &labels,
+            pending.into(),
+        );
+        m.gauge(
+            "update_interrupted",
+            "Whether the last update of the component was interrupted",
+            &labels,
+            c.interrupted.is_some().into(),
+        );
+        if let Some(t) = last_updates.get(name.as_str()) {
+            m.gauge(
+                "last_update_timestamp_seconds",
+                "When the component was last installed, adopted or updated",
+                &labels,
+                t.timestamp(),
+            );
+        }
+        if let Some(v) = validation.components.get(name) {
+            m.gauge(
+                "validate_errors",
+                "The number of installed files of the component found modified or missing",
+                &labels,
+                v.errors.len() as i64,
+            );
+        }
+    }
+    for name in status.adoptable.keys() {
+        m.gauge(
+            "adoptable",
+            "Whether the component is installed but not managed by bootupd",
+            &[("component", name)],
+            1,
+        );
+    }
+    m.gauge(
+        "drifted_files",
+        "The number of managed files modified out of band, seen by bootupd watch",
+        &[],
+        status.drift.len() as i64,
+    );
+    m.render()
+}
+
+/// The metrics of the system, in the Prometheus text format.
+#[context("Collecting metrics")]
+pub(crate) fn collect() -> Result<String> {
+    let status = bootupd::status()?;
+    let validation = bootupd::validate_all(false)?;
+    let history = history::load(Path::new("/"))?;
+    Ok(render(&status, &validation, &history))
+}
+
+/// Atomically replace `path` with the metrics of the system.
+#[context("Writing metrics to {}", path.display())]
+pub(crate) fn write_textfile(path: &Path) -> Result<()> {
+    let metrics = collect()?;
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path.file_name().context("Missing file name")?;
+    openat::Dir::open(parent)?.write_file_contents(name, 0o644, metrics)?;
+    Ok(())
+}
+
+/// Refresh `path` every `interval` in a thread, for the daemon.
+#[cfg(feature = "dbus")]
+pub(crate) fn spawn_refresh(path: &Path, interval: std::time::Duration) -> Result<()> {
+    let path = path.to_owned();
+    std::thread::Builder::new()
+        .name("metrics".into())
+        .spawn(move || loop {
+            if let Err(e) = write_textfile(&path) {
+                log::warn!("{e:#}");
+            }
+            std::thread::sleep(interval);
+        })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{
+        ComponentStatus, ComponentValidation, ContentMetadata, ValidationError,
+        ValidationErrorKind, ValidationVerdict,
+    };
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let meta = |version: &str| ContentMetadata {
+            timestamp: "2025-01-01T00:00:00Z".parse().unwrap(),
+            version: version.into(),
+            signing_keys: Vec::new(),
+        };
+        let mut status = Status::default();
+        status.components.insert(
+            "EFI".into(),
+            ComponentStatus {
+                installed: meta("grub2-2.12-1"),
+                interrupted: None,
+                staged: None,
+                update: Some(meta("grub2-2.12-2")),
+                updatable: ComponentUpdatable::Upgradable,
+                adopted_from: None,
+                efi_vendor: None,
+                devices: Vec::new(),
+                pcr4: None,
+                key_rotation: None,
+                pending_nvram: None,
+                rebuilt: false,
+                update_estimate: None,
+            },
+        );
+        let validation = ValidationReport::new(BTreeMap::from([(
+            "EFI".to_string(),
+            ComponentValidation {
+                verdict: ValidationVerdict::Errors,
+                errors: vec![ValidationError::new(
+                    ValidationErrorKind::Missing,
+                    "EFI/fedora/grubx64.efi",
+                )],
+            },
+        )]));
+        let ok: Result<()> = Ok(());
+        let mut install = HistoryEntry::new(HistoryAction::Install, "EFI", None, Some("1"), &ok);
+        install.timestamp = "2025-01-02T00:00:00Z".parse()?;
+        let err: Result<()> = Err(anyhow::anyhow!("no space left"));
+        let failed = HistoryEntry::new(HistoryAction::Update, "EFI", Some("1"), Some("2"), &err);
+
+        let r = render(&status, &validation, &[install, failed]);
+        assert!(r.contains("# TYPE bootupd_pending_update gauge\n"));
+        assert!(r.contains("bootupd_pending_update{component=\"EFI\"} 1\n"));
+        assert!(r.contains("bootupd_validate_errors{component=\"EFI\"} 1\n"));
+        assert!(r.contains("bootupd_last_update_timestamp_seconds{component=\"EFI\"} 1735776000\n"));
+        assert!(
+            r.contains("bootupd_component_info{component=\"EFI\",version=\"grub2-2.12-1\"} 1\n")
+        );
+        assert!(r.contains("bootupd_drifted_files 0\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
@@ -6,28 +6,40 @@
 
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::sha512string::SHA512String;
 
 /// The directory where updates are stored
 pub(crate) const BOOTUPD_UPDATES_DIR: &str = "usr/lib/bootupd/updates";
 
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ContentMetadata {
+pub struct ContentMetadata {
     /// The timestamp, which is used to determine update availability
-    pub(crate) timestamp: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
     /// Human readable version number, like ostree it is not ever parsed, just displayed
-    pub(crate) version: String,
+    pub version: String,
+    /// The fingerprints of the keys which signed the packages of the
+    /// payload, for rpm based systems
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signing_keys: Vec<String>,
 }
 
 impl ContentMetadata {
     /// Returns `true` if `target` is different and chronologically newer
-    pub(crate) fn can_upgrade_to(&self, target: &Self) -> bool {
+    pub fn can_upgrade_to(&self, target: &Self) -> bool {
         if self.version == target.version {
             return false;
         }
         target.timestamp > self.timestamp
     }
+
+    /// Returns `true` if `target` is different and can be installed, i.e.
+    /// it is newer or downgrades are allowed
+    pub(crate) fn can_install(&self, target: &Self, allow_downgrade: bool) -> bool {
+        self.version != target.version && (allow_downgrade || self.can_upgrade_to(target))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -39,25 +51,179 @@ pub(crate) struct InstalledContent {
     pub(crate) filetree: Option<crate::filetree::FileTree>,
     /// The version this was originally adopted from
     pub(crate) adopted_from: Option<ContentMetadata>,
+    /// Checksums of raw bootloader data written outside of any filesystem
+    /// (e.g. the BIOS boot or PReP partition), keyed by target device (and offset,
+    /// for `device@offset` keys)
+    pub(crate) raw_checksums: Option<BTreeMap<String, SHA512String>>,
+    /// The version written to each ESP, keyed by device, when the content
+    /// is mirrored on several ESPs
+    #[serde(default)]
+    pub(crate) esps: Option<BTreeMap<String, ContentMetadata>>,
+    /// The modules embedded in the BIOS bootloader in addition to those of
+    /// the configuration (e.g. given to `bootupctl backend install`), which
+    /// updates keep embedding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bios_modules: Option<Vec<String>>,
+    /// The predicted PCR 4 value for the installed EFI binaries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pcr4: Option<Pcr4Prediction>,
+    /// The firmware updates staged at install time, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) firmware: Option<Vec<FirmwareUpdate>>,
+    /// The updates of the dbx revocation list appended to the firmware, by
+    /// payload file name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) dbx: Option<BTreeMap<String, SHA512String>>,
+    /// The previous boot chain kept in the ESP while a Secure Boot key
+    /// rotation is in progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) rotation: Option<KeyRotation>,
+    /// The writes to the firmware variables skipped because efivarfs
+    /// wasn't writable, retried by the next update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) pending_nvram: Option<BTreeSet<NvramOperation>>,
+    /// Rebuilt from what is found on the system by `bootupctl state
+    /// repair`, rather than recorded when writing it; until the next update
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) rebuilt: bool,
+    /// The highest SBAT generation of each component (e.g. `shim`, `grub`)
+    /// among the installed EFI binaries, which updates may not lower
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sbat: Option<BTreeMap<String, u32>>,
+    /// The SHA-256 Authenticode digests of the installed EFI binaries, hex
+    /// encoded, by path; validation checks them like the firmware would
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) authenticode: Option<BTreeMap<String, String>>,
+}
+
+/// The boot chain installed before a Secure Boot key rotation (e.g. shim
+/// and GRUB signed with the old CA), kept along the new one until
+/// `bootupctl finalize-rotation`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct KeyRotation {
+    /// The version it was installed from
+    pub(crate) previous: ContentMetadata,
+    /// Its directory, relative to `EFI/`, e.g. `fedora-previous`
+    pub(crate) dir: String,
+    /// Its files, relative to `dir`
+    pub(crate) filetree: crate::filetree::FileTree,
+}
+
+/// A write to the firmware variables, which can be deferred when efivarfs
+/// is absent or read-only (in containers, image builds, or with a
+/// locked-down kernel).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum NvramOperation {
+    /// Creating the boot entry of the vendor loader
+    BootEntry,
+    /// Requesting the firmware to apply the capsules staged on the ESP
+    CapsuleDelivery,
+    /// Appending the revocation list updates to dbx
+    DbxUpdate,
+}
+
+impl std::fmt::Display for NvramOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            NvramOperation::BootEntry => "boot entry",
+            NvramOperation::CapsuleDelivery => "capsule delivery",
+            NvramOperation::DbxUpdate => "dbx update",
+        };
+        f.write_str(s)
+    }
+}
+
+/// The layout version of the state file written by this version; files
+/// without `schema-version` are at version 0.  The field is only written
+/// from version 1 on, since older versions of bootupd refuse unknown fields
+/// and would no longer parse the state after a rollback of the OS.
+pub(crate) const STATE_SCHEMA_VERSION: u32 = 0;
+
+/// A change of the layout of the state file, applied to its JSON before it
+/// is parsed, so that the state of older versions can always be loaded.
+#[allow(dead_code)] // Constructed by the first change of the layout
+struct StateMigration {
+    /// The schema version it migrates to, from the previous one
+    to: u32,
+    description: &'static str,
+    migrate: fn(&mut serde_json::Map<String, serde_json::Value>) -> anyhow::Result<()>,
+}
+
+/// The migrations of the state file, in order.
+const STATE_MIGRATIONS: &[StateMigration] = &[];
+
+/// Migrate the JSON of a state file to `STATE_SCHEMA_VERSION`; returns the
+/// descriptions of the applied migrations.
+pub(crate) fn migrate_state(state: &mut serde_json::Value) -> anyhow::Result<Vec<&'static str>> {
+    use anyhow::Context;
+    const KEY: &str = "schema-version";
+    let state = state
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("The state is not a JSON object"))?;
+    let version = match state.get(KEY) {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid {KEY}: {v}"))?,
+    };
+    if version > STATE_SCHEMA_VERSION {
+        anyhow::bail!(
+            "The state file is at schema version {version}, newer than the supported {STATE_SCHEMA_VERSION}"
+        );
+    }
+    let mut applied = Vec::new();
+    for m in STATE_MIGRATIONS.iter().filter(|m| m.to > version) {
+        (m.migrate)(state)
+            .with_context(|| format!("Migrating the state to schema version {}", m.to))?;
+        state.insert(KEY.into(), m.to.into());
+        applied.push(m.description);
+    }
+    Ok(applied)
 }
 
 /// Will be serialized into /boot/bootupd-state.json
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
 pub(crate) struct SavedState {
+    /// The layout version, see `STATE_SCHEMA_VERSION`
+    #[serde(default, skip_serializing_if = "is_unversioned")]
+    pub(crate) schema_version: u32,
     /// Maps a component name to its currently installed version
     pub(crate) installed: BTreeMap<String, InstalledContent>,
     /// Maps a component name to an in progress update
     pub(crate) pending: Option<BTreeMap<String, ContentMetadata>>,
     /// If static bootloader configs are enabled, this contains the version
     pub(crate) static_configs: Option<ContentMetadata>,
+    /// Maps a component name to the update staged by `update --stage`, to
+    /// be applied at shutdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) staged: Option<BTreeMap<String, ContentMetadata>>,
+}
+
+fn is_unversioned(v: &u32) -> bool {
+    *v == 0
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        Self {
+            schema_version: STATE_SCHEMA_VERSION,
+            installed: Default::default(),
+            pending: None,
+            static_configs: None,
+            staged: None,
+        }
+    }
 }
 
 /// The status of an individual component.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdatable {
+pub enum ComponentUpdatable {
     NoUpdateAvailable,
     AtLatestVersion,
     Upgradable,
@@ -84,27 +250,230 @@ impl ComponentUpdatable {
 /// The status of an individual component.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ComponentStatus {
+pub struct ComponentStatus {
     /// Currently installed version
-    pub(crate) installed: ContentMetadata,
+    pub installed: ContentMetadata,
     /// In progress update that was interrupted
-    pub(crate) interrupted: Option<ContentMetadata>,
+    pub interrupted: Option<ContentMetadata>,
+    /// Update staged by `update --stage`, applied at shutdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged: Option<ContentMetadata>,
     /// Update in the deployed filesystem tree
-    pub(crate) update: Option<ContentMetadata>,
+    pub update: Option<ContentMetadata>,
     /// Is true if the version in `update` is different from `installed`
-    pub(crate) updatable: ComponentUpdatable,
+    pub updatable: ComponentUpdatable,
     /// Originally adopted version
-    pub(crate) adopted_from: Option<ContentMetadata>,
+    pub adopted_from: Option<ContentMetadata>,
+    /// The EFI vendor directory used by the update, if any
+    #[serde(default)]
+    pub efi_vendor: Option<String>,
+    /// Block devices with bootloader data written outside of any filesystem
+    #[serde(default)]
+    pub devices: Vec<String>,
+    /// The predicted PCR 4 value for the installed EFI binaries
+    #[serde(default)]
+    pub pcr4: Option<Pcr4Prediction>,
+    /// The version of the previous boot chain kept in the ESP while a
+    /// Secure Boot key rotation is in progress
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_rotation: Option<ContentMetadata>,
+    /// The writes to the firmware variables deferred until efivarfs is
+    /// writable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_nvram: Option<BTreeSet<NvramOperation>>,
+    /// The record of the installed content was rebuilt by `bootupctl state
+    /// repair`, so the installed version is a guess
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rebuilt: bool,
+    /// What the available update would write, if the component tracks its
+    /// files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_estimate: Option<UpdateEstimate>,
+}
+
+/// The writes of an available update, to schedule it on slow media.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct UpdateEstimate {
+    /// The bytes of the files added or changed
+    pub bytes: u64,
+    /// The number of files added or changed
+    pub files: usize,
+    /// The expected duration, from the write throughput measured by the
+    /// previous updates, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seconds: Option<u64>,
+}
+
+impl InstalledContent {
+    /// A record of content found on the system at `version`, by `bootupctl
+    /// state repair`.
+    pub(crate) fn rebuilt(version: ContentMetadata) -> Self {
+        Self {
+            meta: version.clone(),
+            filetree: None,
+            adopted_from: Some(version),
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: true,
+            sbat: None,
+            authenticode: None,
+        }
+    }
+
+    /// Returns the block devices which have raw bootloader data recorded
+    /// in `raw_checksums`.
+    pub(crate) fn devices(&self) -> Vec<String> {
+        let devices: BTreeSet<&str> = self
+            .raw_checksums
+            .iter()
+            .flat_map(|m| m.keys())
+            .map(|k| k.split_once('@').map_or(k.as_str(), |(dev, _)| dev))
+            .collect();
+        devices.into_iter().map(String::from).collect()
+    }
 }
 
 /// Information on a component that can be adopted
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct Adoptable {
+pub struct Adoptable {
     /// A synthetic version
-    pub(crate) version: ContentMetadata,
+    pub version: ContentMetadata,
     /// True if we are likely to be able to reliably update this system
-    pub(crate) confident: bool,
+    pub confident: bool,
+}
+
+/// The firmware boot entries, relative to the entry for the EFI vendor loader.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct EfiBootStatus {
+    /// The entry used for the current boot
+    pub boot_current: Option<u16>,
+    pub boot_order: Vec<u16>,
+    /// The entry for the vendor loader on our ESP
+    pub entry: Option<u16>,
+    /// Entries on our ESP which reference files that do not exist
+    pub dangling: Vec<u16>,
+}
+
+impl EfiBootStatus {
+    /// Returns `true` if our entry exists and is the first one in
+    /// `BootOrder`, ignoring dangling entries.
+    pub fn is_first(&self) -> bool {
+        self.entry.is_some()
+            && self.boot_order.iter().find(|n| !self.dangling.contains(n)) == self.entry.as_ref()
+    }
+
+    /// Returns `true` if there is nothing to fix.
+    pub fn is_ok(&self) -> bool {
+        self.is_first() && self.dangling.is_empty()
+    }
+}
+
+/// The SBAT generations of the EFI binaries, see
+/// https://github.com/rhboot/shim/blob/main/SBAT.md
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SbatStatus {
+    /// The minimum generations enforced by shim (`SbatLevel`), if set
+    pub level: Option<BTreeMap<String, u32>>,
+    /// The generations of the installed binaries, by path in the ESP
+    pub installed: BTreeMap<String, BTreeMap<String, u32>>,
+    /// The generations of the binaries in the available update
+    pub update: BTreeMap<String, BTreeMap<String, u32>>,
+    /// The entries of the update which are revoked by `level`
+    pub revoked: Vec<String>,
+}
+
+/// How a firmware update was staged.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FirmwareUpdateMethod {
+    /// By fwupd
+    Fwupd,
+    /// As a capsule file in `EFI/UpdateCapsule` on the ESP
+    Capsule,
+}
+
+/// A firmware update staged for the firmware to apply on the next boot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FirmwareUpdate {
+    /// The updated device, or the capsule file
+    pub name: String,
+    /// The new version, if known
+    pub version: Option<String>,
+    pub method: FirmwareUpdateMethod,
+    /// Why it couldn't be staged
+    pub error: Option<String>,
+}
+
+/// A binary measured into PCR 4 when loaded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct MeasuredBinary {
+    /// The path in the ESP, relative to `EFI/`
+    pub path: String,
+    /// The SHA-256 Authenticode digest, as measured
+    pub sha256: String,
+}
+
+/// The TPM PCR 4 (boot manager code) value expected once the firmware
+/// loaded the installed boot chain, for resealing secrets bound to it.
+/// The kernel, when measured there too, extends it further.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Pcr4Prediction {
+    /// The measured binaries, in boot order
+    pub binaries: Vec<MeasuredBinary>,
+    /// The SHA-256 bank value
+    pub sha256: String,
+}
+
+/// An EFI System Partition found on the disks backing the system.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EspStatus {
+    pub device: String,
+    /// Where it is currently mounted, if it is
+    pub mountpoint: Option<String>,
+    /// The version last written to it, when mirroring EFI content
+    #[serde(default)]
+    pub installed: Option<ContentMetadata>,
+    /// Temporary files of an interrupted update found on it, if mounted;
+    /// removed by the next update
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub leftovers: Vec<String>,
+}
+
+/// The Secure Boot state of the firmware and of the installed boot chain.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SecureBootStatus {
+    /// The firmware verifies the binaries it loads
+    pub enabled: bool,
+    /// The platform key is not enrolled, so the firmware isn't locked down
+    pub setup_mode: bool,
+    /// The version embedded in the installed shim, if any
+    pub shim_version: Option<String>,
+    /// Inconsistencies found in the installed boot chain
+    pub problems: Vec<String>,
+}
+
+/// A managed file modified out of band, as seen by `bootupd watch --record`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DriftedFile {
+    pub path: String,
+    /// When the modification was noticed
+    pub time: DateTime<Utc>,
 }
 
 /// Representation of bootupd's worldview at a point in time.
@@ -114,11 +483,165 @@ pub(crate) struct Adoptable {
 #[derive(Serialize, Deserialize, Default, Debug)]
 #[serde(rename_all = "kebab-case")]
 #[serde(deny_unknown_fields)]
-pub(crate) struct Status {
+pub struct Status {
     /// Maps a component name to status
-    pub(crate) components: BTreeMap<String, ComponentStatus>,
+    pub components: BTreeMap<String, ComponentStatus>,
     /// Components that appear to be installed, not via bootupd
-    pub(crate) adoptable: BTreeMap<String, Adoptable>,
+    pub adoptable: BTreeMap<String, Adoptable>,
+    /// The firmware boot entries, if EFI is installed and booted
+    #[serde(default)]
+    pub efi_boot: Option<EfiBootStatus>,
+    /// The SBAT generations of the EFI binaries, if EFI is installed
+    #[serde(default)]
+    pub sbat: Option<SbatStatus>,
+    /// The Secure Boot state, if EFI is installed and booted
+    #[serde(default)]
+    pub secure_boot: Option<SecureBootStatus>,
+    /// The ESPs, if EFI is installed
+    #[serde(default)]
+    pub esps: Vec<EspStatus>,
+    /// The managed files modified out of band, if `bootupd watch --record`
+    /// runs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<DriftedFile>,
+    /// The effective configuration
+    #[serde(default)]
+    pub config: crate::config::Config,
+}
+
+/// The kind of problem found by validation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValidationErrorKind {
+    /// Tracked content was removed
+    Missing,
+    /// Tracked content differs from the installed version
+    Modified,
+    /// Untracked content was found in a managed location
+    Extraneous,
+}
+
+/// A single problem found by validation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ValidationError {
+    pub kind: ValidationErrorKind,
+    /// A path relative to the component root, or a device for raw content
+    pub path: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(kind: ValidationErrorKind, path: impl Into<String>) -> Self {
+        Self {
+            kind,
+            path: path.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = match self.kind {
+            ValidationErrorKind::Missing => "Removed",
+            ValidationErrorKind::Modified => "Changed",
+            ValidationErrorKind::Extraneous => "Extraneous",
+        };
+        write!(f, "{prefix}: {}", self.path)
+    }
+}
+
+/// The outcome of validating a component, or of validating the system.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ValidationVerdict {
+    Valid,
+    Skip,
+    Errors,
+}
+
+/// The validation result of an individual component.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct ComponentValidation {
+    pub verdict: ValidationVerdict,
+    pub errors: Vec<ValidationError>,
+}
+
+/// Output of `bootupctl validate --format=json`; like `Status`, this is
+/// intended to be a stable format.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct ValidationReport {
+    /// The overall verdict
+    pub verdict: ValidationVerdict,
+    /// Maps a component name to its validation result
+    pub components: BTreeMap<String, ComponentValidation>,
+}
+
+impl ValidationReport {
+    /// Aggregate component results: errors in any component are an error,
+    /// otherwise the system is valid if at least one component was validated.
+    pub(crate) fn new(components: BTreeMap<String, ComponentValidation>) -> Self {
+        let verdicts = || components.values().map(|c| c.verdict);
+        let verdict = if verdicts().any(|v| v == ValidationVerdict::Errors) {
+            ValidationVerdict::Errors
+        } else if verdicts().any(|v| v == ValidationVerdict::Valid) {
+            ValidationVerdict::Valid
+        } else {
+            ValidationVerdict::Skip
+        };
+        Self {
+            verdict,
+            components,
+        }
+    }
+}
+
+/// The outcome of a health check, or of all of them, from best to worst.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum HealthVerdict {
+    #[default]
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of one of the checks of `bootupctl health`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct HealthCheck {
+    pub verdict: HealthVerdict,
+    /// The problems found, human readable
+    pub details: Vec<String>,
+}
+
+impl HealthCheck {
+    /// Record a problem, lowering the verdict to `verdict` if it is worse.
+    pub(crate) fn report(&mut self, verdict: HealthVerdict, detail: impl Into<String>) {
+        self.verdict = self.verdict.max(verdict);
+        self.details.push(detail.into());
+    }
+}
+
+/// Output of `bootupctl health --format=json`; like `Status`, this is
+/// intended to be a stable format.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct HealthReport {
+    /// The worst verdict of the checks
+    pub verdict: HealthVerdict,
+    /// Maps a check name to its result
+    pub checks: BTreeMap<String, HealthCheck>,
+}
+
+impl HealthReport {
+    pub(crate) fn new(checks: BTreeMap<String, HealthCheck>) -> Self {
+        let verdict = checks.values().map(|c| c.verdict).max().unwrap_or_default();
+        Self { verdict, checks }
+    }
 }
 
 #[cfg(test)]
@@ -133,13 +656,18 @@ mod test {
         let a = ContentMetadata {
             timestamp: t,
             version: "v1".into(),
+            signing_keys: Vec::new(),
         };
         let b = ContentMetadata {
             timestamp: t + Duration::try_seconds(1).unwrap(),
             version: "v2".into(),
+            signing_keys: Vec::new(),
         };
         assert!(a.can_upgrade_to(&b));
         assert!(!b.can_upgrade_to(&a));
+        assert!(!b.can_install(&a, false));
+        assert!(b.can_install(&a, true));
+        assert!(!a.can_install(&a, true));
     }
 
     /// Validate we're not breaking the serialized format of /boot/bootupd-state.json
@@ -152,6 +680,50 @@ mod test {
             efi.meta.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        assert_eq!(efi.pending_nvram, None);
+        let pending = BTreeSet::from([NvramOperation::DbxUpdate, NvramOperation::BootEntry]);
+        assert_eq!(
+            serde_json::to_string(&pending)?,
+            r#"["boot-entry","dbx-update"]"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_state() -> Result<()> {
+        let data = include_str!("../tests/fixtures/example-state-v0.json");
+        let mut value: serde_json::Value = serde_json::from_str(data)?;
+        assert!(migrate_state(&mut value)?.is_empty());
+        let state: SavedState = serde_json::from_value(value.clone())?;
+        assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+        assert!(state.installed.contains_key("EFI"));
+
+        value["schema-version"] = (STATE_SCHEMA_VERSION + 1).into();
+        assert!(migrate_state(&mut value).is_err());
+        assert_eq!(SavedState::default().schema_version, STATE_SCHEMA_VERSION);
+        Ok(())
+    }
+
+    /// The state file as parsed by the versions before `schema-version`,
+    /// which a rollback of the OS can bring back.
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    #[serde(deny_unknown_fields)]
+    struct SavedStateUnversioned {
+        installed: BTreeMap<String, InstalledContent>,
+        pending: Option<BTreeMap<String, ContentMetadata>>,
+        static_configs: Option<ContentMetadata>,
+    }
+
+    #[test]
+    fn test_state_readable_by_older_versions() -> Result<()> {
+        let data = serde_json::to_string(&SavedState::default())?;
+        let state: SavedStateUnversioned = serde_json::from_str(&data)?;
+        let mut value = serde_json::to_value(&state)?;
+        assert!(migrate_state(&mut value)?.is_empty());
+        let state: SavedState = serde_json::from_value(value)?;
+        assert_eq!(state.schema_version, STATE_SCHEMA_VERSION);
+        assert!(state.installed.is_empty());
         Ok(())
     }
 
@@ -165,6 +737,110 @@ mod test {
             efi.installed.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        assert_eq!(efi.efi_vendor, None);
+        assert!(efi.devices.is_empty());
+
+        let data = include_str!("../tests/fixtures/example-status-v1.json");
+        let status: Status = serde_json::from_str(data)?;
+        let efi = status.components.get("EFI").expect("EFI");
+        assert_eq!(efi.efi_vendor.as_deref(), Some("fedora"));
+        let bios = status.components.get("BIOS").expect("BIOS");
+        assert_eq!(bios.devices, ["/dev/vda", "/dev/vdb"]);
         Ok(())
     }
+
+    #[test]
+    fn test_efi_boot_status() {
+        let mut s = EfiBootStatus {
+            boot_current: Some(3),
+            boot_order: vec![1, 3, 0],
+            entry: Some(3),
+            dangling: vec![],
+        };
+        assert!(!s.is_first());
+        s.dangling.push(1);
+        assert!(s.is_first());
+        assert!(!s.is_ok());
+        s.boot_order = vec![3, 0];
+        s.dangling.clear();
+        assert!(s.is_ok());
+        s.entry = None;
+        assert!(!s.is_ok());
+    }
+
+    #[test]
+    fn test_validation_verdict() {
+        let mut components = BTreeMap::new();
+        let verdict = |c: &BTreeMap<_, _>| ValidationReport::new(c.clone()).verdict;
+        assert_eq!(verdict(&components), ValidationVerdict::Skip);
+        let c = |verdict, errors| ComponentValidation { verdict, errors };
+        components.insert("BIOS".to_string(), c(ValidationVerdict::Skip, vec![]));
+        assert_eq!(verdict(&components), ValidationVerdict::Skip);
+        components.insert("EFI".to_string(), c(ValidationVerdict::Valid, vec![]));
+        assert_eq!(verdict(&components), ValidationVerdict::Valid);
+        let err = ValidationError::new(ValidationErrorKind::Modified, "fedora/shimx64.efi");
+        assert_eq!(err.to_string(), "Changed: fedora/shimx64.efi");
+        components.insert(
+            "systemd-boot".to_string(),
+            c(ValidationVerdict::Errors, vec![err]),
+        );
+        assert_eq!(verdict(&components), ValidationVerdict::Errors);
+    }
+
+    #[test]
+    fn test_installed_devices() {
+        let sum = SHA512String("sha512:00".into());
+        let mut c = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: Utc::now(),
+                version: "v1".into(),
+                signing_keys: Vec::new(),
+            },
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        };
+        assert!(c.devices().is_empty());
+        c.raw_checksums = Some(
+            [
+                ("/dev/mmcblk0@8192", &sum),
+                ("/dev/mmcblk0@65536", &sum),
+                ("/dev/vda", &sum),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+        );
+        assert_eq!(c.devices(), ["/dev/mmcblk0", "/dev/vda"]);
+    }
+
+    #[test]
+    fn test_health_report() {
+        let mut checks = BTreeMap::new();
+        assert_eq!(
+            HealthReport::new(checks.clone()).verdict,
+            HealthVerdict::Pass
+        );
+        let mut updates = HealthCheck::default();
+        updates.report(HealthVerdict::Fail, "EFI: installed v1 is 200 days old");
+        updates.report(HealthVerdict::Warn, "BIOS: update to v2 available");
+        assert_eq!(updates.verdict, HealthVerdict::Fail);
+        assert_eq!(updates.details.len(), 2);
+        checks.insert("validation".to_string(), HealthCheck::default());
+        checks.insert("updates".to_string(), updates);
+        let report = HealthReport::new(checks);
+        assert_eq!(report.verdict, HealthVerdict::Fail);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["checks"]["validation"]["verdict"], "pass");
+    }
 }
@@ -49,6 +49,7 @@ impl ContentMetadata01 {
         NewContentMetadata {
             timestamp,
             version: self.version,
+            signing_keys: Vec::new(),
         }
     }
 }
@@ -59,6 +60,17 @@ impl InstalledContent01 {
             meta: self.meta.upconvert(),
             filetree: self.filetree,
             adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
         }
     }
 }
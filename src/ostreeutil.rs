@@ -4,13 +4,17 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use log::debug;
 
 /// https://github.com/coreos/rpm-ostree/pull/969/commits/dc0e8db5bd92e1f478a0763d1a02b48e57022b59
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
 pub(crate) const BOOT_PREFIX: &str = "usr/lib/ostree-boot";
 const LEGACY_RPMOSTREE_DBPATH: &str = "usr/share/rpm";
 const SYSIMAGE_RPM_DBPATH: &str = "usr/lib/sysimage/rpm";
@@ -35,22 +39,27 @@ fn is_nonempty_dir(path: impl AsRef<Path>) -> Result<bool> {
     Ok(false)
 }
 
-pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Command> {
-    let mut c = std::process::Command::new("rpm");
+/// Returns the first non-empty rpm database path in the sysroot, if any
+pub(crate) fn rpm_dbpath<P: AsRef<Path>>(sysroot: P) -> Result<Option<PathBuf>> {
     let sysroot = sysroot.as_ref();
-    // Take the first non-empty database path
-    let mut arg = None;
     for dbpath in [SYSIMAGE_RPM_DBPATH, LEGACY_RPMOSTREE_DBPATH] {
         let dbpath = sysroot.join(dbpath);
-        if !is_nonempty_dir(&dbpath)? {
-            continue;
+        if is_nonempty_dir(&dbpath)? {
+            return Ok(Some(dbpath));
         }
+    }
+    Ok(None)
+}
+
+pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Command> {
+    let mut c = std::process::Command::new("rpm");
+    // Take the first non-empty database path
+    let arg = rpm_dbpath(sysroot)?.map(|dbpath| {
         let mut s = std::ffi::OsString::new();
         s.push("--dbpath=");
         s.push(dbpath.as_os_str());
-        arg = Some(s);
-        break;
-    }
+        s
+    });
     if let Some(arg) = arg {
         debug!("Using dbpath {arg:?}");
         c.arg(arg);
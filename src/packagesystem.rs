@@ -1,13 +1,105 @@
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+#[cfg(feature = "testing")]
+use std::path::PathBuf;
+#[cfg(feature = "testing")]
+use std::sync::Mutex;
 
 use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
+use openssl::hash::{Hasher, MessageDigest};
+use walkdir::WalkDir;
 
+use crate::component::GenerateOptions;
 use crate::model::*;
 use crate::ostreeutil;
 
+/// The dpkg database, relative to the sysroot
+const DPKG_ADMINDIR: &str = "var/lib/dpkg";
+/// The default rpm database of non-ostree systems, relative to the sysroot
+const RPM_DBPATH: &str = "var/lib/rpm";
+/// The query format of the header signature of rpm packages: `pgpsig`
+/// prints e.g. `RSA/SHA256, Tue 16 Apr 2024 12:00:00 AM UTC, Key ID
+/// 0727707ea15b79cc`
+const RPM_SIGNATURE_QUERYFORMAT: &str =
+    "%{nevra}\t%|RSAHEADER?{%{RSAHEADER:pgpsig}}:{%|DSAHEADER?{%{DSAHEADER:pgpsig}}:{(none)}|}|\n";
+/// Have librpm check the signatures of the headers of the database against
+/// the rpm keyring as it reads them, not only their digests as it does for
+/// queries by default; headers failing the check are skipped with an error.
+const RPM_VERIFY_HEADERS: &str = "_vsflags_query 0";
+/// The query format of the digests of the files of rpm packages: the
+/// algorithm (a PGP hash algorithm ID), the hex digest and the path, which
+/// has no digest for directories and symlinks
+const RPM_DIGESTS_QUERYFORMAT: &str = "[%{FILEDIGESTALGO}\t%{FILEDIGESTS}\t%{FILENAMES}\n]";
+/// Set to `rpm`, `dpkg` or `none` to override package system detection (or
+/// `mock` with the `testing` feature)
+const PACKAGE_SYSTEM_ENV: &str = "BOOTUPD_PACKAGE_SYSTEM";
+
+/// The in-memory package database of the `mock` package system: the
+/// packages and their build time, by the files they own, relative to the
+/// sysroot
+#[cfg(feature = "testing")]
+static MOCK_PACKAGES: Mutex<BTreeMap<PathBuf, (String, DateTime<Utc>)>> =
+    Mutex::new(BTreeMap::new());
+
+/// The package system owning the update payload files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PackageSystem {
+    Rpm,
+    Dpkg,
+    #[cfg(feature = "testing")]
+    Mock,
+}
+
+impl PackageSystem {
+    /// Determine the package system for the target root, if any; rpm is
+    /// preferred if both databases exist.
+    pub(crate) fn detect(sysroot_path: &str) -> Result<Option<Self>> {
+        if let Some(v) = crate::util::getenv_utf8(PACKAGE_SYSTEM_ENV)? {
+            return match v.as_str() {
+                "rpm" => Ok(Some(Self::Rpm)),
+                "dpkg" => Ok(Some(Self::Dpkg)),
+                #[cfg(feature = "testing")]
+                "mock" => Ok(Some(Self::Mock)),
+                "none" => Ok(None),
+                o => bail!("Invalid {PACKAGE_SYSTEM_ENV}: {o}"),
+            };
+        }
+        let sysroot = Path::new(sysroot_path);
+        if ostreeutil::rpm_dbpath(sysroot)?.is_some() || sysroot.join(RPM_DBPATH).exists() {
+            return Ok(Some(Self::Rpm));
+        }
+        if sysroot.join(DPKG_ADMINDIR).join("status").exists() {
+            return Ok(Some(Self::Dpkg));
+        }
+        Ok(None)
+    }
+}
+
+/// Create metadata from a set of packages and their timestamps
+fn metadata_from_packages(pkgs: BTreeMap<String, DateTime<Utc>>) -> Result<ContentMetadata> {
+    if pkgs.is_empty() {
+        bail!("Failed to find any packages matching files in source efidir");
+    }
+    let timestamps: BTreeSet<&DateTime<Utc>> = pkgs.values().collect();
+    // Unwrap safety: We validated pkgs has at least one value above
+    let largest_timestamp = timestamps.iter().last().unwrap();
+    let version = pkgs.keys().fold("".to_string(), |mut s, n| {
+        if !s.is_empty() {
+            s.push(',');
+        }
+        s.push_str(n);
+        s
+    });
+    Ok(ContentMetadata {
+        timestamp: **largest_timestamp,
+        version,
+        signing_keys: Vec::new(),
+    })
+}
+
 /// Parse the output of `rpm -q`
 fn rpm_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
     let pkgs = std::str::from_utf8(stdout)?
@@ -19,33 +111,500 @@ fn rpm_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
                 let nt = DateTime::parse_from_str(ts, "%s")
                     .context("Failed to parse rpm buildtime")?
                     .with_timezone(&chrono::Utc);
-                Ok((name, nt))
+                Ok((name.to_string(), nt))
             } else {
                 bail!("Failed to parse: {}", s);
             }
         })
-        .collect::<Result<BTreeMap<&str, DateTime<Utc>>>>()?;
+        .collect::<Result<BTreeMap<String, DateTime<Utc>>>>()?;
     if pkgs.is_empty() {
         bail!("Failed to find any RPM packages matching files in source efidir");
     }
-    let timestamps: BTreeSet<&DateTime<Utc>> = pkgs.values().collect();
-    // Unwrap safety: We validated pkgs has at least one value above
-    let largest_timestamp = timestamps.iter().last().unwrap();
-    let version = pkgs.keys().fold("".to_string(), |mut s, n| {
-        if !s.is_empty() {
-            s.push(',');
+    metadata_from_packages(pkgs)
+}
+
+/// Parse the output of `dpkg-query -S`, returning the owning package names
+fn dpkg_parse_search(stdout: &[u8]) -> Result<BTreeSet<String>> {
+    let mut pkgs = BTreeSet::new();
+    for line in std::str::from_utf8(stdout)?.lines() {
+        // Skip e.g. "diversion by foo from: /bar"
+        if line.starts_with("diversion by ") {
+            continue;
         }
-        s.push_str(n);
-        s
-    });
+        let Some((names, _path)) = line.split_once(": ") else {
+            bail!("Failed to parse: {}", line);
+        };
+        for name in names.split(", ") {
+            // Drop any architecture qualifier, e.g. "grub-efi-amd64-bin:amd64"
+            let name = name.split_once(':').map(|(n, _)| n).unwrap_or(name);
+            pkgs.insert(name.to_string());
+        }
+    }
+    Ok(pkgs)
+}
+
+/// Parse the output of `dpkg-query -W` with our format
+fn dpkg_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
+    let pkgs = std::str::from_utf8(stdout)?
+        .lines()
+        .map(|s| -> Result<_> {
+            let parts: Vec<_> = s.splitn(3, ',').collect();
+            let [name, version, ts] = parts.as_slice() else {
+                bail!("Failed to parse: {}", s);
+            };
+            let nt = DateTime::parse_from_str(ts, "%s")
+                .context("Failed to parse dpkg modification time")?
+                .with_timezone(&chrono::Utc);
+            Ok((format!("{name}-{version}"), nt))
+        })
+        .collect::<Result<BTreeMap<String, DateTime<Utc>>>>()?;
+    if pkgs.is_empty() {
+        bail!("Failed to find any dpkg packages matching files in source efidir");
+    }
+    metadata_from_packages(pkgs)
+}
+
+/// Query the dpkg database for the packages owning the paths.  dpkg does not
+/// record a build time, so use the time the package was installed.
+fn dpkg_query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let admindir = Path::new(sysroot_path).join(DPKG_ADMINDIR);
+    let mut admindir_arg = std::ffi::OsString::from("--admindir=");
+    admindir_arg.push(admindir.as_os_str());
+
+    let mut c = std::process::Command::new("dpkg-query");
+    c.arg(&admindir_arg).arg("-S");
+    for arg in paths {
+        c.arg(arg.as_ref());
+    }
+    let out = c.output()?;
+    if !out.status.success() {
+        std::io::stderr().write_all(&out.stderr)?;
+        bail!("Failed to invoke dpkg-query -S");
+    }
+    let pkgs = dpkg_parse_search(&out.stdout)?;
+
+    let mut c = std::process::Command::new("dpkg-query");
+    c.arg(&admindir_arg).args([
+        "-W",
+        "-f",
+        "${Package},${Version},${db-fsys:Last-Modified}\n",
+    ]);
+    c.args(pkgs);
+    let out = c.output()?;
+    if !out.status.success() {
+        std::io::stderr().write_all(&out.stderr)?;
+        bail!("Failed to invoke dpkg-query -W");
+    }
+    dpkg_parse_metadata(&out.stdout)
+}
+
+/// Query the package database and list the package and build times.
+pub(crate) fn query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    match PackageSystem::detect(sysroot_path)? {
+        Some(PackageSystem::Rpm) => rpm_query_files(sysroot_path, paths),
+        Some(PackageSystem::Dpkg) => dpkg_query_files(sysroot_path, paths),
+        #[cfg(feature = "testing")]
+        Some(PackageSystem::Mock) => mock_query_files(sysroot_path, paths),
+        None => bail!("Failed to find a package database in {sysroot_path}"),
+    }
+}
+
+/// Record that the package `nevra`, built at `buildtime`, owns `paths`
+/// (files or directories, relative to the sysroot), in the in-memory
+/// package database.
+#[cfg(feature = "testing")]
+pub(crate) fn add_mock_package<T>(
+    nevra: &str,
+    buildtime: DateTime<Utc>,
+    paths: impl IntoIterator<Item = T>,
+) where
+    T: AsRef<Path>,
+{
+    let mut packages = MOCK_PACKAGES.lock().unwrap();
+    for path in paths {
+        let path = path.as_ref();
+        let path = path.strip_prefix("/").unwrap_or(path);
+        packages.insert(path.to_path_buf(), (nevra.to_string(), buildtime));
+    }
+}
+
+/// Empty the in-memory package database.
+#[cfg(feature = "testing")]
+pub(crate) fn clear_mock_packages() {
+    MOCK_PACKAGES.lock().unwrap().clear();
+}
+
+/// Query the in-memory package database, like `rpm -qf`: a file is owned
+/// by the package of its closest recorded ancestor.
+#[cfg(feature = "testing")]
+fn mock_query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let packages = MOCK_PACKAGES.lock().unwrap();
+    let mut pkgs = BTreeMap::new();
+    for path in paths {
+        let path = path.as_ref();
+        let path = path.strip_prefix(sysroot_path).unwrap_or(path);
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let Some((nevra, buildtime)) = path.ancestors().find_map(|p| packages.get(p)) else {
+            bail!("File {path:?} is not owned by any package");
+        };
+        pkgs.insert(nevra.clone(), *buildtime);
+    }
+    metadata_from_packages(pkgs)
+}
+
+/// Derive metadata from the content of an update payload (a file or a
+/// directory), for use when there is no package database.  The version is
+/// derived from a checksum of the file names and contents, and the timestamp
+/// is the most recent modification time.
+pub(crate) fn hash_payload(payload: &Path) -> Result<ContentMetadata> {
+    let mut hasher =
+        Hasher::new(MessageDigest::sha512()).expect("openssl sha512 hasher creation failed");
+    let mut timestamp = None;
+    for entry in WalkDir::new(payload).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let name = path.strip_prefix(payload)?;
+        hasher.update(name.as_os_str().as_bytes())?;
+        hasher.update(b"\0")?;
+        let mut f = std::fs::File::open(path).with_context(|| format!("opening {path:?}"))?;
+        std::io::copy(&mut f, &mut hasher)?;
+        let mtime: DateTime<Utc> = f.metadata()?.modified()?.into();
+        timestamp = timestamp.max(Some(mtime));
+    }
+    let Some(timestamp) = timestamp else {
+        bail!("Failed to find any files in {payload:?}");
+    };
+    let digest = hex::encode(hasher.finish()?);
     Ok(ContentMetadata {
-        timestamp: **largest_timestamp,
-        version,
+        timestamp,
+        version: format!("content-{}", &digest[..16]),
+        signing_keys: Vec::new(),
     })
 }
 
+/// Determine the metadata of an update payload built from `paths`: if there
+/// is a package database, query it, otherwise hash the content of `payload`.
+/// If a version is provided in `opts` it is used instead of the derived one.
+/// The rpm packages owning `paths` must be signed by a key of the rpm
+/// keyring, and the files of `paths` match them, unless
+/// `opts.allow_unsigned` is set.
+pub(crate) fn query_payload<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+    payload: &Path,
+    opts: &GenerateOptions,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let paths: Vec<T> = paths.into_iter().collect();
+    let system = PackageSystem::detect(sysroot_path)?;
+    let mut meta = if system.is_some() {
+        query_files(sysroot_path, &paths)?
+    } else {
+        log::debug!("No package database found, hashing {payload:?}");
+        hash_payload(payload)?
+    };
+    if system == Some(PackageSystem::Rpm) {
+        let verified = rpm_verify_signatures(sysroot_path, &paths).and_then(|(keys, packages)| {
+            rpm_verify_files(sysroot_path, &packages, &paths)?;
+            Ok(keys)
+        });
+        match verified {
+            Ok(keys) => meta.signing_keys = keys,
+            Err(e) if opts.allow_unsigned => log::warn!("{e:#}; continuing as requested"),
+            Err(e) => return Err(e.context("Refusing unsigned payload; use --allow-unsigned")),
+        }
+    }
+    if let Some(version) = opts.version.as_deref() {
+        meta.version = version.to_string();
+    }
+    Ok(meta)
+}
+
+/// Parse the output of `rpm -q` with `RPM_SIGNATURE_QUERYFORMAT`, returning
+/// the ID of the key which signed each package, if any.
+fn rpm_parse_signatures(stdout: &[u8]) -> Result<BTreeMap<String, Option<String>>> {
+    std::str::from_utf8(stdout)?
+        .lines()
+        .map(|line| -> Result<_> {
+            let Some((nevra, sig)) = line.split_once('\t') else {
+                bail!("Failed to parse: {}", line);
+            };
+            let key = sig
+                .rsplit_once("Key ID ")
+                .map(|(_, id)| id.trim().to_ascii_lowercase());
+            Ok((nevra.to_string(), key))
+        })
+        .collect()
+}
+
+/// The fingerprint of the first OpenPGP public key packet of `data`, in
+/// lower case hex.
+fn pgp_fingerprint(data: &[u8]) -> Result<String> {
+    let Some((&tag, rest)) = data.split_first() else {
+        bail!("Empty OpenPGP key");
+    };
+    if tag & 0x80 == 0 {
+        bail!("Invalid OpenPGP packet");
+    }
+    let (tag, len, rest) = if tag & 0x40 != 0 {
+        // New format packet
+        match rest {
+            [l, rest @ ..] if *l < 192 => (tag & 0x3f, *l as usize, rest),
+            [l1, l2, rest @ ..] if *l1 < 224 => (
+                tag & 0x3f,
+                ((*l1 as usize - 192) << 8) + *l2 as usize + 192,
+                rest,
+            ),
+            [255, l @ ..] if l.len() >= 4 => (
+                tag & 0x3f,
+                u32::from_be_bytes(l[..4].try_into().unwrap()) as usize,
+                &l[4..],
+            ),
+            _ => bail!("Invalid OpenPGP packet length"),
+        }
+    } else {
+        // Old format packet
+        let n = match tag & 0x03 {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            _ => bail!("Unsupported OpenPGP packet length"),
+        };
+        if rest.len() < n {
+            bail!("Invalid OpenPGP packet length");
+        }
+        let len = rest[..n].iter().fold(0usize, |l, &b| (l << 8) | b as usize);
+        ((tag >> 2) & 0x0f, len, &rest[n..])
+    };
+    if tag != 6 {
+        bail!("Not an OpenPGP public key packet: {tag}");
+    }
+    let Some(body) = rest.get(..len) else {
+        bail!("Truncated OpenPGP packet");
+    };
+    let digest = match body.first() {
+        Some(4) => {
+            let mut hasher = Hasher::new(MessageDigest::sha1())?;
+            hasher.update(&[0x99])?;
+            hasher.update(&u16::try_from(len)?.to_be_bytes())?;
+            hasher.update(body)?;
+            hasher.finish()?
+        }
+        Some(6) => {
+            let mut hasher = Hasher::new(MessageDigest::sha256())?;
+            hasher.update(&[0x9b])?;
+            hasher.update(&u32::try_from(len)?.to_be_bytes())?;
+            hasher.update(body)?;
+            hasher.finish()?
+        }
+        v => bail!("Unsupported OpenPGP key version {v:?}"),
+    };
+    Ok(hex::encode(digest))
+}
+
+/// The fingerprints of the ASCII armored OpenPGP public keys in `text`, e.g.
+/// the descriptions of the `gpg-pubkey` packages of the rpm keyring.
+fn pgp_armored_fingerprints(text: &str) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+    while lines.any(|l| l == "-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+        // Skip the armor headers, up to an empty line
+        for l in lines.by_ref() {
+            if l.is_empty() {
+                break;
+            }
+        }
+        let data: String = lines
+            .by_ref()
+            .take_while(|l| !l.starts_with("-----END"))
+            .filter(|l| !l.starts_with('='))
+            .collect();
+        let data = openssl::base64::decode_block(&data).context("decoding OpenPGP key")?;
+        r.push(pgp_fingerprint(&data)?);
+    }
+    Ok(r)
+}
+
+/// The fingerprint of the key `id` (a 64-bit key ID) in `keyring`: the key
+/// ID is the end of a v4 fingerprint, and the start of a v6 one.
+fn rpm_keyring_find<'a>(keyring: &'a [String], id: &str) -> Option<&'a str> {
+    keyring
+        .iter()
+        .find(|fpr| match fpr.len() {
+            40 => fpr.ends_with(id),
+            _ => fpr.starts_with(id),
+        })
+        .map(String::as_str)
+}
+
+/// Check that the rpm packages owning `paths` are signed by keys imported
+/// in the rpm database, and that their header signatures verify; returns
+/// the fingerprints of these keys and the packages.
+fn rpm_verify_signatures<T>(sysroot_path: &str, paths: &[T]) -> Result<(Vec<String>, Vec<String>)>
+where
+    T: AsRef<Path>,
+{
+    let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
+    c.args(["--define", RPM_VERIFY_HEADERS]);
+    c.args(["-q", "--queryformat", RPM_SIGNATURE_QUERYFORMAT, "-f"]);
+    for arg in paths {
+        c.arg(arg.as_ref());
+    }
+    let out = c.output()?;
+    // Headers failing verification are skipped, so their files are
+    // reported as not owned by any package
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if !out.status.success() || stderr.lines().any(|l| l.starts_with("error:")) {
+        std::io::stderr().write_all(&out.stderr)?;
+        bail!("Failed to verify the headers of the packages with rpm -qf");
+    }
+    let signatures = rpm_parse_signatures(&out.stdout)?;
+
+    // Fails if there is no key at all
+    let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
+    c.args(["-q", "--queryformat", "%{description}\n", "gpg-pubkey"]);
+    let out = c.output()?;
+    let keyring = if out.status.success() {
+        pgp_armored_fingerprints(std::str::from_utf8(&out.stdout)?)?
+    } else {
+        Vec::new()
+    };
+
+    let mut keys = BTreeSet::new();
+    let mut untrusted = Vec::new();
+    for (nevra, key) in signatures.iter() {
+        match key
+            .as_deref()
+            .map(|id| (id, rpm_keyring_find(&keyring, id)))
+        {
+            Some((_, Some(fpr))) => {
+                keys.insert(fpr.to_string());
+            }
+            Some((id, None)) => untrusted.push(format!("{nevra} (unknown key {id})")),
+            None => untrusted.push(format!("{nevra} (unsigned)")),
+        }
+    }
+    if !untrusted.is_empty() {
+        bail!(
+            "Packages not signed by a key of the rpm database: {}",
+            untrusted.join(", ")
+        );
+    }
+    Ok((keys.into_iter().collect(), signatures.into_keys().collect()))
+}
+
+/// The digest algorithm of a `FILEDIGESTALGO` of rpm.
+fn rpm_digest_algorithm(id: u32) -> Option<MessageDigest> {
+    match id {
+        1 => Some(MessageDigest::md5()),
+        2 => Some(MessageDigest::sha1()),
+        8 => Some(MessageDigest::sha256()),
+        9 => Some(MessageDigest::sha384()),
+        10 => Some(MessageDigest::sha512()),
+        11 => Some(MessageDigest::sha224()),
+        _ => None,
+    }
+}
+
+/// Parse the output of `rpm -q` with `RPM_DIGESTS_QUERYFORMAT`, returning
+/// the algorithm and the digest of each regular file.
+fn rpm_parse_digests(stdout: &[u8]) -> Result<BTreeMap<String, (u32, String)>> {
+    let mut r = BTreeMap::new();
+    for line in std::str::from_utf8(stdout)?.lines() {
+        let parts: Vec<_> = line.splitn(3, '\t').collect();
+        let [algo, digest, path] = parts.as_slice() else {
+            bail!("Failed to parse: {}", line);
+        };
+        if digest.is_empty() {
+            continue;
+        }
+        let algo = algo.parse().with_context(|| format!("parsing {line}"))?;
+        r.insert(path.to_string(), (algo, digest.to_ascii_lowercase()));
+    }
+    Ok(r)
+}
+
+/// Check that the files of the rpm `packages` under `paths` (in the
+/// sysroot) still have the digests recorded in the rpm database, as
+/// `rpm -V` does.
+fn rpm_verify_files<T>(sysroot_path: &str, packages: &[String], paths: &[T]) -> Result<()>
+where
+    T: AsRef<Path>,
+{
+    let sysroot = Path::new(sysroot_path);
+    let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
+    c.args(["-q", "--queryformat", RPM_DIGESTS_QUERYFORMAT]);
+    c.args(packages);
+    let out = c.output()?;
+    if !out.status.success() {
+        std::io::stderr().write_all(&out.stderr)?;
+        bail!("Failed to query the file digests with rpm -q");
+    }
+    let prefixes: Vec<&Path> = paths
+        .iter()
+        .map(|p| p.as_ref())
+        .map(|p| p.strip_prefix(sysroot).unwrap_or(p))
+        .collect();
+    let mut modified = Vec::new();
+    for (path, (algo, expected)) in rpm_parse_digests(&out.stdout)? {
+        let relpath = path.trim_start_matches('/');
+        if !prefixes
+            .iter()
+            .any(|p| Path::new(relpath).starts_with(p.strip_prefix("/").unwrap_or(p)))
+        {
+            continue;
+        }
+        let Some(md) = rpm_digest_algorithm(algo) else {
+            bail!("Unsupported digest algorithm {algo} for {path}");
+        };
+        let mut f = match std::fs::File::open(sysroot.join(relpath)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                modified.push(format!("{path} (missing)"));
+                continue;
+            }
+            Err(e) => return Err(e).with_context(|| format!("opening {path}")),
+        };
+        let mut hasher = Hasher::new(md)?;
+        std::io::copy(&mut f, &mut hasher)?;
+        if hex::encode(hasher.finish()?) != expected {
+            modified.push(path);
+        }
+    }
+    if !modified.is_empty() {
+        bail!(
+            "Files differing from their packages: {}",
+            modified.join(", ")
+        );
+    }
+    Ok(())
+}
+
 /// Query the rpm database and list the package and build times.
-pub(crate) fn query_files<T>(
+fn rpm_query_files<T>(
     sysroot_path: &str,
     paths: impl IntoIterator<Item = T>,
 ) -> Result<ContentMetadata>
@@ -76,3 +635,147 @@ fn test_parse_rpmout() {
         "grub2-efi-x64-1:2.06-95.fc38.x86_64,shim-x64-15.6-2.x86_64"
     );
 }
+
+#[test]
+fn test_parse_rpm_signatures() -> Result<()> {
+    let testdata = "grub2-efi-x64-1:2.06-95.fc38.x86_64\tRSA/SHA256, Wed 12 Apr 2023 05:49:48 PM UTC, Key ID 809a8d7ceb10b464
+shim-x64-15.6-2.x86_64\tRSA/SHA256, Thu 07 Jul 2022 07:36:06 PM UTC, Key ID 809A8D7CEB10B464
+local-grub-1.0-1.x86_64\t(none)
+";
+    let sigs = rpm_parse_signatures(testdata.as_bytes())?;
+    let key = Some("809a8d7ceb10b464".to_string());
+    assert_eq!(sigs["grub2-efi-x64-1:2.06-95.fc38.x86_64"], key);
+    assert_eq!(sigs["shim-x64-15.6-2.x86_64"], key);
+    assert_eq!(sigs["local-grub-1.0-1.x86_64"], None);
+    Ok(())
+}
+
+#[test]
+fn test_pgp_fingerprint() -> Result<()> {
+    // A v4 RSA key packet, old format with a 2 byte length
+    let mut body = vec![4u8, 0x63, 0xc2, 0x4d, 0xbc, 1];
+    body.extend_from_slice(&[0x00, 0x09, 0x01, 0x00, 0x11, 0x01, 0x00, 0x01]);
+    let mut packet = vec![0x99, 0x00, body.len() as u8];
+    packet.extend_from_slice(&body);
+    let mut hasher = Hasher::new(MessageDigest::sha1())?;
+    hasher.update(&[0x99, 0x00, body.len() as u8])?;
+    hasher.update(&body)?;
+    let expected = hex::encode(hasher.finish()?);
+    assert_eq!(pgp_fingerprint(&packet)?, expected);
+    // The same in a new format packet
+    let mut packet = vec![0xc6, body.len() as u8];
+    packet.extend_from_slice(&body);
+    assert_eq!(pgp_fingerprint(&packet)?, expected);
+    // Not a public key packet
+    assert!(pgp_fingerprint(&[0xc2, 0x01, 0x04]).is_err());
+    assert!(pgp_fingerprint(&packet[..5]).is_err());
+
+    // As in the description of gpg-pubkey packages, with a checksum line
+    let armored = format!(
+        "-----BEGIN PGP PUBLIC KEY BLOCK-----\nVersion: rpm-4.19.1.1\n\n{}\n=aBcD\n-----END PGP PUBLIC KEY BLOCK-----\n",
+        openssl::base64::encode_block(&packet)
+    );
+    let armored = format!("{armored}\n{armored}");
+    let keyring = pgp_armored_fingerprints(&armored)?;
+    assert_eq!(keyring.len(), 2);
+    assert_eq!(keyring, [expected.clone(), expected]);
+    let id = &keyring[0][24..];
+    assert_eq!(rpm_keyring_find(&keyring, id), Some(keyring[0].as_str()));
+    assert_eq!(rpm_keyring_find(&keyring, "1161ae6945719a39"), None);
+    Ok(())
+}
+
+#[test]
+fn test_rpm_verify_digests() -> Result<()> {
+    let testdata = "8\t\t/usr/lib/efi/shim
+8\t6A2B\t/usr/lib/efi/shim/EFI/fedora/shimx64.efi
+10\t0f3c\t/usr/lib/efi/shim/EFI/BOOT/BOOTX64.EFI
+";
+    let digests = rpm_parse_digests(testdata.as_bytes())?;
+    assert_eq!(
+        digests.into_iter().collect::<Vec<_>>(),
+        [
+            (
+                "/usr/lib/efi/shim/EFI/BOOT/BOOTX64.EFI".to_string(),
+                (10, "0f3c".to_string())
+            ),
+            (
+                "/usr/lib/efi/shim/EFI/fedora/shimx64.efi".to_string(),
+                (8, "6a2b".to_string())
+            ),
+        ]
+    );
+    assert!(rpm_parse_digests(b"8 6a2b /usr/lib/efi/shim").is_err());
+    assert!(rpm_digest_algorithm(8).is_some());
+    assert!(rpm_digest_algorithm(3).is_none());
+    Ok(())
+}
+
+#[test]
+fn test_parse_dpkg() {
+    let testdata = "grub-efi-amd64-bin:amd64: /usr/lib/grub/x86_64-efi/monolithic/grubx64.efi
+diversion by foo from: /usr/bin/bar
+shim-signed:amd64, shim-helpers-amd64-signed: /usr/lib/shim
+";
+    let pkgs = dpkg_parse_search(testdata.as_bytes()).unwrap();
+    assert_eq!(
+        pkgs.into_iter().collect::<Vec<_>>(),
+        [
+            "grub-efi-amd64-bin",
+            "shim-helpers-amd64-signed",
+            "shim-signed"
+        ]
+    );
+    let testdata = "grub-efi-amd64-bin,2.12-1,1700000000
+shim-signed,1.44+15.8-1,1690000000
+";
+    let parsed = dpkg_parse_metadata(testdata.as_bytes()).unwrap();
+    assert_eq!(
+        parsed.version,
+        "grub-efi-amd64-bin-2.12-1,shim-signed-1.44+15.8-1"
+    );
+    assert_eq!(parsed.timestamp.timestamp(), 1700000000);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_mock_query_files() -> Result<()> {
+    let shim = "2023-01-01T00:00:00Z".parse::<DateTime<Utc>>()?;
+    let grub = "2024-01-01T00:00:00Z".parse::<DateTime<Utc>>()?;
+    add_mock_package("shim-x64-15.8-1.x86_64", shim, ["usr/share/mock/shim"]);
+    add_mock_package(
+        "grub2-efi-x64-2.12-1.x86_64",
+        grub,
+        ["/usr/share/mock/grub"],
+    );
+    let meta = mock_query_files(
+        "/sysroot",
+        [
+            "/sysroot/usr/share/mock/grub/grubx64.efi",
+            "/sysroot/usr/share/mock/shim/EFI/BOOT/BOOTX64.EFI",
+        ],
+    )?;
+    assert_eq!(
+        meta.version,
+        "grub2-efi-x64-2.12-1.x86_64,shim-x64-15.8-1.x86_64"
+    );
+    assert_eq!(meta.timestamp, grub);
+    assert!(mock_query_files("/sysroot", ["/sysroot/usr/share/mock/other"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_hash_payload() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let p = td.path();
+    std::fs::create_dir_all(p.join("EFI/fedora"))?;
+    std::fs::write(p.join("EFI/fedora/grubx64.efi"), "grub data")?;
+    let a = hash_payload(p)?;
+    assert!(a.version.starts_with("content-"));
+    assert_eq!(a.version, hash_payload(p)?.version);
+    // Renames are a change in content
+    std::fs::rename(p.join("EFI/fedora"), p.join("EFI/centos"))?;
+    assert_ne!(a.version, hash_payload(p)?.version);
+    assert!(hash_payload(&p.join("EFI/nonexistent")).is_err());
+    Ok(())
+}
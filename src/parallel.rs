@@ -0,0 +1,113 @@
+//! A small thread pool running independent jobs concurrently, e.g. the
+//! updates of the BIOS and EFI components, or the writes to mirrored ESPs.
+//!
+//! Each job names the devices it writes to; jobs sharing a device are
+//! serialized by per-device locks, taken in a stable order so that jobs
+//! locking several devices can't deadlock.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Mutex;
+
+/// The maximum number of jobs run at once
+const MAX_THREADS: usize = 4;
+
+/// A unit of work for [`run`].
+pub(crate) struct Job<'a, T> {
+    /// The devices written by the job
+    devices: BTreeSet<String>,
+    f: Box<dyn FnOnce() -> T + Send + 'a>,
+}
+
+impl<'a, T> Job<'a, T> {
+    pub(crate) fn new(
+        devices: impl IntoIterator<Item = String>,
+        f: impl FnOnce() -> T + Send + 'a,
+    ) -> Self {
+        Self {
+            devices: devices.into_iter().collect(),
+            f: Box::new(f),
+        }
+    }
+}
+
+/// Run `jobs` on up to `MAX_THREADS` threads, returning their results in
+/// the order of the jobs.
+pub(crate) fn run<T: Send>(jobs: Vec<Job<'_, T>>) -> Vec<T> {
+    if jobs.len() <= 1 {
+        return jobs.into_iter().map(|job| (job.f)()).collect();
+    }
+    let locks: BTreeMap<&str, Mutex<()>> = jobs
+        .iter()
+        .flat_map(|job| job.devices.iter())
+        .map(|d| (d.as_str(), Mutex::new(())))
+        .collect();
+    let n_threads = jobs.len().min(MAX_THREADS);
+    let n_jobs = jobs.len();
+    let mut devices = Vec::with_capacity(n_jobs);
+    let mut queue = VecDeque::with_capacity(n_jobs);
+    for (i, job) in jobs.into_iter().enumerate() {
+        devices.push(job.devices);
+        queue.push_back((i, job.f));
+    }
+    let queue = Mutex::new(queue);
+    let results = Mutex::new((0..n_jobs).map(|_| None).collect::<Vec<_>>());
+    std::thread::scope(|s| {
+        for _ in 0..n_threads {
+            s.spawn(|| loop {
+                let Some((i, f)) = queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+                // BTreeSet iterates in order, which is the locking order
+                let _guards: Vec<_> = devices[i]
+                    .iter()
+                    .map(|d| locks[d.as_str()].lock().unwrap_or_else(|e| e.into_inner()))
+                    .collect();
+                let r = f();
+                results.lock().unwrap()[i] = Some(r);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("job completed"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_run() {
+        let r = run(Vec::<Job<()>>::new());
+        assert!(r.is_empty());
+
+        let jobs = (0..10)
+            .map(|i| Job::new([], move || i * 2))
+            .collect::<Vec<_>>();
+        assert_eq!(run(jobs), (0..10).map(|i| i * 2).collect::<Vec<_>>());
+
+        // Jobs sharing a device never run at the same time
+        let running = AtomicUsize::new(0);
+        let overlaps = AtomicUsize::new(0);
+        let jobs = (0..6)
+            .map(|i| {
+                let devices = [format!("/dev/sd{}", if i % 2 == 0 { "a" } else { "b" })];
+                let (running, overlaps) = (&running, &overlaps);
+                Job::new(devices, move || {
+                    if running.fetch_add(1, Ordering::SeqCst) >= 2 {
+                        overlaps.fetch_add(1, Ordering::SeqCst);
+                    }
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        run(jobs);
+        assert_eq!(overlaps.load(Ordering::SeqCst), 0);
+    }
+}
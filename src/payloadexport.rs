@@ -0,0 +1,297 @@
+//! `bootupd export-payload`: the update payloads as an OCI artifact, for
+//! registry-based distribution of bootloader updates.
+//!
+//! Each component is a tar layer of its files below
+//! `/usr/lib/bootupd/updates`, annotated with its name and version, so that
+//! the artifact can also be used by `bootupctl update --from-image`.
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::Serialize;
+
+use crate::model::{ContentMetadata, BOOTUPD_UPDATES_DIR};
+use crate::payloadstore;
+use crate::util::CommandRunExt;
+
+/// The type of the artifact, in its manifest
+const ARTIFACT_TYPE: &str = "application/vnd.coreos.bootupd.payload.v1";
+/// The prefix of our annotations
+const ANNOTATION_PREFIX: &str = "org.coreos.bootupd";
+const MEDIA_TYPE_EMPTY: &str = "application/vnd.oci.empty.v1+json";
+const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+/// The name of the manifest in the OCI layout
+const LAYOUT_TAG: &str = "latest";
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: &'static str,
+    digest: String,
+    size: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    schema_version: u32,
+    media_type: &'static str,
+    artifact_type: &'static str,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Index {
+    schema_version: u32,
+    media_type: &'static str,
+    manifests: Vec<Descriptor>,
+}
+
+/// The SHA-256 digest of `r`, in OCI format, and its size.
+fn digest<R: Read>(r: &mut R) -> Result<(String, u64)> {
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    let size = std::io::copy(r, &mut hasher)?;
+    Ok((format!("sha256:{}", hex::encode(hasher.finish()?)), size))
+}
+
+/// The path of a blob of `layout`.
+fn blob_path(layout: &Path, digest: &str) -> std::path::PathBuf {
+    let hex = digest.strip_prefix("sha256:").expect("sha256 digest");
+    layout.join("blobs/sha256").join(hex)
+}
+
+/// Add `data` as a blob of `layout`, returning its descriptor.
+fn write_blob(layout: &Path, media_type: &'static str, data: &[u8]) -> Result<Descriptor> {
+    let (digest, size) = digest(&mut &data[..])?;
+    std::fs::write(blob_path(layout, &digest), data)?;
+    Ok(Descriptor {
+        media_type,
+        digest,
+        size,
+        annotations: BTreeMap::new(),
+    })
+}
+
+/// The update payloads of `sysroot`, by component name.
+fn payloads(sysroot: &Path) -> Result<BTreeMap<String, ContentMetadata>> {
+    let updates = sysroot.join(BOOTUPD_UPDATES_DIR);
+    let mut r = BTreeMap::new();
+    for entry in std::fs::read_dir(&updates).with_context(|| format!("reading {updates:?}"))? {
+        let path = entry?.path();
+        let Some(name) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .filter(|n| !payloadstore::is_manifest(n))
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let f = std::fs::File::open(&path)?;
+        let meta = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("parsing {path:?}"))?;
+        r.insert(name.to_string(), meta);
+    }
+    if r.is_empty() {
+        bail!("No update payloads in {updates:?}");
+    }
+    Ok(r)
+}
+
+/// Add the payload of `name` as a layer of `layout`.
+#[context("Archiving payload of {name}")]
+fn write_layer(
+    sysroot: &Path,
+    layout: &Path,
+    name: &str,
+    meta: &ContentMetadata,
+    mtime: DateTime<Utc>,
+) -> Result<Descriptor> {
+    let updates = Path::new(BOOTUPD_UPDATES_DIR);
+    let mut members = vec![
+        updates.join(format!("{name}.json")),
+        updates.join(format!("{name}.json.sig")),
+        updates.join(format!("{name}.tar.zst")),
+        updates.join(format!("{name}.filetree.json")),
+        updates.join(name),
+    ];
+    members.retain(|m| sysroot.join(m).symlink_metadata().is_ok());
+    // With the objects of its files, which the layers of other components
+    // may also contain
+    if payloadstore::stored(&sysroot.join(updates))?
+        .iter()
+        .any(|c| c == name)
+    {
+        let manifest = payloadstore::load_manifest(&sysroot.join(updates), name)?;
+        for meta in manifest.children.values() {
+            members.push(updates.join(payloadstore::object_path(&meta.digest)?));
+        }
+        members.sort();
+        members.dedup();
+    }
+    let tmp = tempfile::NamedTempFile::new_in(layout)?;
+    // Reproducible, for the digests to only change with the content
+    Command::new("tar")
+        .arg("-C")
+        .arg(sysroot)
+        .arg("-cf")
+        .arg(tmp.path())
+        .args(["--sort=name", "--owner=0", "--group=0", "--numeric-owner"])
+        .arg(format!("--mtime=@{}", mtime.timestamp()))
+        .args(members)
+        .run()?;
+    let (digest, size) = digest(&mut std::fs::File::open(tmp.path())?)?;
+    tmp.persist(blob_path(layout, &digest))?;
+    let annotations = BTreeMap::from([
+        (format!("{ANNOTATION_PREFIX}.component"), name.to_string()),
+        (format!("{ANNOTATION_PREFIX}.version"), meta.version.clone()),
+        (
+            "org.opencontainers.image.title".to_string(),
+            format!("{name}.tar"),
+        ),
+    ]);
+    Ok(Descriptor {
+        media_type: MEDIA_TYPE_LAYER,
+        digest,
+        size,
+        annotations,
+    })
+}
+
+/// Write the update payloads of `sysroot` as an OCI layout in `layout`,
+/// which must be empty or not exist.
+#[context("Writing OCI layout {layout:?}")]
+fn write_layout(sysroot: &Path, layout: &Path) -> Result<()> {
+    if layout.exists() && layout.read_dir()?.next().is_some() {
+        bail!("Not empty");
+    }
+    std::fs::create_dir_all(layout.join("blobs/sha256"))?;
+    let payloads = payloads(sysroot)?;
+    // Unwrap safety: payloads() fails if there is no payload
+    let created = payloads.values().map(|m| m.timestamp).max().unwrap();
+    let layers = payloads
+        .iter()
+        .map(|(name, meta)| write_layer(sysroot, layout, name, meta, created))
+        .collect::<Result<Vec<_>>>()?;
+    let mut annotations: BTreeMap<String, String> = payloads
+        .iter()
+        .map(|(name, meta)| {
+            (
+                format!("{ANNOTATION_PREFIX}.component.{name}.version"),
+                meta.version.clone(),
+            )
+        })
+        .collect();
+    annotations.insert(
+        format!("{ANNOTATION_PREFIX}.arch"),
+        std::env::consts::ARCH.to_string(),
+    );
+    annotations.insert(
+        "org.opencontainers.image.created".to_string(),
+        created.to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    let manifest = Manifest {
+        schema_version: 2,
+        media_type: MEDIA_TYPE_MANIFEST,
+        artifact_type: ARTIFACT_TYPE,
+        config: write_blob(layout, MEDIA_TYPE_EMPTY, b"{}")?,
+        layers,
+        annotations,
+    };
+    let mut manifest = write_blob(layout, MEDIA_TYPE_MANIFEST, &serde_json::to_vec(&manifest)?)?;
+    manifest.annotations.insert(
+        "org.opencontainers.image.ref.name".to_string(),
+        LAYOUT_TAG.to_string(),
+    );
+    let index = Index {
+        schema_version: 2,
+        media_type: MEDIA_TYPE_INDEX,
+        manifests: vec![manifest],
+    };
+    std::fs::write(layout.join("index.json"), serde_json::to_vec(&index)?)?;
+    std::fs::write(
+        layout.join("oci-layout"),
+        r#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+    Ok(())
+}
+
+/// Export the update payloads of `/` as an OCI artifact: `output` is the
+/// directory of an OCI layout if it starts with `/` or `.`, otherwise a
+/// reference to push to, by default to a registry.
+#[context("Exporting update payloads to {output}")]
+pub(crate) fn export_oci(output: &str) -> Result<()> {
+    let sysroot = Path::new("/");
+    if output.starts_with('/') || output.starts_with('.') {
+        write_layout(sysroot, Path::new(output))?;
+        println!("Wrote OCI layout {output}");
+        return Ok(());
+    }
+    let tmpdir = tempfile::tempdir_in("/var/tmp")?;
+    let layout = tmpdir.path().join("payload");
+    write_layout(sysroot, &layout)?;
+    Command::new("skopeo")
+        .args(["copy", "--quiet"])
+        .arg(format!("oci:{}:{LAYOUT_TAG}", layout.display()))
+        .arg(crate::updatesource::with_transport(output))
+        .run()?;
+    println!("Pushed {output}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_layout() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysroot = td.path().join("root");
+        let updates = sysroot.join(BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(updates.join("EFI/EFI/fedora"))?;
+        std::fs::write(updates.join("EFI/EFI/fedora/grubx64.efi"), "grub")?;
+        let meta = ContentMetadata {
+            timestamp: "2025-01-01T00:00:00Z".parse()?,
+            version: "grub2-2.12-1".into(),
+            signing_keys: Vec::new(),
+        };
+        std::fs::write(updates.join("EFI.json"), serde_json::to_vec(&meta)?)?;
+        let layout = td.path().join("layout");
+        write_layout(&sysroot, &layout)?;
+
+        let index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(layout.join("index.json"))?)?;
+        let digest = index["manifests"][0]["digest"].as_str().unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(blob_path(&layout, digest))?)?;
+        assert_eq!(manifest["artifactType"], ARTIFACT_TYPE);
+        assert_eq!(
+            manifest["annotations"]["org.coreos.bootupd.component.EFI.version"],
+            "grub2-2.12-1"
+        );
+        let layer = &manifest["layers"][0];
+        assert_eq!(layer["annotations"]["org.coreos.bootupd.component"], "EFI");
+        let layer = blob_path(&layout, layer["digest"].as_str().unwrap());
+        let out = Command::new("tar").arg("-tf").arg(&layer).output()?;
+        let members = String::from_utf8(out.stdout)?;
+        assert!(members
+            .lines()
+            .any(|l| l == "usr/lib/bootupd/updates/EFI/EFI/fedora/grubx64.efi"));
+
+        // The layout isn't overwritten
+        assert!(write_layout(&sysroot, &layout).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+//! Signatures of the update payloads.
+//!
+//! `generate-update-metadata --sign-key` signs the metadata of each update
+//! payload, along with the filetree of its directory, with an ed25519 key
+//! into `<component>.json.sig` next to the metadata.  When
+//! `/etc/bootupd/trusted.d` contains public keys, updates only install
+//! payloads whose signature verifies with one of them and whose content is
+//! the signed one, so that a compromised `/usr` can't feed arbitrary
+//! bootloaders to bootupd.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use openssl::pkey::{Id, PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+
+use crate::component::Component;
+use crate::config::Config;
+use crate::digest::DigestAlgorithm;
+use crate::filetree::FileTree;
+use crate::model::BOOTUPD_UPDATES_DIR;
+
+/// The public keys trusted to sign update payloads, as PEM files
+pub(crate) const TRUSTED_KEYS_DIR: &str = "etc/bootupd/trusted.d";
+/// Appended to the name of the metadata of an update payload
+const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// The signature of an update payload.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct PayloadSignature {
+    /// The content of the payload directory, if the component has one
+    filetree: Option<FileTree>,
+    /// The ed25519 signature of the metadata and the filetree, hex encoded
+    signature: String,
+}
+
+/// The signed message: the metadata as written, then the filetree.
+fn message(metadata: &[u8], filetree: Option<&FileTree>) -> Result<Vec<u8>> {
+    let mut r = metadata.to_vec();
+    r.push(0);
+    serde_json::to_writer(&mut r, &filetree)?;
+    Ok(r)
+}
+
+fn metadata_name(component: &dyn Component) -> String {
+    format!("{}.json", component.name())
+}
+
+/// The filetree of the payload directory of `component`, if any, with the
+/// digests computed with `algorithm`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn payload_filetree(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    algorithm: DigestAlgorithm,
+) -> Result<Option<FileTree>> {
+    sysroot
+        .sub_dir_optional(&crate::component::component_updatedirname(component))?
+        .map(|dir| FileTree::new_from_dir(&dir, algorithm))
+        .transpose()
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn payload_filetree(
+    _: &openat::Dir,
+    _: &dyn Component,
+    _: DigestAlgorithm,
+) -> Result<Option<FileTree>> {
+    Ok(None)
+}
+
+/// Load the ed25519 private key `path`, in PEM format.
+#[context("Loading signing key {}", path.display())]
+pub(crate) fn load_signing_key(path: &Path) -> Result<PKey<Private>> {
+    let pem = std::fs::read(path)?;
+    let key = PKey::private_key_from_pem(&pem)?;
+    if key.id() != Id::ED25519 {
+        bail!("Not an ed25519 key");
+    }
+    Ok(key)
+}
+
+/// Sign the update payload of `component` generated in `sysroot_path`.
+#[context("Signing update payload of {}", component.name())]
+pub(crate) fn sign_payload(
+    sysroot_path: &str,
+    component: &dyn Component,
+    key: &PKey<Private>,
+) -> Result<()> {
+    let sysroot = openat::Dir::open(sysroot_path)?;
+    let dir = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    let name = metadata_name(component);
+    let metadata = dir.read_to_string(name.as_str())?;
+    let digest = Config::load(Path::new(sysroot_path))?.update.digest;
+    let filetree = payload_filetree(&sysroot, component, digest)?;
+    let mut signer = Signer::new_without_digest(key)?;
+    let signature =
+        signer.sign_oneshot_to_vec(&message(metadata.as_bytes(), filetree.as_ref())?)?;
+    let sig = PayloadSignature {
+        filetree,
+        signature: hex::encode(signature),
+    };
+    dir.write_file_with(
+        format!("{name}{SIGNATURE_SUFFIX}"),
+        0o644,
+        |w| -> Result<_> { Ok(serde_json::to_writer(w, &sig)?) },
+    )?;
+    Ok(())
+}
+
+/// Load the ed25519 public keys of `TRUSTED_KEYS_DIR`.
+#[context("Loading trusted keys")]
+fn trusted_keys(sysroot: &openat::Dir) -> Result<Vec<PKey<Public>>> {
+    let Some(dir) = sysroot.sub_dir_optional(TRUSTED_KEYS_DIR)? else {
+        return Ok(Vec::new());
+    };
+    let mut r = Vec::new();
+    for entry in dir.list_dir(".")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(".pem") {
+            continue;
+        }
+        let pem = dir.read_to_string(name.as_str())?;
+        let key =
+            PKey::public_key_from_pem(pem.as_bytes()).with_context(|| format!("parsing {name}"))?;
+        if key.id() != Id::ED25519 {
+            bail!("{name}: not an ed25519 key");
+        }
+        r.push(key);
+    }
+    Ok(r)
+}
+
+/// Check that the update payload of `component` is signed by a trusted key
+/// and unmodified since, if any key is trusted.
+#[context("Verifying update payload of {}", component.name())]
+pub(crate) fn verify_payload(sysroot: &openat::Dir, component: &dyn Component) -> Result<()> {
+    let keys = trusted_keys(sysroot)?;
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let dir = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    let name = metadata_name(component);
+    let metadata = dir.read_to_string(name.as_str())?;
+    let signame = format!("{name}{SIGNATURE_SUFFIX}");
+    let Some(f) = dir.open_file_optional(signame.as_str())? else {
+        bail!("The update payload is not signed");
+    };
+    let sig: PayloadSignature = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("parsing {signame}"))?;
+    let signature = hex::decode(&sig.signature).context("decoding signature")?;
+    let msg = message(metadata.as_bytes(), sig.filetree.as_ref())?;
+    let verified = keys.iter().any(|key| {
+        Verifier::new_without_digest(key)
+            .and_then(|mut v| v.verify_oneshot(&signature, &msg))
+            .unwrap_or(false)
+    });
+    if !verified {
+        bail!("The signature doesn't verify with any key of /{TRUSTED_KEYS_DIR}");
+    }
+    verify_content(sysroot, component, sig.filetree.as_ref())
+}
+
+/// Check that the payload directory of `component` holds exactly the files
+/// of the `signed` filetree, each compared with the digest algorithm it was
+/// signed with, whatever the configuration of this system.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+fn verify_content(
+    sysroot: &openat::Dir,
+    component: &dyn Component,
+    signed: Option<&FileTree>,
+) -> Result<()> {
+    let dir = sysroot.sub_dir_optional(&crate::component::component_updatedirname(component))?;
+    let (dir, signed) = match (dir, signed) {
+        (None, None) => return Ok(()),
+        (Some(dir), Some(signed)) => (dir, signed),
+        _ => bail!("The payload content differs from the signed one"),
+    };
+    let diff = signed.relative_diff_to(&dir)?;
+    if diff.count() > 0 {
+        bail!("The payload content differs from the signed one: {diff}");
+    }
+    let untracked = signed.untracked_in(&dir)?;
+    if !untracked.is_empty() {
+        bail!(
+            "The payload content differs from the signed one: unsigned {}",
+            untracked.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+fn verify_content(_: &openat::Dir, _: &dyn Component, signed: Option<&FileTree>) -> Result<()> {
+    if signed.is_some() {
+        bail!("The payload content differs from the signed one");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() -> Result<()> {
+        let key = PKey::generate_ed25519()?;
+        let public = PKey::public_key_from_der(&key.public_key_to_der()?)?;
+        let filetree = FileTree {
+            children: Default::default(),
+        };
+        let msg = message(b"{\"version\":\"v1\"}", Some(&filetree))?;
+        let signature = Signer::new_without_digest(&key)?.sign_oneshot_to_vec(&msg)?;
+        let mut verifier = Verifier::new_without_digest(&public)?;
+        assert!(verifier.verify_oneshot(&signature, &msg)?);
+        // The metadata is covered by the signature
+        let msg = message(b"{\"version\":\"v2\"}", Some(&filetree))?;
+        let mut verifier = Verifier::new_without_digest(&public)?;
+        assert!(!verifier.verify_oneshot(&signature, &msg)?);
+        Ok(())
+    }
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn test_sign_verify_payload() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let component = crate::efi::Efi::default();
+        let updates = root.join(BOOTUPD_UPDATES_DIR);
+        let shim = updates.join("EFI/fedora/shimx64.efi");
+        std::fs::create_dir_all(shim.parent().unwrap())?;
+        std::fs::write(&shim, "shim")?;
+        std::fs::write(updates.join("EFI.json"), r#"{"version":"v1"}"#)?;
+        let key = PKey::generate_ed25519()?;
+        sign_payload(root.to_str().unwrap(), &component, &key)?;
+        let sysroot = openat::Dir::open(root)?;
+        let keys = root.join(TRUSTED_KEYS_DIR);
+        std::fs::create_dir_all(&keys)?;
+        std::fs::write(keys.join("vendor.pem"), key.public_key_to_pem()?)?;
+        verify_payload(&sysroot, &component)?;
+
+        // Tampered content
+        std::fs::write(&shim, "evil")?;
+        let err = verify_payload(&sysroot, &component).unwrap_err();
+        assert!(format!("{err:#}").contains("differs from the signed one"));
+        std::fs::write(&shim, "shim")?;
+        verify_payload(&sysroot, &component)?;
+
+        // Tampered metadata
+        std::fs::write(updates.join("EFI.json"), r#"{"version":"v2"}"#)?;
+        let err = verify_payload(&sysroot, &component).unwrap_err();
+        assert!(format!("{err:#}").contains("doesn't verify"));
+        std::fs::write(updates.join("EFI.json"), r#"{"version":"v1"}"#)?;
+        verify_payload(&sysroot, &component)?;
+
+        // Signed with an untrusted key
+        let other = PKey::generate_ed25519()?;
+        std::fs::write(keys.join("vendor.pem"), other.public_key_to_pem()?)?;
+        let err = verify_payload(&sysroot, &component).unwrap_err();
+        assert!(format!("{err:#}").contains("doesn't verify"));
+
+        // Unsigned
+        std::fs::remove_file(updates.join("EFI.json.sig"))?;
+        let err = verify_payload(&sysroot, &component).unwrap_err();
+        assert!(format!("{err:#}").contains("not signed"));
+        Ok(())
+    }
+
+    /// The content is compared with the digest algorithm of the signed
+    /// filetree, whichever one this system is configured with.
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn test_verify_payload_digest_algorithms() -> Result<()> {
+        use crate::filetree::FileMetadata;
+
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let component = crate::efi::Efi::default();
+        let updates = root.join(BOOTUPD_UPDATES_DIR);
+        let shim = updates.join("EFI/fedora/shimx64.efi");
+        std::fs::create_dir_all(shim.parent().unwrap())?;
+        std::fs::write(&shim, "shim")?;
+        let metadata = r#"{"version":"v1"}"#;
+        std::fs::write(updates.join("EFI.json"), metadata)?;
+        let key = PKey::generate_ed25519()?;
+        let keys = root.join(TRUSTED_KEYS_DIR);
+        std::fs::create_dir_all(&keys)?;
+        std::fs::write(keys.join("vendor.pem"), key.public_key_to_pem()?)?;
+        let sysroot = openat::Dir::open(root)?;
+        let payload = openat::Dir::open(updates.join("EFI").as_path())?;
+
+        for algorithm in [DigestAlgorithm::Sha512, DigestAlgorithm::Blake3] {
+            let path = "fedora/shimx64.efi";
+            let meta = FileMetadata::new_from_path(&payload, path, algorithm)?;
+            let filetree = FileTree {
+                children: [(path.to_string(), meta)].into(),
+            };
+            let signature = Signer::new_without_digest(&key)?
+                .sign_oneshot_to_vec(&message(metadata.as_bytes(), Some(&filetree))?)?;
+            let sig = PayloadSignature {
+                filetree: Some(filetree),
+                signature: hex::encode(signature),
+            };
+            std::fs::write(updates.join("EFI.json.sig"), serde_json::to_vec(&sig)?)?;
+            verify_payload(&sysroot, &component)?;
+
+            std::fs::write(&shim, "evil")?;
+            let err = verify_payload(&sysroot, &component).unwrap_err();
+            assert!(format!("{err:#}").contains("differs from the signed one"));
+            std::fs::write(&shim, "shim")?;
+
+            // Files not in the signed filetree are refused too
+            let extra = updates.join("EFI/fedora/grubx64.efi");
+            std::fs::write(&extra, "grub")?;
+            let err = verify_payload(&sysroot, &component).unwrap_err();
+            assert!(format!("{err:#}").contains("unsigned fedora/grubx64.efi"));
+            std::fs::remove_file(&extra)?;
+            verify_payload(&sysroot, &component)?;
+        }
+        Ok(())
+    }
+}
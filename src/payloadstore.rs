@@ -0,0 +1,234 @@
+//! Update payloads in a content-addressed object store.
+//!
+//! `generate-update-metadata --dedup` replaces the directory of each
+//! payload by a manifest, `<component>.filetree.json`, the filetree of the
+//! directory, and moves its files to `objects/<algorithm>/<digest>`, so
+//! that the binaries shared between components (e.g. a shim shipped for
+//! EFI and in a UKI payload) are stored once.  The directories are
+//! materialized by `payloadview` before being used.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::digest::Digest;
+use crate::filetree::FileTree;
+
+/// The objects, below the updates directory
+pub(crate) const OBJECTS_DIR: &str = "objects";
+/// Appended to the component name for its manifest
+const MANIFEST_SUFFIX: &str = ".filetree.json";
+
+/// The path of the object of `digest`, relative to the updates directory.
+pub(crate) fn object_path(digest: &Digest) -> Result<PathBuf> {
+    let digest = digest.to_string();
+    match digest.split_once(':') {
+        Some((algo, hex)) if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Path::new(OBJECTS_DIR).join(algo).join(hex))
+        }
+        _ => bail!("Invalid digest {digest:?}"),
+    }
+}
+
+/// Whether `name` is the manifest of a component.
+pub(crate) fn is_manifest(name: &str) -> bool {
+    name.ends_with(MANIFEST_SUFFIX)
+}
+
+/// Load the manifest of `component` in `updates`.
+#[context("Loading manifest of {component}")]
+pub(crate) fn load_manifest(updates: &Path, component: &str) -> Result<FileTree> {
+    let path = updates.join(format!("{component}{MANIFEST_SUFFIX}"));
+    let f = std::fs::File::open(&path).with_context(|| format!("opening {path:?}"))?;
+    Ok(serde_json::from_reader(std::io::BufReader::new(f))?)
+}
+
+/// The components of `updates` whose payload is only available in the
+/// object store.
+pub(crate) fn stored(updates: &Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    let entries = match std::fs::read_dir(updates) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(r),
+        Err(e) => return Err(e).with_context(|| format!("reading {updates:?}")),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(component) = name.to_str().and_then(|n| n.strip_suffix(MANIFEST_SUFFIX)) else {
+            continue;
+        };
+        if !updates.join(component).exists() {
+            r.push(component.to_string());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Move the payload directories of `sysroot_path` to the object store.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+))]
+#[context("Deduplicating update payloads")]
+pub(crate) fn pack(sysroot_path: &str) -> Result<()> {
+    let updates = Path::new(sysroot_path).join(crate::model::BOOTUPD_UPDATES_DIR);
+    let digest = crate::config::Config::load(Path::new(sysroot_path))?
+        .update
+        .digest;
+    for entry in std::fs::read_dir(&updates)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if !entry.file_type()?.is_dir() || name == OBJECTS_DIR {
+            continue;
+        }
+        let Some(name) = name.to_str() else {
+            bail!("Invalid UTF-8 filename: {name:?}");
+        };
+        let dir = entry.path();
+        let tree = FileTree::new_from_dir(&openat::Dir::open(&dir)?, digest)?;
+        let mut shared = 0;
+        for (path, meta) in tree.children.iter() {
+            let object = updates.join(object_path(&meta.digest)?);
+            if object.exists() {
+                shared += 1;
+                continue;
+            }
+            // Unwrap safety: below the objects directory
+            std::fs::create_dir_all(object.parent().unwrap())?;
+            std::fs::rename(dir.join(path), &object)
+                .with_context(|| format!("moving {path} to the object store"))?;
+        }
+        let manifest = updates.join(format!("{name}{MANIFEST_SUFFIX}"));
+        std::fs::write(&manifest, serde_json::to_vec(&tree)?)
+            .with_context(|| format!("writing {manifest:?}"))?;
+        std::fs::remove_dir_all(&dir)?;
+        log::info!(
+            "Moved payload {name} to the object store ({} files, {shared} already stored)",
+            tree.children.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "riscv64"
+)))]
+pub(crate) fn pack(_sysroot_path: &str) -> Result<()> {
+    bail!("Deduplicating payloads is not supported on this architecture")
+}
+
+/// Materialize the payload directory of `component` from `updates` in
+/// `dest`.
+#[context("Materializing payload of {component}")]
+pub(crate) fn materialize(updates: &Path, component: &str, dest: &Path) -> Result<()> {
+    let tree = load_manifest(updates, component)?;
+    let dest = dest.join(component);
+    for (path, meta) in tree.children.iter() {
+        // The manifest is untrusted input: never write outside of `dest`
+        if !Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("Invalid path in manifest: {path:?}");
+        }
+        let target = dest.join(path);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let object = updates.join(object_path(&meta.digest)?);
+        let content = std::fs::read(&object).with_context(|| format!("reading {object:?}"))?;
+        let digest = Digest::compute(meta.digest.algorithm(), &mut content.as_slice())?;
+        if digest != meta.digest {
+            bail!(
+                "Corrupted object for {path}: expected {}, found {digest}",
+                meta.digest
+            );
+        }
+        std::fs::write(&target, content).with_context(|| format!("writing {path}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::DigestAlgorithm;
+
+    #[test]
+    fn test_object_path() -> Result<()> {
+        let digest = Digest::Blake3("blake3:0123abcd".into());
+        assert_eq!(object_path(&digest)?, Path::new("objects/blake3/0123abcd"));
+        assert!(object_path(&Digest::Blake3("blake3:../../etc".into())).is_err());
+        Ok(())
+    }
+
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    ))]
+    #[test]
+    fn test_pack_materialize() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join(crate::model::BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(updates.join("EFI/EFI/fedora"))?;
+        std::fs::create_dir_all(updates.join("UKI"))?;
+        std::fs::write(updates.join("EFI/EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(updates.join("EFI/EFI/fedora/grubx64.efi"), "grub")?;
+        std::fs::write(updates.join("UKI/shimx64.efi"), "shim")?;
+        let efi = FileTree::new_from_dir(
+            &openat::Dir::open(&updates.join("EFI"))?,
+            DigestAlgorithm::default(),
+        )?;
+        pack(td.path().to_str().unwrap())?;
+        assert_eq!(stored(&updates)?, ["EFI", "UKI"]);
+        // The shim is stored once
+        let objects = walkdir::WalkDir::new(updates.join(OBJECTS_DIR))
+            .into_iter()
+            .filter(|e| e.as_ref().is_ok_and(|e| e.file_type().is_file()))
+            .count();
+        assert_eq!(objects, 2);
+
+        let dest = td.path().join("dest");
+        materialize(&updates, "EFI", &dest)?;
+        assert_eq!(
+            FileTree::new_from_dir(
+                &openat::Dir::open(&dest.join("EFI"))?,
+                DigestAlgorithm::default()
+            )?,
+            efi
+        );
+
+        // A corrupted object is never written
+        let (_, meta) = efi.children.iter().next().unwrap();
+        std::fs::write(updates.join(object_path(&meta.digest)?), "evil")?;
+        let dest = td.path().join("corrupted");
+        assert!(materialize(&updates, "EFI", &dest).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_invalid_path() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join("updates");
+        std::fs::create_dir_all(&updates)?;
+        let digest = Digest::compute(crate::digest::DigestAlgorithm::Sha512, &mut &b"x"[..])?;
+        for path in ["../escape", "/etc/passwd", "EFI/../x"] {
+            let manifest = serde_json::json!({
+                "children": { path: { "size": 1, "sha512": digest.to_string() } },
+            });
+            std::fs::write(
+                updates.join(format!("EFI{MANIFEST_SUFFIX}")),
+                serde_json::to_vec(&manifest)?,
+            )?;
+            assert!(materialize(&updates, "EFI", &td.path().join("dest")).is_err());
+        }
+        assert!(!td.path().join("escape").exists());
+        Ok(())
+    }
+}
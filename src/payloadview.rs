@@ -0,0 +1,75 @@
+//! The update payloads as the components read them.
+//!
+//! Payloads can be stored compressed (see `payloadzstd`) or in the object
+//! store (see `payloadstore`) rather than as a directory per component
+//! below `/usr/lib/bootupd/updates`.  Before using them, these are unpacked
+//! to a tmpfs only visible in a private mount namespace, which is bind
+//! mounted over `/usr/lib/bootupd/updates`, so that the components read
+//! them transparently; the payload directories are used as is.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+use crate::model::BOOTUPD_UPDATES_DIR;
+use crate::payloadstore;
+use crate::payloadzstd;
+use crate::util::{self, CommandRunExt};
+
+/// Where the payloads are unpacked, in a private tmpfs
+const UNPACKED_DIR: &str = "/run/bootupd/payloads";
+
+/// Make the packed payloads of `sysroot` available as directories for the
+/// rest of the process, if there are any.  This must be called before
+/// starting any thread.
+#[context("Unpacking update payloads")]
+pub(crate) fn activate(sysroot: &Path) -> Result<()> {
+    let updates = sysroot.join(BOOTUPD_UPDATES_DIR);
+    let compressed = payloadzstd::compressed(&updates)?;
+    let stored = payloadstore::stored(&updates)?;
+    if compressed.is_empty() && stored.is_empty() {
+        return Ok(());
+    }
+    util::enter_private_mount_namespace()?;
+    // Freed with the mount namespace, at the end of the process
+    let dest = Path::new(UNPACKED_DIR);
+    std::fs::create_dir_all(dest).with_context(|| format!("creating {dest:?}"))?;
+    Command::new("mount")
+        .args(["-t", "tmpfs", "-o", "mode=0755", "tmpfs"])
+        .arg(dest)
+        .run()?;
+    for entry in std::fs::read_dir(&updates)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if payloadzstd::is_archive(&name)
+            || payloadstore::is_manifest(&name)
+            || name == payloadstore::OBJECTS_DIR
+        {
+            continue;
+        }
+        Command::new("cp")
+            .args(["-a", "--reflink=auto"])
+            .arg(entry.path())
+            .arg(dest)
+            .run()?;
+    }
+    for component in compressed.iter() {
+        payloadzstd::extract(&updates, component, dest)?;
+    }
+    for component in stored.iter() {
+        payloadstore::materialize(&updates, component, dest)?;
+    }
+    Command::new("mount")
+        .arg("--bind")
+        .arg(dest)
+        .arg(&updates)
+        .run()?;
+    log::debug!(
+        "Unpacked payloads: {}",
+        [compressed, stored].concat().join(", ")
+    );
+    Ok(())
+}
@@ -0,0 +1,101 @@
+//! Update payloads stored compressed with zstd.
+//!
+//! `generate-update-metadata --compress` replaces the directory of each
+//! payload by `<component>.tar.zst` next to its metadata, e.g. to shrink
+//! images shipping the shim and GRUB of several vendors or architectures.
+//! They are decompressed by `payloadview` before being used.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+use crate::model::BOOTUPD_UPDATES_DIR;
+use crate::util::CommandRunExt;
+
+/// Appended to the component name for its compressed payload
+const ARCHIVE_SUFFIX: &str = ".tar.zst";
+
+/// Replace the payload directories of `sysroot_path` by archives.
+#[context("Compressing update payloads")]
+pub(crate) fn compress(sysroot_path: &str) -> Result<()> {
+    let updates = Path::new(sysroot_path).join(BOOTUPD_UPDATES_DIR);
+    for entry in std::fs::read_dir(&updates)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        let mut archive = name.clone();
+        archive.push(ARCHIVE_SUFFIX);
+        Command::new("tar")
+            .arg("-C")
+            .arg(&updates)
+            .args(["--zstd", "--sort=name", "--owner=0", "--group=0"])
+            .args(["--numeric-owner", "-cf"])
+            .arg(updates.join(&archive))
+            .arg(&name)
+            .run()?;
+        std::fs::remove_dir_all(entry.path())?;
+        log::info!("Compressed payload {archive:?}");
+    }
+    Ok(())
+}
+
+/// The components of `updates` whose payload is only available compressed.
+pub(crate) fn compressed(updates: &Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    let entries = match std::fs::read_dir(updates) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(r),
+        Err(e) => return Err(e).with_context(|| format!("reading {updates:?}")),
+    };
+    for entry in entries {
+        let name = entry?.file_name();
+        let Some(component) = name.to_str().and_then(|n| n.strip_suffix(ARCHIVE_SUFFIX)) else {
+            continue;
+        };
+        if !updates.join(component).exists() {
+            r.push(component.to_string());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Whether `name` is the compressed payload of a component.
+pub(crate) fn is_archive(name: &str) -> bool {
+    name.ends_with(ARCHIVE_SUFFIX)
+}
+
+/// Extract the compressed payload of `component` from `updates` to `dest`.
+#[context("Decompressing payload of {component}")]
+pub(crate) fn extract(updates: &Path, component: &str, dest: &Path) -> Result<()> {
+    Command::new("tar")
+        .args(["--zstd", "-xf"])
+        .arg(updates.join(format!("{component}{ARCHIVE_SUFFIX}")))
+        .arg("-C")
+        .arg(dest)
+        .run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path();
+        assert!(compressed(&updates.join("missing"))?.is_empty());
+        std::fs::write(updates.join("EFI.json"), "{}")?;
+        std::fs::write(updates.join("EFI.tar.zst"), "")?;
+        std::fs::write(updates.join("dtb.tar.zst"), "")?;
+        // Not compressed anymore, e.g. generated again without --compress
+        std::fs::create_dir(updates.join("dtb"))?;
+        std::fs::create_dir(updates.join("BIOS"))?;
+        assert_eq!(compressed(updates)?, ["EFI"]);
+        Ok(())
+    }
+}
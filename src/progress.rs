@@ -0,0 +1,110 @@
+//! Progress reporting of long operations, so that e.g. writing an update
+//! to a slow SD card doesn't look hung.
+
+use std::fmt::Display;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+/// How far an operation copying files is.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Progress {
+    pub files_done: u64,
+    pub files_total: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+impl Progress {
+    /// The completion percentage, by size.
+    pub fn percent(&self) -> u64 {
+        if self.bytes_total == 0 {
+            return 100;
+        }
+        self.bytes_done * 100 / self.bytes_total
+    }
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: f64 = 1024.0 * 1024.0;
+        write!(
+            f,
+            "{}/{} files, {:.1}/{:.1} MiB",
+            self.files_done,
+            self.files_total,
+            self.bytes_done as f64 / MIB,
+            self.bytes_total as f64 / MIB
+        )
+    }
+}
+
+/// A callback receiving the progress of an operation; it may be called
+/// from several threads, e.g. when writing to mirrored ESPs.
+pub type ProgressFn<'a> = &'a (dyn Fn(&Progress) + Sync);
+
+/// Ignore the progress of an operation.
+pub(crate) fn ignore(_: &Progress) {}
+
+/// A progress bar on stderr, if it is a terminal.
+pub(crate) struct ProgressBar {
+    label: String,
+    tty: bool,
+    drawn: AtomicBool,
+}
+
+impl ProgressBar {
+    const WIDTH: u64 = 30;
+
+    pub(crate) fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            tty: std::io::stderr().is_terminal(),
+            drawn: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn update(&self, p: &Progress) {
+        if !self.tty {
+            return;
+        }
+        let filled = (p.percent() * Self::WIDTH / 100) as usize;
+        let bar = format!(
+            "{}{}",
+            "#".repeat(filled),
+            " ".repeat(Self::WIDTH as usize - filled)
+        );
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}: [{bar}] {:3}% ({p})", self.label, p.percent());
+        let _ = stderr.flush();
+        self.drawn.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        if self.drawn.load(Ordering::Relaxed) {
+            eprintln!();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let p = Progress {
+            files_done: 3,
+            files_total: 12,
+            bytes_done: 3 * 1024 * 1024,
+            bytes_total: 12 * 1024 * 1024,
+        };
+        assert_eq!(p.percent(), 25);
+        assert_eq!(p.to_string(), "3/12 files, 3.0/12.0 MiB");
+        assert_eq!(Progress::default().percent(), 100);
+    }
+}
@@ -0,0 +1,261 @@
+//! Quarantine of the files removed from the ESPs by `bootupctl validate
+//! --prune`: rather than deleted, they are moved to
+//! `bootupd-quarantine/<timestamp>/` at the root of their ESP, where
+//! `bootupctl quarantine restore` can put them back, until they expire
+//! after `efi.quarantine-days`.
+//!
+//! The quarantine is outside of `EFI/`, so the firmware never sees its
+//! binaries and validation doesn't report them.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use fn_error_context::context;
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::filetree;
+
+/// The directory of the quarantines, at the root of an ESP
+pub(crate) const QUARANTINE_DIR: &str = "bootupd-quarantine";
+/// The format of the name of a quarantine, from its creation time; FAT
+/// doesn't allow `:`
+const ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// The files moved out of an ESP by one prune.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Quarantine {
+    /// Its name, e.g. `20250102T030405Z`
+    pub(crate) id: String,
+    pub(crate) timestamp: DateTime<Utc>,
+    /// The device of the ESP, if not the primary one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) device: Option<String>,
+    /// Its files, relative to `EFI/`
+    pub(crate) files: Vec<String>,
+}
+
+/// The name of a quarantine created at `timestamp`.
+pub(crate) fn new_id(timestamp: DateTime<Utc>) -> String {
+    timestamp.format(ID_FORMAT).to_string()
+}
+
+/// The creation time of the quarantine `id`.
+fn parse_id(id: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(id, ID_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+/// The directory of the quarantine `id` of the ESP mounted at `esp`.
+fn quarantine_path(esp: &Path, id: &str) -> Result<PathBuf> {
+    if parse_id(id).is_none() {
+        bail!("Invalid quarantine {id:?}");
+    }
+    Ok(esp.join(QUARANTINE_DIR).join(id))
+}
+
+/// Move `files` (relative to `EFI/`) of the ESP mounted at `esp` to its
+/// quarantine `id`.
+#[context("Moving files to quarantine {id}")]
+pub(crate) fn quarantine(esp: &Path, id: &str, files: &[String]) -> Result<()> {
+    let dest = quarantine_path(esp, id)?;
+    for f in files {
+        let src = esp.join("EFI").join(f);
+        let dest = dest.join(f);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&src, &dest).with_context(|| format!("moving {src:?}"))?;
+    }
+    filetree::syncfs(&openat::Dir::open(esp)?)?;
+    Ok(())
+}
+
+/// The quarantines of the ESP mounted at `esp`, oldest first.
+#[context("Listing quarantines")]
+pub(crate) fn list(esp: &Path, device: Option<&str>) -> Result<Vec<Quarantine>> {
+    let dir = esp.join(QUARANTINE_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut r = Vec::new();
+    for entry in WalkDir::new(&dir)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+    {
+        let entry = entry?;
+        let id = entry.file_name().to_string_lossy();
+        let Some(timestamp) = parse_id(&id).filter(|_| entry.file_type().is_dir()) else {
+            log::warn!("Ignoring unknown {QUARANTINE_DIR}/{id}");
+            continue;
+        };
+        let mut files = Vec::new();
+        for f in WalkDir::new(entry.path()).sort_by_file_name() {
+            let f = f?;
+            if !f.file_type().is_dir() {
+                files.push(
+                    f.path()
+                        .strip_prefix(entry.path())?
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            }
+        }
+        r.push(Quarantine {
+            id: id.into_owned(),
+            timestamp,
+            device: device.map(ToOwned::to_owned),
+            files,
+        });
+    }
+    Ok(r)
+}
+
+/// Move the files of the quarantine `id` of the ESP mounted at `esp` back
+/// to `EFI/`, unless one of them was installed again since; returns them.
+#[context("Restoring quarantine {id}")]
+pub(crate) fn restore(esp: &Path, id: &str) -> Result<Vec<String>> {
+    let src = quarantine_path(esp, id)?;
+    let Some(quarantine) = list(esp, None)?.into_iter().find(|q| q.id == id) else {
+        bail!("No quarantine {id}");
+    };
+    let efidir = esp.join("EFI");
+    if let Some(f) = quarantine.files.iter().find(|f| efidir.join(f).exists()) {
+        bail!("EFI/{f} exists");
+    }
+    for f in quarantine.files.iter() {
+        let dest = efidir.join(f);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(src.join(f), &dest).with_context(|| format!("restoring {dest:?}"))?;
+    }
+    std::fs::remove_dir_all(&src)?;
+    filetree::syncfs(&openat::Dir::open(esp)?)?;
+    Ok(quarantine.files)
+}
+
+/// Remove the quarantines of the ESP mounted at `esp` selected by
+/// `select`; returns them.
+#[context("Purging quarantines")]
+pub(crate) fn purge(
+    esp: &Path,
+    device: Option<&str>,
+    select: &dyn Fn(&Quarantine) -> bool,
+) -> Result<Vec<Quarantine>> {
+    let mut r = Vec::new();
+    for q in list(esp, device)?.into_iter().filter(|q| select(q)) {
+        std::fs::remove_dir_all(quarantine_path(esp, &q.id)?)?;
+        r.push(q);
+    }
+    if !r.is_empty() {
+        filetree::syncfs(&openat::Dir::open(esp)?)?;
+    }
+    Ok(r)
+}
+
+/// Whether `q` is older than `days` at `now`.
+pub(crate) fn is_expired(q: &Quarantine, days: u32, now: DateTime<Utc>) -> bool {
+    q.timestamp
+        .checked_add_signed(chrono::Duration::days(days.into()))
+        .is_some_and(|t| t < now)
+}
+
+/// Print `quarantines` for humans.
+pub(crate) fn print(quarantines: &[Quarantine]) {
+    if quarantines.is_empty() {
+        println!("No quarantined files.");
+    }
+    for q in quarantines {
+        match q.device.as_deref() {
+            Some(device) => println!("{} (ESP {device}):", q.id),
+            None => println!("{}:", q.id),
+        }
+        for f in q.files.iter() {
+            println!("  EFI/{f}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = td.path();
+        std::fs::create_dir_all(esp.join("EFI/fedora"))?;
+        std::fs::create_dir_all(esp.join("EFI/centos"))?;
+        std::fs::write(esp.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(esp.join("EFI/fedora/old.efi"), "old")?;
+        std::fs::write(esp.join("EFI/centos/shimx64.efi"), "centos")?;
+        assert!(list(esp, None)?.is_empty());
+
+        let timestamp: DateTime<Utc> = "2025-01-02T03:04:05Z".parse()?;
+        let id = new_id(timestamp);
+        assert_eq!(id, "20250102T030405Z");
+        let files = [
+            "centos/shimx64.efi".to_string(),
+            "fedora/old.efi".to_string(),
+        ];
+        quarantine(esp, &id, &files)?;
+        assert!(!esp.join("EFI/fedora/old.efi").exists());
+        assert!(esp.join("EFI/fedora/shimx64.efi").exists());
+        let quarantines = list(esp, Some("/dev/sdb2"))?;
+        assert_eq!(
+            quarantines,
+            [Quarantine {
+                id: id.clone(),
+                timestamp,
+                device: Some("/dev/sdb2".into()),
+                files: files.to_vec(),
+            }]
+        );
+
+        // Never overwrite a file installed again since
+        std::fs::write(esp.join("EFI/fedora/old.efi"), "new")?;
+        assert!(restore(esp, &id).is_err());
+        std::fs::remove_file(esp.join("EFI/fedora/old.efi"))?;
+        assert_eq!(restore(esp, &id)?, files);
+        assert_eq!(
+            std::fs::read_to_string(esp.join("EFI/fedora/old.efi"))?,
+            "old"
+        );
+        assert!(list(esp, None)?.is_empty());
+        assert!(restore(esp, &id).is_err());
+        assert!(restore(esp, "../EFI").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let esp = td.path();
+        std::fs::create_dir_all(esp.join("EFI/fedora"))?;
+        std::fs::write(esp.join("EFI/fedora/a.efi"), "a")?;
+        std::fs::write(esp.join("EFI/fedora/b.efi"), "b")?;
+        let old: DateTime<Utc> = "2025-01-01T00:00:00Z".parse()?;
+        let recent: DateTime<Utc> = "2025-01-30T00:00:00Z".parse()?;
+        quarantine(esp, &new_id(old), &["fedora/a.efi".into()])?;
+        quarantine(esp, &new_id(recent), &["fedora/b.efi".into()])?;
+        std::fs::create_dir_all(esp.join(QUARANTINE_DIR).join("unknown"))?;
+
+        let now: DateTime<Utc> = "2025-02-01T00:00:00Z".parse()?;
+        let purged = purge(esp, None, &|q| is_expired(q, 30, now))?;
+        assert_eq!(purged.len(), 1);
+        assert_eq!(purged[0].timestamp, old);
+        let left = list(esp, None)?;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].timestamp, recent);
+        assert_eq!(purge(esp, None, &|_| true)?.len(), 1);
+        assert!(list(esp, None)?.is_empty());
+        // Unknown directories are left alone
+        assert!(esp.join(QUARANTINE_DIR).join("unknown").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! SBAT (Secure Boot Advanced Targeting) metadata of EFI binaries.
+//!
+//! shim refuses to load binaries whose `.sbat` section lists a component
+//! generation lower than the minimum recorded in the `SbatLevel` variable,
+//! so installing such a binary would leave the system unbootable.
+//! See https://github.com/rhboot/shim/blob/main/SBAT.md for the format.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use walkdir::WalkDir;
+
+use crate::efivars;
+
+/// The vendor GUID of the variables created by shim
+const SHIM_LOCK_GUID: &str = "605dab50-e046-4300-abb6-3dd810dd8b23";
+/// The runtime accessible copy of `SbatLevel`
+const SBAT_LEVEL_VAR: &str = "SbatLevelRT";
+/// The PE section holding the SBAT metadata
+const SBAT_SECTION: &[u8] = b".sbat";
+
+/// Maps a component name to its generation.
+pub(crate) type Generations = BTreeMap<String, u32>;
+
+pub(crate) fn le_u16(buf: &[u8], off: usize) -> Option<u16> {
+    buf.get(off..off + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+pub(crate) fn le_u32(buf: &[u8], off: usize) -> Option<u32> {
+    buf.get(off..off + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Returns the raw content of the section `name` of a PE image, or `None`
+/// if this isn't a PE image or there is no such section.
+fn pe_section<'a>(data: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    if !data.starts_with(b"MZ") {
+        return None;
+    }
+    let pe = le_u32(data, 0x3c)? as usize;
+    if data.get(pe..pe + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff = pe + 4;
+    let nsections = le_u16(data, coff + 2)? as usize;
+    let optional_header_size = le_u16(data, coff + 16)? as usize;
+    let sections = coff + 20 + optional_header_size;
+    (0..nsections).find_map(|i| {
+        let section = data.get(sections + i * 40..sections + (i + 1) * 40)?;
+        let section_name = section[..8].split(|&b| b == 0).next()?;
+        if section_name != name {
+            return None;
+        }
+        let virtual_size = le_u32(section, 8)? as usize;
+        let raw_size = le_u32(section, 16)? as usize;
+        let offset = le_u32(section, 20)? as usize;
+        data.get(offset..offset + virtual_size.min(raw_size))
+    })
+}
+
+/// Parse SBAT CSV data, either from a binary or from `SbatLevel`; only the
+/// component names and generations are kept.
+pub(crate) fn parse(buf: &[u8]) -> Result<Generations> {
+    let buf = buf.split(|&b| b == 0).next().unwrap_or_default();
+    let s = std::str::from_utf8(buf).context("SBAT data is not UTF-8")?;
+    let mut r = Generations::new();
+    for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let mut fields = line.split(',');
+        let name = fields.next().unwrap_or_default();
+        let Some(generation) = fields.next() else {
+            bail!("Invalid SBAT entry: {line}");
+        };
+        let generation = generation
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid SBAT generation: {line}"))?;
+        r.insert(name.to_string(), generation);
+    }
+    Ok(r)
+}
+
+/// Read the SBAT metadata of an EFI binary; returns `None` if it has none.
+#[context("Reading SBAT metadata of {}", path.display())]
+pub(crate) fn read_file(path: &Path) -> Result<Option<Generations>> {
+    let data = std::fs::read(path)?;
+    pe_section(&data, SBAT_SECTION).map(parse).transpose()
+}
+
+/// An SBAT entry of a binary, with its vendor fields, e.g.
+/// `grub.rh,2,Red Hat,grub2,2.06-104.fc40,mailto:secalert@redhat.com`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub(crate) component: String,
+    pub(crate) vendor: String,
+    pub(crate) package: String,
+    pub(crate) version: String,
+}
+
+impl Entry {
+    /// Returns `true` for the entries added by a distribution, whose
+    /// component names are e.g. `shim.redhat` or `grub.debian`.
+    pub(crate) fn is_vendor(&self) -> bool {
+        self.component.contains('.')
+    }
+}
+
+/// Parse the SBAT CSV data of a binary, keeping the vendor fields; the
+/// `sbat` entry describing the format is left out.
+pub(crate) fn parse_entries(buf: &[u8]) -> Result<Vec<Entry>> {
+    let buf = buf.split(|&b| b == 0).next().unwrap_or_default();
+    let s = std::str::from_utf8(buf).context("SBAT data is not UTF-8")?;
+    let mut r = Vec::new();
+    for line in s.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [component, _generation, vendor, package, version, ..] = fields.as_slice() else {
+            bail!("Invalid SBAT entry: {line}");
+        };
+        if *component == "sbat" {
+            continue;
+        }
+        r.push(Entry {
+            component: component.to_string(),
+            vendor: vendor.to_string(),
+            package: package.to_string(),
+            version: version.to_string(),
+        });
+    }
+    Ok(r)
+}
+
+/// Read the SBAT entries of an EFI binary; returns `None` if it has none.
+#[context("Reading SBAT metadata of {}", path.display())]
+pub(crate) fn read_entries(path: &Path) -> Result<Option<Vec<Entry>>> {
+    let data = std::fs::read(path)?;
+    pe_section(&data, SBAT_SECTION)
+        .map(parse_entries)
+        .transpose()
+}
+
+/// The package and version of a binary, e.g. `grub2-2.06-104.fc40`, from
+/// its vendor entry, which is more precise than the upstream one.
+pub(crate) fn package_version(entries: &[Entry]) -> Option<String> {
+    let entry = entries
+        .iter()
+        .find(|e| e.is_vendor())
+        .or_else(|| entries.first())?;
+    Some(format!("{}-{}", entry.package, entry.version))
+}
+
+/// Read the SBAT metadata of all the EFI binaries under `dir`, by path
+/// relative to it.
+#[context("Scanning {} for SBAT metadata", dir.display())]
+pub(crate) fn scan(dir: &Path) -> Result<BTreeMap<String, Generations>> {
+    let mut r = BTreeMap::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let is_efi = entry
+            .path()
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("efi"));
+        if !entry.file_type().is_file() || !is_efi {
+            continue;
+        }
+        if let Some(generations) = read_file(entry.path())? {
+            let rel = entry.path().strip_prefix(dir)?;
+            r.insert(rel.to_string_lossy().into_owned(), generations);
+        }
+    }
+    Ok(r)
+}
+
+/// The revocation policy applied by shim, if any.
+pub(crate) fn read_level() -> Result<Option<Generations>> {
+    efivars::read_vendor_var(SBAT_LEVEL_VAR, SHIM_LOCK_GUID)?
+        .map(|buf| parse(&buf))
+        .transpose()
+}
+
+/// Returns the entries of `generations` revoked by `level`.
+pub(crate) fn revoked(generations: &Generations, level: &Generations) -> Vec<String> {
+    generations
+        .iter()
+        .filter_map(|(name, generation)| {
+            let min = level.get(name)?;
+            (generation < min).then(|| format!("{name},{generation} (minimum {min})"))
+        })
+        .collect()
+}
+
+/// The highest generation of each component among `files`, e.g. the
+/// binaries of an ESP as returned by `scan`.
+pub(crate) fn highest(files: &BTreeMap<String, Generations>) -> Generations {
+    let mut r = Generations::new();
+    for (name, generation) in files.values().flatten() {
+        let highest = r.entry(name.clone()).or_insert(*generation);
+        *highest = (*highest).max(*generation);
+    }
+    r
+}
+
+/// Format generations like a `SbatLevel` entry list, e.g. `shim,4 grub,3`.
+pub(crate) fn format(generations: &Generations) -> String {
+    generations
+        .iter()
+        .map(|(name, generation)| format!("{name},{generation}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal PE image with a single section.
+    fn pe_image(name: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[..2].copy_from_slice(b"MZ");
+        data[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data.extend_from_slice(b"PE\0\0");
+        let mut coff = [0u8; 20];
+        coff[2..4].copy_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&coff);
+        let offset = data.len() + 40;
+        let mut section = [0u8; 40];
+        section[..name.len()].copy_from_slice(name);
+        section[8..12].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        section[16..20].copy_from_slice(&512u32.to_le_bytes());
+        section[20..24].copy_from_slice(&(offset as u32).to_le_bytes());
+        data.extend_from_slice(&section);
+        data.extend_from_slice(content);
+        data.resize(offset + 512, 0);
+        data
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let shim = b"sbat,1,SBAT Version,sbat,1,https://github.com/rhboot/shim/blob/main/SBAT.md\n\
+                     shim,4,UEFI shim,shim,1,https://github.com/rhboot/shim\n\
+                     shim.redhat,1,Red Hat,shim,15.8,mail:secalert@redhat.com\n";
+        let generations = parse(shim)?;
+        assert_eq!(format(&generations), "sbat,1 shim,4 shim.redhat,1");
+
+        let level = parse(b"sbat,1,2024010900\nshim,4\ngrub,3\ngrub.debian,4\n\0")?;
+        assert_eq!(level.get("grub"), Some(&3));
+        assert!(revoked(&generations, &level).is_empty());
+        let level = parse(b"sbat,1,2025010900\nshim,5\n")?;
+        assert_eq!(revoked(&generations, &level), ["shim,4 (minimum 5)"]);
+
+        assert!(parse(b"sbat\n").is_err());
+        assert!(parse(b"sbat,one\n").is_err());
+
+        let entries = parse_entries(shim)?;
+        assert_eq!(entries.len(), 2);
+        assert!(!entries[0].is_vendor());
+        assert_eq!(entries[1].component, "shim.redhat");
+        assert_eq!(entries[1].vendor, "Red Hat");
+        assert_eq!(package_version(&entries).as_deref(), Some("shim-15.8"));
+        let upstream = parse_entries(b"sbat,1,SBAT,sbat,1,url\ngrub,3,FSF,grub,2.12,url\n")?;
+        assert_eq!(package_version(&upstream).as_deref(), Some("grub-2.12"));
+        assert_eq!(package_version(&[]), None);
+        assert!(parse_entries(b"grub,3\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pe_section() -> Result<()> {
+        let image = pe_image(b".sbat", b"sbat,1\ngrub,3\n");
+        assert_eq!(
+            pe_section(&image, SBAT_SECTION),
+            Some(&b"sbat,1\ngrub,3\n"[..])
+        );
+        assert_eq!(pe_section(&image, b".text"), None);
+        assert_eq!(pe_section(b"grub.cfg", SBAT_SECTION), None);
+
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("fedora"))?;
+        std::fs::write(td.path().join("fedora/grubx64.efi"), &image)?;
+        std::fs::write(td.path().join("fedora/grub.cfg"), "set timeout=1")?;
+        std::fs::write(td.path().join("fedora/mmx64.efi"), pe_image(b".text", b""))?;
+        let r = scan(td.path())?;
+        assert_eq!(r.len(), 1);
+        assert_eq!(format(&r["fedora/grubx64.efi"]), "grub,3 sbat,1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_highest() -> Result<()> {
+        let files = BTreeMap::from([
+            (
+                "BOOT/BOOTX64.EFI".to_string(),
+                parse(
+                    b"sbat,1
+shim,3
+",
+                )?,
+            ),
+            (
+                "fedora/shimx64.efi".to_string(),
+                parse(
+                    b"sbat,1
+shim,4
+",
+                )?,
+            ),
+            (
+                "fedora/grubx64.efi".to_string(),
+                parse(
+                    b"sbat,1
+grub,3
+",
+                )?,
+            ),
+        ]);
+        let highest = highest(&files);
+        assert_eq!(format(&highest), "grub,3 sbat,1 shim,4");
+        // An update shipping an older shim lowers its generation
+        let update = parse(
+            b"sbat,1
+shim,3
+grub,4
+",
+        )?;
+        assert_eq!(revoked(&update, &highest), ["shim,3 (minimum 4)"]);
+        Ok(())
+    }
+}
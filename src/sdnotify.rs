@@ -0,0 +1,53 @@
+//! Progress of long operations for systemd, with sd_notify(3): when running
+//! in a unit, `systemctl status` shows what bootupd is doing, and each step
+//! extends the timeout of the unit, so that slow updates (e.g. to an SD
+//! card, or at shutdown) aren't killed.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use libsystemd::daemon::{self, NotifyState};
+
+use crate::progress::Progress;
+
+/// How much each notification extends the timeout of the unit, from now
+const EXTEND_TIMEOUT: Duration = Duration::from_secs(90);
+/// The minimum interval between notifications of the progress of a copy
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When the progress of a copy was last notified
+static LAST_PROGRESS: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Show `status` in `systemctl status` and extend the timeout of the unit,
+/// if running in one which accepts notifications.
+pub(crate) fn status(status: &str) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    let states = [
+        NotifyState::Status(status.to_string()),
+        NotifyState::Other(format!(
+            "EXTEND_TIMEOUT_USEC={}",
+            EXTEND_TIMEOUT.as_micros()
+        )),
+    ];
+    if let Err(e) = daemon::notify(false, &states) {
+        log::debug!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Like `status`, for the progress `p` of writing the update of
+/// `component`; as this is called for each file, notifications are rate
+/// limited, except for the last one.
+pub(crate) fn update_progress(component: &str, p: &Progress) {
+    {
+        // Unwrap safety: not poisoned, as nothing panics with it held
+        let mut last = LAST_PROGRESS.lock().unwrap();
+        let done = p.files_done >= p.files_total;
+        if !done && last.is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL) {
+            return;
+        }
+        *last = Some(Instant::now());
+    }
+    status(&format!("Writing {component} update: {p}"));
+}
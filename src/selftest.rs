@@ -0,0 +1,254 @@
+//! `bootupd selftest`: an end-to-end smoke test of the components of the
+//! update payload of the running system, for packagers and CI, without a
+//! virtual machine.
+//!
+//! A sparse disk image is partitioned with an ESP (and a BIOS boot
+//! partition on x86_64) and a `/boot` filesystem, and attached to a loop
+//! device.  In a private mount namespace, its `/boot` and ESP are mounted
+//! over those of the system, so that the components are installed,
+//! validated, updated and adopted on the image exactly as they would be on
+//! a booted system.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::blockdev::{self, LoopDevice};
+use crate::bootupd::{self, ComponentUpdateResult, ConfigMode};
+use crate::component::InstallComponentOptions;
+use crate::model::*;
+use crate::util::{self, CommandRunExt};
+
+/// The size of the disk image, sparse
+const IMAGE_SIZE: u64 = 1 << 30;
+/// The GPT partition type of Linux filesystems, for `/boot`
+const LINUX_FS_TYPE_GUID: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+/// The sfdisk script partitioning the image.
+fn partition_script() -> String {
+    let mut script = String::from("label: gpt\n");
+    if cfg!(target_arch = "x86_64") {
+        script.push_str(&format!(
+            "size=1MiB, type={}, name=\"BIOS-BOOT\"\n",
+            blockdev::BIOS_BOOT_TYPE_GUID
+        ));
+    }
+    script.push_str(&format!(
+        "size=127MiB, type={}, name=\"EFI-SYSTEM\"\n",
+        blockdev::ESP_TYPE_GUID
+    ));
+    script.push_str(&format!("type={LINUX_FS_TYPE_GUID}, name=\"boot\"\n"));
+    script
+}
+
+/// Create the partitioned disk image `path`.
+#[context("Creating disk image {path:?}")]
+fn create_image(path: &Path) -> Result<()> {
+    let f = std::fs::File::create(path)?;
+    f.set_len(IMAGE_SIZE)?;
+    drop(f);
+    let mut child = Command::new("sfdisk")
+        .args(["--quiet", "--no-reread"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("running sfdisk")?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(partition_script().as_bytes())?;
+    let st = child.wait()?;
+    if !st.success() {
+        bail!("sfdisk failed: {st}");
+    }
+    Ok(())
+}
+
+/// The partition of `device` of type `parttype`.
+fn find_partition(device: &str, parttype: &str) -> Result<String> {
+    blockdev::partitions_of(device)?
+        .into_iter()
+        .find(|p| p.parttype.eq_ignore_ascii_case(parttype))
+        .map(|p| p.node)
+        .ok_or_else(|| anyhow::anyhow!("No partition of type {parttype} on {device}"))
+}
+
+/// Format the partitions of `device` and mount them over `/boot` and
+/// `/boot/efi`, in the private mount namespace.
+#[context("Mounting the disk image")]
+fn mount_image(device: &str) -> Result<()> {
+    let esp = find_partition(device, blockdev::ESP_TYPE_GUID)?;
+    let boot = find_partition(device, LINUX_FS_TYPE_GUID)?;
+    Command::new("mkfs.fat")
+        .args(["-F", "32"])
+        .arg(&esp)
+        .run()?;
+    Command::new("mkfs.ext4").arg("-q").arg(&boot).run()?;
+    util::enter_private_mount_namespace()?;
+    Command::new("mount").arg(&boot).arg("/boot").run()?;
+    std::fs::create_dir("/boot/efi")?;
+    Command::new("mount").arg(&esp).arg("/boot/efi").run()?;
+    Ok(())
+}
+
+/// Fail with the validation errors of the installed components, if any.
+fn check_valid() -> Result<String> {
+    let report = bootupd::validate_all(false)?;
+    let mut errs = Vec::new();
+    for (name, c) in report.components.iter() {
+        errs.extend(c.errors.iter().map(|e| format!("{name}: {e}")));
+    }
+    if !errs.is_empty() {
+        bail!("{}", errs.join("; "));
+    }
+    let names: Vec<_> = report.components.keys().map(String::as_str).collect();
+    Ok(format!("valid: {}", names.join(", ")))
+}
+
+/// Install the components to the image, like to a disk image build.
+fn install(device: &str) -> Result<String> {
+    bootupd::install(
+        "/",
+        "/",
+        &[device.to_string()],
+        ConfigMode::Static,
+        &InstallComponentOptions::default(),
+        None,
+        false,
+    )?;
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    if state.installed.is_empty() {
+        bail!("No component was installed");
+    }
+    let names: Vec<_> = state.installed.keys().map(String::as_str).collect();
+    Ok(format!("installed: {}", names.join(", ")))
+}
+
+/// Mark the installed components as older than the payload, and update
+/// them.
+fn update() -> Result<String> {
+    let sysroot = openat::Dir::open("/")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    for inst in state.installed.values_mut() {
+        inst.meta = ContentMetadata {
+            timestamp: Default::default(),
+            version: "selftest".to_string(),
+            signing_keys: Vec::new(),
+        };
+    }
+    SavedState::acquire_write_lock(sysroot)?.update_state(&state)?;
+    let names: Vec<_> = state.installed.keys().cloned().collect();
+    let mut errs = Vec::new();
+    for (name, r) in bootupd::update_components(&names, false, &|_, _| {})? {
+        match r {
+            Ok(ComponentUpdateResult::Updated { .. }) => {}
+            Ok(ComponentUpdateResult::AtLatestVersion) => errs.push(format!("{name}: not updated")),
+            Err(e) => errs.push(format!("{name}: {e:#}")),
+        }
+    }
+    if !errs.is_empty() {
+        bail!("{}", errs.join("; "));
+    }
+    Ok(format!("updated: {}", names.join(", ")))
+}
+
+/// Forget the installed components, and adopt them.
+fn adopt() -> Result<String> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    for dir in [SavedState::STATEFILE_DIR, SavedState::STATEFILE_COPY_DIR] {
+        let statefile = Path::new("/").join(dir).join(SavedState::STATEFILE_NAME);
+        if statefile.exists() {
+            std::fs::remove_file(&statefile).with_context(|| format!("removing {statefile:?}"))?;
+        }
+    }
+    let status = bootupd::status()?;
+    let mut adopted = Vec::new();
+    for name in status.adoptable.keys() {
+        bootupd::adopt_and_update(name)?;
+        adopted.push(name.as_str());
+    }
+    if adopted.is_empty() {
+        bail!("No component is adoptable");
+    }
+    let missed: Vec<_> = state
+        .installed
+        .keys()
+        .filter(|n| !adopted.contains(&n.as_str()))
+        .map(String::as_str)
+        .collect();
+    if !missed.is_empty() {
+        // Some components, e.g. BIOS without a recorded device, can't be
+        // found again; not an error
+        println!("  not adoptable: {}", missed.join(", "));
+    }
+    Ok(format!("adopted: {}", adopted.join(", ")))
+}
+
+/// Run the steps; returns `true` if they all succeeded.
+fn run_steps(device: &str) -> Result<bool> {
+    mount_image(device)?;
+    let steps: [(&str, &dyn Fn() -> Result<String>); 6] = [
+        ("install", &|| install(device)),
+        ("validate", &check_valid),
+        ("update", &update),
+        ("validate", &check_valid),
+        ("adopt", &adopt),
+        ("validate", &check_valid),
+    ];
+    let mut ok = true;
+    for (name, step) in steps {
+        match step() {
+            Ok(msg) => println!("{name}: ok ({msg})"),
+            Err(e) => {
+                println!("{name}: FAILED: {e:#}");
+                ok = false;
+                break;
+            }
+        }
+    }
+    if let Err(e) = Command::new("umount").args(["-R", "/boot"]).run() {
+        log::warn!("{e:#}");
+    }
+    Ok(ok)
+}
+
+/// Run the self-test against a disk image in a temporary directory.
+pub(crate) fn run() -> Result<()> {
+    if !rustix::process::getuid().is_root() {
+        bail!("The self-test requires root privileges");
+    }
+    let td = tempfile::Builder::new()
+        .prefix("bootupd-selftest")
+        .tempdir_in("/var/tmp")?;
+    let image = td.path().join("disk.img");
+    create_image(&image)?;
+    let loopdev = LoopDevice::attach(&image)?;
+    let ok = run_steps(&loopdev.path)?;
+    drop(loopdev);
+    if !ok {
+        bail!("Self-test failed");
+    }
+    println!("Self-test passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition_script() {
+        let script = partition_script();
+        assert!(script.starts_with("label: gpt\n"));
+        assert!(script.contains(blockdev::ESP_TYPE_GUID));
+        assert_eq!(
+            script.contains(blockdev::BIOS_BOOT_TYPE_GUID),
+            cfg!(target_arch = "x86_64")
+        );
+        assert!(script.ends_with(&format!("type={LINUX_FS_TYPE_GUID}, name=\"boot\"\n")));
+    }
+}
@@ -0,0 +1,530 @@
+//! Support for the syslinux BIOS bootloader on legacy images and live media,
+//! installed by `extlinux` to `/boot/extlinux` (or adopted from
+//! `/boot/syslinux`).
+//!
+//! The update payload holds the MBR boot code of syslinux (`mbr.bin` for
+//! DOS partitioned disks, `gptmbr.bin` for GPT) and its COM32 modules.
+//! Like the BIOS component runs grub2-install, installing and updating run
+//! `extlinux --install`, which writes `ldlinux.sys` and `ldlinux.c32` and
+//! the boot sector of the filesystem of `/boot`; the modules used by
+//! `extlinux.conf` (e.g. `menu.c32`) are then refreshed from the payload, as
+//! they must match the version of `ldlinux.c32`.  The boot code is written
+//! to the first 440 bytes of each disk, leaving the partition table alone;
+//! it boots the partition of `/boot` marked bootable, which install takes
+//! care of.
+//!
+//! `extlinux.conf` belongs to the administrator: it is only written on
+//! install, from `/usr/lib/bootupd/syslinux/extlinux.conf` if the OS ships
+//! it and the target has none.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::bios::checksum_regions;
+use crate::blockdev;
+use crate::component::*;
+use crate::config::Config;
+use crate::filetree::FileTree;
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::sha512string::SHA512String;
+
+/// extlinux file path
+pub(crate) const EXTLINUX_BIN: &str = "usr/sbin/extlinux";
+/// The files of syslinux in the OS
+const SYSLINUX_DATA: &str = "usr/share/syslinux";
+/// The template of the config written on install, if the OS ships one
+const CONFIG_TEMPLATE: &str = "usr/lib/bootupd/syslinux/extlinux.conf";
+/// The config of syslinux, in its directory
+const CONFIG: &str = "extlinux.conf";
+/// The directories of syslinux in `/boot`, the first one being used on
+/// install
+const INSTALL_DIRS: &[&str] = &["extlinux", "syslinux"];
+/// The core of syslinux, written by `extlinux --install`
+const LDLINUX_SYS: &str = "ldlinux.sys";
+/// The COM32 library of syslinux, written by `extlinux --install`
+const LDLINUX_C32: &str = "ldlinux.c32";
+/// The MBR boot code for DOS partitioned disks
+const MBR_BIN: &str = "mbr.bin";
+/// The MBR boot code for GPT partitioned disks
+const GPTMBR_BIN: &str = "gptmbr.bin";
+/// Size of the MBR boot code area
+const MBR_BOOTCODE_SIZE: u64 = 440;
+/// Size of the boot sector written by `extlinux --install` at the start of
+/// the partition of `/boot`
+const VBR_SIZE: u64 = 512;
+
+/// Returns `true` if the target root ships extlinux.
+pub(crate) fn is_available(root: &Path) -> bool {
+    root.join(EXTLINUX_BIN).exists()
+}
+
+/// The boot code to write to a disk with `partitions`: `gptmbr.bin` boots
+/// the partition with the legacy BIOS bootable attribute on GPT, and
+/// `mbr.bin` the active one on DOS.
+fn boot_code_for(partitions: &[blockdev::Partition]) -> &'static str {
+    // GPT partition types are GUIDs, MBR ones hex bytes
+    if partitions.iter().any(|p| p.parttype.contains('-')) {
+        GPTMBR_BIN
+    } else {
+        MBR_BIN
+    }
+}
+
+/// The directory of `/boot` (e.g. `extlinux`) holding `ldlinux.sys`, if any.
+fn find_install_dir(boot: &Path) -> Option<&'static str> {
+    INSTALL_DIRS
+        .iter()
+        .copied()
+        .find(|d| boot.join(d).join(LDLINUX_SYS).exists())
+}
+
+/// The directory of `/boot` of the installed `tree`.
+fn installed_dir(tree: &FileTree) -> Result<&str> {
+    tree.children
+        .keys()
+        .find_map(|path| path.strip_suffix(LDLINUX_SYS)?.strip_suffix('/'))
+        .ok_or_else(|| anyhow::anyhow!("No {LDLINUX_SYS} in the installed files"))
+}
+
+/// Whether `name` is the name of a COM32 module.
+fn is_module(name: &str) -> bool {
+    name.ends_with(".c32")
+}
+
+/// Compute a checksum of the boot code of `device`.
+#[context("Computing checksum of boot code on {device}")]
+fn checksum_boot_code(device: &str) -> Result<SHA512String> {
+    let mut f = std::fs::File::open(device).with_context(|| format!("opening {device}"))?;
+    checksum_regions(&mut f, &[(0, MBR_BOOTCODE_SIZE)])
+}
+
+/// Compute a checksum of the boot sector at a location recorded by
+/// `vbr_location`.
+#[context("Computing checksum of boot sector at {location}")]
+fn checksum_vbr(location: &str) -> Result<SHA512String> {
+    let (device, offset) = location
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow::anyhow!("Invalid location {location}"))?;
+    let offset: u64 = offset.parse()?;
+    let mut f = std::fs::File::open(device).with_context(|| format!("opening {device}"))?;
+    checksum_regions(&mut f, &[(offset, VBR_SIZE)])
+}
+
+/// The location of the boot sector of `partition`, as its disk and offset,
+/// e.g. `/dev/sda@1048576`.
+fn vbr_location(partition: &str) -> Result<String> {
+    let (disk, _) = blockdev::partition_location(partition)?;
+    let start = blockdev::partitions_of(&disk)?
+        .into_iter()
+        .find(|p| p.node == partition)
+        .map(|p| p.start)
+        .ok_or_else(|| anyhow::anyhow!("Failed to find {partition} on {disk}"))?;
+    // The start of partitions is in 512 byte sectors
+    Ok(format!("{disk}@{}", start * 512))
+}
+
+/// The partitions holding the filesystem of `boot`, e.g. the members of a
+/// RAID1 array.
+#[context("Finding partitions of {}", boot.display())]
+fn boot_partitions(boot: &Path) -> Result<Vec<String>> {
+    let fsinfo = crate::filesystem::inspect_filesystem(&openat::Dir::open(boot)?, ".")?;
+    let partitions = blockdev::backing_partitions(&fsinfo.source)?;
+    if partitions.is_empty() {
+        bail!("{} is not on a partition", fsinfo.source);
+    }
+    Ok(partitions)
+}
+
+#[derive(Default)]
+pub(crate) struct Syslinux {}
+
+impl Syslinux {
+    /// Run `extlinux --install` on `dir`, then copy the modules of the
+    /// payload it has, or all of them if `all_modules`; returns the files
+    /// written.
+    #[context("Installing syslinux to {}", dir.display())]
+    fn install_dir(
+        &self,
+        updated: &openat::Dir,
+        dir: &Path,
+        all_modules: bool,
+    ) -> Result<Vec<String>> {
+        let extlinux = Path::new("/").join(EXTLINUX_BIN);
+        if !extlinux.exists() {
+            bail!("Failed to find {:?}", extlinux);
+        }
+        std::fs::create_dir_all(dir)?;
+        crate::sdnotify::status(&format!("Running extlinux on {}", dir.display()));
+        let mut cmd = Command::new(extlinux);
+        cmd.arg("--install").arg(dir);
+        let cmdout = cmd.output()?;
+        if !cmdout.status.success() {
+            std::io::stderr().write_all(&cmdout.stderr)?;
+            bail!("Failed to run {:?}", cmd);
+        }
+        let mut r = vec![LDLINUX_SYS.to_string(), LDLINUX_C32.to_string()];
+        for entry in updated.list_dir(".")? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            // Written by extlinux, which comes with the same modules
+            if !is_module(name) || name == LDLINUX_C32 {
+                continue;
+            }
+            if !all_modules && !dir.join(name).exists() {
+                continue;
+            }
+            let mut src = updated.open_file(name)?;
+            let mut dest = std::fs::File::create(dir.join(name))?;
+            std::io::copy(&mut src, &mut dest)?;
+            dest.sync_all()?;
+            r.push(name.to_string());
+        }
+        Ok(r)
+    }
+
+    /// Write the boot code of the payload to each of the `devices`,
+    /// returning their checksums.
+    fn write_boot_code(
+        &self,
+        updated: &openat::Dir,
+        devices: &[String],
+    ) -> Result<BTreeMap<String, SHA512String>> {
+        let mut r = BTreeMap::new();
+        for device in devices {
+            let name = boot_code_for(&blockdev::partitions_of(device)?);
+            let mut code = Vec::new();
+            updated.open_file(name)?.read_to_end(&mut code)?;
+            if code.len() as u64 > MBR_BOOTCODE_SIZE {
+                bail!("{name} is larger than {MBR_BOOTCODE_SIZE} bytes");
+            }
+            let mut f = OpenOptions::new()
+                .write(true)
+                .open(device)
+                .with_context(|| format!("opening {device}"))?;
+            f.write_all(&code)?;
+            f.sync_all()?;
+            drop(f);
+            println!("Wrote {name} to {device}");
+            r.insert(device.clone(), checksum_boot_code(device)?);
+        }
+        Ok(r)
+    }
+
+    /// Install syslinux to the directory `dirname` of `boot`, and its boot
+    /// code to `devices`.
+    fn install_all(
+        &self,
+        updated: &openat::Dir,
+        meta: ContentMetadata,
+        boot: &Path,
+        dirname: &str,
+        devices: &[String],
+        all_modules: bool,
+    ) -> Result<InstalledContent> {
+        let dir = boot.join(dirname);
+        let written = self.install_dir(updated, &dir, all_modules)?;
+        let mut raw_checksums = self.write_boot_code(updated, devices)?;
+        for partition in boot_partitions(boot)? {
+            let location = vbr_location(&partition)?;
+            raw_checksums.insert(location.clone(), checksum_vbr(&location)?);
+        }
+        let digest = Config::load(Path::new("/"))?.update.digest;
+        let mut tree = FileTree::new_from_dir(&openat::Dir::open(&dir)?, digest)?;
+        tree.children.retain(|name, _| written.contains(name));
+        let children = tree
+            .children
+            .into_iter()
+            .map(|(name, meta)| (format!("{dirname}/{name}"), meta))
+            .collect();
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(FileTree { children }),
+            adopted_from: None,
+            raw_checksums: Some(raw_checksums),
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+}
+
+impl Component for Syslinux {
+    fn name(&self) -> &'static str {
+        "syslinux"
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+        let updated = src_root
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let boot = Path::new(dest_root).join("boot");
+        let dirname = INSTALL_DIRS[0];
+        for partition in boot_partitions(&boot)? {
+            blockdev::set_legacy_bootable(&partition)?;
+        }
+        if let Some(mut template) = updated.open_file_optional(CONFIG)? {
+            let config = boot.join(dirname).join(CONFIG);
+            if !config.exists() {
+                std::fs::create_dir_all(boot.join(dirname))?;
+                std::io::copy(&mut template, &mut std::fs::File::create(&config)?)?;
+            }
+        }
+        self.install_all(&updated, meta, &boot, dirname, devices, true)
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let sysroot = Path::new(sysroot_path);
+        let extlinux = sysroot.join(EXTLINUX_BIN);
+        if !extlinux.exists() {
+            bail!("Failed to find {:?}", extlinux);
+        }
+        let data = sysroot.join(SYSLINUX_DATA);
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest).with_context(|| format!("creating {dest:?}"))?;
+        for entry in std::fs::read_dir(&data).with_context(|| format!("reading {data:?}"))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if !(is_module(name) || name == MBR_BIN || name == GPTMBR_BIN) {
+                continue;
+            }
+            std::fs::copy(&path, dest.join(name)).with_context(|| format!("copying {path:?}"))?;
+        }
+        for name in [MBR_BIN, GPTMBR_BIN] {
+            if !dest.join(name).exists() {
+                bail!("Failed to find {SYSLINUX_DATA}/{name}");
+            }
+        }
+        let template = sysroot.join(CONFIG_TEMPLATE);
+        if template.exists() {
+            std::fs::copy(&template, dest.join(CONFIG))?;
+        }
+
+        // The version of extlinux, which writes ldlinux.sys
+        let meta = packagesystem::query_payload(sysroot_path, [&extlinux], &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if find_install_dir(Path::new("/boot")).is_none() {
+            log::debug!("No {LDLINUX_SYS} found, skip adopt");
+            return Ok(None);
+        }
+        crate::component::query_adopt_state()
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+        let boot = Path::new("/boot");
+        // SAFETY: query_adopt checked it
+        let dirname = find_install_dir(boot).unwrap();
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let devices = blockdev::get_bootloader_devices("/")?;
+        let mut inst =
+            self.install_all(&updated, update.clone(), boot, dirname, &devices, false)?;
+        inst.adopted_from = Some(meta.version);
+        Ok(inst)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        _: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed syslinux found!"))?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        let dest_root = std::fs::read_link(dest_fd)?;
+        let devices = blockdev::get_bootloader_devices(&dest_root)?;
+        let boot = dest_root.join("boot");
+        self.install_all(
+            &updated,
+            updatemeta,
+            &boot,
+            installed_dir(currentf)?,
+            &devices,
+            false,
+        )
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed syslinux found!"))?;
+        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        let dest_root = std::fs::read_link(dest_fd)?;
+        let devices = blockdev::get_bootloader_devices(&dest_root)?;
+        let mut r = vec![format!(
+            "Run: /{EXTLINUX_BIN} --install /boot/{}",
+            installed_dir(currentf)?
+        )];
+        for device in devices {
+            let name = boot_code_for(&blockdev::partitions_of(&device)?);
+            r.push(format!("Write: {name} to {device}"));
+        }
+        Ok(r)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let (Some(currentf), Some(expected)) =
+            (current.filetree.as_ref(), current.raw_checksums.as_ref())
+        else {
+            return Ok(ValidationResult::Skip);
+        };
+        let boot = openat::Dir::open("/boot")?;
+        let diff = currentf.relative_diff_to(&boot)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
+        }
+        for (location, expected) in expected.iter() {
+            let device = location
+                .split_once('@')
+                .map_or(location.as_str(), |(d, _)| d);
+            let found = if !Path::new(device).exists() {
+                errs.push(ValidationError::new(ValidationErrorKind::Missing, location));
+                continue;
+            } else if location.contains('@') {
+                checksum_vbr(location)?
+            } else {
+                checksum_boot_code(device)?
+            };
+            if &found != expected {
+                errs.push(ValidationError::new(
+                    ValidationErrorKind::Modified,
+                    location,
+                ));
+            }
+        }
+        // The boot code of the MBR boots the partition marked bootable
+        for partition in boot_partitions(Path::new("/boot"))? {
+            if !blockdev::is_legacy_bootable(&partition)? {
+                errs.push(ValidationError::new(
+                    ValidationErrorKind::Modified,
+                    partition,
+                ));
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::DigestAlgorithm;
+    use crate::filetree::FileMetadata;
+
+    #[test]
+    fn test_boot_code_for() {
+        let partition = |parttype: &str| blockdev::Partition {
+            node: "/dev/sda1".into(),
+            start: 2048,
+            size: 2048,
+            parttype: parttype.into(),
+        };
+        assert_eq!(boot_code_for(&[partition("83")]), MBR_BIN);
+        assert_eq!(
+            boot_code_for(&[partition(blockdev::BIOS_BOOT_TYPE_GUID)]),
+            GPTMBR_BIN
+        );
+        assert_eq!(boot_code_for(&[]), MBR_BIN);
+    }
+
+    #[test]
+    fn test_install_dirs() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boot = td.path();
+        assert_eq!(find_install_dir(boot), None);
+        std::fs::create_dir_all(boot.join("syslinux"))?;
+        std::fs::write(boot.join("syslinux").join(LDLINUX_SYS), "ldlinux")?;
+        assert_eq!(find_install_dir(boot), Some("syslinux"));
+
+        let meta = FileMetadata::new_from_path(
+            &openat::Dir::open(boot)?,
+            "syslinux/ldlinux.sys",
+            DigestAlgorithm::default(),
+        )?;
+        let tree = FileTree {
+            children: BTreeMap::from([
+                ("syslinux/menu.c32".to_string(), meta.clone()),
+                ("syslinux/ldlinux.sys".to_string(), meta),
+            ]),
+        };
+        assert_eq!(installed_dir(&tree)?, "syslinux");
+        assert!(installed_dir(&FileTree {
+            children: BTreeMap::new()
+        })
+        .is_err());
+        Ok(())
+    }
+}
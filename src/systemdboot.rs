@@ -0,0 +1,344 @@
+//! Support for systemd-boot, managed as a set of plain files in the ESP
+//! (the same layout `bootctl install` creates).
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::config::Config;
+use crate::efi::{self, Efi};
+use crate::filetree;
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+
+/// The directory where systemd ships its EFI binaries
+pub(crate) const SYSTEMD_BOOT_SRCDIR: &str = "usr/lib/systemd/boot/efi";
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) const SYSTEMD_BOOT_EFI: &str = "systemd-bootx64.efi";
+#[cfg(target_arch = "aarch64")]
+pub(crate) const SYSTEMD_BOOT_EFI: &str = "systemd-bootaa64.efi";
+#[cfg(target_arch = "riscv64")]
+pub(crate) const SYSTEMD_BOOT_EFI: &str = "systemd-bootriscv64.efi";
+
+/// The directory under `EFI/` owned by systemd-boot
+const SYSTEMD_VENDOR_DIR: &str = "systemd";
+
+/// The configuration of systemd-boot, relative to the root of the ESP
+const LOADER_CONF: &str = "loader/loader.conf";
+
+/// Prefix of the identification string systemd-boot embeds in its binary,
+/// e.g. `#### LoaderInfo: systemd-boot 255.4-1.fc40 ####`
+const LOADER_INFO_PREFIX: &[u8] = b"#### LoaderInfo: systemd-boot ";
+
+/// Returns the version of a systemd-boot binary, e.g. `255.4-1.fc40`.
+fn loader_version(data: &[u8]) -> Option<String> {
+    let start = data
+        .windows(LOADER_INFO_PREFIX.len())
+        .position(|w| w == LOADER_INFO_PREFIX)?
+        + LOADER_INFO_PREFIX.len();
+    let len = data[start..].windows(5).position(|w| w == b" ####")?;
+    let version = std::str::from_utf8(&data[start..start + len]).ok()?.trim();
+    (!version.is_empty()).then(|| version.to_string())
+}
+
+/// Returns `true` if the target root ships a systemd-boot binary.
+pub(crate) fn is_available(root: &Path) -> bool {
+    root.join(SYSTEMD_BOOT_SRCDIR)
+        .join(SYSTEMD_BOOT_EFI)
+        .exists()
+}
+
+#[derive(Default)]
+pub(crate) struct SystemdBoot {
+    esp: Efi,
+}
+
+impl SystemdBoot {
+    /// Path of the systemd-boot binary relative to the `EFI` directory
+    fn installed_path() -> String {
+        format!("{SYSTEMD_VENDOR_DIR}/{SYSTEMD_BOOT_EFI}")
+    }
+
+    /// Copy every file in `src` to the (empty) target `EFI` directory.
+    #[context("Copying systemd-boot to ESP")]
+    fn copy_to_esp(
+        src: &openat::Dir,
+        dest: &openat::Dir,
+        config: &Config,
+    ) -> Result<filetree::FileTree> {
+        let ft = filetree::FileTree::new_from_dir(src, config.update.digest)?;
+        efi::ProtectedPaths::new(&config.efi, &[&ft]).check_paths(ft.children.keys())?;
+        let empty = filetree::FileTree {
+            children: BTreeMap::new(),
+        };
+        let diff = empty.diff(&ft)?;
+        filetree::apply_diff(src, dest, &diff, None)?;
+        Ok(ft)
+    }
+}
+
+impl Component for SystemdBoot {
+    fn name(&self) -> &'static str {
+        "systemd-boot"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        let Some(esp) = self.esp.open_esp_optional()? else {
+            log::trace!("No ESP detected");
+            return Ok(None);
+        };
+        let installed = Self::installed_path();
+        if !esp.exists(installed.as_str())? {
+            log::trace!("No systemd-boot found in ESP");
+            return Ok(None);
+        }
+        let efidir = esp.recover_path()?;
+        let binary = efidir.join(&installed);
+        // A layout set up by `bootctl install` also has a loader.conf, which
+        // isn't part of the payload and is left alone
+        let configured = efidir
+            .parent()
+            .is_some_and(|root| root.join(LOADER_CONF).exists());
+        let mut adoptable = match crate::component::query_adopt_state()? {
+            Some(adoptable) => adoptable,
+            // Not an ostree system
+            None => Adoptable {
+                version: ContentMetadata {
+                    timestamp: binary.metadata()?.modified()?.into(),
+                    version: UNKNOWN_VERSION.to_string(),
+                    signing_keys: Vec::new(),
+                },
+                confident: configured,
+            },
+        };
+        if adoptable.version.version == UNKNOWN_VERSION {
+            let data = std::fs::read(&binary).with_context(|| format!("reading {binary:?}"))?;
+            if let Some(version) = loader_version(&data) {
+                adoptable.version.version = version;
+            }
+        }
+        Ok(Some(adoptable))
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        updatemeta: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+
+        let esp = self.esp.open_esp()?;
+        efi::validate_esp(&esp)?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+        let updatef =
+            filetree::FileTree::new_from_dir(&updated, digest).context("reading update dir")?;
+        // As for EFI, only touch files that we know about.
+        let diff = updatef.relative_diff_to(&esp)?;
+        efi::ProtectedPaths::load(&[&updatef])?.check(&updatef, &diff)?;
+        log::trace!("applying adoption diff: {}", &diff);
+        filetree::apply_diff(&updated, &esp, &diff, None).context("applying filesystem changes")?;
+        Ok(InstalledContent {
+            meta: updatemeta.clone(),
+            filetree: Some(updatef),
+            adopted_from: Some(meta.version),
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+        log::debug!("Found metadata {}", meta.version);
+        let srcdir = src_root.sub_dir(&component_updatedirname(self))?;
+        let destdir = &self.esp.ensure_mounted_esp(Path::new(dest_root))?;
+        let destd = &openat::Dir::open(destdir)
+            .with_context(|| format!("opening dest dir {}", destdir.display()))?;
+        efi::validate_esp(destd)?;
+        destd.ensure_dir_all("EFI", 0o755)?;
+        let efidir = destd.sub_dir("EFI")?;
+        let config = Config::load(&src_root.recover_path()?)?;
+        let ft = Self::copy_to_esp(&srcdir, &efidir, &config)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path)
+            .join(SYSTEMD_BOOT_SRCDIR)
+            .join(SYSTEMD_BOOT_EFI);
+        if !src.exists() {
+            bail!("Failed to find {:?}", src);
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        // Also populate the removable media path, as `bootctl install` does
+        for target in [Self::installed_path().as_str(), efi::FALLBACK_EFI] {
+            let target = dest.join(target);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent).with_context(|| format!("creating {parent:?}"))?;
+            }
+            std::fs::copy(&src, &target).with_context(|| format!("copying {src:?}"))?;
+        }
+
+        let meta = packagesystem::query_payload(sysroot_path, [&src], &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        progress: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed systemd-boot found!"))?;
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let digest = Config::load(Path::new("/"))?.update.digest;
+        let updatef =
+            filetree::FileTree::new_from_dir(&updated, digest).context("reading update dir")?;
+        let diff = currentf.diff(&updatef)?;
+        efi::ProtectedPaths::load(&[currentf, &updatef])?.check(&updatef, &diff)?;
+        let destdir = self.esp.open_esp().context("opening EFI dir")?;
+        efi::validate_esp(&destdir)?;
+        log::trace!("applying diff: {}", &diff);
+        let opts = filetree::ApplyUpdateOptions {
+            progress: Some(progress),
+            ..Default::default()
+        };
+        filetree::apply_diff(&updated, &destdir, &diff, Some(&opts))
+            .context("applying filesystem changes")?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(updatef),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        plan_filetree_update(sysroot, self, current)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let Some(efidir) = self.esp.open_esp_optional()? else {
+            return Ok(ValidationResult::Skip);
+        };
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed systemd-boot found!"))?;
+        let diff = currentf.relative_diff_to(&efidir)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn repair(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<Repaired> {
+        let destdir = self.esp.open_esp().context("opening EFI dir")?;
+        efi::validate_esp(&destdir)?;
+        Ok(Repaired {
+            inst: current.clone(),
+            repaired: repair_filetree(sysroot, self, current, &destdir)?,
+        })
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        // The static GRUB configs don't apply to systemd-boot
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loader_version() {
+        let data = b"\0\0#### LoaderInfo: systemd-boot 255.4-1.fc40 ####\0";
+        assert_eq!(loader_version(data).as_deref(), Some("255.4-1.fc40"));
+        assert_eq!(
+            loader_version(b"#### LoaderInfo: systemd-stub 255 ####"),
+            None
+        );
+        assert_eq!(loader_version(b"#### LoaderInfo: systemd-boot  ####"), None);
+        assert_eq!(loader_version(b"GRUB"), None);
+    }
+}
@@ -0,0 +1,278 @@
+//! Test doubles, with the `testing` feature, for tests of the install and
+//! update flows on machines without root, a package database or UEFI.
+//!
+//! The `mock` component ships the files of `usr/lib/bootupd-mock` in the
+//! sysroot, and installs them to `boot/mock`, tracked like the other
+//! components in a filetree.  The `mock` package system, selected with
+//! `BOOTUPD_PACKAGE_SYSTEM=mock`, answers from an in-memory database filled
+//! with [`add_mock_package`].
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::prelude::*;
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::config::Config;
+use crate::filetree::{self, FileTree};
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::util::CommandRunExt;
+
+/// The payload of the `mock` component, relative to the sysroot
+const MOCK_SOURCE_DIR: &str = "usr/lib/bootupd-mock";
+/// Where the `mock` component is installed, relative to the sysroot
+const MOCK_TARGET_DIR: &str = "boot/mock";
+
+/// Record that the package `nevra`, built at `buildtime`, owns `paths`
+/// (files or directories, relative to the sysroot) for the `mock` package
+/// system.
+pub fn add_mock_package<T>(
+    nevra: &str,
+    buildtime: DateTime<Utc>,
+    paths: impl IntoIterator<Item = T>,
+) where
+    T: AsRef<Path>,
+{
+    packagesystem::add_mock_package(nevra, buildtime, paths)
+}
+
+/// Forget the packages of the `mock` package system.
+pub fn clear_mock_packages() {
+    packagesystem::clear_mock_packages()
+}
+
+pub(crate) struct MockComponent {
+    /// The root of the installed system, for `validate`
+    root: PathBuf,
+}
+
+impl Default for MockComponent {
+    fn default() -> Self {
+        Self { root: "/".into() }
+    }
+}
+
+impl MockComponent {
+    /// Returns `true` if `root` ships the payload or has an update of the
+    /// `mock` component.
+    pub(crate) fn is_available(root: &Path) -> bool {
+        root.join(MOCK_SOURCE_DIR).exists()
+            || root
+                .join(component_updatedirname(&Self::default()))
+                .exists()
+    }
+
+    fn open_target(&self, root: &Path) -> Result<openat::Dir> {
+        let path = root.join(MOCK_TARGET_DIR);
+        std::fs::create_dir_all(&path).with_context(|| format!("creating {path:?}"))?;
+        Ok(openat::Dir::open(&path)?)
+    }
+}
+
+impl Component for MockComponent {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        Ok(None)
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, _: &ContentMetadata) -> Result<InstalledContent> {
+        bail!("Adopting {} is not supported", self.name())
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _: &[String],
+        _: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            bail!("No update metadata for component {} found", self.name());
+        };
+        let srcdir = src_root.sub_dir(&component_updatedirname(self))?;
+        let digest = Config::load(&src_root.recover_path()?)?.update.digest;
+        let ft = FileTree::new_from_dir(&srcdir, digest)?;
+        let destdir = self.open_target(Path::new(dest_root))?;
+        let empty = FileTree {
+            children: Default::default(),
+        };
+        let opts = filetree::ApplyUpdateOptions {
+            skip_sync: true,
+            ..Default::default()
+        };
+        filetree::apply_diff(&srcdir, &destdir, &empty.diff(&ft)?, Some(&opts))?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path).join(MOCK_SOURCE_DIR);
+        if !src.exists() {
+            bail!("Failed to find {src:?}");
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest).with_context(|| format!("removing {dest:?}"))?;
+        }
+        std::fs::create_dir_all(dest.parent().unwrap())?;
+        std::process::Command::new("cp")
+            .arg("-rp")
+            .arg(&src)
+            .arg(&dest)
+            .run()?;
+        let meta = packagesystem::query_payload(sysroot_path, [&src], &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        progress: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let Some(updatemeta) = self.query_update(sysroot)? else {
+            bail!("No update available for {}", self.name());
+        };
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed mock found!"))?;
+        let srcdir = sysroot.sub_dir(&component_updatedirname(self))?;
+        let digest = Config::load(&sysroot.recover_path()?)?.update.digest;
+        let updatef = FileTree::new_from_dir(&srcdir, digest)?;
+        let diff = currentf.diff(&updatef)?;
+        let destdir = self.open_target(&sysroot.recover_path()?)?;
+        let opts = filetree::ApplyUpdateOptions {
+            skip_sync: true,
+            progress: Some(progress),
+            ..Default::default()
+        };
+        filetree::apply_diff(&srcdir, &destdir, &diff, Some(&opts))?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(updatef),
+            ..current.clone()
+        })
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        plan_filetree_update(sysroot, self, current)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed mock found!"))?;
+        let root = openat::Dir::open(&self.root)?;
+        let Some(destdir) = root.sub_dir_optional(MOCK_TARGET_DIR)? else {
+            return Ok(ValidationResult::Skip);
+        };
+        let diff = currentf.relative_diff_to(&destdir)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
+        }
+        if errs.is_empty() {
+            Ok(ValidationResult::Valid)
+        } else {
+            Ok(ValidationResult::Errors(errs))
+        }
+    }
+
+    fn repair(&self, sysroot: &openat::Dir, current: &InstalledContent) -> Result<Repaired> {
+        let destdir = self.open_target(&sysroot.recover_path()?)?;
+        let repaired = repair_filetree(sysroot, self, current, &destdir)?;
+        Ok(Repaired {
+            inst: current.clone(),
+            repaired,
+        })
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_component() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        let rootstr = root.to_str().unwrap();
+        let src = root.join(MOCK_SOURCE_DIR);
+        std::fs::create_dir_all(src.join("sub"))?;
+        std::fs::create_dir_all(root.join(BOOTUPD_UPDATES_DIR))?;
+        std::fs::write(src.join("a.conf"), "v1")?;
+        std::fs::write(src.join("sub/b.conf"), "v1")?;
+        let c = MockComponent {
+            root: root.to_path_buf(),
+        };
+        assert!(MockComponent::is_available(root));
+        let v1 = c.generate_update_metadata(rootstr, &GenerateOptions::default())?;
+        let sysroot = openat::Dir::open(root)?;
+        let inst = c.install(&sysroot, rootstr, &[], &Default::default())?;
+        assert_eq!(inst.meta.version, v1.version);
+        let target = root.join(MOCK_TARGET_DIR);
+        assert_eq!(std::fs::read_to_string(target.join("sub/b.conf"))?, "v1");
+        assert!(matches!(c.validate(&inst)?, ValidationResult::Valid));
+
+        std::fs::write(src.join("a.conf"), "v2")?;
+        std::fs::remove_file(src.join("sub/b.conf"))?;
+        let v2 = c.generate_update_metadata(rootstr, &GenerateOptions::default())?;
+        assert_ne!(v1.version, v2.version);
+        assert_eq!(
+            c.plan_update(&sysroot, &inst)?,
+            ["Write: a.conf", "Remove: sub/b.conf"]
+        );
+        let inst = c.run_update(&sysroot, &inst, &|_| {})?;
+        assert_eq!(inst.meta.version, v2.version);
+        assert_eq!(std::fs::read_to_string(target.join("a.conf"))?, "v2");
+        assert!(!target.join("sub/b.conf").exists());
+
+        std::fs::write(target.join("a.conf"), "tampered")?;
+        assert!(matches!(c.validate(&inst)?, ValidationResult::Errors(_)));
+        assert_eq!(c.repair(&sysroot, &inst)?.repaired, ["a.conf"]);
+        assert!(matches!(c.validate(&inst)?, ValidationResult::Valid));
+        Ok(())
+    }
+}
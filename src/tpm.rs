@@ -0,0 +1,271 @@
+//! Prediction of the TPM PCR 4 value after a bootloader update.
+//!
+//! On EFI systems, the firmware measures the Authenticode digest of every
+//! application it loads into PCR 4, after a fixed action event and the
+//! separator ending the pre-boot measurements; shim then measures GRUB the
+//! same way.  Secrets sealed against PCR 4 (e.g. by Clevis or
+//! systemd-cryptenroll) must be resealed against the new value when these
+//! binaries change.  Only the SHA-256 bank is predicted.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::model::{MeasuredBinary, Pcr4Prediction};
+use crate::sbat::{le_u16, le_u32};
+
+/// The `EV_EFI_ACTION` event measured before the boot option is started
+const BOOT_OPTION_ACTION: &[u8] = b"Calling EFI Application from Boot Option";
+/// The `EV_SEPARATOR` event data
+const SEPARATOR: &[u8] = &[0; 4];
+/// The index of the certificate table in the data directories
+const CERTIFICATE_TABLE: u32 = 4;
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    openssl::sha::sha256(data).to_vec()
+}
+
+/// Returns `data[start..end]`, or an error if this is out of bounds.
+fn range(data: &[u8], start: usize, end: usize) -> Result<&[u8]> {
+    data.get(start..end)
+        .ok_or_else(|| anyhow::anyhow!("Truncated PE image at {start:#x}..{end:#x}"))
+}
+
+/// Compute the SHA-256 Authenticode digest of a PE image, i.e. the digest
+/// of its headers and sections without the checksum and signatures, which
+/// is what the firmware and shim measure.
+pub(crate) fn authenticode_digest(data: &[u8]) -> Result<Vec<u8>> {
+    let field = |off| le_u32(data, off).context("Truncated PE image");
+    if !data.starts_with(b"MZ") {
+        bail!("Not a PE image");
+    }
+    let pe = field(0x3c)? as usize;
+    if range(data, pe, pe + 4)? != b"PE\0\0" {
+        bail!("Not a PE image");
+    }
+    let coff = pe + 4;
+    let nsections = le_u16(data, coff + 2).context("Truncated PE image")? as usize;
+    let optional_header_size = le_u16(data, coff + 16).context("Truncated PE image")? as usize;
+    let opt = coff + 20;
+    let directories = match le_u16(data, opt) {
+        Some(0x10b) => opt + 96,
+        Some(0x20b) => opt + 112,
+        _ => bail!("Unknown PE optional header"),
+    };
+    let checksum = opt + 64;
+    let headers_size = field(opt + 60)? as usize;
+    let mut hasher = Hasher::new(MessageDigest::sha256())?;
+    hasher.update(range(data, 0, checksum)?)?;
+    let mut certs_size = 0;
+    if field(directories - 4)? > CERTIFICATE_TABLE {
+        let certs = directories + CERTIFICATE_TABLE as usize * 8;
+        certs_size = field(certs + 4)? as usize;
+        hasher.update(range(data, checksum + 4, certs)?)?;
+        hasher.update(range(data, certs + 8, headers_size)?)?;
+    } else {
+        hasher.update(range(data, checksum + 4, headers_size)?)?;
+    }
+    let table = opt + optional_header_size;
+    let mut sections = Vec::new();
+    for i in 0..nsections {
+        let section = range(data, table + i * 40, table + (i + 1) * 40)?;
+        let size = le_u32(section, 16).unwrap() as usize;
+        let offset = le_u32(section, 20).unwrap() as usize;
+        if size > 0 {
+            sections.push((offset, size));
+        }
+    }
+    sections.sort();
+    let mut hashed = headers_size;
+    for (offset, size) in sections {
+        hasher.update(range(data, offset, offset + size)?)?;
+        hashed += size;
+    }
+    // Data appended after the sections, besides the signatures
+    let end = data.len().saturating_sub(certs_size);
+    if end > hashed {
+        hasher.update(range(data, hashed, end)?)?;
+    }
+    Ok(hasher.finish()?.to_vec())
+}
+
+/// The SHA-256 Authenticode digests of the EFI binaries among `paths`,
+/// relative to `dir`, hex encoded; `.efi` files which aren't PE images are
+/// left out.
+#[context("Computing Authenticode digests")]
+pub(crate) fn authenticode_digests<'a>(
+    dir: &Path,
+    paths: impl IntoIterator<Item = &'a String>,
+) -> Result<BTreeMap<String, String>> {
+    let mut r = BTreeMap::new();
+    for path in paths {
+        let is_efi = Path::new(path)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("efi"));
+        if !is_efi {
+            continue;
+        }
+        let full = dir.join(path);
+        let data = std::fs::read(&full).with_context(|| format!("reading {full:?}"))?;
+        match authenticode_digest(&data) {
+            Ok(digest) => {
+                r.insert(path.clone(), hex::encode(digest));
+            }
+            Err(e) => log::debug!("Not recording the digest of {path}: {e:#}"),
+        }
+    }
+    Ok(r)
+}
+
+/// Returns the value of a PCR extended with the `digests` of events, from
+/// its initial zero value.
+fn extend<'a>(digests: impl IntoIterator<Item = &'a [u8]>) -> Vec<u8> {
+    digests.into_iter().fold(vec![0; 32], |pcr, digest| {
+        sha256(&[pcr.as_slice(), digest].concat())
+    })
+}
+
+/// Predict the value of PCR 4 once the firmware loaded `chain`, paths of
+/// binaries relative to `efidir` in boot order.  Anything loaded later, such
+/// as the kernel, is measured on top of it.
+#[context("Predicting PCR 4")]
+pub(crate) fn predict_pcr4(efidir: &Path, chain: &[String]) -> Result<Pcr4Prediction> {
+    let mut digests = vec![sha256(BOOT_OPTION_ACTION), sha256(SEPARATOR)];
+    let mut binaries = Vec::new();
+    for path in chain {
+        let full = efidir.join(path);
+        let data = std::fs::read(&full).with_context(|| format!("reading {full:?}"))?;
+        let digest = authenticode_digest(&data).with_context(|| format!("hashing {path}"))?;
+        binaries.push(MeasuredBinary {
+            path: path.clone(),
+            sha256: hex::encode(&digest),
+        });
+        digests.push(digest);
+    }
+    Ok(Pcr4Prediction {
+        binaries,
+        sha256: hex::encode(extend(digests.iter().map(Vec::as_slice))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal PE32+ image with a single section, signed with `cert`.
+    fn pe_image(content: &[u8], cert: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[..2].copy_from_slice(b"MZ");
+        data[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        data.extend_from_slice(b"PE\0\0");
+        let mut coff = [0u8; 20];
+        coff[2..4].copy_from_slice(&1u16.to_le_bytes());
+        coff[16..18].copy_from_slice(&240u16.to_le_bytes());
+        data.extend_from_slice(&coff);
+        let mut opt = [0u8; 240];
+        opt[..2].copy_from_slice(&0x20bu16.to_le_bytes());
+        opt[60..64].copy_from_slice(&0x200u32.to_le_bytes());
+        opt[108..112].copy_from_slice(&16u32.to_le_bytes());
+        let certs = 0x200 + content.len();
+        opt[144..148].copy_from_slice(&(certs as u32).to_le_bytes());
+        opt[148..152].copy_from_slice(&(cert.len() as u32).to_le_bytes());
+        data.extend_from_slice(&opt);
+        let mut section = [0u8; 40];
+        section[..5].copy_from_slice(b".text");
+        section[16..20].copy_from_slice(&(content.len() as u32).to_le_bytes());
+        section[20..24].copy_from_slice(&0x200u32.to_le_bytes());
+        data.extend_from_slice(&section);
+        data.resize(0x200, 0);
+        data.extend_from_slice(content);
+        data.extend_from_slice(cert);
+        data
+    }
+
+    #[test]
+    fn test_authenticode_digest() -> Result<()> {
+        let unsigned = pe_image(b"code", b"");
+        let digest = authenticode_digest(&unsigned)?;
+        // The signatures and checksum are not part of the digest
+        let mut signed = pe_image(b"code", b"signature");
+        assert_eq!(digest, authenticode_digest(&signed)?);
+        signed[0x40 + 24 + 64] = 0xff;
+        assert_eq!(digest, authenticode_digest(&signed)?);
+        // But the code is
+        assert_ne!(digest, authenticode_digest(&pe_image(b"edoc", b""))?);
+        assert!(authenticode_digest(b"MZ").is_err());
+        assert!(authenticode_digest(&unsigned[..0x100]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_extend() {
+        let initial = extend([
+            sha256(BOOT_OPTION_ACTION).as_slice(),
+            sha256(SEPARATOR).as_slice(),
+        ]);
+        assert_eq!(
+            hex::encode(initial),
+            "7a94ffe8a7729a566d3d3c577fcb4b6b1e671f31540375f80eae6382ab785e35"
+        );
+        let digest: Vec<u8> = (0..32).collect();
+        assert_eq!(
+            extend([digest.as_slice()]),
+            sha256(&[vec![0; 32], digest].concat())
+        );
+    }
+
+    #[test]
+    fn test_predict_pcr4() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("fedora"))?;
+        std::fs::write(
+            td.path().join("fedora/shimx64.efi"),
+            pe_image(b"shim", b"sig"),
+        )?;
+        std::fs::write(
+            td.path().join("fedora/grubx64.efi"),
+            pe_image(b"grub", b"sig"),
+        )?;
+        let chain = [
+            "fedora/shimx64.efi".to_string(),
+            "fedora/grubx64.efi".to_string(),
+        ];
+        let r = predict_pcr4(td.path(), &chain)?;
+        assert_eq!(r.binaries.len(), 2);
+        assert_eq!(r.binaries[0].path, "fedora/shimx64.efi");
+        assert_eq!(
+            r.binaries[1].sha256,
+            hex::encode(authenticode_digest(&pe_image(b"grub", b""))?)
+        );
+        assert_ne!(r.sha256, predict_pcr4(td.path(), &chain[..1])?.sha256);
+        assert!(predict_pcr4(td.path(), &["fedora/mmx64.efi".to_string()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_authenticode_digests() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join("fedora"))?;
+        std::fs::write(
+            td.path().join("fedora/shimx64.efi"),
+            pe_image(b"shim", b"sig"),
+        )?;
+        std::fs::write(td.path().join("fedora/bogus.efi"), "not a PE image")?;
+        std::fs::write(td.path().join("fedora/grub.cfg"), "set timeout=1")?;
+        let paths = [
+            "fedora/shimx64.efi".to_string(),
+            "fedora/bogus.efi".to_string(),
+            "fedora/grub.cfg".to_string(),
+        ];
+        let r = authenticode_digests(td.path(), &paths)?;
+        assert_eq!(r.len(), 1);
+        assert_eq!(
+            r["fedora/shimx64.efi"],
+            hex::encode(authenticode_digest(&pe_image(b"shim", b""))?)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! The intent journal of component updates.
+//!
+//! Before an update modifies the installed content of a component, the
+//! versions it updates from and to, the changes it makes and its phase are
+//! recorded in `/boot/bootupd-transactions.json`; the entry is removed once
+//! the new state is saved.  An entry found by the next update means that
+//! the installed content and the state file may disagree: the update is
+//! rolled forward if its payload is still available, otherwise rolled back
+//! by restoring the recorded version from the available payload.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContentMetadata, SavedState};
+
+/// The journal, in `SavedState::STATEFILE_DIR`
+pub(crate) const TRANSACTIONS_NAME: &str = "bootupd-transactions.json";
+
+/// How far an update went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Phase {
+    /// The component is writing its new content
+    Writing,
+    /// The new content is written, and the state file is being saved
+    Recording,
+}
+
+/// An update of a component, until it is recorded in the state file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Transaction {
+    pub(crate) from: ContentMetadata,
+    pub(crate) to: ContentMetadata,
+    /// The changes of the update, as printed by `update --dry-run`
+    pub(crate) files: Vec<String>,
+    pub(crate) phase: Phase,
+}
+
+/// Maps a component name to its update in progress.
+pub(crate) type Transactions = BTreeMap<String, Transaction>;
+
+/// Load the journal of `sysroot`, dropping the updates which were recorded
+/// in `state` before the journal could be updated.
+#[context("Loading update journal")]
+pub(crate) fn load(sysroot: &openat::Dir, state: &SavedState) -> Result<Transactions> {
+    let Some(dir) = sysroot.sub_dir_optional(SavedState::STATEFILE_DIR)? else {
+        return Ok(Default::default());
+    };
+    let Some(f) = dir.open_file_optional(TRANSACTIONS_NAME)? else {
+        return Ok(Default::default());
+    };
+    let mut r: Transactions = serde_json::from_reader(std::io::BufReader::new(f))
+        .with_context(|| format!("parsing {TRANSACTIONS_NAME}"))?;
+    r.retain(|name, tx| {
+        state
+            .installed
+            .get(name)
+            .is_some_and(|inst| inst.meta.version != tx.to.version)
+    });
+    Ok(r)
+}
+
+/// Atomically replace the journal of `sysroot`, removing it if empty.
+#[context("Saving update journal")]
+pub(crate) fn save(sysroot: &openat::Dir, transactions: &Transactions) -> Result<()> {
+    let dir = sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+    if transactions.is_empty() {
+        dir.remove_file_optional(TRANSACTIONS_NAME)?;
+        return Ok(());
+    }
+    dir.write_file_with_sync(TRANSACTIONS_NAME, 0o644, |w| -> Result<()> {
+        serde_json::to_writer(w, transactions)?;
+        Ok(())
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::InstalledContent;
+
+    fn meta(version: &str) -> ContentMetadata {
+        ContentMetadata {
+            timestamp: Default::default(),
+            version: version.into(),
+            signing_keys: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_load_save() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir(td.path().join(SavedState::STATEFILE_DIR))?;
+        let sysroot = openat::Dir::open(td.path())?;
+        let mut state = SavedState::default();
+        assert!(load(&sysroot, &state)?.is_empty());
+
+        let tx = Transaction {
+            from: meta("1"),
+            to: meta("2"),
+            files: vec!["Write: EFI/fedora/grubx64.efi".into()],
+            phase: Phase::Writing,
+        };
+        let transactions = Transactions::from([("EFI".to_string(), tx.clone())]);
+        save(&sysroot, &transactions)?;
+        let inst = InstalledContent {
+            meta: meta("1"),
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        };
+        state.installed.insert("EFI".into(), inst);
+        assert_eq!(load(&sysroot, &state)?["EFI"], tx);
+
+        // Recorded in the state file, but not yet removed from the journal
+        state.installed.get_mut("EFI").unwrap().meta = meta("2");
+        assert!(load(&sysroot, &state)?.is_empty());
+
+        save(&sysroot, &Transactions::new())?;
+        let path = td
+            .path()
+            .join(SavedState::STATEFILE_DIR)
+            .join(TRANSACTIONS_NAME);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
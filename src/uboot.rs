@@ -0,0 +1,512 @@
+//! Support for U-Boot on single board computers, where the firmware (SPL,
+//! u-boot.itb etc.) is written at fixed raw offsets of the boot device.
+//!
+//! Which images go where is described by a per-board manifest, shipped by the
+//! OS in `/usr/lib/bootupd/u-boot/<board>.json`:
+//!
+//! ```json
+//! {
+//!   "compatible": ["pine64,rock64"],
+//!   "images": [
+//!     { "path": "/usr/share/uboot/rock64-rk3328/idbloader.img", "offset": 32768 },
+//!     { "path": "/usr/share/uboot/rock64-rk3328/u-boot.itb", "offset": 8388608 }
+//!   ]
+//! }
+//! ```
+//!
+//! An optional `hwpart` (e.g. `boot0`) selects an eMMC boot partition of the
+//! device instead of the device itself.  The kernel makes these read-only
+//! (`force_ro`); they are unlocked for writing, then synced and locked
+//! again.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::{Deserialize, Serialize};
+
+use crate::blockdev;
+use crate::component::*;
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+use crate::sha512string::SHA512String;
+
+/// The directory containing the per-board manifests
+pub(crate) const MANIFEST_DIR: &str = "usr/lib/bootupd/u-boot";
+/// The name of the manifest in the update payload for a board
+const PAYLOAD_MANIFEST: &str = "manifest.json";
+/// Use this board at install time, instead of detecting it
+const BOARD_ENV: &str = "BOOTUPD_UBOOT_BOARD";
+/// The device tree compatible strings of the running system
+const DT_COMPATIBLE: &str = "/proc/device-tree/compatible";
+/// Size of a disk sector as used by the partition table
+const SECTOR_SIZE: u64 = 512;
+/// End of the protective MBR, the primary GPT header and its partition
+/// entries (LBA 0 to 33)
+const PARTITION_TABLE_END: u64 = 34 * SECTOR_SIZE;
+
+/// A single image to write at a raw offset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RawImage {
+    /// Path to the image in the OS
+    pub(crate) path: String,
+    /// Byte offset on the target device
+    pub(crate) offset: u64,
+}
+
+impl RawImage {
+    /// The name of the image in the update payload
+    fn payload_name(&self) -> Result<&str> {
+        Utf8Path::new(&self.path)
+            .file_name()
+            .ok_or_else(|| anyhow!("Invalid image path {}", self.path))
+    }
+}
+
+/// Describes how to install U-Boot for a board.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BoardManifest {
+    /// Device tree compatible strings identifying the board
+    pub(crate) compatible: Vec<String>,
+    /// eMMC hardware partition to write to (e.g. `boot0`)
+    pub(crate) hwpart: Option<String>,
+    /// The images to write
+    pub(crate) images: Vec<RawImage>,
+}
+
+/// Returns `true` if the target root ships U-Boot manifests.
+pub(crate) fn is_available(root: &Path) -> bool {
+    root.join(MANIFEST_DIR).exists()
+}
+
+/// Parse the NUL-separated device tree compatible property.
+fn parse_compatible(buf: &[u8]) -> Vec<String> {
+    buf.split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Find the board whose manifest matches the most specific compatible string.
+fn find_board<'a>(
+    manifests: &'a BTreeMap<String, BoardManifest>,
+    compatible: &[String],
+) -> Option<&'a str> {
+    compatible.iter().find_map(|c| {
+        manifests
+            .iter()
+            .find(|(_, m)| m.compatible.contains(c))
+            .map(|(name, _)| name.as_str())
+    })
+}
+
+/// Load all board manifests from the update payload.
+fn load_payload_manifests(updated: &openat::Dir) -> Result<BTreeMap<String, BoardManifest>> {
+    let mut r = BTreeMap::new();
+    for entry in updated.list_dir(".")? {
+        let entry = entry?;
+        if !matches!(updated.get_file_type(&entry)?, openat::SimpleType::Dir) {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str() else {
+            bail!("Invalid UTF-8 filename: {:?}", entry.file_name())
+        };
+        let path = format!("{name}/{PAYLOAD_MANIFEST}");
+        let Some(f) = updated.open_file_optional(path.as_str())? else {
+            continue;
+        };
+        let manifest: BoardManifest = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("parsing {path}"))?;
+        r.insert(name.to_string(), manifest);
+    }
+    Ok(r)
+}
+
+/// Resolve the device to write to, taking into account an eMMC hardware
+/// partition, which is made writable until the returned guard is dropped.
+#[context("Resolving target device for {device}")]
+fn target_device(
+    device: &str,
+    hwpart: Option<&str>,
+) -> Result<(PathBuf, Option<blockdev::EmmcBootPartition>)> {
+    let Some(hwpart) = hwpart else {
+        return Ok((device.into(), None));
+    };
+    let target = format!("{device}{hwpart}");
+    let unlocked = blockdev::EmmcBootPartition::unlock(&target)?;
+    Ok((target.into(), Some(unlocked)))
+}
+
+/// Ensure none of the images would overwrite the partition table or a partition.
+#[context("Checking image placement on {device}")]
+fn check_placement(device: &str, images: &[(u64, u64)]) -> Result<()> {
+    let partitions = blockdev::partitions_of(device)?;
+    check_overlap(&partitions, images)
+}
+
+fn check_overlap(partitions: &[blockdev::Partition], images: &[(u64, u64)]) -> Result<()> {
+    for &(offset, len) in images {
+        if offset < PARTITION_TABLE_END {
+            bail!("Image at offset {offset} would overwrite the partition table");
+        }
+        let end = offset
+            .checked_add(len)
+            .ok_or_else(|| anyhow!("Image at offset {offset} is too large"))?;
+        for p in partitions.iter() {
+            let (pstart, pend) = (p.start * SECTOR_SIZE, (p.start + p.size) * SECTOR_SIZE);
+            if offset < pend && pstart < end {
+                bail!(
+                    "Image at offset {offset} would overwrite partition {}",
+                    p.node
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Format the location of an image as recorded in `raw_checksums`.
+fn format_location(target: &Path, offset: u64, len: u64) -> String {
+    format!("{}@{offset}+{len}", target.display())
+}
+
+/// Parse a location recorded by `format_location` into device, offset
+/// and length.
+fn parse_location(location: &str) -> Result<(&str, u64, u64)> {
+    let parse = || {
+        let (device, region) = location.rsplit_once('@')?;
+        let (offset, len) = region.split_once('+')?;
+        Some((device, offset.parse().ok()?, len.parse().ok()?))
+    };
+    parse().ok_or_else(|| anyhow!("Invalid location {location}"))
+}
+
+#[derive(Default)]
+pub(crate) struct UBoot {}
+
+impl UBoot {
+    /// Select the board to install for.
+    fn select_board(&self, manifests: &BTreeMap<String, BoardManifest>) -> Result<String> {
+        if let Some(board) = crate::util::getenv_utf8(BOARD_ENV)? {
+            if !manifests.contains_key(&board) {
+                bail!("No U-Boot manifest for board {board}");
+            }
+            return Ok(board);
+        }
+        if manifests.len() == 1 {
+            // SAFETY: We just checked the length
+            return Ok(manifests.keys().next().unwrap().clone());
+        }
+        let compatible = match std::fs::read(DT_COMPATIBLE) {
+            Ok(buf) => parse_compatible(&buf),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        find_board(manifests, &compatible)
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| {
+                anyhow!("Failed to find a U-Boot manifest for this board; set {BOARD_ENV}")
+            })
+    }
+
+    /// Write the images for the board to the device, returning their checksums.
+    #[context("Writing U-Boot images")]
+    fn write_images(
+        &self,
+        updated: &openat::Dir,
+        device: &str,
+    ) -> Result<BTreeMap<String, SHA512String>> {
+        let manifests = load_payload_manifests(updated)?;
+        let board = self.select_board(&manifests)?;
+        // SAFETY: select_board returns a known board
+        let manifest = manifests.get(&board).unwrap();
+        log::debug!("Installing U-Boot for board {board}");
+        let boarddir = updated.sub_dir(board.as_str())?;
+
+        let (target, unlocked) = target_device(device, manifest.hwpart.as_deref())?;
+        let mut images = Vec::new();
+        for image in manifest.images.iter() {
+            let mut buf = Vec::new();
+            boarddir
+                .open_file(image.payload_name()?)?
+                .read_to_end(&mut buf)?;
+            images.push((image, buf));
+        }
+        // eMMC boot partitions have no partition table
+        if manifest.hwpart.is_none() {
+            let placement: Vec<_> = images
+                .iter()
+                .map(|(i, buf)| (i.offset, buf.len() as u64))
+                .collect();
+            check_placement(device, &placement)?;
+        }
+
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(&target)
+            .with_context(|| format!("opening {target:?}"))?;
+        let mut checksums = BTreeMap::new();
+        for (image, buf) in images.iter() {
+            f.seek(SeekFrom::Start(image.offset))?;
+            f.write_all(buf)?;
+            let mut hasher = Hasher::new(MessageDigest::sha512())?;
+            hasher.update(buf)?;
+            let key = format_location(&target, image.offset, buf.len() as u64);
+            checksums.insert(key, SHA512String::from_hasher(&mut hasher));
+            println!("Wrote {} to {}", image.path, target.display());
+        }
+        f.sync_all()?;
+        drop(f);
+        // Lock the eMMC boot partition again
+        drop(unlocked);
+        Ok(checksums)
+    }
+
+    /// Read back and checksum an image at a location recorded by `write_images`.
+    fn checksum_location(location: &str) -> Result<SHA512String> {
+        let (device, offset, len) = parse_location(location)?;
+        let mut f = std::fs::File::open(device).with_context(|| format!("opening {device}"))?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut hasher = Hasher::new(MessageDigest::sha512())?;
+        let n = std::io::copy(&mut f.take(len), &mut hasher)?;
+        if n != len {
+            bail!("Short read from {location}");
+        }
+        Ok(SHA512String::from_hasher(&mut hasher))
+    }
+}
+
+impl Component for UBoot {
+    fn name(&self) -> &'static str {
+        "u-boot"
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        _dest_root: &str,
+        devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+        // Updates only support a single boot device
+        let [device] = devices else {
+            anyhow::bail!("Expected a single target device, found {}", devices.len());
+        };
+        let updated = src_root
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let raw_checksums = self.write_images(&updated, device)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: Some(raw_checksums),
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let sysroot = Path::new(sysroot_path);
+        let dest = component_updatedir(sysroot_path, self);
+        let mut sources = Vec::new();
+        for entry in std::fs::read_dir(sysroot.join(MANIFEST_DIR))? {
+            let path = entry?.path();
+            let Some(board) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if path.extension().and_then(|s| s.to_str()) != Some("json") {
+                continue;
+            }
+            let manifest: BoardManifest =
+                serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(&path)?))
+                    .with_context(|| format!("parsing {path:?}"))?;
+            let boarddir = dest.join(board);
+            std::fs::create_dir_all(&boarddir)?;
+            for image in manifest.images.iter() {
+                let src = sysroot.join(image.path.trim_start_matches('/'));
+                std::fs::copy(&src, boarddir.join(image.payload_name()?))
+                    .with_context(|| format!("copying {src:?}"))?;
+                sources.push(src);
+            }
+            std::fs::copy(&path, boarddir.join(PAYLOAD_MANIFEST))?;
+        }
+        if sources.is_empty() {
+            bail!("Failed to find any U-Boot images in {MANIFEST_DIR}");
+        }
+
+        let meta = packagesystem::query_payload(sysroot_path, sources, &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        // We have no way to know what is currently written on the device
+        Ok(None)
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, _: &ContentMetadata) -> Result<InstalledContent> {
+        bail!("Adoption is not supported for {}", self.name())
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        _: &InstalledContent,
+        _: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let device = blockdev::get_single_device("/")?;
+        let raw_checksums = self.write_images(&updated, &device)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: Some(raw_checksums),
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn plan_update(&self, sysroot: &openat::Dir, _: &InstalledContent) -> Result<Vec<String>> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let manifests = load_payload_manifests(&updated)?;
+        let board = self.select_board(&manifests)?;
+        // SAFETY: select_board returns a known board
+        let manifest = manifests.get(&board).unwrap();
+        let device = blockdev::get_single_device("/")?;
+        // Don't use target_device(), which makes eMMC boot partitions writable
+        let target = format!("{device}{}", manifest.hwpart.as_deref().unwrap_or_default());
+        let r = manifest
+            .images
+            .iter()
+            .map(|i| format!("Write: {} to {target}@{}", i.path, i.offset))
+            .collect();
+        Ok(r)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let Some(expected) = current.raw_checksums.as_ref() else {
+            return Ok(ValidationResult::Skip);
+        };
+        let mut errs = Vec::new();
+        for (location, expected) in expected.iter() {
+            if &Self::checksum_location(location)? != expected {
+                errs.push(ValidationError::new(
+                    ValidationErrorKind::Modified,
+                    location,
+                ));
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_board() -> Result<()> {
+        let compatible = parse_compatible(b"pine64,rock64\0rockchip,rk3328\0");
+        assert_eq!(compatible, ["pine64,rock64", "rockchip,rk3328"]);
+        let mut manifests = BTreeMap::new();
+        let rk3328: BoardManifest = serde_json::from_str(
+            r#"{"compatible": ["rockchip,rk3328"], "images": [{"path": "/usr/share/uboot/rk3328/u-boot.itb", "offset": 8388608}]}"#,
+        )?;
+        assert_eq!(rk3328.images[0].payload_name()?, "u-boot.itb");
+        assert_eq!(rk3328.hwpart, None);
+        manifests.insert("rk3328".to_string(), rk3328);
+        assert_eq!(find_board(&manifests, &compatible), Some("rk3328"));
+        let rock64 = BoardManifest {
+            compatible: vec!["pine64,rock64".into()],
+            hwpart: None,
+            images: Vec::new(),
+        };
+        manifests.insert("rock64".to_string(), rock64);
+        // The most specific compatible wins
+        assert_eq!(find_board(&manifests, &compatible), Some("rock64"));
+        assert_eq!(find_board(&manifests, &["foo,bar".to_string()]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_overlap() -> Result<()> {
+        let partitions = [blockdev::Partition {
+            node: "/dev/vda1".into(),
+            start: 32768,
+            size: 2048,
+            parttype: String::new(),
+        }];
+        check_overlap(&partitions, &[(32768, 4096), (8388608, 8192)])?;
+        // The MBR, GPT header and GPT entries
+        for offset in [0, 512, 8192, 33 * 512] {
+            assert!(check_overlap(&partitions, &[(offset, 512)]).is_err());
+        }
+        assert!(check_overlap(&partitions, &[(16 * 1024 * 1024 - 512, 1024)]).is_err());
+        assert!(check_overlap(&partitions, &[(u64::MAX, 1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_location() -> Result<()> {
+        let location = format_location(Path::new("/dev/mmcblk0boot0"), 32768, 4096);
+        assert_eq!(location, "/dev/mmcblk0boot0@32768+4096");
+        assert_eq!(
+            parse_location(&location)?,
+            ("/dev/mmcblk0boot0", 32768, 4096)
+        );
+        assert!(parse_location("/dev/mmcblk0@32768").is_err());
+        Ok(())
+    }
+}
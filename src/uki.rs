@@ -0,0 +1,448 @@
+/*
+ * Copyright (C) 2020 Red Hat, Inc.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+//! Support for Unified Kernel Images (UKIs), managed as `EFI/Linux/*.efi`
+//! on the XBOOTLDR partition if `/boot` is one, and on the ESP otherwise
+//! (where systemd-boot looks for them).
+//!
+//! The update payload holds the UKIs shipped in `/usr/lib/modules`, named
+//! after their kernel version.  Unlike the other components, the UKIs of
+//! previous updates are kept, up to the retention count of
+//! `/etc/bootupd/config.toml`, so that older kernels can still be booted.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+
+use crate::component::*;
+use crate::config::Config;
+use crate::efi::{self, Efi};
+use crate::filetree::{self, FileTree, FileTreeDiff};
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+
+/// The directory of the kernels, one sub-directory per version
+const MODULES_DIR: &str = "usr/lib/modules";
+
+/// The names of the UKI shipped in the directory of a kernel, e.g. by
+/// Fedora's `kernel-uki-virt`
+const UKI_NAMES: &[&str] = &["uki.efi", "vmlinuz-virt.efi"];
+
+/// The directory of the UKIs, relative to the ESP or XBOOTLDR partition
+const UKI_DIR: &str = "EFI/Linux";
+
+/// The UKIs shipped in `root`, by kernel version.
+fn find_ukis(root: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let modules = root.join(MODULES_DIR);
+    let mut r = BTreeMap::new();
+    let entries = match std::fs::read_dir(&modules) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(r),
+        Err(e) => return Err(e).with_context(|| format!("reading {modules:?}")),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let Some(kver) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+        if let Some(uki) = UKI_NAMES
+            .iter()
+            .map(|name| entry.path().join(name))
+            .find(|p| p.exists())
+        {
+            r.insert(kver, uki);
+        }
+    }
+    Ok(r)
+}
+
+/// Returns `true` if the target root ships a UKI.
+pub(crate) fn is_available(root: &Path) -> bool {
+    find_ukis(root).is_ok_and(|ukis| !ukis.is_empty())
+}
+
+/// The version of the booted kernel, whose UKI is never pruned.
+fn booted_kernel() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Compare two kernel versions (or UKI file names), with sequences of
+/// digits compared as numbers, so that `6.10` is newer than `6.9`.
+pub(crate) fn version_cmp(mut a: &str, mut b: &str) -> Ordering {
+    fn split(s: &str) -> (bool, &str, &str) {
+        let digits = s.starts_with(|c: char| c.is_ascii_digit());
+        let end = s
+            .find(|c: char| c.is_ascii_digit() != digits)
+            .unwrap_or(s.len());
+        (digits, &s[..end], &s[end..])
+    }
+    loop {
+        match (a.is_empty(), b.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            (false, false) => {}
+        }
+        let (a_digits, a_part, a_rest) = split(a);
+        let (b_digits, b_part, b_rest) = split(b);
+        let r = if a_digits && b_digits {
+            let a_part = a_part.trim_start_matches('0');
+            let b_part = b_part.trim_start_matches('0');
+            a_part.len().cmp(&b_part.len()).then(a_part.cmp(b_part))
+        } else {
+            a_part.cmp(b_part)
+        };
+        if r != Ordering::Equal {
+            return r;
+        }
+        a = a_rest;
+        b = b_rest;
+    }
+}
+
+/// The changes from the `current` UKIs to the `update` ones, keeping the
+/// newest previous UKIs up to `keep` in total, as well as the `booted` one;
+/// returns them along with the resulting tree.
+fn plan_prune(
+    current: &FileTree,
+    update: &FileTree,
+    keep: usize,
+    booted: Option<&str>,
+) -> Result<(FileTreeDiff, FileTree)> {
+    let mut diff = current.diff(update)?;
+    let mut previous: Vec<_> = diff.removals.iter().collect();
+    // Newest first
+    previous.sort_by(|a, b| version_cmp(b, a));
+    let mut tree = update.clone();
+    let mut n = update.children.len();
+    for name in previous {
+        let is_booted = booted.is_some_and(|k| name.strip_suffix(".efi") == Some(k));
+        if n < keep || is_booted {
+            tree.children
+                .insert(name.clone(), current.children[name].clone());
+            n += 1;
+        }
+    }
+    diff.removals.retain(|f| !tree.children.contains_key(f));
+    Ok((diff, tree))
+}
+
+#[derive(Default)]
+pub(crate) struct Uki {
+    esp: Efi,
+}
+
+impl Uki {
+    /// The XBOOTLDR partition mounted at `/boot` of `root`, if any.
+    fn xbootldr(root: &Path) -> Result<Option<PathBuf>> {
+        let boot = root.join("boot");
+        if !boot.exists() {
+            return Ok(None);
+        }
+        let bootdir = openat::Dir::open(&boot)?;
+        let fs = crate::filesystem::inspect_filesystem(&bootdir, ".")?;
+        if fs.fstype != "vfat" || !fs.source.starts_with("/dev/") {
+            return Ok(None);
+        }
+        if !crate::blockdev::is_xbootldr(&fs.source)? {
+            return Ok(None);
+        }
+        Ok(Some(boot))
+    }
+
+    /// The partition holding the UKIs of `root`, mounting the ESP if needed.
+    fn target_partition(&self, root: &Path) -> Result<PathBuf> {
+        if let Some(boot) = Self::xbootldr(root)? {
+            log::debug!("Using XBOOTLDR partition {boot:?}");
+            return Ok(boot);
+        }
+        self.esp.ensure_mounted_esp(root)
+    }
+
+    /// Open (creating it if needed) the directory of the UKIs of `root`.
+    #[context("Opening UKI directory")]
+    fn open_target(&self, root: &Path) -> Result<openat::Dir> {
+        let partition = self.target_partition(root)?;
+        let dir = openat::Dir::open(&partition)
+            .with_context(|| format!("opening {}", partition.display()))?;
+        efi::validate_esp(&dir)?;
+        dir.ensure_dir_all(UKI_DIR, 0o755)?;
+        Ok(dir.sub_dir(UKI_DIR)?)
+    }
+
+    /// The directory of the UKIs of the booted system, if there is one.
+    fn open_target_optional(&self) -> Result<Option<openat::Dir>> {
+        let root = Path::new("/");
+        if let Some(boot) = Self::xbootldr(root)? {
+            return Ok(openat::Dir::open(&boot)?.sub_dir_optional(UKI_DIR)?);
+        }
+        let Some(efidir) = self.esp.open_esp_optional()? else {
+            return Ok(None);
+        };
+        Ok(efidir.sub_dir_optional("Linux")?)
+    }
+
+    /// The installed UKIs and those of the update, with the changes between
+    /// them once pruned.
+    fn plan(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<(openat::Dir, FileTreeDiff, FileTree)> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed UKIs found!"))?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let config = Config::load(Path::new("/"))?;
+        let updatef =
+            FileTree::new_from_dir(&updated, config.update.digest).context("reading update dir")?;
+        let keep = config.uki.keep;
+        let booted = booted_kernel();
+        let (diff, tree) = plan_prune(currentf, &updatef, keep, booted.as_deref())?;
+        Ok((updated, diff, tree))
+    }
+}
+
+impl Component for Uki {
+    fn name(&self) -> &'static str {
+        "UKI"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        // UKIs written by e.g. kernel-install are managed by it
+        Ok(None)
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, _: &ContentMetadata) -> Result<InstalledContent> {
+        bail!("Adopting UKIs is not supported")
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+        log::debug!("Found metadata {}", meta.version);
+        let srcdir = src_root.sub_dir(&component_updatedirname(self))?;
+        let destd = self.open_target(Path::new(dest_root))?;
+        let digest = Config::load(&src_root.recover_path()?)?.update.digest;
+        let ft = FileTree::new_from_dir(&srcdir, digest)?;
+        let empty = FileTree {
+            children: BTreeMap::new(),
+        };
+        let diff = empty.diff(&ft)?;
+        filetree::apply_diff(&srcdir, &destd, &diff, None).context("copying UKIs")?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let ukis = find_ukis(Path::new(sysroot_path))?;
+        if ukis.is_empty() {
+            bail!("Failed to find UKIs in {sysroot_path}/{MODULES_DIR}");
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest).with_context(|| format!("creating {dest:?}"))?;
+        for (kver, src) in ukis.iter() {
+            let target = dest.join(format!("{kver}.efi"));
+            std::fs::copy(src, &target).with_context(|| format!("copying {src:?}"))?;
+        }
+
+        let meta = packagesystem::query_payload(sysroot_path, ukis.values(), &dest, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        progress: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let (updated, diff, tree) = self.plan(sysroot, current)?;
+        let destdir = self.open_target(Path::new("/"))?;
+        log::trace!("applying diff: {}", &diff);
+        let opts = filetree::ApplyUpdateOptions {
+            progress: Some(progress),
+            ..Default::default()
+        };
+        filetree::apply_diff(&updated, &destdir, &diff, Some(&opts))
+            .context("applying filesystem changes")?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(tree),
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Vec<String>> {
+        let (_, diff, _) = self.plan(sysroot, current)?;
+        let mut writes: Vec<_> = diff.additions.iter().chain(diff.changes.iter()).collect();
+        writes.sort();
+        let mut removals: Vec<_> = diff.removals.iter().collect();
+        removals.sort();
+        let r = writes
+            .into_iter()
+            .map(|f| format!("Write: {UKI_DIR}/{f}"))
+            .chain(
+                removals
+                    .into_iter()
+                    .map(|f| format!("Remove: {UKI_DIR}/{f}")),
+            )
+            .collect();
+        Ok(r)
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed UKIs found!"))?;
+        let Some(ukidir) = self.open_target_optional()? else {
+            return Ok(ValidationResult::Skip);
+        };
+        let diff = currentf.relative_diff_to(&ukidir)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Modified, f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(ValidationError::new(ValidationErrorKind::Missing, f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Digest;
+    use crate::filetree::FileMetadata;
+    use crate::sha512string::SHA512String;
+
+    fn tree(names: &[&str]) -> FileTree {
+        let children = names
+            .iter()
+            .map(|&name| {
+                let meta = FileMetadata {
+                    size: 1,
+                    digest: Digest::Sha512(SHA512String(format!("sha512:{name}"))),
+                    xattrs: Default::default(),
+                };
+                (name.to_string(), meta)
+            })
+            .collect();
+        FileTree { children }
+    }
+
+    #[test]
+    fn test_version_cmp() {
+        assert_eq!(
+            version_cmp("6.9.0-1.fc41.x86_64", "6.10.0-1.fc41.x86_64"),
+            Ordering::Less
+        );
+        assert_eq!(
+            version_cmp("6.10.2-1.fc41.x86_64", "6.10.10-1.fc41.x86_64"),
+            Ordering::Less
+        );
+        assert_eq!(version_cmp("6.1.0", "6.1.0"), Ordering::Equal);
+        assert_eq!(version_cmp("6.01", "6.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_plan_prune() -> Result<()> {
+        let current = tree(&["6.8.0.efi", "6.9.0.efi", "6.10.0.efi"]);
+        let update = tree(&["6.11.0.efi"]);
+
+        let (diff, r) = plan_prune(&current, &update, 3, None)?;
+        assert_eq!(
+            r.children.keys().collect::<Vec<_>>(),
+            ["6.10.0.efi", "6.11.0.efi", "6.9.0.efi"]
+        );
+        assert_eq!(diff.removals.iter().collect::<Vec<_>>(), ["6.8.0.efi"]);
+        assert_eq!(diff.additions.iter().collect::<Vec<_>>(), ["6.11.0.efi"]);
+
+        // The booted UKI is kept in addition
+        let (diff, r) = plan_prune(&current, &update, 2, Some("6.8.0"))?;
+        assert_eq!(
+            r.children.keys().collect::<Vec<_>>(),
+            ["6.10.0.efi", "6.11.0.efi", "6.8.0.efi"]
+        );
+        assert_eq!(diff.removals.iter().collect::<Vec<_>>(), ["6.9.0.efi"]);
+
+        // The UKIs of the update are never pruned
+        let (diff, r) = plan_prune(&current, &update, 0, None)?;
+        assert_eq!(r, update);
+        assert_eq!(diff.removals.len(), 3);
+
+        // An unchanged UKI isn't rewritten
+        let (diff, _) = plan_prune(&current, &tree(&["6.10.0.efi"]), 1, None)?;
+        assert_eq!(diff.count(), 2);
+        assert!(diff.additions.is_empty() && diff.changes.is_empty());
+        Ok(())
+    }
+}
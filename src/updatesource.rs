@@ -0,0 +1,324 @@
+//! Update payloads taken from a container image (e.g. a bootc image)
+//! instead of the booted deployment.
+//!
+//! The image is copied by skopeo to an OCI layout, and the content of
+//! `/usr/lib/bootupd/updates` is extracted from its layers.  During the
+//! update, it is bind mounted over the payloads of the booted deployment in
+//! a private mount namespace, so the components read it transparently.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use serde::Deserialize;
+
+use crate::model::BOOTUPD_UPDATES_DIR;
+use crate::util::CommandRunExt;
+
+/// Where the payloads of the image are extracted
+const IMAGE_UPDATES_DIR: &str = "/run/bootupd/image-updates";
+/// The reference of the image extracted to `IMAGE_UPDATES_DIR`
+const IMAGE_REF_FILE: &str = ".image-ref";
+
+/// The transports understood by skopeo
+const TRANSPORTS: &[&str] = &[
+    "containers-storage",
+    "dir",
+    "docker",
+    "docker-archive",
+    "docker-daemon",
+    "oci",
+    "oci-archive",
+];
+
+/// Components whose payload isn't self-contained: the BIOS and syslinux
+/// updates run the grub2-install or extlinux of the booted deployment, which
+/// don't match the version of the image.
+const EXCLUDED_COMPONENTS: &[&str] = &["BIOS", "syslinux"];
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Index {
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    layers: Vec<Descriptor>,
+}
+
+/// `imgref` with an explicit transport, defaulting to a registry.
+pub(crate) fn with_transport(imgref: &str) -> String {
+    match imgref.split_once(':') {
+        Some((transport, _)) if TRANSPORTS.contains(&transport) => imgref.to_string(),
+        _ => format!("docker://{imgref}"),
+    }
+}
+
+/// The path of the blob `digest` in the OCI layout `layout`.
+fn blob_path(layout: &Path, digest: &str) -> Result<PathBuf> {
+    match digest.split_once(':') {
+        Some((algo, hex))
+            if !algo.is_empty()
+                && !hex.is_empty()
+                && !algo.contains('/')
+                && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Ok(layout.join("blobs").join(algo).join(hex))
+        }
+        _ => bail!("Invalid digest {digest:?}"),
+    }
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let f = std::fs::File::open(path).with_context(|| format!("opening {path:?}"))?;
+    serde_json::from_reader(std::io::BufReader::new(f)).with_context(|| format!("parsing {path:?}"))
+}
+
+/// Remove `path`, whatever its type, if it exists.
+fn remove_all(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+    .with_context(|| format!("removing {path:?}"))
+}
+
+/// The path of a layer member, without a leading `./` or `/`.
+fn member_path(name: &str) -> &str {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    name.trim_start_matches('/').trim_end_matches('/')
+}
+
+/// Returns `true` if `path` is `dir` or below it.
+fn is_below(path: &str, dir: &str) -> bool {
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// What a layer member means for the update payloads.
+#[derive(Debug, PartialEq, Eq)]
+enum Member {
+    /// Outside of the payloads
+    Ignored,
+    /// Content to extract
+    Payload,
+    /// A whiteout, removing this path of the previous layers
+    Whiteout(String),
+}
+
+fn classify(name: &str) -> Member {
+    let path = member_path(name);
+    let (dir, base) = path.rsplit_once('/').unwrap_or(("", path));
+    if let Some(target) = base.strip_prefix(".wh.") {
+        // An opaque directory hides the content of the previous layers
+        let removed = match (target, dir) {
+            (".wh..opq", dir) => dir.to_string(),
+            (target, "") => target.to_string(),
+            (target, dir) => format!("{dir}/{target}"),
+        };
+        // Also the whiteout of a parent of the payloads
+        if removed.is_empty()
+            || is_below(&removed, BOOTUPD_UPDATES_DIR)
+            || is_below(BOOTUPD_UPDATES_DIR, &removed)
+        {
+            return Member::Whiteout(removed);
+        }
+        return Member::Ignored;
+    }
+    if is_below(path, BOOTUPD_UPDATES_DIR) {
+        Member::Payload
+    } else {
+        Member::Ignored
+    }
+}
+
+/// Apply the layer `layer` to the payloads extracted in `dest`.
+#[context("Extracting layer {layer:?}")]
+fn extract_layer(layer: &Path, dest: &Path) -> Result<()> {
+    let output = Command::new("tar").arg("-tf").arg(layer).output()?;
+    if !output.status.success() {
+        bail!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let list = String::from_utf8(output.stdout).context("Invalid UTF-8 in layer")?;
+    let mut members = Vec::new();
+    for name in list.lines() {
+        match classify(name) {
+            Member::Ignored => {}
+            Member::Payload => members.push(name),
+            Member::Whiteout(removed) => {
+                // A parent of the payloads removes all of them
+                let removed = if is_below(&removed, BOOTUPD_UPDATES_DIR) {
+                    removed.as_str()
+                } else {
+                    BOOTUPD_UPDATES_DIR
+                };
+                remove_all(&dest.join(removed))?;
+            }
+        }
+    }
+    if members.is_empty() {
+        return Ok(());
+    }
+    Command::new("tar")
+        .arg("-xf")
+        .arg(layer)
+        .arg("-C")
+        .arg(dest)
+        .arg("--no-recursion")
+        .args(members)
+        .run()
+}
+
+/// Copy the image `imgref` and extract its update payloads.
+#[context("Fetching update payloads from {imgref}")]
+pub(crate) fn fetch(imgref: &str) -> Result<()> {
+    let dest = Path::new(IMAGE_UPDATES_DIR);
+    remove_all(dest)?;
+    std::fs::create_dir_all(dest).with_context(|| format!("creating {dest:?}"))?;
+    // Layers can be large, so not in /tmp
+    let tmpdir = tempfile::tempdir_in("/var/tmp")?;
+    let layout = tmpdir.path().join("image");
+    Command::new("skopeo")
+        .args(["copy", "--quiet"])
+        .arg(with_transport(imgref))
+        .arg(format!("oci:{}:image", layout.display()))
+        .run()?;
+    let index: Index = read_json(&layout.join("index.json"))?;
+    let [manifest] = index.manifests.as_slice() else {
+        bail!(
+            "Expected a single manifest, found {}",
+            index.manifests.len()
+        );
+    };
+    let manifest: Manifest = read_json(&blob_path(&layout, &manifest.digest)?)?;
+    for layer in manifest.layers.iter() {
+        extract_layer(&blob_path(&layout, &layer.digest)?, dest)?;
+    }
+    let updates = dest.join(BOOTUPD_UPDATES_DIR);
+    if !updates.is_dir() {
+        bail!("No update payloads in image");
+    }
+    for name in EXCLUDED_COMPONENTS {
+        remove_all(&updates.join(format!("{name}.json")))?;
+        remove_all(&updates.join(format!("{name}.tar.zst")))?;
+        remove_all(&updates.join(name))?;
+    }
+    std::fs::write(dest.join(IMAGE_REF_FILE), imgref)?;
+    Ok(())
+}
+
+/// The update payloads of an image, used in place of those of the booted
+/// deployment until dropped.
+#[derive(Debug)]
+pub(crate) struct ImageUpdates {
+    _private: (),
+}
+
+impl ImageUpdates {
+    /// Use the update payloads of `imgref`, fetched beforehand (e.g. before
+    /// running without network access) or now.  This must be called before
+    /// starting any thread.
+    #[context("Using update payloads from {imgref}")]
+    pub(crate) fn activate(imgref: &str) -> Result<Self> {
+        let dest = Path::new(IMAGE_UPDATES_DIR);
+        let fetched = std::fs::read_to_string(dest.join(IMAGE_REF_FILE)).ok();
+        if fetched.as_deref() != Some(imgref) {
+            fetch(imgref)?;
+        }
+        let r = Self { _private: () };
+        crate::util::enter_private_mount_namespace()?;
+        Command::new("mount")
+            .arg("--bind")
+            .arg(dest.join(BOOTUPD_UPDATES_DIR))
+            .arg(Path::new("/").join(BOOTUPD_UPDATES_DIR))
+            .run()?;
+        log::info!("Using update payloads from {imgref}");
+        crate::payloadview::activate(Path::new("/"))?;
+        Ok(r)
+    }
+}
+
+impl Drop for ImageUpdates {
+    fn drop(&mut self) {
+        if let Err(e) = remove_all(Path::new(IMAGE_UPDATES_DIR)) {
+            log::warn!("{e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_transport() {
+        assert_eq!(
+            with_transport("quay.io/fedora/fedora-bootc:41"),
+            "docker://quay.io/fedora/fedora-bootc:41"
+        );
+        assert_eq!(
+            with_transport("docker://quay.io/fedora/fedora-bootc:41"),
+            "docker://quay.io/fedora/fedora-bootc:41"
+        );
+        assert_eq!(
+            with_transport("containers-storage:localhost/os"),
+            "containers-storage:localhost/os"
+        );
+        assert_eq!(
+            with_transport("localhost:5000/os"),
+            "docker://localhost:5000/os"
+        );
+    }
+
+    #[test]
+    fn test_blob_path() -> Result<()> {
+        let layout = Path::new("/l");
+        assert_eq!(
+            blob_path(layout, "sha256:0123abcd")?,
+            Path::new("/l/blobs/sha256/0123abcd")
+        );
+        assert!(blob_path(layout, "sha256:../../etc").is_err());
+        assert!(blob_path(layout, "0123abcd").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify("usr/bin/bash"), Member::Ignored);
+        assert_eq!(classify("usr/lib/bootupd/updates"), Member::Payload);
+        assert_eq!(classify("./usr/lib/bootupd/updates/"), Member::Payload);
+        assert_eq!(
+            classify("usr/lib/bootupd/updates/EFI.json"),
+            Member::Payload
+        );
+        assert_eq!(classify("usr/lib/bootupd/updates-old/x"), Member::Ignored);
+        assert_eq!(
+            classify("usr/lib/bootupd/updates/EFI/.wh.grubx64.efi"),
+            Member::Whiteout("usr/lib/bootupd/updates/EFI/grubx64.efi".into())
+        );
+        assert_eq!(
+            classify("usr/lib/bootupd/updates/EFI/.wh..wh..opq"),
+            Member::Whiteout("usr/lib/bootupd/updates/EFI".into())
+        );
+        assert_eq!(
+            classify("usr/lib/.wh.bootupd"),
+            Member::Whiteout("usr/lib/bootupd".into())
+        );
+        assert_eq!(classify("usr/lib/.wh.grub"), Member::Ignored);
+        assert_eq!(classify("usr/share/.wh..wh..opq"), Member::Ignored);
+        assert_eq!(classify(".wh.usr"), Member::Whiteout("usr".into()));
+    }
+}
@@ -80,6 +80,50 @@ pub(crate) fn ensure_writable_mount<P: AsRef<Path>>(p: P) -> Result<()> {
     Ok(())
 }
 
+/// Remount `p` read-only, e.g. once done writing to it.
+pub(crate) fn remount_read_only<P: AsRef<Path>>(p: P) -> Result<()> {
+    let p = p.as_ref();
+    Command::new("mount")
+        .args(["-o", "remount,ro"])
+        .arg(p)
+        .run()
+        .with_context(|| format!("Failed to remount {p:?} read-only"))
+}
+
+/// Whether `enter_private_mount_namespace` was called.
+static ENTERED_MOUNT_NAMESPACE: std::sync::atomic::AtomicBool =
+    std::sync::atomic::AtomicBool::new(false);
+
+/// Move this process to a private mount namespace, once, so that what it
+/// mounts (e.g. an ESP the system keeps unmounted) is neither visible to
+/// the rest of the system nor left behind if we crash.  Mounts made
+/// elsewhere are still propagated to us.
+///
+/// This only applies to the calling thread and the threads (and processes)
+/// it creates later, so it must be called from the main thread before
+/// starting any other: the daemon and CLI entry points call it at startup.
+pub(crate) fn enter_private_mount_namespace() -> Result<()> {
+    if in_private_mount_namespace() {
+        return Ok(());
+    }
+    // SAFETY: unshare() has no memory safety requirements
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Creating mount namespace");
+    }
+    Command::new("mount")
+        .args(["--make-rslave", "/"])
+        .run()
+        .context("Making mounts private")?;
+    ENTERED_MOUNT_NAMESPACE.store(true, std::sync::atomic::Ordering::SeqCst);
+    log::debug!("Entered a private mount namespace");
+    Ok(())
+}
+
+/// Whether this process moved to a private mount namespace.
+pub(crate) fn in_private_mount_namespace() -> bool {
+    ENTERED_MOUNT_NAMESPACE.load(std::sync::atomic::Ordering::SeqCst)
+}
+
 /// Runs the provided Command object, captures its stdout, and swallows its stderr except on
 /// failure. Returns a Result<String> describing whether the command failed, and if not, its
 /// standard output. Output is assumed to be UTF-8. Errors are adequately prefixed with the full
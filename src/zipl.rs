@@ -0,0 +1,180 @@
+//! Support for the IBM Z (s390x) boot loader, which is installed by `zipl`.
+
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use fn_error_context::context;
+
+use crate::component::*;
+use crate::model::*;
+use crate::packagesystem;
+use crate::progress::ProgressFn;
+
+/// zipl file path
+pub(crate) const ZIPL_BIN: &str = "usr/sbin/zipl";
+
+/// The bootmap written by zipl into the target directory
+const ZIPL_BOOTMAP: &str = "boot/bootmap";
+
+#[derive(Default)]
+pub(crate) struct Zipl {}
+
+impl Zipl {
+    // Run zipl, using the BLS entries from the target /boot
+    #[context("Running zipl")]
+    fn run_zipl(&self, dest_root: &str) -> Result<()> {
+        let zipl = Path::new("/").join(ZIPL_BIN);
+        if !zipl.exists() {
+            bail!("Failed to find {:?}", zipl);
+        }
+        let boot_dir = Path::new(dest_root).join("boot");
+
+        let mut cmd = Command::new(zipl);
+        cmd.args(["--target", boot_dir.to_str().unwrap()]);
+        let config = Path::new(dest_root).join("etc/zipl.conf");
+        if config.exists() {
+            cmd.args(["--config", config.to_str().unwrap()]);
+        }
+
+        let cmdout = cmd.output()?;
+        if !cmdout.status.success() {
+            std::io::stderr().write_all(&cmdout.stderr)?;
+            bail!("Failed to run {:?}", cmd);
+        }
+        Ok(())
+    }
+}
+
+impl Component for Zipl {
+    fn name(&self) -> &'static str {
+        "zipl"
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _devices: &[String],
+        _opts: &InstallComponentOptions,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            anyhow::bail!("No update metadata for component {} found", self.name());
+        };
+
+        self.run_zipl(dest_root)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: None,
+            adopted_from: None,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn generate_update_metadata(
+        &self,
+        sysroot_path: &str,
+        opts: &GenerateOptions,
+    ) -> Result<ContentMetadata> {
+        let zipl = Path::new(sysroot_path).join(ZIPL_BIN);
+        if !zipl.exists() {
+            bail!("Failed to find {:?}", zipl);
+        }
+
+        // Query the package database for the package owning zipl (i.e. s390utils)
+        let meta = packagesystem::query_payload(sysroot_path, [&zipl], &zipl, opts)?;
+        write_update_metadata(sysroot_path, self, &meta)?;
+        Ok(meta)
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if !Path::new("/").join(ZIPL_BOOTMAP).exists() {
+            log::debug!("No zipl bootmap found, skip adopt");
+            return Ok(None);
+        }
+        crate::component::query_adopt_state()
+    }
+
+    fn adopt_update(&self, _: &openat::Dir, update: &ContentMetadata) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+
+        self.run_zipl("/")?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: None,
+            adopted_from: Some(meta.version),
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        _: &InstalledContent,
+        _: ProgressFn,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let dest_fd = format!("/proc/self/fd/{}", sysroot.as_raw_fd());
+        let dest_root = std::fs::read_link(dest_fd)?;
+        let dest_root = dest_root.to_string_lossy().into_owned();
+        self.run_zipl(&dest_root)?;
+
+        let adopted_from = None;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: None,
+            adopted_from,
+            raw_checksums: None,
+            esps: None,
+            bios_modules: None,
+            pcr4: None,
+            firmware: None,
+            dbx: None,
+            rotation: None,
+            pending_nvram: None,
+            rebuilt: false,
+            sbat: None,
+            authenticode: None,
+        })
+    }
+
+    fn plan_update(&self, _: &openat::Dir, _: &InstalledContent) -> Result<Vec<String>> {
+        Ok(vec![format!("Run: /{ZIPL_BIN}")])
+    }
+
+    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
+        Ok(ValidationResult::Skip)
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}